@@ -0,0 +1,158 @@
+//! the heap used for 2000 pods as `serde_json::Value`s and by the cache, uncompressed, with zstd and filled by a
+//! list: `cargo bench --bench memory`
+//!
+//! A separate target, so the counting allocator doesn't replace the allocator of the tests.
+
+use big_brother::{
+    engine::{Cache, CacheCompression},
+    k8s_client::api::{Resource, ResourceId, ResourceVersion},
+};
+use serde_json::{json, Value};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+const PODS: usize = 2000;
+
+/// counts the bytes the current thread has allocated and not freed yet
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+fn count(bytes: isize) {
+    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + bytes));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size() as isize);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+/// what `build` keeps allocated on this thread
+fn allocated<T>(build: impl FnOnce() -> T) -> (usize, T) {
+    let before = ALLOCATED.with(Cell::get);
+    let built = build();
+    (ALLOCATED.with(Cell::get).saturating_sub(before) as usize, built)
+}
+
+/// a pod like a deployment creates them
+fn pod(i: usize) -> (ResourceId, Value) {
+    let app = format!("app-{}", i % 50);
+    let name = format!("{}-7d9f8b6c5-{:05}", app, i);
+    let namespace = format!("team-{}", i % 20);
+    let container = |name: &str| {
+        json!({
+            "name": name, "image": format!("registry.example.com/{}:1.2.{}", app, i % 7),
+            "ports": [{ "containerPort": 8080, "protocol": "TCP" }],
+            "env": [{ "name": "APP", "value": app }, { "name": "LOG_LEVEL", "value": "info" }],
+            "resources": { "requests": { "cpu": "100m", "memory": "128Mi" } },
+            "volumeMounts": [{ "name": "token", "mountPath": "/var/run/secrets/kubernetes.io/serviceaccount" }],
+            "terminationMessagePath": "/dev/termination-log", "imagePullPolicy": "IfNotPresent",
+        })
+    };
+    let condition = |ty: &str| json!({ "type": ty, "status": "True", "lastTransitionTime": "2024-01-01T00:00:00Z" });
+    let object = json!({
+        "apiVersion": "v1", "kind": "Pod",
+        "metadata": {
+            "name": name, "namespace": namespace, "uid": format!("{:08x}-pod", i),
+            "resourceVersion": (i + 1).to_string(), "creationTimestamp": "2024-01-01T00:00:00Z",
+            "labels": { "app": app, "pod-template-hash": "7d9f8b6c5", "tier": "backend" },
+            "ownerReferences": [{
+                "apiVersion": "apps/v1", "kind": "ReplicaSet", "name": format!("{}-7d9f8b6c5", app),
+                "uid": format!("{:08x}-rs", i % 50), "controller": true, "blockOwnerDeletion": true,
+            }],
+        },
+        "spec": {
+            "containers": [container("main"), container("sidecar")],
+            "nodeName": format!("node-{}", i % 30), "restartPolicy": "Always", "dnsPolicy": "ClusterFirst",
+            "serviceAccountName": "default", "terminationGracePeriodSeconds": 30,
+        },
+        "status": {
+            "phase": "Running", "podIP": format!("10.0.{}.{}", i / 250 % 250, i % 250), "qosClass": "Burstable",
+            "conditions": [condition("Initialized"), condition("Ready"), condition("PodScheduled")],
+        },
+    });
+    let res = ResourceId {
+        api_version: "v1".into(),
+        kind: "Pod".into(),
+        name,
+        namespace: Some(namespace),
+    };
+    (res, object)
+}
+
+fn main() {
+    let pods = (0..PODS).map(pod).collect::<Vec<_>>();
+    let json = pods
+        .iter()
+        .map(|(_, object)| serde_json::to_vec(object).unwrap().len())
+        .sum::<usize>();
+    let (values, _kept) = allocated(|| pods.iter().map(|(_, object)| object.clone()).collect::<Vec<_>>());
+    let cached = |compression| {
+        allocated(|| {
+            let mut cache = Cache::with_capacity(1, compression);
+            for (rv, (res, object)) in pods.iter().enumerate() {
+                cache.update(res.clone(), rv as ResourceVersion + 1, object.clone());
+            }
+            cache
+        })
+    };
+    let (plain, cache) = cached(CacheCompression::None);
+    let (zstd, compressed) = cached(CacheCompression::Zstd);
+    // the items of a list come without their apiVersion and kind, the list has them
+    let items = pods
+        .iter()
+        .map(|(res, object)| {
+            let mut rest = object.clone();
+            let map = rest.as_object_mut().unwrap();
+            map.remove("apiVersion");
+            map.remove("kind");
+            (res.clone(), rest)
+        })
+        .collect::<Vec<_>>();
+    let (listed, listed_cache) = allocated(|| {
+        let mut cache = Cache::with_capacity(1, CacheCompression::None);
+        for (rv, (res, rest)) in items.iter().enumerate() {
+            let item = Resource {
+                api_version: "v1".into(),
+                kind: "Pod".into(),
+                rest: rest.clone(),
+            };
+            cache.update(res.clone(), rv as ResourceVersion + 1, item);
+        }
+        cache
+    });
+    println!("{} pods, {} bytes of JSON", PODS, json);
+    println!("serde_json::Value         {:>10} bytes", values);
+    println!("cache                     {:>10} bytes", plain);
+    println!("cache, zstd               {:>10} bytes", zstd);
+    println!("cache, listed             {:>10} bytes", listed);
+    assert!(plain < values, "{} < {}", plain, values);
+    assert!(zstd < plain, "{} < {}", zstd, plain);
+    assert!(listed < values, "{} < {}", listed, values);
+    assert_eq!(compressed.get(&pods[7].0).unwrap().object(), pods[7].1);
+    assert_eq!(
+        compressed.get(&pods[7].0).unwrap().line(),
+        cache.get(&pods[7].0).unwrap().line()
+    );
+    assert_eq!(
+        listed_cache.get(&pods[7].0).unwrap().line(),
+        cache.get(&pods[7].0).unwrap().line()
+    );
+}
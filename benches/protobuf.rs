@@ -0,0 +1,255 @@
+//! decoding what the API server sends for 2000 pods, as JSON the way the engine did before protobuf and as protobuf:
+//! `cargo bench --bench protobuf`
+//!
+//! The JSON watch is only decoded into `destream_json::Value`s here, the engine serializes them into the cached line
+//! without converting them to `serde_json` first.
+
+use big_brother::k8s_client::protobuf;
+use bytes::Bytes;
+use destream_json::{try_decode_iter, Value as DValue};
+use futures_util::{stream, StreamExt};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+const PODS: usize = 2000;
+/// what a response body arrives in
+const CHUNK_SIZE: usize = 16 * 1024;
+
+fn key(tag: u32, wire_type: u8, out: &mut Vec<u8>) {
+    varint(u64::from(tag) << 3 | u64::from(wire_type), out);
+}
+
+fn varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn int(tag: u32, value: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    key(tag, 0, &mut out);
+    varint(value as u64, &mut out);
+    out
+}
+
+fn len(tag: u32, bytes: impl AsRef<[u8]>) -> Vec<u8> {
+    let bytes = bytes.as_ref();
+    let mut out = Vec::new();
+    key(tag, 2, &mut out);
+    varint(bytes.len() as u64, &mut out);
+    out.extend_from_slice(bytes);
+    out
+}
+
+fn entry(tag: u32, key: &str, value: &str) -> Vec<u8> {
+    len(tag, [len(1, key), len(2, value)].concat())
+}
+
+fn object(api_version: &str, kind: &str, raw: &[u8]) -> Vec<u8> {
+    let type_meta = [len(1, api_version), len(2, kind)].concat();
+    [&b"k8s\0"[..], &len(1, type_meta), &len(2, raw)].concat()
+}
+
+fn time(tag: u32, seconds: i64) -> Vec<u8> {
+    len(tag, int(1, seconds))
+}
+
+/// a pod of a deployment as the API server would encode it
+fn pod(i: usize) -> Vec<u8> {
+    let name = format!("web-7d4b9c8f6d-{:05}", i);
+    let metadata = [
+        len(1, &name),
+        len(2, "web-7d4b9c8f6d-"),
+        len(3, "default"),
+        len(5, format!("0b5c4a2e-1f3d-4c6b-9a8e-{:012}", i)),
+        len(6, (1000 + i).to_string()),
+        time(8, 1_700_000_000),
+        entry(11, "app", "web"),
+        entry(11, "pod-template-hash", "7d4b9c8f6d"),
+        entry(12, "kubectl.kubernetes.io/restartedAt", "2023-11-14T22:13:20Z"),
+        len(
+            13,
+            [
+                len(1, "ReplicaSet"),
+                len(3, "web-7d4b9c8f6d"),
+                len(4, "5f0c1a3e-7d2b-4e8f-a6c9-1b2d3e4f5a6b"),
+                len(5, "apps/v1"),
+                int(6, 1),
+                int(7, 1),
+            ]
+            .concat(),
+        ),
+    ]
+    .concat();
+    let container = [
+        len(1, "web"),
+        len(2, "registry.example.com/web:1.4.2"),
+        len(4, "--port=8080"),
+        len(4, "--log-format=json"),
+        len(6, [len(1, "http"), int(3, 8080), len(4, "TCP")].concat()),
+        len(7, [len(1, "RUST_LOG"), len(2, "info")].concat()),
+        len(7, [len(1, "LISTEN"), len(2, "0.0.0.0:8080")].concat()),
+        len(
+            8,
+            [
+                len(1, [len(1, "memory"), len(2, len(1, "256Mi"))].concat()),
+                len(2, [len(1, "cpu"), len(2, len(1, "100m"))].concat()),
+                len(2, [len(1, "memory"), len(2, len(1, "128Mi"))].concat()),
+            ]
+            .concat(),
+        ),
+        len(
+            9,
+            [
+                len(1, "kube-api-access"),
+                int(2, 1),
+                len(3, "/var/run/secrets/kubernetes.io/serviceaccount"),
+            ]
+            .concat(),
+        ),
+        len(13, "/dev/termination-log"),
+        len(14, "IfNotPresent"),
+        len(20, "File"),
+    ]
+    .concat();
+    let spec = [
+        len(
+            1,
+            [
+                len(1, "config"),
+                len(2, len(19, [len(1, len(1, "web-config")), int(3, 420)].concat())),
+            ]
+            .concat(),
+        ),
+        len(2, container),
+        len(3, "Always"),
+        int(4, 30),
+        len(6, "ClusterFirst"),
+        len(8, "web"),
+        len(9, "web"),
+        len(10, format!("node-{}", i % 50)),
+        len(19, "default-scheduler"),
+        len(
+            22,
+            [
+                len(1, "node.kubernetes.io/not-ready"),
+                len(2, "Exists"),
+                len(4, "NoExecute"),
+                int(5, 300),
+            ]
+            .concat(),
+        ),
+        int(25, 0),
+        int(30, 1),
+        len(31, "PreemptLowerPriority"),
+    ]
+    .concat();
+    let condition = |ty: &str| len(2, [len(1, ty), len(2, "True"), time(4, 1_700_000_010)].concat());
+    let status = [
+        len(1, "Running"),
+        condition("Initialized"),
+        condition("Ready"),
+        condition("ContainersReady"),
+        condition("PodScheduled"),
+        len(5, format!("10.0.0.{}", i % 50)),
+        len(6, format!("10.244.{}.{}", i / 250, i % 250)),
+        time(7, 1_700_000_000),
+        len(
+            8,
+            [
+                len(1, "web"),
+                len(2, len(2, time(1, 1_700_000_005))),
+                len(3, ""),
+                int(4, 1),
+                int(5, 0),
+                len(6, "registry.example.com/web:1.4.2"),
+                len(
+                    7,
+                    "registry.example.com/web@sha256:4f1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c5d6e7f8",
+                ),
+                len(8, format!("containerd://{:064x}", i)),
+                int(9, 1),
+            ]
+            .concat(),
+        ),
+        len(9, "Burstable"),
+    ]
+    .concat();
+    [len(1, metadata), len(2, spec), len(3, status)].concat()
+}
+
+fn chunks(body: &[u8]) -> Vec<Bytes> {
+    body.chunks(CHUNK_SIZE).map(Bytes::copy_from_slice).collect()
+}
+
+/// mean time of a run, after a warm-up
+fn measure<T>(name: &str, size: usize, mut run: impl FnMut() -> T) {
+    drop(run());
+    let (mut runs, started) = (0, Instant::now());
+    while started.elapsed() < Duration::from_secs(2) {
+        drop(run());
+        runs += 1;
+    }
+    let mean = started.elapsed() / runs;
+    println!("{:<24}{:>10} bytes{:>10.2} ms", name, size, mean.as_secs_f64() * 1000.0);
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let pods = (0..PODS).map(pod).collect::<Vec<_>>();
+
+    let items = pods.iter().map(|pod| len(2, pod)).collect::<Vec<_>>().concat();
+    let list = object("v1", "PodList", &[len(1, len(2, "3000")), items].concat());
+    let decoded = protobuf::decode(&list).unwrap();
+    let list_json = serde_json::to_vec(&decoded).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&list_json).unwrap(),
+        decoded
+    );
+
+    let watch = pods
+        .iter()
+        .map(|pod| {
+            let event = [len(1, "MODIFIED"), len(2, len(1, object("v1", "Pod", pod)))].concat();
+            [&(event.len() as u32).to_be_bytes()[..], &event].concat()
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let watch_json = pods
+        .iter()
+        .map(|pod| {
+            let object = protobuf::decode(&object("v1", "Pod", pod)).unwrap();
+            let mut line = serde_json::to_vec(&json!({"type": "MODIFIED", "object": object})).unwrap();
+            line.push(b'\n');
+            line
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    let (watch, watch_json) = (chunks(&watch), chunks(&watch_json));
+
+    println!("{} pods", PODS);
+    measure("list, JSON", list_json.len(), || {
+        serde_json::from_slice::<serde_json::Value>(&list_json).unwrap()
+    });
+    measure("list, protobuf", list.len(), || protobuf::decode(&list).unwrap());
+    measure("watch, JSON", watch_json.iter().map(Bytes::len).sum(), || {
+        rt.block_on(async {
+            let body = stream::iter(watch_json.iter().cloned().map(Ok::<_, reqwest::Error>));
+            let events = try_decode_iter::<_, _, DValue>((), body).await;
+            let events = events.map(Result::unwrap).collect::<Vec<_>>().await;
+            assert_eq!(events.len(), PODS);
+        })
+    });
+    measure("watch, protobuf", watch.iter().map(Bytes::len).sum(), || {
+        rt.block_on(async {
+            let body = stream::iter(watch.iter().cloned().map(Ok::<_, reqwest::Error>));
+            let events = protobuf::watch_events(body)
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(events.len(), PODS);
+        })
+    });
+}
@@ -0,0 +1,235 @@
+//! async client for big-brother's HTTP API
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), big_brother_client::ClientError> {
+//! use futures_util::StreamExt;
+//!
+//! let client = big_brother_client::Client::new("http://big-brother:8080")?.with_token("secret");
+//! let mut events = Box::pin(client.watch().include(["Pod", "Service"]).stream());
+//! while let Some(event) = events.next().await {
+//!     let event = event?;
+//!     println!("{} {} {}", event.event_type, event.resource.kind, event.resource.name);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+mod mirror;
+mod watch;
+
+pub use mirror::{Mirror, MirrorTask};
+pub use watch::{Filter, Kinds, Watch};
+
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// identifies an object across all resource types
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResourceId {
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
+    pub namespace: Option<String>,
+}
+
+impl ResourceId {
+    /// from `apiVersion`, `kind` and `metadata`, `None` if one of them is missing
+    pub fn of(object: &Value) -> Option<Self> {
+        let str_at = |pointer: &str| object.pointer(pointer).and_then(Value::as_str).map(String::from);
+        Some(Self {
+            api_version: str_at("/apiVersion")?,
+            kind: str_at("/kind")?,
+            name: str_at("/metadata/name")?,
+            namespace: str_at("/metadata/namespace"),
+        })
+    }
+}
+
+pub type ResourceVersion = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum EventType {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventType::Added => write!(f, "added"),
+            EventType::Modified => write!(f, "modified"),
+            EventType::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+/// one change as served by `/watch`, deleted objects only carry `apiVersion`, `kind` and `metadata`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputEvent {
+    pub event_type: EventType,
+    pub resource: ResourceId,
+    pub resource_version: ResourceVersion,
+    pub object: Value,
+}
+
+/// the cached objects as of `resource_version`, a watch from `resource_version + 1` continues where it ends
+#[derive(Debug, Clone, PartialEq)]
+pub struct List {
+    /// `None` while the server's cache is empty
+    pub resource_version: Option<ResourceVersion>,
+    /// in the order of their last change
+    pub items: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawList {
+    #[serde(rename = "resourceVersion")]
+    resource_version: String,
+    items: Vec<Value>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("Invalid URL: {:?}", _0)]
+    Url(#[from] url::ParseError),
+    #[error("Request error: {:?}", _0)]
+    Request(#[from] reqwest::Error),
+    #[error("Unexpected status [{}]", _0)]
+    Status(StatusCode),
+    #[error("Invalid event: {}", _0)]
+    Invalid(String),
+    #[error("Not supported: {}", _0)]
+    Unsupported(&'static str),
+}
+
+impl ClientError {
+    /// the server will keep rejecting the request (e.g. a bad token), reconnecting is pointless
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::Url(_) | Self::Unsupported(_) => true,
+            Self::Status(status) => status.is_client_error() && *status != StatusCode::TOO_MANY_REQUESTS,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    base_url: Url,
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+impl Client {
+    /// `base_url` is where `/watch` is served, e.g. `http://big-brother:8080` or `https://example.com/big-brother/`
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        let mut base_url = Url::parse(base_url)?;
+        // `join` replaces the last segment of a path without a trailing slash
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        Ok(Self {
+            base_url,
+            http: reqwest::Client::new(),
+            token: None,
+        })
+    }
+
+    /// sent as `Authorization: Bearer <token>`
+    pub fn with_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// e.g. to trust a private CA or present a client certificate
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// all objects, then every change until the stream is dropped
+    pub fn watch(&self) -> Watch {
+        Watch::new(self.clone())
+    }
+
+    /// a consistent snapshot of the objects passing `filter`, which can't have a `subtree`
+    pub async fn list(&self, filter: &Filter) -> Result<List, ClientError> {
+        if filter.subtree.is_some() {
+            return Err(ClientError::Unsupported("subtree is only supported by watch"));
+        }
+        let mut url = self.base_url.join("list")?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("format", "json");
+            for (key, value) in filter.query() {
+                query.append_pair(key, &value);
+            }
+        }
+        let response = self.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(ClientError::Status(response.status()));
+        }
+        let list: RawList =
+            serde_json::from_slice(&response.bytes().await?).map_err(|err| ClientError::Invalid(err.to_string()))?;
+        let resource_version = match list.resource_version.parse() {
+            Ok(0) => None,
+            Ok(rv) => Some(rv),
+            Err(_) => return Err(ClientError::Invalid("invalid resourceVersion".into())),
+        };
+        Ok(List {
+            resource_version,
+            items: list.items,
+        })
+    }
+
+    fn get(&self, url: Url) -> reqwest::RequestBuilder {
+        let request = self.http.get(url);
+        match &self.token {
+            Some(token) => request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+}
+
+/// a line of `/watch`, bookmarks only move the position a watch resumes from
+#[derive(Debug, PartialEq)]
+enum Line {
+    Event(OutputEvent),
+    Bookmark(ResourceVersion),
+}
+
+#[derive(Debug, Deserialize)]
+struct RawLine {
+    #[serde(rename = "type")]
+    ty: String,
+    object: Value,
+}
+
+impl Line {
+    fn parse(line: &[u8]) -> Result<Self, ClientError> {
+        let line: RawLine = serde_json::from_slice(line).map_err(|err| ClientError::Invalid(err.to_string()))?;
+        let str_at = |pointer: &str| line.object.pointer(pointer).and_then(Value::as_str).map(String::from);
+        let missing = |what: &str| ClientError::Invalid(format!("missing {}", what));
+        let resource_version = str_at("/metadata/resourceVersion")
+            .and_then(|rv| rv.parse().ok())
+            .ok_or_else(|| missing("or invalid metadata.resourceVersion"))?;
+        let event_type = match line.ty.as_str() {
+            "BOOKMARK" => return Ok(Line::Bookmark(resource_version)),
+            "ADDED" => EventType::Added,
+            "MODIFIED" => EventType::Modified,
+            "DELETED" => EventType::Deleted,
+            other => return Err(ClientError::Invalid(format!("unknown type {:?}", other))),
+        };
+        let resource = ResourceId::of(&line.object).ok_or_else(|| missing("apiVersion, kind or metadata.name"))?;
+        Ok(Line::Event(OutputEvent {
+            event_type,
+            resource,
+            resource_version,
+            object: line.object,
+        }))
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{ClientError, EventType, OutputEvent, ResourceId, ResourceVersion};
+use futures_util::{Stream, StreamExt};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tokio::task::JoinHandle;
+
+/// the current objects of a watch
+#[derive(Debug, Clone, Default)]
+pub struct Mirror {
+    objects: HashMap<ResourceId, Value>,
+    resource_version: Option<ResourceVersion>,
+    last_error: Option<String>,
+}
+
+impl Mirror {
+    pub fn apply(&mut self, event: OutputEvent) {
+        match event.event_type {
+            EventType::Added | EventType::Modified => {
+                self.objects.insert(event.resource, event.object);
+            }
+            EventType::Deleted => {
+                self.objects.remove(&event.resource);
+            }
+        }
+        self.resource_version = self.resource_version.max(Some(event.resource_version));
+        self.last_error = None;
+    }
+
+    pub fn get(&self, id: &ResourceId) -> Option<&Value> {
+        self.objects.get(id)
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = (&ResourceId, &Value)> {
+        self.objects.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// of the latest change applied
+    pub fn resource_version(&self) -> Option<ResourceVersion> {
+        self.resource_version
+    }
+
+    /// why the watch is currently disconnected, cleared by the next event
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+/// applies a watch to a shared `Mirror` until dropped
+#[derive(Debug)]
+pub struct MirrorTask {
+    mirror: Arc<RwLock<Mirror>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for MirrorTask {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl MirrorTask {
+    pub(crate) fn spawn<S>(mirror: Mirror, events: S) -> Self
+    where
+        S: Stream<Item = Result<OutputEvent, ClientError>> + Send + 'static,
+    {
+        let mirror = Arc::new(RwLock::new(mirror));
+        let shared = Arc::clone(&mirror);
+        let task = tokio::task::spawn(async move {
+            let mut events = Box::pin(events);
+            while let Some(event) = events.next().await {
+                let mut mirror = shared.write().expect("Mirror lock poisoned");
+                match event {
+                    Ok(event) => mirror.apply(event),
+                    Err(err) => mirror.last_error = Some(err.to_string()),
+                }
+            }
+        });
+        Self { mirror, task }
+    }
+
+    pub fn mirror(&self) -> &Arc<RwLock<Mirror>> {
+        &self.mirror
+    }
+}
@@ -0,0 +1,370 @@
+use crate::{Client, ClientError, Line, Mirror, MirrorTask, OutputEvent, ResourceVersion};
+use futures_util::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use std::{collections::BTreeMap, time::Duration};
+
+/// `include`/`exclude` of `/watch` and `/list`, by kind
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Kinds {
+    #[default]
+    All,
+    Include(Vec<String>),
+    Exclude(Vec<String>),
+}
+
+/// the objects `/watch` and `/list` return, everything by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    pub kinds: Kinds,
+    pub namespace: Option<String>,
+    /// `labelSelector`, all of them have to match, deletions by the labels the object had
+    pub labels: BTreeMap<String, String>,
+    /// only objects with an owner reference to this uid
+    pub owner_uid: Option<String>,
+    /// uid of an object, which is selected with everything it transitively owns, only `/watch` supports it
+    pub subtree: Option<String>,
+}
+
+impl Filter {
+    /// only objects of these kinds, replaces a previous `include` or `exclude`
+    pub fn include<I: IntoIterator<Item = S>, S: Into<String>>(mut self, kinds: I) -> Self {
+        self.kinds = Kinds::Include(kinds.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// everything but objects of these kinds, replaces a previous `include` or `exclude`
+    pub fn exclude<I: IntoIterator<Item = S>, S: Into<String>>(mut self, kinds: I) -> Self {
+        self.kinds = Kinds::Exclude(kinds.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn namespace<S: Into<String>>(mut self, namespace: S) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// adds to the labels that have to match
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn owner_uid<S: Into<String>>(mut self, uid: S) -> Self {
+        self.owner_uid = Some(uid.into());
+        self
+    }
+
+    pub fn subtree<S: Into<String>>(mut self, uid: S) -> Self {
+        self.subtree = Some(uid.into());
+        self
+    }
+
+    pub(crate) fn query(&self) -> Vec<(&'static str, String)> {
+        let kinds = match &self.kinds {
+            Kinds::All => None,
+            Kinds::Include(kinds) => Some(("include", kinds.join(","))),
+            Kinds::Exclude(kinds) => Some(("exclude", kinds.join(","))),
+        };
+        let labels = (!self.labels.is_empty()).then(|| {
+            let labels = self.labels.iter().map(|(key, value)| format!("{}={}", key, value));
+            ("labelSelector", labels.collect::<Vec<_>>().join(","))
+        });
+        kinds
+            .into_iter()
+            .chain(self.namespace.clone().map(|namespace| ("namespace", namespace)))
+            .chain(labels)
+            .chain(self.owner_uid.clone().map(|uid| ("ownerUid", uid)))
+            .chain(self.subtree.clone().map(|uid| ("subtree", uid)))
+            .collect()
+    }
+}
+
+/// builds a `/watch` request, see `stream`
+#[derive(Debug, Clone)]
+pub struct Watch {
+    client: Client,
+    filter: Filter,
+    resource_version: Option<ResourceVersion>,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Watch {
+    pub(crate) fn new(client: Client) -> Self {
+        Self {
+            client,
+            filter: Filter::default(),
+            resource_version: None,
+            min_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// only objects of these kinds, see `Filter::include`
+    pub fn include<I: IntoIterator<Item = S>, S: Into<String>>(mut self, kinds: I) -> Self {
+        self.filter = self.filter.include(kinds);
+        self
+    }
+
+    /// everything but objects of these kinds, see `Filter::exclude`
+    pub fn exclude<I: IntoIterator<Item = S>, S: Into<String>>(mut self, kinds: I) -> Self {
+        self.filter = self.filter.exclude(kinds);
+        self
+    }
+
+    pub fn namespace<S: Into<String>>(mut self, namespace: S) -> Self {
+        self.filter = self.filter.namespace(namespace);
+        self
+    }
+
+    /// see `Filter::label`
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.filter = self.filter.label(key, value);
+        self
+    }
+
+    pub fn owner_uid<S: Into<String>>(mut self, uid: S) -> Self {
+        self.filter = self.filter.owner_uid(uid);
+        self
+    }
+
+    /// the object with this uid and everything it transitively owns, also the changes that take an object out
+    pub fn subtree<S: Into<String>>(mut self, uid: S) -> Self {
+        self.filter = self.filter.subtree(uid);
+        self
+    }
+
+    /// replaces everything the builders above set
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// only changes from `rv` on (inclusive) instead of every cached object
+    pub fn resource_version(mut self, rv: ResourceVersion) -> Self {
+        self.resource_version = Some(rv);
+        self
+    }
+
+    /// delays between reconnects, doubled after every failure up to `max`
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min;
+        self.max_delay = max.max(min);
+        self
+    }
+
+    async fn connect(
+        &self,
+        position: Option<ResourceVersion>,
+    ) -> Result<BoxStream<'static, reqwest::Result<bytes::Bytes>>, ClientError> {
+        let mut url = self.client.base_url.join("watch")?;
+        {
+            let mut query = url.query_pairs_mut();
+            for (key, value) in self.filter.query() {
+                query.append_pair(key, &value);
+            }
+            if let Some(rv) = position {
+                query.append_pair("resourceVersion", &rv.to_string());
+            }
+        }
+        let response = self.client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(ClientError::Status(response.status()));
+        }
+        Ok(response.bytes_stream().boxed())
+    }
+
+    /// reconnects whenever the connection ends or fails, resuming after the last event it returned, so nothing is
+    /// missed or repeated; errors are returned in between, the stream only ends after a permanent one
+    pub fn stream(self) -> impl Stream<Item = Result<OutputEvent, ClientError>> {
+        let state = State {
+            position: self.resource_version,
+            delay: self.min_delay,
+            watch: self,
+            body: None,
+            buffer: Vec::new(),
+            reconnect: false,
+            done: false,
+        };
+        stream::unfold(state, |mut state| async move {
+            let next = state.next().await?;
+            Some((next, state))
+        })
+    }
+
+    /// keeps a `Mirror` of the watched objects up to date in the background
+    pub fn mirror(self) -> MirrorTask {
+        MirrorTask::spawn(Mirror::default(), self.stream())
+    }
+}
+
+struct State {
+    watch: Watch,
+    /// where to resume, the resourceVersion after the last event
+    position: Option<ResourceVersion>,
+    /// before the next reconnect
+    delay: Duration,
+    body: Option<BoxStream<'static, reqwest::Result<bytes::Bytes>>>,
+    buffer: Vec<u8>,
+    reconnect: bool,
+    done: bool,
+}
+
+impl State {
+    async fn next(&mut self) -> Option<Result<OutputEvent, ClientError>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                match Line::parse(&line) {
+                    Ok(Line::Event(event)) => {
+                        self.position = Some(event.resource_version + 1);
+                        return Some(Ok(event));
+                    }
+                    Ok(Line::Bookmark(rv)) => self.position = Some(rv),
+                    Err(err) => return Some(Err(err)),
+                }
+                continue;
+            }
+            let body = match &mut self.body {
+                Some(body) => body,
+                None => {
+                    if self.reconnect {
+                        tokio::time::sleep(self.delay).await;
+                        self.delay = (self.delay * 2).min(self.watch.max_delay);
+                    }
+                    self.reconnect = true;
+                    match self.watch.connect(self.position).await {
+                        Ok(body) => {
+                            self.delay = self.watch.min_delay;
+                            self.body.insert(body)
+                        }
+                        Err(err) => {
+                            self.done = err.is_permanent();
+                            return Some(Err(err));
+                        }
+                    }
+                }
+            };
+            match body.next().await {
+                Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Some(Err(err)) => {
+                    self.body = None;
+                    self.buffer.clear();
+                    return Some(Err(err.into()));
+                }
+                None => {
+                    self.body = None;
+                    self.buffer.clear();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EventType;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const FIRST: &str = concat!(
+        r#"{"type":"MODIFIED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","resourceVersion":"3"}}}"#,
+        "\n",
+        r#"{"type":"BOOKMARK","object":{"metadata":{"resourceVersion":"7"}}}"#,
+        "\n",
+    );
+    const SECOND: &str = concat!(
+        r#"{"type":"DELETED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","resourceVersion":"8"}}}"#,
+        "\n",
+    );
+
+    /// answers each connection with one body and closes it, returns the request lines
+    async fn serve(listener: TcpListener, bodies: Vec<&'static str>) -> Vec<String> {
+        let mut requests = Vec::new();
+        for body in bodies {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let n = socket.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_string();
+            requests.push(request.lines().next().unwrap().to_string());
+            assert!(request.contains("authorization: Bearer secret"));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, vec![FIRST, SECOND]));
+
+        let client = Client::new(&format!("http://{}", addr)).unwrap().with_token("secret");
+        let watch = client
+            .watch()
+            .include(["Pod"])
+            .backoff(Duration::from_millis(1), Duration::from_millis(1));
+        let mut stream = Box::pin(watch.stream());
+        let modified = stream.next().await.unwrap().unwrap();
+        assert_eq!(modified.event_type, EventType::Modified);
+        assert_eq!(modified.resource.name, "a");
+        let deleted = stream.next().await.unwrap().unwrap();
+        assert_eq!(deleted.event_type, EventType::Deleted);
+        assert_eq!(deleted.resource_version, 8);
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests[0], "GET /watch?include=Pod HTTP/1.1");
+        // resumed from the bookmark
+        assert_eq!(requests[1], "GET /watch?include=Pod&resourceVersion=7 HTTP/1.1");
+    }
+
+    #[test]
+    fn namespace() {
+        assert_eq!(
+            Filter::default().include(["Pod"]).namespace("a").query(),
+            [("include", "Pod".into()), ("namespace", "a".into())]
+        );
+    }
+
+    #[test]
+    fn label_selector() {
+        let filter = Filter::default().label("tier", "web").label("app", "shop");
+        assert_eq!(filter.query(), [("labelSelector", "app=shop,tier=web".into())]);
+    }
+
+    #[test]
+    fn owner_uid() {
+        let filter = Filter::default().exclude(["Event"]).owner_uid("1234");
+        assert_eq!(
+            filter.query(),
+            [("exclude", "Event".into()), ("ownerUid", "1234".into())]
+        );
+    }
+
+    #[test]
+    fn subtree() {
+        assert_eq!(Filter::default().subtree("1234").query(), [("subtree", "1234".into())]);
+        let watch = Client::new("http://localhost")
+            .unwrap()
+            .watch()
+            .namespace("a")
+            .subtree("1234");
+        assert_eq!(watch.filter, Filter::default().namespace("a").subtree("1234"));
+    }
+}
@@ -0,0 +1,308 @@
+#!/usr/bin/env python3
+"""Generates src/k8s_client/protobuf/schema.json, what the protobuf decoder knows about the built-in types.
+
+The field tags and types come from the prost structs of the `k8s-pb` crate, the JSON names (and which message fields
+Go inlines with `json:",inline"`) from the `k8s-openapi` crate, both for the same Kubernetes version:
+
+    curl -sL https://static.crates.io/crates/k8s-pb/k8s-pb-0.9.0.crate | tar xz -C /tmp
+    curl -sL https://static.crates.io/crates/k8s-openapi/k8s-openapi-0.28.0.crate | tar xz -C /tmp
+    hack/protobuf-schema.py /tmp/k8s-pb-0.9.0/src /tmp/k8s-openapi-0.28.0/src/v1_35 > src/k8s_client/protobuf/schema.json
+"""
+import json
+import os
+import re
+import sys
+
+# decoded by hand (`Type` in src/k8s_client/protobuf/mod.rs), their fields are not needed
+SPECIAL = {
+    "apimachinery.pkg.apis.meta.v1.Time",
+    "apimachinery.pkg.apis.meta.v1.MicroTime",
+    "apimachinery.pkg.apis.meta.v1.Duration",
+    "apimachinery.pkg.apis.meta.v1.FieldsV1",
+    "apimachinery.pkg.api.resource.Quantity",
+    "apimachinery.pkg.util.intstr.IntOrString",
+    "apimachinery.pkg.runtime.RawExtension",
+}
+# `*string` where "" means something else than no value, other optional strings are omitted when empty like Go does
+POINTER_STRINGS = {
+    ("api.core.v1.PersistentVolumeClaimSpec", "storageClassName"),
+    ("api.core.v1.PersistentVolumeClaimSpec", "volumeAttributesClassName"),
+}
+# optional numbers and booleans that Go keeps as values instead of pointers, they are always encoded and Go omits them
+# when they're zero. Other optional numbers and booleans are pointers and only encoded when set, zero included. Neither
+# crate says which fields are pointers, this is taken from the Go types
+VALUE_SCALARS = {
+    ("apimachinery.pkg.apis.meta.v1.ObjectMeta", "generation"),
+    ("apimachinery.pkg.apis.meta.v1.Status", "code"),
+    ("apimachinery.pkg.apis.meta.v1.StatusDetails", "retryAfterSeconds"),
+    ("apimachinery.pkg.apis.meta.v1.Condition", "observedGeneration"),
+    ("api.core.v1.ContainerPort", "hostPort"),
+    ("api.core.v1.VolumeMount", "readOnly"),
+    ("api.core.v1.Container", "stdin"),
+    ("api.core.v1.Container", "stdinOnce"),
+    ("api.core.v1.Container", "tty"),
+    ("api.core.v1.EphemeralContainerCommon", "stdin"),
+    ("api.core.v1.EphemeralContainerCommon", "stdinOnce"),
+    ("api.core.v1.EphemeralContainerCommon", "tty"),
+    ("api.core.v1.Probe", "initialDelaySeconds"),
+    ("api.core.v1.Probe", "timeoutSeconds"),
+    ("api.core.v1.Probe", "periodSeconds"),
+    ("api.core.v1.Probe", "successThreshold"),
+    ("api.core.v1.Probe", "failureThreshold"),
+    ("api.core.v1.PodSpec", "hostNetwork"),
+    ("api.core.v1.PodSpec", "hostPID"),
+    ("api.core.v1.PodSpec", "hostIPC"),
+    ("api.core.v1.PodStatus", "observedGeneration"),
+    ("api.core.v1.PodCondition", "observedGeneration"),
+    ("api.core.v1.ContainerStateTerminated", "signal"),
+    ("api.core.v1.ServicePort", "nodePort"),
+    ("api.core.v1.ServiceSpec", "healthCheckNodePort"),
+    ("api.core.v1.ServiceSpec", "publishNotReadyAddresses"),
+    ("api.core.v1.NodeSpec", "unschedulable"),
+    ("api.core.v1.ContainerImage", "sizeBytes"),
+    ("api.core.v1.ReplicationControllerSpec", "minReadySeconds"),
+    ("api.core.v1.ReplicationControllerStatus", "fullyLabeledReplicas"),
+    ("api.core.v1.ReplicationControllerStatus", "readyReplicas"),
+    ("api.core.v1.ReplicationControllerStatus", "availableReplicas"),
+    ("api.core.v1.ReplicationControllerStatus", "observedGeneration"),
+    ("api.core.v1.Event", "count"),
+    ("api.core.v1.EventSeries", "count"),
+    ("api.core.v1.PersistentVolumeClaimVolumeSource", "readOnly"),
+    ("api.core.v1.AwsElasticBlockStoreVolumeSource", "partition"),
+    ("api.core.v1.AwsElasticBlockStoreVolumeSource", "readOnly"),
+    ("api.core.v1.GcePersistentDiskVolumeSource", "partition"),
+    ("api.core.v1.GcePersistentDiskVolumeSource", "readOnly"),
+    ("api.core.v1.NfsVolumeSource", "readOnly"),
+    ("api.core.v1.IscsiVolumeSource", "readOnly"),
+    ("api.core.v1.IscsiVolumeSource", "chapAuthDiscovery"),
+    ("api.core.v1.IscsiVolumeSource", "chapAuthSession"),
+    ("api.core.v1.IscsiPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.IscsiPersistentVolumeSource", "chapAuthDiscovery"),
+    ("api.core.v1.IscsiPersistentVolumeSource", "chapAuthSession"),
+    ("api.core.v1.RbdVolumeSource", "readOnly"),
+    ("api.core.v1.RbdPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.CephFsVolumeSource", "readOnly"),
+    ("api.core.v1.CephFsPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.CinderVolumeSource", "readOnly"),
+    ("api.core.v1.CinderPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.FcVolumeSource", "readOnly"),
+    ("api.core.v1.FlexVolumeSource", "readOnly"),
+    ("api.core.v1.FlexPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.AzureFileVolumeSource", "readOnly"),
+    ("api.core.v1.AzureFilePersistentVolumeSource", "readOnly"),
+    ("api.core.v1.QuobyteVolumeSource", "readOnly"),
+    ("api.core.v1.GlusterfsVolumeSource", "readOnly"),
+    ("api.core.v1.GlusterfsPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.PortworxVolumeSource", "readOnly"),
+    ("api.core.v1.ScaleIoVolumeSource", "sslEnabled"),
+    ("api.core.v1.ScaleIoVolumeSource", "readOnly"),
+    ("api.core.v1.ScaleIoPersistentVolumeSource", "sslEnabled"),
+    ("api.core.v1.ScaleIoPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.StorageOsVolumeSource", "readOnly"),
+    ("api.core.v1.StorageOsPersistentVolumeSource", "readOnly"),
+    ("api.core.v1.CSIPersistentVolumeSource", "readOnly"),
+    ("api.apps.v1.DeploymentSpec", "minReadySeconds"),
+    ("api.apps.v1.DeploymentSpec", "paused"),
+    ("api.apps.v1.DeploymentStatus", "observedGeneration"),
+    ("api.apps.v1.DeploymentStatus", "replicas"),
+    ("api.apps.v1.DeploymentStatus", "updatedReplicas"),
+    ("api.apps.v1.DeploymentStatus", "readyReplicas"),
+    ("api.apps.v1.DeploymentStatus", "availableReplicas"),
+    ("api.apps.v1.DeploymentStatus", "unavailableReplicas"),
+    ("api.apps.v1.ReplicaSetSpec", "minReadySeconds"),
+    ("api.apps.v1.ReplicaSetStatus", "fullyLabeledReplicas"),
+    ("api.apps.v1.ReplicaSetStatus", "readyReplicas"),
+    ("api.apps.v1.ReplicaSetStatus", "availableReplicas"),
+    ("api.apps.v1.ReplicaSetStatus", "observedGeneration"),
+    ("api.apps.v1.StatefulSetSpec", "minReadySeconds"),
+    ("api.apps.v1.StatefulSetStatus", "observedGeneration"),
+    ("api.apps.v1.StatefulSetStatus", "readyReplicas"),
+    ("api.apps.v1.StatefulSetStatus", "currentReplicas"),
+    ("api.apps.v1.StatefulSetStatus", "updatedReplicas"),
+    ("api.apps.v1.DaemonSetSpec", "minReadySeconds"),
+    ("api.apps.v1.DaemonSetStatus", "observedGeneration"),
+    ("api.apps.v1.DaemonSetStatus", "updatedNumberScheduled"),
+    ("api.apps.v1.DaemonSetStatus", "numberAvailable"),
+    ("api.apps.v1.DaemonSetStatus", "numberUnavailable"),
+    ("api.batch.v1.JobStatus", "active"),
+    ("api.batch.v1.JobStatus", "succeeded"),
+    ("api.batch.v1.JobStatus", "failed"),
+    ("api.autoscaling.v2.HorizontalPodAutoscalerStatus", "currentReplicas"),
+    ("api.policy.v1.PodDisruptionBudgetStatus", "observedGeneration"),
+    ("api.networking.v1.ServiceBackendPort", "number"),
+    ("api.scheduling.v1.PriorityClass", "globalDefault"),
+    ("api.events.v1.Event", "deprecatedCount"),
+}
+# custom JSON marshalling that isn't worth decoding, these kinds are requested as JSON
+SKIPPED_GROUPS = ("apiextensions_apiserver", "metrics")
+
+PROST = re.compile(r'#\[prost\((\w+)(?: = "([^"]*)")?(?:, (optional|repeated))?(?:, packed = "false")?, tag = "(\d+)"\)\]')
+FIELD = re.compile(r"pub (r#)?(\w+):\s*(.*),$")
+STRUCT = re.compile(r"^pub struct (\w+) \{(\})?$")
+RESOURCE = re.compile(r"^impl crate::Resource for (\w+) \{")
+CONST = re.compile(r'const (API_VERSION|KIND): &\'static str = "([^"]*)";')
+
+
+def normalize(name):
+    return name.replace("_", "").lower()
+
+
+def camel(snake):
+    head, *tail = snake.split("_")
+    return head + "".join(part[:1].upper() + part[1:] for part in tail)
+
+
+def resolve(module, path):
+    """full name of a prost type path as seen from `module`"""
+    parts = path.split("::")
+    module = list(module)
+    while parts[0] == "super":
+        module.pop()
+        parts.pop(0)
+    return ".".join(module + parts)
+
+
+def inner_type(ty):
+    """the message of `Option<T>`, `Vec<T>` or the value of `BTreeMap<String, T>`"""
+    ty = re.sub(r"\s", "", ty).replace("::core::option::Option<", "").replace("::prost::alloc::vec::Vec<", "")
+    ty = ty.replace("::prost::alloc::collections::BTreeMap<::prost::alloc::string::String,", "")
+    return ty.rstrip(">,")
+
+
+def read_pb(root):
+    messages, kinds = {}, {}
+    for directory, _, files in os.walk(root):
+        if "mod.rs" not in files:
+            continue
+        module = os.path.relpath(directory, root).split(os.sep)
+        if module[0] in SKIPPED_GROUPS or module == ["."]:
+            continue
+        lines = []
+        with open(os.path.join(directory, "mod.rs")) as file:
+            for line in file:
+                # long field types are wrapped
+                last = lines[-1] if lines else ""
+                if last.startswith("pub ") and (last.count("<") > last.count(">") or not last.endswith((",", "{", "}"))):
+                    lines[-1] += line.strip()
+                else:
+                    lines.append(line.strip())
+        struct, attribute, resource = None, None, None
+        for line in lines:
+            if STRUCT.match(line):
+                struct = ".".join(module + [STRUCT.match(line).group(1)])
+                messages[struct] = []
+                if STRUCT.match(line).group(2):
+                    struct = None
+            elif line == "}":
+                struct = None
+            elif struct and PROST.match(line):
+                attribute = PROST.match(line)
+            elif struct and attribute and FIELD.match(line):
+                kind, map_types, label, tag = attribute.groups()
+                name, ty = FIELD.match(line).group(2), FIELD.match(line).group(3)
+                if kind == "btree_map":
+                    value = map_types.split(", ")[1]
+                    ty = resolve(module, inner_type(ty)) if value == "message" else value
+                    mode = "map"
+                else:
+                    ty = resolve(module, inner_type(ty)) if kind == "message" else kind
+                    mode = "repeated" if label == "repeated" else ""
+                messages[struct].append([int(tag), name, ty, mode])
+                attribute = None
+            if RESOURCE.match(line):
+                resource = {"name": ".".join(module + [RESOURCE.match(line).group(1)])}
+            elif resource is not None and CONST.search(line):
+                key, value = CONST.search(line).groups()
+                resource[key] = value
+                if "API_VERSION" in resource and "KIND" in resource:
+                    kinds["{}/{}".format(resource["API_VERSION"], resource["KIND"])] = resource["name"]
+                    list_name = resource["name"] + "List"
+                    kinds["{}/{}List".format(resource["API_VERSION"], resource["KIND"])] = list_name
+                    resource = None
+    # the object of `ERROR` watch events
+    kinds["v1/Status"] = "apimachinery.pkg.apis.meta.v1.Status"
+    return messages, {kind: name for kind, name in kinds.items() if name in messages}
+
+
+def read_openapi(root):
+    """JSON names and whether they are optional (`omitempty`) by normalized field name, by lowercase definition,
+    prost spells acronyms differently (`PodOs`)"""
+    definitions = {}
+    for directory, _, files in os.walk(root):
+        for name in files:
+            with open(os.path.join(directory, name)) as file:
+                source = file.read()
+            definition = re.match(r"// Generated from definition io\.k8s\.(\S+)", source)
+            if not definition:
+                continue
+            fields = re.findall(r'"([^"]+)" => Field::Key_(\w+),', source)
+            optional = {normalize(field): bool(option) for field, option in re.findall(r"\n    pub (?:r#)?(\w+): (Option<)?", source)}
+            definitions[definition.group(1).replace("-", "_").lower()] = {
+                normalize(key): (json, optional.get(normalize(key), True)) for json, key in fields
+            }
+    return definitions
+
+
+def inlines(messages, ty, json_names):
+    """whether every field of `ty` is one of `json_names`, directly or through a message that is inlined as well"""
+    return all(
+        normalize(field) in json_names or (inner in messages and inlines(messages, inner, json_names))
+        for _, field, inner, _ in messages[ty]
+    )
+
+
+def main(pb_root, openapi_root):
+    messages, kinds = read_pb(pb_root)
+    definitions = read_openapi(openapi_root)
+    schema = {"kinds": kinds, "messages": {}, "arrays": []}
+    pending = list(kinds.values())
+    # inlined messages have no definition of their own, their fields are named in the one they're inlined into
+    inherited = {}
+    unused_values = set(VALUE_SCALARS)
+    while pending:
+        name = pending.pop()
+        if name in schema["messages"] or name in SPECIAL or name in schema["arrays"]:
+            continue
+        fields = messages[name]
+        json_names = definitions.get(name.lower(), inherited.get(name))
+        if json_names is None and [field[1:] for field in fields] == [["items", "string", "repeated"]]:
+            # e.g. `ExtraValue`, a `[]string` in Go
+            schema["arrays"].append(name)
+            continue
+        if json_names is None and not name.endswith("List"):
+            print("no JSON names for {}, guessing".format(name), file=sys.stderr)
+        decoded = []
+        for tag, field, ty, mode in fields:
+            json_name, omitempty = (json_names or {}).get(normalize(field), (None, True))
+            if json_name is None and json_names is not None and ty in messages:
+                if inlines(messages, ty, json_names):
+                    json_name, mode = "", "inline"
+                    inherited.setdefault(ty, json_names)
+            if json_name is None:
+                json_name = camel(field)
+                if json_names is not None:
+                    print("{}.{} has no JSON name, guessing {}".format(name, field, json_name), file=sys.stderr)
+            if ty in ("bool", "int32", "int64"):
+                unused_values.discard((name, json_name))
+            # only matters for the zero values of optional fields
+            if not omitempty:
+                pointer = False
+            elif ty == "string":
+                pointer = (name, json_name) in POINTER_STRINGS
+            elif ty in ("bool", "int32", "int64"):
+                pointer = (name, json_name) not in VALUE_SCALARS
+            else:
+                pointer = False
+            decoded.append([tag, json_name, ty, mode, omitempty, pointer])
+            if ty in messages:
+                pending.append(ty)
+        schema["messages"][name] = sorted(decoded)
+    if unused_values:
+        sys.exit("VALUE_SCALARS that are no number or boolean field: {}".format(sorted(unused_values)))
+    schema["arrays"].sort()
+    json.dump(schema, sys.stdout, sort_keys=True, separators=(",", ":"))
+    print()
+
+
+if __name__ == "__main__":
+    main(*sys.argv[1:])
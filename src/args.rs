@@ -1,19 +1,227 @@
-use std::path::PathBuf;
+use crate::{audit::AuditLevel, k8s_client::api::ResourceVersion};
+use std::{path::PathBuf, str::FromStr};
 use structopt::{clap::ArgGroup, StructOpt};
 
+/// one of these or `tokens` in `--config` is required
 #[derive(Debug, StructOpt)]
-#[structopt(group = ArgGroup::with_name("token").required(true))]
+#[structopt(group = ArgGroup::with_name("token"))]
 pub struct Token {
-    #[structopt(long="token-path", group = "token")]
+    #[structopt(long = "token-path", group = "token")]
     pub path: Option<PathBuf>,
-    #[structopt(long="insecure-no-token", group = "token")]
+    /// YAML file of named tokens, each with its own allowed kinds, namespaces and endpoints
+    #[structopt(long = "token-registry", group = "token")]
+    pub registry: Option<PathBuf>,
+    /// validate clients' Kubernetes tokens with TokenReview and authorize them with SubjectAccessReview
+    #[structopt(long = "token-review", group = "token")]
+    pub review: bool,
+    /// JWKS file to verify clients' JWTs against, reloaded when it changes
+    #[structopt(long = "jwks", group = "token")]
+    pub jwks: Option<PathBuf>,
+    #[structopt(long = "insecure-no-token", group = "token")]
     pub none: bool,
 }
 
+#[derive(Debug, StructOpt)]
+pub struct Jwt {
+    /// required `iss` claim
+    #[structopt(long = "jwt-issuer", requires = "jwks")]
+    pub issuer: Option<String>,
+    /// required `aud` claim
+    #[structopt(long = "jwt-audience", requires = "jwks")]
+    pub audience: Option<String>,
+    /// allowed clock skew in seconds
+    #[structopt(long = "jwt-leeway", default_value = "60")]
+    pub leeway: u64,
+    /// claim holding the token's groups
+    #[structopt(long = "jwt-groups-claim", default_value = "groups")]
+    pub groups_claim: String,
+    /// YAML file mapping subjects and groups to allowed kinds, namespaces and endpoints
+    #[structopt(long = "jwt-rules", requires = "jwks")]
+    pub rules: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Tls {
+    /// PEM certificate chain, serves HTTPS instead of HTTP, reloaded when it changes
+    #[structopt(name = "tls-cert", long = "tls-cert", requires = "tls-key")]
+    pub cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`
+    #[structopt(name = "tls-key", long = "tls-key", requires = "tls-cert")]
+    pub key: Option<PathBuf>,
+    /// PEM CA bundle to verify client certificates against, with `--insecure-no-token` certificates are mandatory
+    #[structopt(long = "client-ca", requires = "tls-cert")]
+    pub client_ca: Option<PathBuf>,
+    /// YAML file mapping certificate common names and organizations to allowed kinds, namespaces and endpoints
+    #[structopt(long = "client-cert-rules", requires = "client-ca")]
+    pub client_cert_rules: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Audit {
+    /// none, metadata, request (adds filter and resourceVersion) or objects (adds every delivered object)
+    #[structopt(long = "audit-level", default_value = "none")]
+    pub level: AuditLevel,
+    /// JSON lines file, stdout if omitted
+    #[structopt(name = "audit-log", long = "audit-log")]
+    pub path: Option<PathBuf>,
+    /// size in MiB after which the audit log is rotated
+    #[structopt(long = "audit-log-max-size", default_value = "100")]
+    pub max_size: u64,
+    /// number of rotated audit logs to keep
+    #[structopt(long = "audit-log-max-backups", default_value = "5")]
+    pub max_backups: usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WatchLimits {
+    /// concurrent watches per client, 0 is unlimited
+    #[structopt(long = "watch-max-connections", default_value = "16")]
+    pub max_connections: usize,
+    /// sustained new watches per second per client, 0 is unlimited
+    #[structopt(long = "watch-reconnect-qps", default_value = "1")]
+    pub reconnect_qps: f64,
+    /// new watches per client allowed at once
+    #[structopt(long = "watch-reconnect-burst", default_value = "10")]
+    pub reconnect_burst: u32,
+    /// delivery rate per client across all of its watches, 0 is unlimited
+    #[structopt(long = "watch-bytes-per-second", default_value = "0")]
+    pub bytes_per_second: u32,
+    /// concurrent watches without resourceVersion replaying the whole cache, 0 is unlimited
+    #[structopt(long = "max-concurrent-replays", default_value = "4")]
+    pub max_concurrent_replays: usize,
+}
+
+/// how the subcommands reach a running big-brother
+#[derive(Debug, StructOpt)]
+pub struct Remote {
+    /// base URL of the big-brother to query
+    #[structopt(long = "server", env = "BIG_BROTHER_SERVER", default_value = "http://localhost:8080")]
+    pub server: String,
+    /// file containing the bearer token for the server
+    #[structopt(long = "token-path", env = "BIG_BROTHER_TOKEN_PATH")]
+    pub token_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Kinds {
+    /// only objects of these kinds, comma separated
+    #[structopt(long = "include", use_delimiter = true, conflicts_with = "exclude")]
+    pub include: Vec<String>,
+    /// all objects except those of these kinds, comma separated
+    #[structopt(long = "exclude", use_delimiter = true)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Pretty,
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown output {:?}, expected pretty or json", s)),
+        }
+    }
+}
+
+/// without a subcommand, the server is run
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// print the events of a running big-brother as they happen
+    Watch {
+        #[structopt(flatten)]
+        remote: Remote,
+        #[structopt(flatten)]
+        kinds: Kinds,
+        /// only changes from this resourceVersion on, instead of all cached objects first
+        #[structopt(long = "resource-version")]
+        resource_version: Option<ResourceVersion>,
+        /// pretty or json (one line as served by /watch per event)
+        #[structopt(short = "o", long = "output", default_value = "pretty")]
+        output: Output,
+    },
+    /// print one cached object
+    Get {
+        #[structopt(flatten)]
+        remote: Remote,
+        kind: String,
+        name: String,
+        #[structopt(short = "n", long = "namespace")]
+        namespace: Option<String>,
+        /// needed when several API groups have a kind of that name
+        #[structopt(long = "api-version")]
+        api_version: Option<String>,
+        /// pretty (YAML) or json
+        #[structopt(short = "o", long = "output", default_value = "pretty")]
+        output: Output,
+    },
+    /// print the cached objects
+    List {
+        #[structopt(flatten)]
+        remote: Remote,
+        #[structopt(flatten)]
+        kinds: Kinds,
+        /// pretty (a table) or json (the objects with their resourceVersion)
+        #[structopt(short = "o", long = "output", default_value = "pretty")]
+        output: Output,
+    },
+    /// write the cached objects as of one resourceVersion to an NDJSON file that --replay can load
+    Dump {
+        #[structopt(flatten)]
+        remote: Remote,
+        #[structopt(flatten)]
+        kinds: Kinds,
+        /// compressed if it ends in .gz or .zst
+        path: PathBuf,
+    },
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Args {
     #[structopt(flatten)]
     pub token: Token,
+    #[structopt(flatten)]
+    pub jwt: Jwt,
+    #[structopt(flatten)]
+    pub tls: Tls,
+    #[structopt(flatten)]
+    pub audit: Audit,
+    #[structopt(flatten)]
+    pub watch_limits: WatchLimits,
+    /// YAML configuration file, reloaded on SIGHUP or when it changes
+    #[structopt(long = "config", env = "BIG_BROTHER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// YAML file configuring sinks that changes are pushed to
+    #[structopt(long = "sinks")]
+    pub sinks: Option<PathBuf>,
+    /// serve NDJSON watch dumps (optionally .gz or .zst) instead of watching a cluster
+    #[structopt(long = "replay")]
+    pub replay: Vec<PathBuf>,
+    /// 0 loads the dumps at once, otherwise events are paced by their timestamps, 1 is the original pace
+    #[structopt(long = "replay-speed", default_value = "0")]
+    pub replay_speed: f64,
+    /// address to serve on, 0.0.0.0:8080 unless set in `--config`
+    #[structopt(long = "listen")]
+    pub listen: Option<String>,
+    /// kubeconfig context to use instead of "current-context"
+    #[structopt(long = "context")]
+    pub context: Option<String>,
+    /// maximum sustained requests per second to the API server, 0 disables rate limiting
+    #[structopt(long = "kube-api-qps", default_value = "20")]
+    pub kube_api_qps: f64,
+    /// maximum burst of requests to the API server
+    #[structopt(long = "kube-api-burst", default_value = "40")]
+    pub kube_api_burst: u32,
+    /// maximum number of initial lists running at the same time
+    #[structopt(long = "max-concurrent-lists", default_value = "8")]
+    pub max_concurrent_lists: usize,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
 pub fn parse() -> Args {
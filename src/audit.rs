@@ -0,0 +1,372 @@
+use crate::{
+    k8s_client::api::{ResourceId, ResourceVersion},
+    scope::Endpoint,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// at `AuditLevel::Objects` a long watch is written in records of at most this many objects,
+/// instead of keeping every object in memory until the client disconnects
+const OBJECTS_PER_RECORD: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AuditLevel {
+    /// nothing is recorded
+    None,
+    /// who used which endpoint, for how long, and how much was delivered
+    Metadata,
+    /// additionally the filter and starting resourceVersion of the request
+    Request,
+    /// additionally every delivered object
+    Objects,
+}
+
+impl FromStr for AuditLevel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "metadata" => Ok(Self::Metadata),
+            "request" => Ok(Self::Request),
+            "objects" => Ok(Self::Objects),
+            _ => Err(format!(
+                "unknown audit level {:?}, expected none, metadata, request or objects",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub level: AuditLevel,
+    /// `None` writes to stdout
+    pub path: Option<PathBuf>,
+    /// the file is rotated once it grows beyond this
+    pub max_size: u64,
+    /// number of rotated files kept as `<path>.1`, `<path>.2`, ...
+    pub max_backups: usize,
+}
+
+/// one JSON line per authenticated request, written when the request finishes
+/// (or more, see `OBJECTS_PER_RECORD`, the counters are totals so far in each of them)
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    pub start: DateTime<Utc>,
+    pub identity: Option<String>,
+    pub endpoint: Endpoint,
+    pub remote_addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<ResourceVersion>,
+    pub objects_delivered: u64,
+    pub bytes_delivered: u64,
+    pub duration_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub objects: Option<Vec<AuditObject>>,
+    /// more records of the same request follow
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditObject {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    level: AuditLevel,
+    writer: Option<Arc<Mutex<AuditWriter>>>,
+}
+
+#[derive(Debug)]
+enum AuditWriter {
+    Stdout,
+    File(RotatingFile),
+}
+
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_size: u64,
+    max_backups: usize,
+}
+
+/// records one request, the record is written when the entry is dropped, i.e. when the response is finished
+#[derive(Debug)]
+pub struct AuditEntry {
+    log: AuditLog,
+    started: Instant,
+    record: AuditRecord,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Result<Self, io::Error> {
+        let writer = match (config.level, &config.path) {
+            (AuditLevel::None, _) => None,
+            (_, None) => Some(AuditWriter::Stdout),
+            (_, Some(path)) => Some(AuditWriter::File(RotatingFile::open(
+                path.clone(),
+                config.max_size,
+                config.max_backups,
+            )?)),
+        };
+        Ok(Self {
+            level: config.level,
+            writer: writer.map(|writer| Arc::new(Mutex::new(writer))),
+        })
+    }
+
+    pub fn start(
+        &self,
+        identity: Option<&str>,
+        endpoint: Endpoint,
+        remote_addr: Option<String>,
+        filter: Option<String>,
+        resource_version: Option<ResourceVersion>,
+    ) -> AuditEntry {
+        let request = self.level >= AuditLevel::Request;
+        AuditEntry {
+            log: self.clone(),
+            started: Instant::now(),
+            record: AuditRecord {
+                start: Utc::now(),
+                identity: identity.map(String::from),
+                endpoint,
+                remote_addr,
+                filter: filter.filter(|_| request),
+                resource_version: resource_version.filter(|_| request),
+                objects_delivered: 0,
+                bytes_delivered: 0,
+                duration_seconds: 0.0,
+                objects: match self.level >= AuditLevel::Objects {
+                    true => Some(Vec::new()),
+                    false => None,
+                },
+                partial: false,
+            },
+        }
+    }
+
+    fn write(&self, record: &AuditRecord) {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => return tracing::error!(error = %err, "could not serialize audit record"),
+        };
+        line.push(b'\n');
+        let result = match &mut *writer.lock().expect("Audit log lock poisoned") {
+            AuditWriter::Stdout => io::stdout().lock().write_all(&line),
+            AuditWriter::File(file) => file.write(&line),
+        };
+        if let Err(err) = result {
+            tracing::error!(error = %err, "could not write audit record");
+        }
+    }
+}
+
+impl AuditEntry {
+    pub fn delivered(&mut self, res: &ResourceId, bytes: usize) {
+        self.record.objects_delivered += 1;
+        self.record.bytes_delivered += bytes as u64;
+        if let Some(objects) = &mut self.record.objects {
+            objects.push(AuditObject {
+                api_version: res.api_version.clone(),
+                kind: res.kind.clone(),
+                namespace: res.namespace.clone(),
+                name: res.name.clone(),
+            });
+            if objects.len() >= OBJECTS_PER_RECORD {
+                self.record.partial = true;
+                self.record.duration_seconds = self.started.elapsed().as_secs_f64();
+                self.log.write(&self.record);
+                self.record.partial = false;
+                if let Some(objects) = &mut self.record.objects {
+                    objects.clear();
+                }
+            }
+        }
+    }
+
+    /// for responses that aren't made of individual objects
+    pub fn delivered_bytes(&mut self, bytes: usize) {
+        self.record.bytes_delivered += bytes as u64;
+    }
+}
+
+impl Drop for AuditEntry {
+    fn drop(&mut self) {
+        self.record.duration_seconds = self.started.elapsed().as_secs_f64();
+        self.log.write(&self.record);
+    }
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_backups: usize) -> Result<Self, io::Error> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_backups,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<(), io::Error> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        let backup = |n: usize| -> PathBuf { format!("{}.{}", self.path.display(), n).into() };
+        if self.max_backups == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_backups).rev() {
+                rename_if_exists(&backup(n), &backup(n + 1))?;
+            }
+            fs::rename(&self.path, backup(1))?;
+        }
+        *self = Self::open(self.path.clone(), self.max_size, self.max_backups)?;
+        Ok(())
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<(), io::Error> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod(name: &str) -> ResourceId {
+        ResourceId {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: name.into(),
+            namespace: Some("default".into()),
+        }
+    }
+
+    #[test]
+    fn levels_and_rotation() {
+        let dir = std::env::temp_dir().join(format!("big-brother-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(&AuditConfig {
+            level: AuditLevel::Objects,
+            path: Some(path.clone()),
+            max_size: 400,
+            max_backups: 1,
+        })
+        .unwrap();
+
+        let mut entry = log.start(
+            Some("team-a"),
+            Endpoint::Watch,
+            None,
+            Some("include=Pod".into()),
+            Some(7),
+        );
+        entry.delivered(&pod("a"), 10);
+        entry.delivered(&pod("b"), 20);
+        drop(entry);
+        let record: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(record["identity"], "team-a");
+        assert_eq!(record["endpoint"], "watch");
+        assert_eq!(record["filter"], "include=Pod");
+        assert_eq!(record["resourceVersion"], 7);
+        assert_eq!(record["objectsDelivered"], 2);
+        assert_eq!(record["bytesDelivered"], 30);
+        assert_eq!(record["objects"][1]["name"], "b");
+
+        // each record is more than half of max_size, so every write rotates
+        for name in &["c", "d", "e"] {
+            let mut entry = log.start(Some(name), Endpoint::Watch, None, None, None);
+            entry.delivered(&pod(name), 1);
+        }
+        let current = fs::read_to_string(&path).unwrap();
+        let backup = fs::read_to_string(dir.join("audit.log.1")).unwrap();
+        assert!(current.contains("\"identity\":\"e\""));
+        assert!(backup.contains("\"identity\":\"d\""));
+        assert!(!dir.join("audit.log.2").exists());
+    }
+
+    #[test]
+    fn objects_in_chunks() {
+        let dir = std::env::temp_dir().join(format!("big-brother-audit-chunks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let log = AuditLog::new(&AuditConfig {
+            level: AuditLevel::Objects,
+            path: Some(path.clone()),
+            max_size: u64::MAX,
+            max_backups: 1,
+        })
+        .unwrap();
+
+        let mut entry = log.start(Some("team-a"), Endpoint::Watch, None, None, None);
+        for i in 0..OBJECTS_PER_RECORD + 1 {
+            entry.delivered(&pod(&i.to_string()), 1);
+        }
+        // written before the client disconnects
+        let records = fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&records).unwrap();
+        assert_eq!(record["partial"], true);
+        assert_eq!(record["objectsDelivered"], OBJECTS_PER_RECORD);
+        assert_eq!(record["objects"].as_array().unwrap().len(), OBJECTS_PER_RECORD);
+        assert_eq!(entry.record.objects.as_ref().unwrap().len(), 1);
+
+        drop(entry);
+        let records = fs::read_to_string(&path).unwrap();
+        let last: serde_json::Value = serde_json::from_str(records.lines().nth(1).unwrap()).unwrap();
+        assert!(last.get("partial").is_none());
+        assert_eq!(last["objectsDelivered"], OBJECTS_PER_RECORD + 1);
+        assert_eq!(last["objects"][0]["name"], OBJECTS_PER_RECORD.to_string());
+    }
+
+    #[test]
+    fn metadata_level() {
+        let log = AuditLog {
+            level: AuditLevel::Metadata,
+            writer: None,
+        };
+        let entry = log.start(None, Endpoint::List, None, Some("include=Pod".into()), Some(7));
+        assert_eq!(entry.record.filter, None);
+        assert_eq!(entry.record.resource_version, None);
+        assert!(entry.record.objects.is_none());
+    }
+}
@@ -1,45 +1,150 @@
-use actix_web::{http, FromRequest, HttpRequest, ResponseError};
+use actix_web::{http, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::{FutureExt, LocalBoxFuture};
 use reqwest::StatusCode;
+use serde::Deserialize;
 use std::{
+    collections::HashSet,
     fmt::{self},
     fs,
-    future::{ready, Ready},
+    future::ready,
     io,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
 };
 
-use crate::utils::read_token;
+use crate::{
+    jwt::{JwtError, JwtVerifier},
+    k8s_client::{api::ResourceId, K8sClientError},
+    scope::{Endpoint, Identity, Scope, ScopeRules},
+    tls::ClientCertificate,
+    token_review::{ReviewedUser, TokenReviewer},
+    utils::read_token,
+};
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct BearerConfig {
-    path: Option<PathBuf>,
+#[derive(Debug, Clone, Default)]
+pub enum BearerConfig {
+    /// no authentication, everyone gets full access
+    #[default]
+    None,
+    /// single shared token with full access
+    Token(PathBuf),
+    /// file of named tokens, each with its own scope
+    Registry(PathBuf),
+    /// named tokens from the configuration file, replaced when it is reloaded
+    Tokens(Arc<RwLock<TokenRegistry>>),
+    /// clients present their own Kubernetes tokens, access is decided by the API server's RBAC
+    TokenReview(Arc<TokenReviewer>),
+    /// JWTs from an identity provider, verified offline against a JWKS
+    Jwt(Arc<JwtVerifier>),
 }
 impl BearerConfig {
-    pub fn new(path: Option<PathBuf>) -> Result<Self, io::Error> {
-        let path = path.into();
+    pub fn token(path: PathBuf) -> Result<Self, io::Error> {
         // just to fail early in case the token file is unreadable
-        let path = match path {
-            Some(path) => {
-                read_token(&mut fs::File::open(&path)?)?;
-                Some(path)
-            }
-            None => None,
-        };
-        Ok(Self { path })
+        read_token(&mut fs::File::open(&path)?)?;
+        Ok(Self::Token(path))
     }
+    pub fn registry(path: PathBuf) -> Result<Self, io::Error> {
+        // just to fail early in case the registry is unreadable or invalid
+        TokenRegistry::from_path(&path)?;
+        Ok(Self::Registry(path))
+    }
+}
+
+/// rules mapping client certificates to scopes, a certificate without a matching rule (or without rules) grants
+/// nothing and the client authenticates like one without a certificate
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertRules(pub Option<PathBuf>);
+
+/// content of the token registry file, re-read on every request so tokens can be rotated without restart
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenRegistry {
+    pub tokens: Vec<NamedToken>,
 }
-impl Default for BearerConfig {
-    fn default() -> Self {
-        Self { path: None }
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "NamedTokenEntry")]
+pub struct NamedToken {
+    pub name: String,
+    pub token: String,
+    pub scope: Scope,
+}
+
+/// a token as written, the scope next to it: unknown fields are rejected so that a misspelled restriction doesn't
+/// grant full access, see `ScopeRuleEntry`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamedTokenEntry {
+    name: String,
+    token: String,
+    #[serde(default)]
+    kinds: Option<HashSet<String>>,
+    #[serde(default)]
+    namespaces: Option<HashSet<String>>,
+    #[serde(default)]
+    endpoints: Option<HashSet<Endpoint>>,
+}
+
+impl From<NamedTokenEntry> for NamedToken {
+    fn from(entry: NamedTokenEntry) -> Self {
+        Self {
+            name: entry.name,
+            token: entry.token,
+            scope: Scope {
+                kinds: entry.kinds,
+                namespaces: entry.namespaces,
+                endpoints: entry.endpoints,
+            },
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+impl TokenRegistry {
+    pub fn from_path(path: &Path) -> Result<Self, io::Error> {
+        let file = fs::File::open(path)?;
+        serde_yaml::from_reader(file).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn find(&self, header: &[u8]) -> Option<NamedToken> {
+        self.tokens
+            .iter()
+            .find(|named| header == format!("Bearer {}", named.token.trim_end()).as_bytes())
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum BearerResponseError {
     ConfigRead,
     ConfigMissing,
     MissingAuthHeader,
     BearerMissmatch,
+    /// reason is sent to the client in `WWW-Authenticate`
+    InvalidToken(String),
+    Forbidden,
+    ReviewUnavailable,
+}
+impl BearerResponseError {
+    /// `WWW-Authenticate` challenge as described in RFC 6750
+    fn challenge(&self) -> Option<String> {
+        const REALM: &str = "Bearer realm=\"big-brother\"";
+        match self {
+            Self::MissingAuthHeader => Some(REALM.to_string()),
+            Self::BearerMissmatch => Some(format!("{}, error=\"invalid_token\"", REALM)),
+            Self::InvalidToken(reason) => Some(format!(
+                "{}, error=\"invalid_token\", error_description=\"{}\"",
+                REALM,
+                reason.replace('"', "'")
+            )),
+            Self::Forbidden => Some(format!("{}, error=\"insufficient_scope\"", REALM)),
+            Self::ConfigRead | Self::ConfigMissing | Self::ReviewUnavailable => None,
+        }
+    }
+}
+/// the review itself is logged where it failed
+impl From<K8sClientError> for BearerResponseError {
+    fn from(_: K8sClientError) -> Self {
+        Self::ReviewUnavailable
+    }
 }
 impl fmt::Display for BearerResponseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -57,40 +162,234 @@ impl ResponseError for BearerResponseError {
             Self::ConfigRead => StatusCode::INTERNAL_SERVER_ERROR,
             Self::MissingAuthHeader => StatusCode::UNAUTHORIZED,
             Self::BearerMissmatch => StatusCode::UNAUTHORIZED,
+            Self::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::ReviewUnavailable => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code());
+        if let Some(challenge) = self.challenge() {
+            resp.insert_header((http::header::WWW_AUTHENTICATE, challenge));
+        }
+        resp.content_type("text/plain; charset=utf-8").body(self.to_string())
+    }
+}
+
+/// authenticated client, `name` is `None` for the shared token or when authentication is disabled
+pub struct Bearer {
+    pub name: Option<String>,
+    pub scope: Scope,
+    pub reviewed: Option<ReviewedUser>,
 }
+impl Bearer {
+    fn with_scope(name: Option<String>, scope: Scope) -> Self {
+        Self {
+            name,
+            scope,
+            reviewed: None,
+        }
+    }
+
+    /// whether the client may see `res` through `endpoint`, fails when a `SubjectAccessReview` couldn't be made
+    pub async fn allows(&self, endpoint: Endpoint, res: &ResourceId) -> Result<bool, K8sClientError> {
+        if !self.scope.allows(res) {
+            return Ok(false);
+        }
+        match &self.reviewed {
+            None => Ok(true),
+            Some(reviewed) => reviewed.allows(endpoint.verb(), res).await,
+        }
+    }
+
+    /// `None` when no rule matches the certificate, the token decides then
+    fn from_certificate(
+        identity: &Identity,
+        rules: Option<&ClientCertRules>,
+    ) -> Result<Option<Self>, BearerResponseError> {
+        let path = match rules.and_then(|rules| rules.0.as_deref()) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let scope = ScopeRules::from_path(path)
+            .map_err(|_| BearerResponseError::ConfigRead)?
+            .find(identity);
+        if scope.is_none() {
+            tracing::debug!(
+                subject = identity.subject.as_str(),
+                "no rule matches client certificate, falling back to the token"
+            );
+        }
+        Ok(scope.map(|scope| Bearer::with_scope(Some(identity.subject.clone()), scope)))
+    }
 
-pub struct Bearer;
+    pub fn require(&self, endpoint: Endpoint) -> Result<(), BearerResponseError> {
+        if self.scope.allows_endpoint(endpoint) {
+            return Ok(());
+        }
+        tracing::warn!(
+            token = self.name.as_deref().unwrap_or_default(),
+            ?endpoint,
+            "token is not allowed to access the endpoint"
+        );
+        Err(BearerResponseError::Forbidden)
+    }
+}
 impl FromRequest for Bearer {
     type Error = BearerResponseError;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
     type Config = BearerConfig;
 
     fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
         fn from_request_inner(req: &HttpRequest) -> Result<Bearer, BearerResponseError> {
-            let config: &BearerConfig = req.app_data::<BearerConfig>().ok_or(BearerResponseError::ConfigMissing)?;
+            let config: &BearerConfig = req
+                .app_data::<BearerConfig>()
+                .ok_or(BearerResponseError::ConfigMissing)?;
 
-            let token_path = match &config.path {
-                None => return Ok(Self),
-                Some(path) => path,
-            };
-            let token: String = || -> Result<String, io::Error> {
-                let file = &mut fs::File::open(token_path)?;
-                read_token(file)
-            }()
-            .map_err(|_| BearerResponseError::ConfigRead)?;
-
-            let header = match req.headers().get(http::header::AUTHORIZATION) {
-                None => return Err(BearerResponseError::MissingAuthHeader),
-                Some(header) => header,
+            let header = || {
+                req.headers()
+                    .get(http::header::AUTHORIZATION)
+                    .ok_or(BearerResponseError::MissingAuthHeader)
             };
-            if header.as_bytes() != token.as_bytes() {
-                return Err(BearerResponseError::BearerMissmatch);
+
+            match config {
+                BearerConfig::None => Ok(Bearer::with_scope(None, Scope::full())),
+                BearerConfig::Token(token_path) => {
+                    let token: String = || -> Result<String, io::Error> {
+                        let file = &mut fs::File::open(token_path)?;
+                        read_token(file)
+                    }()
+                    .map_err(|_| BearerResponseError::ConfigRead)?;
+
+                    if header()?.as_bytes() != token.as_bytes() {
+                        return Err(BearerResponseError::BearerMissmatch);
+                    }
+                    Ok(Bearer::with_scope(None, Scope::full()))
+                }
+                BearerConfig::Registry(registry_path) => {
+                    let registry =
+                        TokenRegistry::from_path(registry_path).map_err(|_| BearerResponseError::ConfigRead)?;
+                    let named = registry
+                        .find(header()?.as_bytes())
+                        .ok_or(BearerResponseError::BearerMissmatch)?;
+                    Ok(Bearer::with_scope(Some(named.name), named.scope))
+                }
+                BearerConfig::Tokens(registry) => {
+                    let named = registry
+                        .read()
+                        .expect("Token registry lock poisoned")
+                        .find(header()?.as_bytes())
+                        .ok_or(BearerResponseError::BearerMissmatch)?;
+                    Ok(Bearer::with_scope(Some(named.name), named.scope))
+                }
+                BearerConfig::Jwt(verifier) => {
+                    let token = header()?
+                        .to_str()
+                        .ok()
+                        .and_then(|header| header.strip_prefix("Bearer "))
+                        .ok_or(BearerResponseError::BearerMissmatch)?;
+                    match verifier.authorize(token) {
+                        Ok((identity, scope)) => Ok(Bearer::with_scope(Some(identity.subject), scope)),
+                        Err(JwtError::Invalid(reason)) => Err(BearerResponseError::InvalidToken(reason)),
+                        Err(err @ JwtError::NoMatchingRule(_)) => {
+                            tracing::warn!(error = %err, "JWT rejected");
+                            Err(BearerResponseError::Forbidden)
+                        }
+                        Err(err) => {
+                            tracing::error!(error = %err, "JWT verification failed");
+                            Err(BearerResponseError::ConfigRead)
+                        }
+                    }
+                }
+                BearerConfig::TokenReview(_) => unreachable!("handled asynchronously"),
             }
-            Ok(Self)
         }
 
-        ready(from_request_inner(req))
+        async fn review(reviewer: Arc<TokenReviewer>, header: Option<String>) -> Result<Bearer, BearerResponseError> {
+            let token = header
+                .as_deref()
+                .ok_or(BearerResponseError::MissingAuthHeader)?
+                .strip_prefix("Bearer ")
+                .ok_or(BearerResponseError::BearerMissmatch)?;
+            let reviewed = reviewer.authenticate(token).await.map_err(|err| {
+                tracing::error!(error = %err, "TokenReview failed");
+                BearerResponseError::ReviewUnavailable
+            })?;
+            let reviewed = reviewed.ok_or(BearerResponseError::BearerMissmatch)?;
+            Ok(Bearer {
+                name: Some(reviewed.user.username.clone()),
+                scope: Scope::full(),
+                reviewed: Some(reviewed),
+            })
+        }
+
+        // a certificate verified during the TLS handshake that a rule maps to a scope takes precedence over the
+        // Authorization header
+        if let Some(ClientCertificate(identity)) = req.extensions().get::<ClientCertificate>() {
+            match Bearer::from_certificate(identity, req.app_data::<ClientCertRules>()) {
+                Ok(Some(bearer)) => return ready(Ok(bearer)).boxed_local(),
+                Err(err) => return ready(Err(err)).boxed_local(),
+                Ok(None) => {}
+            }
+        }
+        match req.app_data::<BearerConfig>() {
+            Some(BearerConfig::TokenReview(reviewer)) => {
+                let header = req
+                    .headers()
+                    .get(http::header::AUTHORIZATION)
+                    .and_then(|header| header.to_str().ok())
+                    .map(String::from);
+                review(Arc::clone(reviewer), header).boxed_local()
+            }
+            _ => ready(from_request_inner(req)).boxed_local(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{dev::Payload, test::TestRequest};
+
+    async fn bearer(rules: Option<PathBuf>, header: Option<&str>) -> Result<Bearer, BearerResponseError> {
+        let registry: TokenRegistry = serde_yaml::from_str("tokens: [{name: t, token: secret, kinds: [Pod]}]").unwrap();
+        let mut req = TestRequest::default()
+            .app_data(BearerConfig::Tokens(Arc::new(RwLock::new(registry))))
+            .app_data(ClientCertRules(rules));
+        if let Some(header) = header {
+            req = req.insert_header((http::header::AUTHORIZATION, header));
+        }
+        let req = req.to_http_request();
+        req.extensions_mut().insert(ClientCertificate(Identity {
+            subject: "alice".into(),
+            groups: vec!["team-a".into()],
+        }));
+        Bearer::from_request(&req, &mut Payload::None).await
+    }
+
+    /// a verified certificate only grants what a rule gives it, otherwise the token decides
+    #[tokio::test]
+    async fn client_certificate() {
+        assert_eq!(
+            bearer(None, None).await.err(),
+            Some(BearerResponseError::MissingAuthHeader)
+        );
+        let token = bearer(None, Some("Bearer secret")).await.unwrap();
+        assert_eq!(token.name.as_deref(), Some("t"));
+
+        let rules = std::env::temp_dir().join(format!("big-brother-cert-rules-{}", std::process::id()));
+        fs::write(&rules, "rules: [{groups: [team-a], namespaces: [a]}]").unwrap();
+        let cert = bearer(Some(rules.clone()), Some("Bearer secret")).await.unwrap();
+        assert_eq!(cert.name.as_deref(), Some("alice"));
+        assert_eq!(cert.scope.namespaces, Some(HashSet::from(["a".into()])));
+
+        fs::write(&rules, "rules: [{subjects: [bob]}]").unwrap();
+        assert_eq!(
+            bearer(Some(rules.clone()), None).await.err(),
+            Some(BearerResponseError::MissingAuthHeader)
+        );
+        let token = bearer(Some(rules), Some("Bearer secret")).await.unwrap();
+        assert_eq!(token.name.as_deref(), Some("t"));
     }
 }
@@ -0,0 +1,292 @@
+use crate::{
+    args::{Command, Kinds, Output, Remote},
+    k8s_client::api::ResourceVersion,
+    sink::Compression,
+};
+use big_brother_client::{Client, ClientError, Filter, ResourceId};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error("Request to the server failed: {}", _0)]
+    Client(#[from] ClientError),
+    #[error("Could not read the token from \"{}\": {:?}", _0.display(), _1)]
+    Token(PathBuf, #[source] io::Error),
+    #[error("Could not write \"{}\": {:?}", _0.display(), _1)]
+    Write(PathBuf, #[source] io::Error),
+    #[error("Could not write the output: {:?}", _0)]
+    Output(#[from] io::Error),
+    #[error("{} not found", _0)]
+    NotFound(String),
+    #[error("{} exists in several API groups ({}), pick one with --api-version", _0, _1)]
+    Ambiguous(String, String),
+}
+
+pub async fn run(command: Command) -> Result<(), CliError> {
+    match command {
+        Command::Watch {
+            remote,
+            kinds,
+            resource_version,
+            output,
+        } => watch(client(&remote)?, filter(&kinds), resource_version, output).await,
+        Command::Get {
+            remote,
+            kind,
+            name,
+            namespace,
+            api_version,
+            output,
+        } => {
+            let client = client(&remote)?;
+            let object = get(&client, &kind, &name, namespace.as_deref(), api_version.as_deref()).await?;
+            let text = match output {
+                Output::Pretty => serde_yaml::to_string(&object).expect("serializing JSON values can't fail"),
+                Output::Json => serde_json::to_string_pretty(&object).expect("serializing JSON values can't fail"),
+            };
+            writeln!(io::stdout().lock(), "{}", text.trim_end())?;
+            Ok(())
+        }
+        Command::List { remote, kinds, output } => list(client(&remote)?, filter(&kinds), output).await,
+        Command::Dump { remote, kinds, path } => dump(client(&remote)?, filter(&kinds), path).await,
+    }
+}
+
+fn client(remote: &Remote) -> Result<Client, CliError> {
+    let client = Client::new(&remote.server)?;
+    Ok(match &remote.token_path {
+        Some(path) => {
+            let token = fs::read_to_string(path).map_err(|err| CliError::Token(path.clone(), err))?;
+            client.with_token(token.trim())
+        }
+        None => client,
+    })
+}
+
+fn filter(kinds: &Kinds) -> Filter {
+    match (kinds.include.is_empty(), kinds.exclude.is_empty()) {
+        (false, _) => Filter::default().include(kinds.include.clone()),
+        (true, false) => Filter::default().exclude(kinds.exclude.clone()),
+        (true, true) => Filter::default(),
+    }
+}
+
+fn resource_version(object: &Value) -> &str {
+    object
+        .pointer("/metadata/resourceVersion")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+/// reconnects until the server rejects the watch for good
+async fn watch(
+    client: Client,
+    filter: Filter,
+    resource_version: Option<ResourceVersion>,
+    output: Output,
+) -> Result<(), CliError> {
+    let mut watch = client.watch().filter(filter);
+    if let Some(rv) = resource_version {
+        watch = watch.resource_version(rv);
+    }
+    let mut events = Box::pin(watch.stream());
+    while let Some(event) = events.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) if err.is_permanent() => return Err(err.into()),
+            Err(err) => {
+                eprintln!("{}, reconnecting", err);
+                continue;
+            }
+        };
+        let mut stdout = io::stdout().lock();
+        match output {
+            Output::Pretty => writeln!(
+                stdout,
+                "{:<8} {:>10} {}/{} {}",
+                event.event_type,
+                event.resource_version,
+                event.resource.api_version,
+                event.resource.kind,
+                match &event.resource.namespace {
+                    Some(namespace) => format!("{}/{}", namespace, event.resource.name),
+                    None => event.resource.name.clone(),
+                }
+            )?,
+            Output::Json => writeln!(
+                stdout,
+                "{}",
+                json!({ "type": event.event_type, "object": event.object })
+            )?,
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+async fn get(
+    client: &Client,
+    kind: &str,
+    name: &str,
+    namespace: Option<&str>,
+    api_version: Option<&str>,
+) -> Result<Value, CliError> {
+    let list = client.list(&Filter::default().include([kind])).await?;
+    let wanted = |res: &ResourceId| {
+        res.name == name
+            && res.namespace.as_deref() == namespace
+            && api_version.is_none_or(|api_version| api_version == res.api_version)
+    };
+    let mut found = list
+        .items
+        .into_iter()
+        .filter_map(|object| ResourceId::of(&object).filter(wanted).map(|res| (res, object)))
+        .collect::<Vec<_>>();
+    let described = match namespace {
+        Some(namespace) => format!("{} {}/{}", kind, namespace, name),
+        None => format!("{} {}", kind, name),
+    };
+    match found.len() {
+        0 => Err(CliError::NotFound(described)),
+        1 => Ok(found.remove(0).1),
+        _ => {
+            let api_versions = found
+                .iter()
+                .map(|(res, _)| res.api_version.as_str())
+                .collect::<Vec<_>>();
+            Err(CliError::Ambiguous(described, api_versions.join(", ")))
+        }
+    }
+}
+
+async fn list(client: Client, filter: Filter, output: Output) -> Result<(), CliError> {
+    let list = client.list(&filter).await?;
+    let mut stdout = io::stdout().lock();
+    if output == Output::Json {
+        let list = json!({
+            "resourceVersion": list.resource_version.unwrap_or_default().to_string(),
+            "items": list.items,
+        });
+        let text = serde_json::to_string_pretty(&list).expect("serializing JSON values can't fail");
+        writeln!(stdout, "{}", text)?;
+        return Ok(());
+    }
+    let mut rows = list
+        .items
+        .iter()
+        .filter_map(|object| {
+            let res = ResourceId::of(object)?;
+            let rv = resource_version(object).to_string();
+            Some([
+                res.api_version,
+                res.kind,
+                res.namespace.unwrap_or_default(),
+                res.name,
+                rv,
+            ])
+        })
+        .collect::<Vec<_>>();
+    rows.sort();
+    let header = ["APIVERSION", "KIND", "NAMESPACE", "NAME", "RESOURCEVERSION"].map(String::from);
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("   ");
+        writeln!(stdout, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// every object as a MODIFIED line in the order of their last change, then a bookmark where a watch would continue
+async fn dump(client: Client, filter: Filter, path: PathBuf) -> Result<(), CliError> {
+    let list = client.list(&filter).await?;
+    let mut lines = Vec::new();
+    for object in &list.items {
+        writeln!(lines, "{}", json!({ "type": "MODIFIED", "object": object }))?;
+    }
+    if let Some(rv) = list.resource_version {
+        let bookmark =
+            json!({ "type": "BOOKMARK", "object": { "metadata": { "resourceVersion": (rv + 1).to_string() } } });
+        writeln!(lines, "{}", bookmark)?;
+    }
+    let write_err = |err| CliError::Write(path.clone(), err);
+    let encoded = Compression::of_path(&path).encode(lines).map_err(write_err)?;
+    fs::write(&path, encoded).map_err(write_err)?;
+    eprintln!(
+        "{} objects as of resourceVersion {} written to {}",
+        list.items.len(),
+        list.resource_version.unwrap_or_default(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::collections::HashMap;
+
+    const LIST: &str = concat!(
+        r#"{"resourceVersion":"9","items":["#,
+        r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","namespace":"x","resourceVersion":"3"}},"#,
+        r#"{"apiVersion":"v2","kind":"Pod","metadata":{"name":"a","namespace":"y","resourceVersion":"5"}},"#,
+        r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","namespace":"y","resourceVersion":"9"}}"#,
+        r#"]}"#,
+    );
+
+    async fn list(query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        assert_eq!(query.get("format").map(String::as_str), Some("json"));
+        assert_eq!(query.get("include").map(String::as_str), Some("Pod"));
+        HttpResponse::Ok().body(LIST)
+    }
+
+    #[test]
+    fn dump_and_get() {
+        actix_web::rt::System::new().block_on(dump_and_get_inner());
+    }
+
+    async fn dump_and_get_inner() {
+        let server = HttpServer::new(|| App::new().route("/list", web::get().to(list)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+        let client = Client::new(&format!("http://{}", addr)).unwrap();
+
+        let path = std::env::temp_dir().join(format!("big-brother-dump-{}.ndjson.zst", std::process::id()));
+        dump(client.clone(), Filter::default().include(["Pod"]), path.clone())
+            .await
+            .unwrap();
+        let events = crate::replay::load(std::slice::from_ref(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(events.len(), 3);
+
+        let object = get(&client, "Pod", "a", Some("y"), Some("v1")).await.unwrap();
+        assert_eq!(resource_version(&object), "9");
+        assert!(matches!(
+            get(&client, "Pod", "a", Some("y"), None).await,
+            Err(CliError::Ambiguous(..))
+        ));
+        assert!(matches!(
+            get(&client, "Pod", "a", None, None).await,
+            Err(CliError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,390 @@
+use crate::{
+    bearer::{NamedToken, TokenRegistry},
+    engine::{CacheCompression, Engine, ResourceFilter, UpstreamConfig, DEFAULT_BROADCAST_CAPACITY},
+    ha::HaConfig,
+    k8s_client::{protobuf::ApiEncoding, RetryBackoff},
+    log::{self, LogFilter, LogFormat, LogLevel},
+    shutdown::{Shutdown, DEFAULT_GRACE_PERIOD_SECONDS},
+    sink::{Sinks, SinksConfig},
+    telemetry::TelemetryConfig,
+};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// `BIG_BROTHER_LOG_LEVEL=debug` overrides `logLevel`, `__` separates nested keys (`BIG_BROTHER_BACKOFF__MULTIPLIER`)
+pub const ENV_PREFIX: &str = "BIG_BROTHER_";
+/// command line options that share the prefix, not settings: the path of the file itself and where the subcommands
+/// find a running big-brother
+const ENV_OPTIONS: &[&str] = &["BIG_BROTHER_CONFIG", "BIG_BROTHER_SERVER", "BIG_BROTHER_TOKEN_PATH"];
+/// how often the file is checked for changes, SIGHUP reloads immediately
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// content of the `--config` file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    // only read at startup
+    pub listen: Option<String>,
+    #[serde(default = "default_broadcast_capacity")]
+    pub broadcast_capacity: usize,
+    /// how the cached objects are kept in memory
+    #[serde(default)]
+    pub cache_compression: CacheCompression,
+    /// how objects are requested from the API server
+    #[serde(default)]
+    pub api_encoding: ApiEncoding,
+    #[serde(default)]
+    pub backoff: RetryBackoff,
+    #[serde(default)]
+    pub resources: ResourceFilter,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub telemetry: Option<TelemetryConfig>,
+    /// time to drain `/watch` clients and sinks after SIGTERM before connections are closed
+    #[serde(default = "default_shutdown_grace_period_seconds")]
+    pub shutdown_grace_period_seconds: u64,
+    /// leader election between replicas, without it every replica watches the API server on its own
+    pub ha: Option<HaConfig>,
+    /// mirror another big-brother instead of watching the API server
+    pub upstream: Option<UpstreamConfig>,
+
+    // applied again on reload
+    /// same as the entries of a `--token-registry` file
+    pub tokens: Option<Vec<NamedToken>>,
+    /// same as the content of a `--sinks` file
+    pub sinks: Option<SinksConfig>,
+    #[serde(default)]
+    pub log_level: LogLevel,
+    /// per module levels, e.g. `engine: debug`
+    #[serde(default)]
+    pub log_filters: BTreeMap<String, LogLevel>,
+}
+
+fn default_broadcast_capacity() -> usize {
+    DEFAULT_BROADCAST_CAPACITY
+}
+
+fn default_shutdown_grace_period_seconds() -> u64 {
+    DEFAULT_GRACE_PERIOD_SECONDS
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Could not read \"{}\": {:?}", _0.display(), _1)]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Invalid configuration: {}", _0)]
+    Invalid(String),
+    #[error("Could not listen for SIGHUP: {:?}", _0)]
+    Signal(#[source] io::Error),
+}
+
+impl Config {
+    /// without a file, only the environment is used
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let yaml = match path {
+            Some(path) => Some(fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_path_buf(), err))?),
+            None => None,
+        };
+        Self::parse(yaml.as_deref(), std::env::vars()).map_err(|err| match (path, err) {
+            (Some(path), ConfigError::Invalid(reason)) => {
+                ConfigError::Invalid(format!("{}: {}", path.display(), reason))
+            }
+            (_, err) => err,
+        })
+    }
+
+    fn parse(yaml: Option<&str>, env: impl Iterator<Item = (String, String)>) -> Result<Self, ConfigError> {
+        let invalid = |err: serde_yaml::Error| ConfigError::Invalid(err.to_string());
+        let mut value = match yaml {
+            Some(yaml) if !yaml.trim().is_empty() => serde_yaml::from_str(yaml).map_err(invalid)?,
+            _ => Value::Mapping(Mapping::new()),
+        };
+        for (key, raw) in env {
+            if ENV_OPTIONS.contains(&key.as_str()) {
+                continue;
+            }
+            if let Some(path) = key.strip_prefix(ENV_PREFIX) {
+                override_value(&mut value, &key, path, &raw)?;
+            }
+        }
+        let config: Config = serde_yaml::from_value(value).map_err(invalid)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.broadcast_capacity == 0 {
+            return Err(ConfigError::Invalid("broadcastCapacity must be at least 1".into()));
+        }
+        self.backoff
+            .validate()
+            .map_err(|reason| ConfigError::Invalid(format!("backoff: {}", reason)))?;
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.validate().map_err(ConfigError::Invalid)?;
+        }
+        if let Some(ha) = &self.ha {
+            ha.validate().map_err(ConfigError::Invalid)?;
+        }
+        if let Some(upstream) = &self.upstream {
+            if self.ha.is_some() {
+                return Err(ConfigError::Invalid("`upstream` can't be combined with `ha`".into()));
+            }
+            upstream.validate().map_err(ConfigError::Invalid)?;
+        }
+        self.log_filter().env_filter().map(drop).map_err(ConfigError::Invalid)
+    }
+
+    pub fn log_filter(&self) -> LogFilter {
+        LogFilter {
+            level: self.log_level,
+            filters: self.log_filters.clone(),
+        }
+    }
+
+    /// settings that differ from `other` but are only read at startup
+    fn startup_changes(&self, other: &Config) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.listen != other.listen {
+            changes.push("listen");
+        }
+        if self.broadcast_capacity != other.broadcast_capacity {
+            changes.push("broadcastCapacity");
+        }
+        if self.cache_compression != other.cache_compression {
+            changes.push("cacheCompression");
+        }
+        if self.api_encoding != other.api_encoding {
+            changes.push("apiEncoding");
+        }
+        if self.backoff != other.backoff {
+            changes.push("backoff");
+        }
+        if self.log_format != other.log_format {
+            changes.push("logFormat");
+        }
+        if self.telemetry != other.telemetry {
+            changes.push("telemetry");
+        }
+        if self.shutdown_grace_period_seconds != other.shutdown_grace_period_seconds {
+            changes.push("shutdownGracePeriodSeconds");
+        }
+        if self.ha != other.ha {
+            changes.push("ha");
+        }
+        if self.upstream != other.upstream {
+            changes.push("upstream");
+        }
+        changes
+    }
+}
+
+/// `path` is the part of `key` after the prefix, e.g. `BACKOFF__MAX_INTERVAL_MS` sets `backoff.maxIntervalMs`
+fn override_value(value: &mut Value, key: &str, path: &str, raw: &str) -> Result<(), ConfigError> {
+    let not_mapping = || ConfigError::Invalid(format!("{} overrides a setting that is not a mapping", key));
+    let segments = path.split("__").map(camel_case).collect::<Vec<_>>();
+    let (last, parents) = segments.split_last().expect("split always yields a segment");
+    let mut current = value;
+    for segment in parents {
+        let mapping = current.as_mapping_mut().ok_or_else(not_mapping)?;
+        current = mapping
+            .entry(Value::String(segment.clone()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+    }
+    // numbers and booleans are recognized, anything else stays a string
+    let parsed = serde_yaml::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()));
+    current
+        .as_mapping_mut()
+        .ok_or_else(not_mapping)?
+        .insert(Value::String(last.clone()), parsed);
+    Ok(())
+}
+
+/// `MAX_INTERVAL_MS` -> `maxIntervalMs`
+fn camel_case(segment: &str) -> String {
+    let mut words = segment.split('_').filter(|word| !word.is_empty());
+    let mut camel = words.next().unwrap_or_default().to_lowercase();
+    for word in words {
+        let word = word.to_lowercase();
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+/// what a reload can change at runtime
+#[derive(Debug)]
+pub struct Reloadable {
+    /// `None` unless the tokens come from the configuration
+    pub tokens: Option<Arc<RwLock<TokenRegistry>>>,
+    /// `None` when `--sinks` is used instead of the configuration
+    pub sinks: Option<Sinks>,
+    /// `None` with `--replay` or `upstream`, where `resources` doesn't apply
+    pub engine: Option<Arc<Engine>>,
+}
+
+impl Reloadable {
+    async fn apply(&mut self, previous: &Config, config: &Config) {
+        for setting in previous.startup_changes(config) {
+            tracing::warn!(setting, "changing this setting only takes effect after a restart");
+        }
+        if let Err(err) = log::reload(&config.log_filter()) {
+            tracing::error!(error = %err, "could not apply the log filters");
+        }
+        match (&self.tokens, &config.tokens) {
+            (Some(registry), Some(tokens)) => {
+                *registry.write().expect("Token registry lock poisoned") = TokenRegistry { tokens: tokens.clone() };
+            }
+            (None, None) => {}
+            _ => tracing::warn!("switching between `tokens` and another authentication mode requires a restart"),
+        }
+        if let (Some(engine), true) = (&self.engine, previous.resources != config.resources) {
+            if let Err(err) = engine.reload(config.resources.clone()).await {
+                tracing::error!(error_kind = err.kind(), error = %err, "could not apply the resource filter");
+            }
+        }
+        match &mut self.sinks {
+            Some(sinks) if sinks.config() != config.sinks.as_ref() => {
+                if let Err(err) = sinks.replace(config.sinks.clone()).await {
+                    tracing::error!(error = %err, "keeping the previous sinks");
+                }
+            }
+            Some(_) => {}
+            None if config.sinks.is_some() => tracing::warn!("`sinks` is ignored, --sinks is used instead"),
+            None => {}
+        }
+    }
+}
+
+/// reloads the file on SIGHUP or when it changes until shutdown, an invalid file keeps the previous configuration
+pub fn watch(
+    path: PathBuf,
+    mut current: Config,
+    mut reloadable: Reloadable,
+    shutdown: Shutdown,
+) -> Result<(), ConfigError> {
+    let mut hangup = signal(SignalKind::hangup()).map_err(ConfigError::Signal)?;
+    let mut last_modified = modified(&path);
+    tokio::task::spawn(async move {
+        let stopped = shutdown.requested();
+        tokio::pin!(stopped);
+        loop {
+            tokio::select! {
+                _ = &mut stopped => return,
+                _ = hangup.recv() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {
+                    if modified(&path) == last_modified {
+                        continue;
+                    }
+                }
+            }
+            last_modified = modified(&path);
+            match Config::load(Some(&path)) {
+                Err(err) => tracing::error!(error = %err, "keeping the previous configuration"),
+                Ok(config) => {
+                    reloadable.apply(&current, &config).await;
+                    current = config;
+                    tracing::info!(path = %path.display(), "reloaded configuration");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::parse(Some(""), env(&[])).unwrap();
+        assert_eq!(config.broadcast_capacity, DEFAULT_BROADCAST_CAPACITY);
+        assert_eq!(config.backoff, RetryBackoff::default());
+        assert_eq!(config.log_level, LogLevel::Info);
+        assert!(config.tokens.is_none());
+    }
+
+    #[test]
+    fn env_overrides() {
+        let yaml = "{listen: '0.0.0.0:9000', backoff: {multiplier: 2}, resources: {exclude: [events]}}";
+        let config = Config::parse(
+            Some(yaml),
+            env(&[
+                ("BIG_BROTHER_LISTEN", "127.0.0.1:8080"),
+                ("BIG_BROTHER_BACKOFF__MAX_INTERVAL_MS", "5000"),
+                ("BIG_BROTHER_LOG_LEVEL", "debug"),
+                ("BIG_BROTHER_LOG_FILTERS__ENGINE", "trace"),
+                ("BIG_BROTHER_CONFIG", "/etc/big-brother.yaml"),
+                ("OTHER", "ignored"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.listen.as_deref(), Some("127.0.0.1:8080"));
+        assert_eq!(config.backoff.multiplier, 2.0);
+        assert_eq!(config.backoff.max_interval_ms, 5000);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.log_filters.get("engine"), Some(&LogLevel::Trace));
+        assert!(!config.resources.matches(None, "events"));
+        assert!(config.resources.matches(Some("apps"), "deployments"));
+    }
+
+    /// e.g. `BIG_BROTHER_SERVER=http://x:8080 big-brother --insecure-no-token`
+    #[test]
+    fn env_options() {
+        let config = Config::parse(
+            None,
+            env(&[
+                ("BIG_BROTHER_SERVER", "http://x:8080"),
+                ("BIG_BROTHER_TOKEN_PATH", "/var/run/token"),
+                ("BIG_BROTHER_CONFIG", "/etc/big-brother.yaml"),
+            ]),
+        );
+        assert!(config.is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        let err = |yaml, vars: &[(&str, &str)]| match Config::parse(Some(yaml), env(vars)) {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(err("{listne: x}", &[]).contains("unknown field `listne`"));
+        assert!(err("{backoff: {multiplier: 0.5}}", &[]).starts_with("backoff: multiplier"));
+        assert!(err("{}", &[("BIG_BROTHER_LOG_LEVEL", "loud")]).contains("unknown variant `loud`"));
+        assert!(err("{listen: x}", &[("BIG_BROTHER_LISTEN__PORT", "1")]).contains("not a mapping"));
+        assert!(err("{logFilters: {'engine=debug': info}}", &[]).contains("not a module path"));
+        // would have been unrestricted
+        assert!(err("{tokens: [{name: a, token: t, namespace: [a]}]}", &[]).contains("unknown field `namespace`"));
+    }
+
+    #[test]
+    fn resource_filter() {
+        let filter: ResourceFilter = serde_yaml::from_str("{include: [pods, deployments.apps, events]}").unwrap();
+        assert!(filter.matches(None, "pods"));
+        assert!(filter.matches(Some("apps"), "deployments"));
+        assert!(!filter.matches(Some("extensions"), "deployments"));
+        assert!(!filter.matches(None, "services"));
+    }
+}
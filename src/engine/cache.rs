@@ -1,10 +1,16 @@
-use crate::k8s_client::api::{ResourceId, ResourceVersion};
+use super::stored::{event_line, CacheCompression, Meta, Object, Stored};
+use crate::{
+    k8s_client::api::{ResourceId, ResourceVersion},
+    telemetry::Origin,
+};
+use bytes::Bytes;
+use chrono::{SecondsFormat, Utc};
 use itertools::Itertools;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
-    array::IntoIter,
-    collections::{BTreeMap, HashMap},
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    sync::Arc,
 };
 use tokio::sync::broadcast;
 use tokio_stream::{
@@ -12,29 +18,249 @@ use tokio_stream::{
     Stream, StreamExt,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum OutputEventType {
     Modified,
     Deleted,
+    /// only carries `metadata.resourceVersion`, where a client should resume
+    Bookmark,
+}
+
+impl OutputEventType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OutputEventType::Modified => "MODIFIED",
+            OutputEventType::Deleted => "DELETED",
+            OutputEventType::Bookmark => "BOOKMARK",
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Clone, PartialEq)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "EventJson")]
 pub struct OutputEvent {
+    ty: OutputEventType,
+    /// serialized once, shared by every subscriber
+    line: Bytes,
+    meta: Arc<Meta>,
+    /// only set for live changes, not for the backlog of cached objects
+    origin: Option<Origin>,
+}
+
+#[derive(Deserialize)]
+struct EventJson {
     #[serde(rename = "type")]
     ty: OutputEventType,
     object: Value,
 }
 
+impl From<EventJson> for OutputEvent {
+    fn from(event: EventJson) -> Self {
+        Self::new(event.ty, &event.object, None)
+    }
+}
+
+/// the origin doesn't tell events apart
+impl PartialEq for OutputEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.line == other.line
+    }
+}
+
+impl OutputEvent {
+    fn new(ty: OutputEventType, object: &Value, origin: Option<Origin>) -> Self {
+        Self {
+            ty,
+            line: event_line(ty.as_str(), object),
+            meta: Arc::new(Meta::of(object)),
+            origin,
+        }
+    }
+    fn cached(stored: &Stored) -> Self {
+        Self {
+            ty: OutputEventType::Modified,
+            line: stored.line(),
+            meta: Arc::clone(stored.meta()),
+            origin: None,
+        }
+    }
+    pub fn bookmark(rv: ResourceVersion) -> Self {
+        let object = serde_json::json!({ "metadata": { "resourceVersion": rv.to_string() } });
+        Self::new(OutputEventType::Bookmark, &object, None)
+    }
+    /// of an object that isn't cached anymore, with nothing but its id
+    pub fn deleted(res: ResourceId, rv: ResourceVersion) -> Self {
+        Self::new(OutputEventType::Deleted, &deleted_event(res, rv), None)
+    }
+    /// as `/watch` sends it, with the trailing newline
+    pub fn line(&self) -> &Bytes {
+        &self.line
+    }
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.ty == OutputEventType::Deleted
+    }
+    /// taken from the object's metadata, deleted objects carry the resourceVersion of their deletion
+    pub fn resource_version(&self) -> Option<ResourceVersion> {
+        self.meta.resource_version
+    }
+    /// `line` with the `timestamp` `--replay-speed` paces by, when the change was cached
+    /// (or now, for the backlog of cached objects)
+    pub fn timestamped_line(&self) -> Vec<u8> {
+        let timestamp = self.origin.as_ref().map_or_else(Utc::now, |origin| origin.timestamp);
+        // replaces the closing `}` of the event and the newline
+        let mut line = self.line[..self.line.len() - 2].to_vec();
+        line.extend_from_slice(br#","timestamp":""#);
+        line.extend_from_slice(timestamp.to_rfc3339_opts(SecondsFormat::Millis, true).as_bytes());
+        line.extend_from_slice(b"\"}\n");
+        line
+    }
+}
+
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 1024;
+
+/// `include`/`exclude` of `/watch` and `/list`, by kind
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Kinds {
+    #[default]
+    All,
+    Include(HashSet<String>),
+    Exclude(HashSet<String>),
+}
+
+impl Kinds {
+    pub fn matches(&self, kind: &str) -> bool {
+        match self {
+            Kinds::All => true,
+            Kinds::Include(kinds) => kinds.contains(kind),
+            Kinds::Exclude(kinds) => !kinds.contains(kind),
+        }
+    }
+}
+
+/// which objects a query is interested in, every condition must hold; deletions match by the labels and owner
+/// references the object had when it was deleted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selector {
+    pub kinds: Kinds,
+    pub namespace: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    /// `metadata.uid` of an owner in `metadata.ownerReferences`
+    pub owner_uid: Option<String>,
+}
+
+impl Selector {
+    /// a label selector of `key=value` pairs separated by commas
+    pub fn parse_labels(selector: &str) -> Result<BTreeMap<String, String>, String> {
+        selector
+            .split(',')
+            .filter(|term| !term.trim().is_empty())
+            .map(|term| match term.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    Ok((key.trim().to_string(), value.trim_start_matches('=').trim().to_string()))
+                }
+                _ => Err(format!("invalid label selector term {:?}, expected key=value", term)),
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, res: &ResourceId, meta: &Meta) -> bool {
+        if !self.kinds.matches(&res.kind) {
+            return false;
+        }
+        if self.namespace.is_some() && res.namespace != self.namespace {
+            return false;
+        }
+        let labels_match = self.labels.iter().all(|(key, value)| meta.label(key) == Some(value));
+        let owner_matches = self
+            .owner_uid
+            .as_ref()
+            .is_none_or(|uid| meta.owner_uids().any(|owner| owner == uid));
+        labels_match && owner_matches
+    }
+
+    /// bookmarks always pass
+    pub fn matches_event(&self, res: &ResourceId, event: &OutputEvent) -> bool {
+        match event.ty {
+            OutputEventType::Modified | OutputEventType::Deleted => self.matches(res, &event.meta),
+            OutputEventType::Bookmark => true,
+        }
+    }
+
+    /// index entries that contain every match, besides the types
+    fn index_keys(&self) -> Vec<IndexKey> {
+        let mut keys = Vec::new();
+        if let Some(namespace) = &self.namespace {
+            keys.push(IndexKey::Namespace(namespace.clone()));
+        }
+        for (key, value) in &self.labels {
+            keys.push(IndexKey::Label(Arc::from(key.as_str()), Arc::from(value.as_str())));
+        }
+        if let Some(uid) = &self.owner_uid {
+            keys.push(IndexKey::Owner(Arc::from(uid.as_str())));
+        }
+        keys
+    }
+}
+
+/// secondary indexes over the cached objects besides their type, deleted objects aren't indexed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum IndexKey {
+    Namespace(String),
+    /// the same strings as in the labels of every `Meta` that has them
+    Label(Arc<str>, Arc<str>),
+    /// `uid` of an owner reference
+    Owner(Arc<str>),
+    /// `metadata.uid`, unique
+    Uid(Arc<str>),
+}
+
+fn index_keys(res: &ResourceId, meta: &Meta) -> HashSet<IndexKey> {
+    let mut keys = HashSet::new();
+    if let Some(namespace) = &res.namespace {
+        keys.insert(IndexKey::Namespace(namespace.clone()));
+    }
+    for (key, value) in &meta.labels {
+        keys.insert(IndexKey::Label(Arc::clone(key), Arc::clone(value)));
+    }
+    for uid in &meta.owners {
+        keys.insert(IndexKey::Owner(Arc::clone(uid)));
+    }
+    if let Some(uid) = &meta.uid {
+        keys.insert(IndexKey::Uid(Arc::clone(uid)));
+    }
+    keys
+}
+
 #[derive(Debug)]
 pub struct Cache {
-    resources: HashMap<ResourceId, Option<(ResourceVersion, Value)>>,
-    changes: BTreeMap<ResourceVersion, ResourceId>,
-    tx: broadcast::Sender<(ResourceId, OutputEvent)>,
+    /// the ids are shared by the changes, the indexes and the events
+    resources: HashMap<Arc<ResourceId>, Option<(ResourceVersion, Stored)>>,
+    /// the meta of every deleted object in `resources` as of its deletion, which its deletion is selected by
+    tombstones: HashMap<Arc<ResourceId>, Arc<Meta>>,
+    /// deletions found by a relist all carry the resourceVersion of the list
+    changes: BTreeMap<ResourceVersion, Vec<Arc<ResourceId>>>,
+    /// the cached objects by apiVersion and kind, few enough to be scanned for a kind filter
+    types: HashMap<(String, String), HashSet<Arc<ResourceId>>>,
+    indexes: HashMap<IndexKey, HashSet<Arc<ResourceId>>>,
+    compression: CacheCompression,
+    tx: broadcast::Sender<(Arc<ResourceId>, OutputEvent)>,
 }
 
+type Change = Result<(Arc<ResourceId>, OutputEvent), BroadcastStreamRecvError>;
+
 fn deleted_event(res: ResourceId, rv: ResourceVersion) -> Value {
-    let mut meta = IntoIter::new([
+    let mut meta = IntoIterator::into_iter([
         ("name".to_string(), Value::String(res.name)),
         ("resourceVersion".to_string(), Value::String(rv.to_string())),
     ])
@@ -43,7 +269,7 @@ fn deleted_event(res: ResourceId, rv: ResourceVersion) -> Value {
         meta.insert("namespace".to_string(), Value::String(ns));
     }
 
-    let obj = IntoIter::new([
+    let obj = IntoIterator::into_iter([
         ("apiVersion".to_string(), Value::String(res.api_version)),
         ("kind".to_string(), Value::String(res.kind)),
         ("metadata".to_string(), Value::Object(meta)),
@@ -53,49 +279,210 @@ fn deleted_event(res: ResourceId, rv: ResourceVersion) -> Value {
 }
 
 impl Cache {
-    pub fn new() -> Self {
-        let (tx, _) = broadcast::channel(1024);
+    #[cfg(test)]
+    pub(crate) fn new() -> Self {
+        Self::with_capacity(DEFAULT_BROADCAST_CAPACITY, CacheCompression::None)
+    }
+    pub fn with_capacity(broadcast_capacity: usize, compression: CacheCompression) -> Self {
+        let (tx, _) = broadcast::channel(broadcast_capacity);
         Cache {
             resources: HashMap::new(),
+            tombstones: HashMap::new(),
             changes: BTreeMap::new(),
+            types: HashMap::new(),
+            indexes: HashMap::new(),
+            compression,
             tx,
         }
     }
-    fn update_internal(&mut self, res: ResourceId, rv: ResourceVersion, value: Option<Value>) {
-        if let Some(Some((old_rv, _))) = self.resources.insert(res.clone(), value.map(|v| (rv, v))) {
-            self.changes.remove(&old_rv);
+    /// the id as it is already shared, if it is cached
+    fn shared(&self, res: ResourceId) -> Arc<ResourceId> {
+        match self.resources.get_key_value(&res) {
+            Some((res, _)) => Arc::clone(res),
+            None => Arc::new(res),
+        }
+    }
+    /// with the strings of the index instead of copies
+    fn intern(&self, mut meta: Meta) -> Meta {
+        for (key, value) in &mut meta.labels {
+            let label = IndexKey::Label(Arc::clone(key), Arc::clone(value));
+            if let Some((IndexKey::Label(k, v), _)) = self.indexes.get_key_value(&label) {
+                *key = Arc::clone(k);
+                *value = Arc::clone(v);
+            }
+        }
+        for uid in &mut meta.owners {
+            if let Some((IndexKey::Owner(owner), _)) = self.indexes.get_key_value(&IndexKey::Owner(Arc::clone(uid))) {
+                *uid = Arc::clone(owner);
+            }
+        }
+        meta
+    }
+    fn update_internal(&mut self, res: ResourceId, rv: ResourceVersion, stored: Option<Stored>) -> Arc<ResourceId> {
+        let res = self.shared(res);
+        let type_key = (res.api_version.clone(), res.kind.clone());
+        let added = stored
+            .as_ref()
+            .map(|stored| index_keys(&res, stored.meta()))
+            .unwrap_or_default();
+        if stored.is_some() {
+            self.types.entry(type_key).or_default().insert(Arc::clone(&res));
+            self.tombstones.remove(&res);
+        } else {
+            remove_from(&mut self.types, &type_key, &res);
+        }
+        for key in &added {
+            self.indexes.entry(key.clone()).or_default().insert(Arc::clone(&res));
+        }
+        if let Some(Some((old_rv, old))) = self.resources.insert(Arc::clone(&res), stored.map(|s| (rv, s))) {
+            if let Entry::Occupied(mut changed) = self.changes.entry(old_rv) {
+                changed.get_mut().retain(|changed| *changed != res);
+                if changed.get().is_empty() {
+                    changed.remove();
+                }
+            }
+            for key in index_keys(&res, old.meta()).difference(&added) {
+                remove_from(&mut self.indexes, key, &res);
+            }
         }
-        self.changes.insert(rv, res);
+        self.changes.entry(rv).or_default().push(Arc::clone(&res));
+        res
     }
 
-    pub fn update(&mut self, res: ResourceId, rv: ResourceVersion, value: Value) {
-        self.update_internal(res.clone(), rv, Some(value.clone()));
-        self.tx
-            .send((
-                res,
-                OutputEvent {
-                    ty: OutputEventType::Modified,
-                    object: value,
-                },
-            ))
-            .ok(); // `send` will fail when there are no currently receivers, but we don't really care
+    /// an object that is already cached with the same resourceVersion is left alone, relisting after a restart of
+    /// the watches doesn't repeat it to every subscriber
+    pub fn update(&mut self, res: ResourceId, rv: ResourceVersion, value: impl Object) {
+        if let Some(Some((cached, _))) = self.resources.get(&res) {
+            if *cached == rv {
+                return;
+            }
+        }
+        // only the line and what the indexes need are kept
+        let meta = Arc::new(self.intern(value.meta()));
+        let line = event_line(OutputEventType::Modified.as_str(), &value);
+        drop(value);
+        let stored = Stored::new(line.clone(), Arc::clone(&meta), self.compression);
+        let res = self.update_internal(res, rv, Some(stored));
+        let event = OutputEvent {
+            ty: OutputEventType::Modified,
+            line,
+            meta,
+            origin: Some(Origin::current()),
+        };
+        self.tx.send((res, event)).ok(); // `send` will fail when there are no currently receivers, but we don't really care
     }
 
+    /// the deletion keeps the labels and owners of the cached object, if there is one
     pub fn remove(&mut self, res: ResourceId, rv: ResourceVersion) {
-        self.update_internal(res.clone(), rv, None);
-        self.tx
-            .send((
-                res.clone(),
-                OutputEvent {
-                    ty: OutputEventType::Deleted,
-                    object: deleted_event(res, rv),
-                },
-            ))
-            .ok(); // `send` will fail when there are no currently receivers, but we don't really care
-    }
-    pub fn list(&self) -> String {
-        let it = self.resources.iter().map(|(res, v)| match v {
-            Some((rv, _)) => format!(
+        let object = deleted_event(res.clone(), rv);
+        let meta = match self.get(&res) {
+            Some(stored) => Meta {
+                resource_version: Some(rv),
+                ..Meta::clone(stored.meta())
+            },
+            None => Meta::of(&object),
+        };
+        let meta = Arc::new(meta);
+        let event = OutputEvent {
+            ty: OutputEventType::Deleted,
+            line: event_line(OutputEventType::Deleted.as_str(), &object),
+            meta: Arc::clone(&meta),
+            origin: Some(Origin::current()),
+        };
+        let res = self.update_internal(res, rv, None);
+        self.tombstones.insert(Arc::clone(&res), meta);
+        self.tx.send((res, event)).ok(); // `send` will fail when there are no currently receivers, but we don't really care
+    }
+    /// removes the cached objects of a type that a list at `rv` didn't return, they were deleted while nobody watched
+    pub fn retain_listed(&mut self, api_version: &str, kind: &str, listed: &HashSet<ResourceId>, rv: ResourceVersion) {
+        let gone = self
+            .types
+            .get(&(api_version.to_string(), kind.to_string()))
+            .into_iter()
+            .flatten()
+            .filter(|res| !listed.contains(&***res))
+            .map(|res| ResourceId::clone(res))
+            .collect::<Vec<_>>();
+        for res in gone {
+            self.remove(res, rv);
+        }
+    }
+    /// the meta of the objects deleted at `rv` or later as of their deletion, a watch resuming from `rv` replays them
+    pub fn deleted_since(&self, rv: ResourceVersion) -> impl Iterator<Item = (&ResourceId, &Meta)> {
+        self.tombstones
+            .iter()
+            .filter(move |(_, meta)| meta.resource_version.is_some_and(|deleted| deleted >= rv))
+            .map(|(res, meta)| (&**res, &**meta))
+    }
+    /// of the latest change, including deletions
+    pub fn last_resource_version(&self) -> Option<ResourceVersion> {
+        self.changes.keys().next_back().copied()
+    }
+    pub fn ids(&self) -> impl Iterator<Item = &ResourceId> {
+        self.resources.keys().map(|res| &**res)
+    }
+    pub fn get(&self, res: &ResourceId) -> Option<&Stored> {
+        self.resources.get(res)?.as_ref().map(|(_, stored)| stored)
+    }
+    pub fn by_uid(&self, uid: &str) -> Option<(&ResourceId, &Stored)> {
+        let res = self.indexes.get(&IndexKey::Uid(Arc::from(uid)))?.iter().next()?;
+        self.get(res).map(|stored| (&**res, stored))
+    }
+    /// the ids `selector` may match, from the smallest index that covers it
+    fn candidates(&self, selector: &Selector) -> Vec<&Arc<ResourceId>> {
+        let types = self
+            .types
+            .iter()
+            .filter(|((_, kind), _)| selector.kinds.matches(kind))
+            .map(|(_, ids)| ids)
+            .collect::<Vec<_>>();
+        let mut smallest = (types.iter().map(|ids| ids.len()).sum::<usize>(), None);
+        for key in selector.index_keys() {
+            let len = self.indexes.get(&key).map_or(0, HashSet::len);
+            if len < smallest.0 {
+                smallest = (len, Some(key));
+            }
+        }
+        match smallest.1 {
+            Some(key) => self.indexes.get(&key).into_iter().flatten().collect(),
+            None => types.into_iter().flatten().collect(),
+        }
+    }
+    /// the cached objects matching `selector` in the order of their last change, without deletions
+    pub fn select(&self, selector: &Selector) -> Vec<(&ResourceId, &Stored)> {
+        self.select_shared(selector)
+            .into_iter()
+            .map(|(res, stored)| (&**res, stored))
+            .collect()
+    }
+    fn select_shared(&self, selector: &Selector) -> Vec<(&Arc<ResourceId>, &Stored)> {
+        let mut selected = self
+            .candidates(selector)
+            .into_iter()
+            .filter_map(|res| match &self.resources[res] {
+                Some((rv, stored)) if selector.matches(res, stored.meta()) => Some((*rv, res, stored)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        selected.sort_unstable_by_key(|(rv, _, _)| *rv);
+        selected.into_iter().map(|(_, res, stored)| (res, stored)).collect()
+    }
+    /// an HTML table, without a selector deleted objects are included without a resourceVersion
+    pub fn list<F: Fn(&ResourceId) -> bool>(&self, selector: &Selector, filter: F) -> String {
+        let rows: Box<dyn Iterator<Item = (&ResourceId, Option<ResourceVersion>)>> = match selector.is_empty() {
+            true => Box::new(
+                self.resources
+                    .iter()
+                    .map(|(res, v)| (&**res, v.as_ref().map(|(rv, _)| *rv))),
+            ),
+            false => Box::new(
+                self.select(selector)
+                    .into_iter()
+                    .map(|(res, _)| (res, self.resources[res].as_ref().map(|(rv, _)| *rv))),
+            ),
+        };
+        let it = rows.filter(|(res, _)| filter(res)).map(|(res, rv)| match rv {
+            Some(rv) => format!(
                 "<td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td>",
                 res.api_version, res.kind, res.namespace, res.name, rv
             ),
@@ -107,40 +494,86 @@ impl Cache {
         let head = std::iter::once("<table><tr><th>apiVersion</th><th>kind</th><th>(namespace)</th><th>name</th><th>resourceVersion</th></tr><tr>".to_string());
         let it = Itertools::intersperse(it, "</tr><tr>".to_string());
         let tail = std::iter::once("</tr></table>".to_string());
-        let s = head.chain(it).chain(tail).collect::<String>();
-        s
+        head.chain(it).chain(tail).collect::<String>()
+    }
+    /// `replay_guard` is dropped as soon as the backlog of cached objects has been consumed,
+    /// resuming from `rv` also includes the deletions since then
+    pub fn stream<G: Send + 'static>(
+        &self,
+        rv: Option<ResourceVersion>,
+        replay_guard: G,
+    ) -> impl Stream<Item = Change> {
+        self.stream_selected(rv, &Selector::default(), replay_guard)
     }
-    pub fn stream(
+    /// only the backlog is restricted to `selector`, live changes have to be checked with `Selector::matches_event`
+    pub fn stream_selected<G: Send + 'static>(
         &self,
         rv: Option<ResourceVersion>,
-    ) -> impl Stream<Item = Result<(ResourceId, OutputEvent), BroadcastStreamRecvError>> {
+        selector: &Selector,
+        replay_guard: G,
+    ) -> impl Stream<Item = Change> {
+        let modified = |res: &Arc<ResourceId>, stored: &Stored| Ok((Arc::clone(res), OutputEvent::cached(stored)));
         let range = match rv {
+            // the indexes find the objects without going through every change
+            None if !selector.is_empty() => {
+                let changes = self
+                    .select_shared(selector)
+                    .into_iter()
+                    .map(|(res, stored)| modified(res, stored))
+                    .collect::<Vec<_>>();
+                return self.chain_live(changes, replay_guard);
+            }
             Some(rv) => self.changes.range(rv..),
             None => self.changes.range(..),
         };
         let changes = range
-            .filter_map(|(_rv, res)| {
-                self.resources[res].as_ref().map(|(_rv, value)| {
-                    Ok((
-                        res.clone(),
-                        OutputEvent {
-                            ty: OutputEventType::Modified,
-                            object: value.clone(),
-                        },
-                    ))
-                })
+            .flat_map(|(change_rv, ids)| ids.iter().map(move |res| (change_rv, res)))
+            .filter(|(_, res)| match &self.resources[*res] {
+                Some((_, stored)) => selector.matches(res, stored.meta()),
+                None => selector.matches(res, &self.tombstones[*res]),
+            })
+            .filter_map(|(change_rv, res)| match &self.resources[res] {
+                Some((_rv, stored)) => Some(modified(res, stored)),
+                None if rv.is_some() => {
+                    let object = deleted_event(ResourceId::clone(res), *change_rv);
+                    let event = OutputEvent {
+                        ty: OutputEventType::Deleted,
+                        line: event_line(OutputEventType::Deleted.as_str(), &object),
+                        meta: Arc::clone(&self.tombstones[res]),
+                        origin: None,
+                    };
+                    Some(Ok((Arc::clone(res), event)))
+                }
+                None => None,
             })
             .collect::<Vec<_>>();
+        self.chain_live(changes, replay_guard)
+    }
+    fn chain_live<G: Send + 'static>(&self, changes: Vec<Change>, replay_guard: G) -> impl Stream<Item = Change> {
         // TODO: prove that we can't skip/duplicate events here
         let event_stream = BroadcastStream::new(self.tx.subscribe());
-        let stream = tokio_stream::iter(changes);
-        stream.chain(event_stream)
+        let stream = tokio_stream::iter(changes).map(move |change| {
+            let _ = &replay_guard;
+            change
+        });
+        // unlike tokio_stream's, this chain drops the first stream (and the guard) once it's exhausted
+        futures_util::StreamExt::chain(stream, event_stream)
+    }
+}
+
+fn remove_from<K: Eq + std::hash::Hash>(index: &mut HashMap<K, HashSet<Arc<ResourceId>>>, key: &K, res: &ResourceId) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(res);
+        if ids.is_empty() {
+            index.remove(key);
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json::json;
 
     fn make_res(api_version: &str, kind: &str, name: &str, namespace: Option<&str>) -> ResourceId {
         ResourceId {
@@ -151,17 +584,10 @@ mod test {
         }
     }
     fn make_evt_modified(object: Value) -> OutputEvent {
-        OutputEvent {
-            object,
-            ty: OutputEventType::Modified,
-        }
+        OutputEvent::new(OutputEventType::Modified, &object, None)
     }
     fn make_evt_deleted(res: ResourceId, rv: ResourceVersion) -> OutputEvent {
-        let object = deleted_event(res, rv);
-        OutputEvent {
-            object,
-            ty: OutputEventType::Deleted,
-        }
+        OutputEvent::new(OutputEventType::Deleted, &deleted_event(res, rv), None)
     }
 
     #[tokio::test]
@@ -171,10 +597,16 @@ mod test {
         let res2 = make_res("av", "k", "n2", None);
         cache.update(res1.clone(), 1, Value::Null);
         cache.update(res2.clone(), 2, Value::Null);
-        let mut stream = Box::pin(cache.stream(None));
+        let mut stream = Box::pin(cache.stream(None, ()));
         drop(cache);
-        assert_eq!(stream.next().await, Some(Ok((res1, make_evt_modified(Value::Null)))));
-        assert_eq!(stream.next().await, Some(Ok((res2, make_evt_modified(Value::Null)))));
+        assert_eq!(
+            stream.next().await,
+            Some(Ok((Arc::new(res1), make_evt_modified(Value::Null))))
+        );
+        assert_eq!(
+            stream.next().await,
+            Some(Ok((Arc::new(res2), make_evt_modified(Value::Null))))
+        );
         assert_eq!(stream.next().await, None);
     }
     #[tokio::test]
@@ -183,34 +615,187 @@ mod test {
         let res = make_res("av", "k", "n", None);
         cache.update(res.clone(), 1, Value::Null);
         cache.update(res.clone(), 2, Value::Null);
-        let mut stream = Box::pin(cache.stream(None));
+        let mut stream = Box::pin(cache.stream(None, ()));
         drop(cache);
-        assert_eq!(stream.next().await, Some(Ok((res, make_evt_modified(Value::Null)))));
+        assert_eq!(
+            stream.next().await,
+            Some(Ok((Arc::new(res), make_evt_modified(Value::Null))))
+        );
         assert_eq!(stream.next().await, None);
     }
     #[tokio::test]
+    async fn retain_listed() {
+        let mut cache = Cache::new();
+        let (a, b, c) = (
+            make_res("av", "k", "a", None),
+            make_res("av", "k", "b", None),
+            make_res("av", "k", "c", None),
+        );
+        let other = make_res("av", "other", "a", None);
+        cache.update(a.clone(), 1, Value::Null);
+        cache.update(b.clone(), 2, Value::Null);
+        cache.update(c.clone(), 3, Value::Null);
+        cache.update(other.clone(), 4, Value::Null);
+        // listed at the resourceVersion "c" was last changed at
+        let listed = IntoIterator::into_iter([c.clone()]).collect();
+        cache.retain_listed("av", "k", &listed, 3);
+        assert!(cache.get(&a).is_none() && cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some() && cache.get(&other).is_some());
+        let mut resumed = Box::pin(cache.stream(Some(3), ()));
+        drop(cache);
+        let mut changes = Vec::new();
+        for _ in 0..4 {
+            let (res, event) = resumed.next().await.unwrap().unwrap();
+            changes.push((res.kind.clone(), res.name.clone(), event.is_deleted()));
+        }
+        changes.sort();
+        let expected = [
+            ("k", "a", true),
+            ("k", "b", true),
+            ("k", "c", false),
+            ("other", "a", false),
+        ];
+        let expected = expected
+            .iter()
+            .map(|(kind, name, deleted)| (kind.to_string(), name.to_string(), *deleted));
+        assert_eq!(changes, expected.collect::<Vec<_>>());
+        assert_eq!(resumed.next().await, None);
+    }
+    #[tokio::test]
     async fn del_before_listening() {
         let res = make_res("av", "k", "n", None);
         let mut cache = Cache::new();
         cache.update(res.clone(), 1, Value::Null);
         cache.remove(res.clone(), 2);
-        let mut stream = Box::pin(cache.stream(None));
+        let mut stream = Box::pin(cache.stream(None, ()));
+        // a client resuming from before the deletion learns about it
+        let mut resumed = Box::pin(cache.stream(Some(2), ()));
         drop(cache);
         assert_eq!(stream.next().await, None);
+        assert_eq!(
+            resumed.next().await,
+            Some(Ok((Arc::new(res.clone()), make_evt_deleted(res, 2))))
+        );
+        assert_eq!(resumed.next().await, None);
+    }
+    #[tokio::test]
+    async fn indexes() {
+        let pod = |name: &str, namespace: &str, app: &str| {
+            let res = make_res("v1", "Pod", name, Some(namespace));
+            let object = serde_json::json!({
+                "metadata": { "labels": { "app": app }, "ownerReferences": [{ "uid": format!("rs-{}", app) }] }
+            });
+            (res, object)
+        };
+        let mut cache = Cache::new();
+        let (a, a_obj) = pod("a", "x", "web");
+        let (b, b_obj) = pod("b", "x", "db");
+        let (c, c_obj) = pod("c", "y", "web");
+        cache.update(a.clone(), 1, a_obj);
+        cache.update(b.clone(), 2, b_obj);
+        cache.update(c.clone(), 3, c_obj);
+        cache.update(make_res("v1", "Service", "web", Some("x")), 4, Value::Null);
+        let ids = |cache: &Cache, selector: &Selector| {
+            cache
+                .select(selector)
+                .into_iter()
+                .map(|(res, _)| res.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let web = Selector {
+            labels: Selector::parse_labels("app=web").unwrap(),
+            ..Selector::default()
+        };
+        assert_eq!(ids(&cache, &web), [a.clone(), c.clone()]);
+        let web_in_x = Selector {
+            namespace: Some("x".into()),
+            ..web.clone()
+        };
+        assert_eq!(ids(&cache, &web_in_x), vec![a.clone()]);
+        let pods = Selector {
+            kinds: Kinds::Include(["Pod".to_string()].into()),
+            ..Selector::default()
+        };
+        assert_eq!(ids(&cache, &pods), [a.clone(), b.clone(), c.clone()]);
+        let owned = Selector {
+            owner_uid: Some("rs-db".into()),
+            ..Selector::default()
+        };
+        assert_eq!(ids(&cache, &owned), vec![b.clone()]);
+
+        // a relabeled object moves between the index entries
+        let (_, relabeled) = pod("a", "x", "db");
+        cache.update(a.clone(), 5, relabeled);
+        assert_eq!(ids(&cache, &web), vec![c.clone()]);
+        assert_eq!(ids(&cache, &owned), [b.clone(), a.clone()]);
+        cache.remove(c.clone(), 6);
+        assert_eq!(ids(&cache, &web), []);
+        assert!(!cache.indexes.contains_key(&IndexKey::Label("app".into(), "web".into())));
+
+        let mut stream = Box::pin(cache.stream_selected(None, &owned, ()));
+        let mut resumed = Box::pin(cache.stream_selected(Some(5), &web, ()));
+        drop(cache);
+        assert_eq!(*stream.next().await.unwrap().unwrap().0, b);
+        assert_eq!(*stream.next().await.unwrap().unwrap().0, a);
+        assert_eq!(stream.next().await, None);
+        // "c" was deleted with the label it had
+        assert_eq!(
+            resumed.next().await,
+            Some(Ok((Arc::new(c.clone()), make_evt_deleted(c, 6))))
+        );
+        assert_eq!(resumed.next().await, None);
+    }
+    /// deletions are selected by the labels the object had, live and when resuming
+    #[tokio::test]
+    async fn deletions_selected() {
+        let mut cache = Cache::new();
+        let (web, db) = (
+            make_res("v1", "Pod", "web", Some("x")),
+            make_res("v1", "Pod", "db", Some("x")),
+        );
+        cache.update(web.clone(), 1, json!({"metadata": {"labels": {"app": "web"}}}));
+        cache.update(db.clone(), 2, json!({"metadata": {"labels": {"app": "db"}}}));
+        let selector = Selector {
+            labels: Selector::parse_labels("app=web").unwrap(),
+            ..Selector::default()
+        };
+        let mut live = Box::pin(cache.stream(Some(3), ()));
+        cache.remove(web.clone(), 3);
+        cache.remove(db.clone(), 4);
+        let mut resumed = Box::pin(cache.stream_selected(Some(3), &selector, ()));
+        drop(cache);
+
+        let (res, event) = live.next().await.unwrap().unwrap();
+        assert_eq!((&*res, event.is_deleted()), (&web, true));
+        assert_eq!(event.resource_version(), Some(3));
+        assert!(selector.matches_event(&res, &event));
+        let (res, event) = live.next().await.unwrap().unwrap();
+        assert_eq!(*res, db);
+        assert!(!selector.matches_event(&res, &event));
+
+        assert_eq!(
+            resumed.next().await,
+            Some(Ok((Arc::new(web.clone()), make_evt_deleted(web, 3))))
+        );
+        assert_eq!(resumed.next().await, None);
     }
     #[tokio::test]
     async fn del_after_listening() {
         let res = make_res("av", "k", "n", None);
         let mut cache = Cache::new();
         cache.update(res.clone(), 1, Value::Null);
-        let mut stream = Box::pin(cache.stream(None));
+        let mut stream = Box::pin(cache.stream(None, ()));
         cache.remove(res.clone(), 2);
         drop(cache);
         assert_eq!(
             stream.next().await,
-            Some(Ok((res.clone(), make_evt_modified(Value::Null))))
+            Some(Ok((Arc::new(res.clone()), make_evt_modified(Value::Null))))
+        );
+        assert_eq!(
+            stream.next().await,
+            Some(Ok((Arc::new(res.clone()), make_evt_deleted(res, 2))))
         );
-        assert_eq!(stream.next().await, Some(Ok((res.clone(), make_evt_deleted(res, 2)))));
         assert_eq!(stream.next().await, None);
     }
 }
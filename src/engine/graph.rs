@@ -0,0 +1,234 @@
+use super::{
+    cache::{Cache, OutputEvent},
+    stored::{owner_references, Stored},
+    Selector,
+};
+use crate::k8s_client::api::{ResourceId, ResourceVersion};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// everything the object with `root` as uid transitively owns, closest first
+pub fn descendants<'a>(cache: &'a Cache, root: &str) -> Vec<(&'a ResourceId, &'a Stored)> {
+    let mut found = Vec::new();
+    let mut visited = HashSet::from([root.to_string()]);
+    let mut queue = VecDeque::from([root.to_string()]);
+    while let Some(uid) = queue.pop_front() {
+        let owned = Selector {
+            owner_uid: Some(uid),
+            ..Selector::default()
+        };
+        for (res, object) in cache.select(&owned) {
+            // ownership cycles are invalid, but must not loop forever
+            if let Some(uid) = &object.meta().uid {
+                if !visited.insert(uid.to_string()) {
+                    continue;
+                }
+                queue.push_back(uid.to_string());
+            }
+            found.push((res, object));
+        }
+    }
+    found
+}
+
+/// the owners of `object` up to those that aren't owned by anything, closest first, and the owner references that
+/// point to objects that aren't cached (e.g. of kinds that aren't watched)
+pub fn ancestors<'a>(cache: &'a Cache, object: &'a Stored) -> (Vec<(&'a ResourceId, &'a Stored)>, Vec<Value>) {
+    let mut found = Vec::new();
+    let mut unresolved = Vec::new();
+    let mut visited = object
+        .meta()
+        .uid
+        .as_deref()
+        .map(String::from)
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let mut queue = VecDeque::from([object]);
+    while let Some(object) = queue.pop_front() {
+        for uid in object.meta().owner_uids() {
+            if !visited.insert(uid.to_string()) {
+                continue;
+            }
+            match cache.by_uid(uid) {
+                Some((res, owner)) => {
+                    found.push((res, owner));
+                    queue.push_back(owner);
+                }
+                // only the cached line has the rest of the reference
+                None => unresolved.extend(
+                    owner_references(&object.object())
+                        .find(|reference| reference.get("uid").and_then(Value::as_str) == Some(uid))
+                        .cloned(),
+                ),
+            }
+        }
+    }
+    (found, unresolved)
+}
+
+/// the object with `root` as uid and everything it transitively owns, kept up to date with the events of a watch
+#[derive(Debug)]
+pub struct Subtree {
+    root: String,
+    /// with their uid
+    members: HashMap<ResourceId, Option<String>>,
+    uids: HashSet<String>,
+}
+
+impl Subtree {
+    /// has to be created with the same view of the cache the watch starts from, a watch resuming from `since` also
+    /// replays the deletions of the members that were deleted after it
+    pub fn new(cache: &Cache, root: String, since: Option<ResourceVersion>) -> Self {
+        let deleted = since
+            .map(|rv| cache.deleted_since(rv).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut members = cache
+            .by_uid(&root)
+            .map(|(res, _)| (res.clone(), Some(root.clone())))
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        for (res, object) in descendants(cache, &root) {
+            members.insert(res.clone(), object.meta().uid.as_deref().map(String::from));
+        }
+        let mut queue = members.values().flatten().cloned().collect::<VecDeque<_>>();
+        queue.push_back(root.clone());
+        let mut visited = HashSet::new();
+        while let Some(uid) = queue.pop_front() {
+            if !visited.insert(uid.clone()) {
+                continue;
+            }
+            for (res, meta) in &deleted {
+                let joins = meta.uid.as_deref() == Some(root.as_str()) || meta.owner_uids().any(|owner| owner == uid);
+                if joins && !members.contains_key(*res) {
+                    let deleted_uid = meta.uid.as_deref().map(String::from);
+                    queue.extend(deleted_uid.clone());
+                    members.insert(ResourceId::clone(res), deleted_uid);
+                }
+            }
+        }
+        let uids = members.values().flatten().cloned().collect();
+        Self { root, members, uids }
+    }
+
+    /// whether the event concerns the subtree, the change that takes an object out of it still does
+    pub fn update(&mut self, res: &ResourceId, event: &OutputEvent) -> bool {
+        if event.is_deleted() {
+            return self.leave(res);
+        }
+        let meta = event.meta();
+        let uid = meta.uid.as_deref();
+        let member = uid == Some(self.root.as_str()) || meta.owner_uids().any(|owner| self.uids.contains(owner));
+        if member {
+            if let Some(uid) = uid {
+                self.uids.insert(uid.to_string());
+            }
+            self.members.insert(res.clone(), uid.map(String::from));
+            return true;
+        }
+        self.leave(res)
+    }
+
+    /// whether `res` was a member
+    fn leave(&mut self, res: &ResourceId) -> bool {
+        match self.members.remove(res) {
+            Some(uid) => {
+                if let Some(uid) = uid {
+                    self.uids.remove(&uid);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    fn object(kind: &str, uid: &str, owner: Option<&str>) -> (ResourceId, Value) {
+        let res = ResourceId {
+            api_version: "v1".into(),
+            kind: kind.into(),
+            name: uid.into(),
+            namespace: Some("default".into()),
+        };
+        let owners = owner.map(|owner| json!([{ "kind": "Owner", "uid": owner }]));
+        let object = json!({ "metadata": { "uid": uid, "ownerReferences": owners.unwrap_or(json!([])) } });
+        (res, object)
+    }
+
+    fn names(objects: &[(&ResourceId, &Stored)]) -> Vec<String> {
+        objects.iter().map(|(res, _)| res.name.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn graph() {
+        let mut cache = Cache::new();
+        let (deploy, deploy_obj) = object("Deployment", "deploy", Some("missing"));
+        let (rs, rs_obj) = object("ReplicaSet", "rs", Some("deploy"));
+        let (pod, pod_obj) = object("Pod", "pod", Some("rs"));
+        let (other, other_obj) = object("Pod", "other", None);
+        cache.update(deploy.clone(), 1, deploy_obj);
+        cache.update(rs, 2, rs_obj);
+        cache.update(pod.clone(), 3, pod_obj.clone());
+        cache.update(other.clone(), 4, other_obj);
+
+        assert_eq!(names(&descendants(&cache, "deploy")), ["rs", "pod"]);
+        let (owners, unresolved) = ancestors(&cache, cache.get(&pod).unwrap());
+        assert_eq!(names(&owners), ["rs", "deploy"]);
+        assert_eq!(unresolved, [json!({ "kind": "Owner", "uid": "missing" })]);
+
+        let mut subtree = Subtree::new(&cache, "deploy".into(), Some(5));
+        let mut events = Box::pin(cache.stream(Some(5), ()));
+        // a new pod of the replica set joins, one that is orphaned leaves, others are ignored
+        let (new_pod, new_pod_obj) = object("Pod", "new", Some("rs"));
+        cache.update(new_pod, 5, new_pod_obj);
+        cache.update(other, 6, json!({ "metadata": { "uid": "other", "labels": {} } }));
+        let (_, orphaned) = object("Pod", "pod", None);
+        cache.update(pod.clone(), 7, orphaned);
+        cache.update(pod.clone(), 8, json!({ "metadata": { "uid": "pod", "labels": {} } }));
+        cache.remove(deploy, 9);
+        drop(cache);
+        let mut seen = Vec::new();
+        while let Some(Ok((res, event))) = events.next().await {
+            if subtree.update(&res, &event) {
+                seen.push((res.name.clone(), event.is_deleted()));
+            }
+        }
+        assert_eq!(
+            seen,
+            [("new".into(), false), ("pod".into(), false), ("deploy".into(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_with_deletion() {
+        let mut cache = Cache::new();
+        let (deploy, deploy_obj) = object("Deployment", "deploy", None);
+        let (rs, rs_obj) = object("ReplicaSet", "rs", Some("deploy"));
+        let (pod, pod_obj) = object("Pod", "pod", Some("rs"));
+        let (other, other_obj) = object("Pod", "other", None);
+        cache.update(deploy, 1, deploy_obj);
+        cache.update(rs.clone(), 2, rs_obj);
+        cache.update(pod.clone(), 3, pod_obj);
+        cache.update(other.clone(), 4, other_obj);
+        // a client that has seen everything up to 4 resumes after the replica set and its pod were deleted
+        cache.remove(pod, 5);
+        cache.remove(rs, 6);
+        cache.remove(other, 7);
+
+        let mut subtree = Subtree::new(&cache, "deploy".into(), Some(5));
+        let mut events = Box::pin(cache.stream(Some(5), ()));
+        drop(cache);
+        let mut seen = Vec::new();
+        while let Some(Ok((res, event))) = events.next().await {
+            if subtree.update(&res, &event) {
+                seen.push((res.name.clone(), event.is_deleted()));
+            }
+        }
+        assert_eq!(seen, [("pod".into(), true), ("rs".into(), true)]);
+    }
+}
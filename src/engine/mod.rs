@@ -1,145 +1,505 @@
 mod cache;
+mod graph;
+#[allow(dead_code, clippy::multiple_bound_locations)]
 mod k8s_resource_output;
+mod stored;
 mod to_serde;
+mod upstream;
 
 use crate::{
-    engine::to_serde::convert_value_to_value,
+    engine::to_serde::Json,
     error::Error,
     event::{Event, EventType},
     k8s_client::{
         api::{
             ApiGroupListGetter, ApiResource, ApiResourceListGetter, ApiVersionListGetter, CoreResourceListGetter,
-            ListItem, Resource, ResourceId, ResourceListGetter, ResourceVersion,
+            K8sApiError, ListItem, Resource, ResourceId, ResourceListGetter, ResourceVersion, Status, StatusError,
         },
-        K8sClient,
+        protobuf::{self, ApiEncoding},
+        K8sClient, K8sClientError,
     },
+    log::Throttle,
 };
-pub use cache::Cache;
+use backoff::backoff::Backoff;
+pub use cache::{Cache, Kinds, OutputEvent, Selector, DEFAULT_BROADCAST_CAPACITY};
 use destream_json::{try_decode_iter, Value as DValue};
-use std::{convert::TryFrom, sync::Arc};
-use tokio::sync::RwLock;
+use futures_util::Stream;
+pub use graph::{ancestors, descendants, Subtree};
+use reqwest::Response;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+pub use stored::{CacheCompression, Meta, Object, Stored};
+use tokio::{sync::RwLock, task::JoinHandle};
 use tokio_stream::StreamExt;
+use tracing::Instrument;
+pub use upstream::{Upstream, UpstreamConfig, UpstreamError};
 
-pub async fn watch(k8s_client: K8sClient) -> Result<Engine, Error> {
-    let engine = Engine {
-        k8s_client,
-        cache: Arc::new(RwLock::new(Cache::new())),
-    };
-    engine.watch().await?;
-    Ok(engine)
-}
+/// a resource that keeps failing (e.g. a broken CRD) logs at most one error per interval
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// even with `backoff.initialIntervalMs: 0`, a watch that keeps failing isn't restarted in a busy loop
+const MIN_REWATCH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct Engine {
     k8s_client: K8sClient,
     cache: Arc<RwLock<Cache>>,
+    pending: PendingLists,
+    types: ResourceTypes,
+    /// replaced by `reload`
+    resources: Mutex<ResourceFilter>,
+    encoding: ApiEncoding,
+    /// between `start` and `stop`, a follower only discovers
+    started: AtomicBool,
+    /// one per watched resource, by `Discovered::name`
+    tasks: Mutex<HashMap<String, Watch>>,
+}
+
+/// the task watching a resource, and what it caches
+#[derive(Debug)]
+struct Watch {
+    api_version: String,
+    kind: String,
+    task: JoinHandle<()>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// events a `/watch` client may fall behind before it is disconnected
+    pub broadcast_capacity: usize,
+    pub compression: CacheCompression,
+    pub resources: ResourceFilter,
+    pub encoding: ApiEncoding,
+}
+
+/// selects the watched resources by plural name, either plain (`pods`) or qualified with the group
+/// (`deployments.apps`), `include` defaults to everything and `exclude` takes precedence
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceFilter {
+    #[serde(default)]
+    pub include: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl ResourceFilter {
+    pub fn matches(&self, group: Option<&str>, plural: &str) -> bool {
+        let qualified = match group {
+            Some(group) => format!("{}.{}", plural, group),
+            None => plural.to_string(),
+        };
+        let listed = |names: &[String]| names.iter().any(|name| name == plural || *name == qualified);
+        self.include.as_deref().map(listed).unwrap_or(true) && !listed(&self.exclude)
+    }
+}
+
+/// maps `(apiVersion, kind)` of watched objects to the plural resource name used in API paths and RBAC rules
+#[derive(Debug, Clone, Default)]
+pub struct ResourceTypes(Arc<std::sync::RwLock<HashMap<(String, String), String>>>);
+
+impl ResourceTypes {
+    pub fn insert(&self, api_version: String, kind: String, plural: String) {
+        self.0
+            .write()
+            .expect("Resource types lock poisoned")
+            .insert((api_version, kind), plural);
+    }
+    /// returns `(group, plural)`, group of core resources is ""
+    pub fn get(&self, api_version: &str, kind: &str) -> Option<(String, String)> {
+        let types = self.0.read().expect("Resource types lock poisoned");
+        let plural = types.get(&(api_version.to_string(), kind.to_string()))?;
+        let group = match api_version.rsplit_once('/') {
+            Some((group, _version)) => group,
+            None => "",
+        };
+        Some((group.to_string(), plural.clone()))
+    }
+}
+
+/// resource types (`<apiVersion>/<plural>`) whose initial list has not finished yet
+#[derive(Debug, Clone, Default)]
+pub struct PendingLists(Arc<Mutex<BTreeSet<String>>>);
+
+/// removes the resource type from `PendingLists` when dropped
+struct PendingGuard {
+    pending: PendingLists,
+    name: String,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.pending
+            .0
+            .lock()
+            .expect("Pending lists lock poisoned")
+            .remove(&self.name);
+    }
+}
+
+impl PendingLists {
+    fn insert(&self, name: String) -> PendingGuard {
+        self.0.lock().expect("Pending lists lock poisoned").insert(name.clone());
+        PendingGuard {
+            pending: self.clone(),
+            name,
+        }
+    }
+    pub fn get(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .expect("Pending lists lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// a resource type found by discovery that passes the resource filter
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    group: Option<String>,
+    version: String,
+    api_resource: ApiResource,
+}
+
+impl Discovered {
+    fn api_version(&self) -> String {
+        match &self.group {
+            None => self.version.clone(),
+            Some(group) => format!("{}/{}", group, self.version),
+        }
+    }
+
+    /// `<apiVersion>/<plural>`
+    fn name(&self) -> String {
+        format!("{}/{}", self.api_version(), self.api_resource.name)
+    }
+}
+
+/// the `Status` of an `ERROR` watch event, as the error of a request that failed with it
+fn status_error(status: Option<Status>) -> K8sClientError {
+    let err = StatusError::from_status(status.unwrap_or_default());
+    K8sClientError::from(K8sApiError::from(Box::new(err)))
+}
+
+fn watch_error(value: &DValue) -> Option<K8sClientError> {
+    let map = match value {
+        DValue::Map(map) => map,
+        _ => return None,
+    };
+    match map.get("type") {
+        Some(DValue::String(ty)) if ty == "ERROR" => {}
+        _ => return None,
+    }
+    let status = map
+        .get("object")
+        .and_then(|object| Status::deserialize(serde_json::to_value(Json(object)).ok()?).ok());
+    Some(status_error(status))
+}
+
+/// what a watch sends, whichever encoding the API server chose
+enum Watched {
+    /// the object as it was decoded, serialized only into the line the cache keeps
+    Event(Event<Box<dyn Object + Send>>),
+    /// an `ERROR` event, the API server ends the watch after it
+    Failed(K8sClientError),
+}
+
+async fn watch_events(response: Response) -> Pin<Box<dyn Stream<Item = Result<Watched, Error>> + Send>> {
+    if protobuf::is_content_type(response.headers()) {
+        Box::pin(protobuf::watch_events(response.bytes_stream()).map(|event| {
+            let event = event?;
+            if event.event_type == "ERROR" {
+                return Ok(Watched::Failed(status_error(serde_json::from_value(event.object).ok())));
+            }
+            let event = Event::try_from(event)?;
+            Ok(Watched::Event(Event {
+                event_type: event.event_type,
+                resource: event.resource,
+                value: Box::new(event.value),
+                resource_version: event.resource_version,
+            }))
+        }))
+    } else {
+        let values = try_decode_iter::<_, _, DValue>((), response.bytes_stream()).await;
+        Box::pin(values.map(|value| {
+            let value = value?;
+            if let Some(err) = watch_error(&value) {
+                return Ok(Watched::Failed(err));
+            }
+            let event = Event::try_from(value)?;
+            Ok(Watched::Event(Event {
+                event_type: event.event_type,
+                resource: event.resource,
+                value: Box::new(event.value),
+                resource_version: event.resource_version,
+            }))
+        }))
+    }
 }
 
 impl Engine {
-    fn watch_resource(&self, group: Option<String>, version: String, api_resource: ApiResource) {
-        // this filters out all "*/status", "*/scale", "*/approval", "v1/bindings" and "v1/componentstatuses" which we don't care about
-        if !api_resource.verbs.contains(&"watch".to_string()) {
-            return;
+    /// nothing is watched until `start`
+    pub fn new(k8s_client: K8sClient, config: &EngineConfig) -> Self {
+        Self {
+            k8s_client,
+            cache: Arc::new(RwLock::new(Cache::with_capacity(
+                config.broadcast_capacity,
+                config.compression,
+            ))),
+            pending: PendingLists::default(),
+            types: ResourceTypes::default(),
+            resources: Mutex::new(config.resources.clone()),
+            encoding: config.encoding,
+            started: AtomicBool::new(false),
+            tasks: Mutex::default(),
         }
-        println!("watching \"{}\"", api_resource.name);
+    }
+
+    fn watch_resource(&self, resource: Discovered) {
+        let (name, api_version) = (resource.name(), resource.api_version());
+        let Discovered {
+            group,
+            version,
+            api_resource,
+        } = resource;
+        let span = tracing::info_span!(
+            "watch",
+            group = group.as_deref().unwrap_or(""),
+            version = version.as_str(),
+            resource = api_resource.name.as_str()
+        );
+        span.in_scope(|| tracing::info!("watching"));
         let k8s_client = self.k8s_client.clone();
         let cache = Arc::clone(&self.cache);
-        tokio::task::spawn(async move {
-            let getter = ResourceListGetter {
-                group: group.clone(),
-                version: version.clone(),
-                plural: api_resource.name.clone(),
-            };
-            let api_version = match &group {
-                None => version.clone(),
-                Some(group) => format!("{}/{}", group, version),
-            };
-            match k8s_client.get(&getter).await {
-                Err(err) => eprintln!("k8s client error {:?}", err),
-                Ok(resource_list) => match resource_list.metadata.resource_version.parse::<ResourceVersion>() {
-                    Err(_) => {
-                        eprintln!("Invalid resource version {:?}", resource_list.metadata.resource_version);
-                    }
-                    Ok(rv) => {
-                        let mut last_rv = rv;
-                        {
-                            let mut writer = cache.write().await;
-                            for resource in resource_list.items {
-                                match serde_json::from_value::<ListItem>(resource.clone()) {
-                                    Err(err) => {
-                                        eprintln!("{:?} {} {}", group, version, api_resource.name);
-                                        eprintln!("{:#?}", resource);
-                                        eprintln!("Could not deserialize value to resource: {:?}", err)
+        let encoding = self.encoding;
+        let (resource_api_version, kind) = (api_version.clone(), api_resource.kind.clone());
+        let pending = self.pending.insert(name.clone());
+        let task = tokio::task::spawn(
+            async move {
+                let getter = ResourceListGetter {
+                    group: group.clone(),
+                    version: version.clone(),
+                    plural: api_resource.name.clone(),
+                    protobuf: encoding == ApiEncoding::Protobuf && protobuf::supports(&api_version, &api_resource.kind),
+                };
+                let mut pending = Some(pending);
+                let mut backoff = k8s_client.retry_backoff(MIN_REWATCH_INTERVAL);
+                let mut deserialize_errors = Throttle::new(ERROR_LOG_INTERVAL);
+                let mut watch_errors = Throttle::new(ERROR_LOG_INTERVAL);
+                let mut event_errors = Throttle::new(ERROR_LOG_INTERVAL);
+                // a list replaces what is cached of the type, also after the watch fell too far behind
+                'list: loop {
+                    let resource_list = match k8s_client.list(&getter).await {
+                        Err(err) => {
+                            tracing::error!(error_kind = err.kind(), error = %err, "list failed");
+                            return;
+                        }
+                        Ok(resource_list) => resource_list,
+                    };
+                    let rv = match resource_list.metadata.resource_version.parse::<ResourceVersion>() {
+                        Err(_) => {
+                            tracing::error!(
+                                resource_version = resource_list.metadata.resource_version.as_str(),
+                                "list returned an invalid resourceVersion"
+                            );
+                            return;
+                        }
+                        Ok(rv) => rv,
+                    };
+                    let mut last_rv = rv;
+                    let list_span = tracing::info_span!(
+                        parent: None,
+                        "list",
+                        group = group.as_deref().unwrap_or(""),
+                        version = version.as_str(),
+                        resource = api_resource.name.as_str(),
+                        resource_version = rv,
+                        items = resource_list.items.len(),
+                    );
+                    async {
+                        let mut writer = cache.write().await;
+                        let mut listed = HashSet::new();
+                        for resource in resource_list.items {
+                            match ListItem::deserialize(&resource) {
+                                Err(err) => {
+                                    if deserialize_errors.check().is_some() {
+                                        tracing::error!(
+                                            error_kind = "deserialize",
+                                            error = %err,
+                                            "could not deserialize a listed item"
+                                        );
                                     }
-                                    Ok(res) => match res.metadata.resource_version.parse::<ResourceVersion>() {
+                                    tracing::debug!(object = %resource, "item that could not be deserialized");
+                                }
+                                Ok(res) => {
+                                    let k8s_resource = ResourceId {
+                                        api_version: api_version.clone(),
+                                        kind: api_resource.kind.clone(),
+                                        name: res.metadata.name.clone(),
+                                        namespace: res.metadata.namespace.clone(),
+                                    };
+                                    listed.insert(k8s_resource.clone());
+                                    match res.metadata.resource_version.parse::<ResourceVersion>() {
                                         Ok(rv) => {
-                                            let k8s_resource = ResourceId {
-                                                api_version: api_version.clone(),
-                                                kind: api_resource.kind.clone(),
-                                                name: res.metadata.name.clone(),
-                                                namespace: res.metadata.namespace.clone(),
-                                            };
                                             let value = Resource {
                                                 api_version: resource_list.api_version.clone(),
                                                 kind: resource_list.kind.clone(),
                                                 rest: resource,
                                             };
-                                            // expectations:
-                                            // serde_json::to_value fails if `T`'s implementation of `Serialize` decides to fail, or if `T` contains a map with non-string keys.
-                                            // None of those cases shall happen
-                                            writer.update(
-                                                k8s_resource,
-                                                rv,
-                                                serde_json::to_value(&value).expect("Resource serialization failed"),
-                                            );
+                                            // serialized straight into its line, without a copy of the tree
+                                            writer.update(k8s_resource, rv, value);
                                         }
-                                        Err(_) => {
-                                            eprintln!("Invalid resourceVersion {:?}", res.metadata.resource_version)
-                                        }
-                                    },
+                                        Err(_) => tracing::error!(
+                                            name = res.metadata.name.as_str(),
+                                            resource_version = res.metadata.resource_version.as_str(),
+                                            "listed item has an invalid resourceVersion"
+                                        ),
+                                    }
                                 }
                             }
-                        } // drop writer
-
-                        loop {
-                            match k8s_client.watch(&getter, last_rv).await {
-                                Err(err) => eprintln!("Watch error: {:?}", err),
-                                Ok(response) => {
-                                    let json_stream =
-                                        try_decode_iter::<_, _, DValue>((), response.bytes_stream()).await;
-                                    tokio::pin!(json_stream);
-                                    while let Some(value) = json_stream.next().await {
-                                        match value
-                                            .map_err(Error::DeserializeStream)
-                                            .and_then(|value| Event::try_from(value).map_err(Error::EventParseError))
-                                        {
-                                            Err(e) => eprintln!("{:?}", e),
-                                            Ok(evt) => {
-                                                last_rv = evt.resource_version;
-                                                let mut writer = cache.write().await;
-                                                match &evt.event_type {
-                                                    EventType::Added | EventType::Modified => writer.update(
-                                                        evt.resource.clone(),
-                                                        evt.resource_version,
-                                                        convert_value_to_value(&evt.value),
-                                                    ),
-                                                    EventType::Deleted => {
-                                                        writer.remove(evt.resource.clone(), evt.resource_version)
-                                                    }
-                                                }
+                        }
+                        // deleted while nothing watched, e.g. before a follower took over or while the watch was gone
+                        writer.retain_listed(&api_version, &api_resource.kind, &listed, rv);
+                    } // drop writer
+                    .instrument(list_span)
+                    .await;
+                    pending.take();
+
+                    loop {
+                        let watched = match k8s_client.watch(&getter, last_rv).await {
+                            Err(err) => Err(err),
+                            Ok(response) => {
+                                watch_errors.reset();
+                                let mut events = watch_events(response).await;
+                                let mut ended = Ok(());
+                                while let Some(watched) = events.next().await {
+                                    let evt = match watched {
+                                        Ok(Watched::Event(evt)) => evt,
+                                        Ok(Watched::Failed(err)) => {
+                                            ended = Err(err);
+                                            break;
+                                        }
+                                        Err(err) => {
+                                            if let Some(suppressed) = event_errors.check() {
+                                                tracing::warn!(
+                                                    error_kind = err.kind(),
+                                                    error = %err,
+                                                    suppressed,
+                                                    "invalid watch event"
+                                                );
+                                            }
+                                            continue;
+                                        }
+                                    };
+                                    backoff.reset();
+                                    last_rv = evt.resource_version;
+                                    // a trace of its own, followed to every subscriber
+                                    let event_span = tracing::info_span!(
+                                        parent: None,
+                                        "event",
+                                        group = group.as_deref().unwrap_or(""),
+                                        version = version.as_str(),
+                                        resource = api_resource.name.as_str(),
+                                        name = evt.resource.name.as_str(),
+                                        namespace = evt.resource.namespace.as_deref().unwrap_or(""),
+                                        resource_version = evt.resource_version,
+                                        r#type = ?evt.event_type,
+                                    );
+                                    async {
+                                        let mut writer = cache.write().await;
+                                        match evt.event_type {
+                                            EventType::Added | EventType::Modified => {
+                                                writer.update(evt.resource, evt.resource_version, evt.value)
                                             }
+                                            EventType::Deleted => writer.remove(evt.resource, evt.resource_version),
                                         }
                                     }
+                                    .instrument(event_span)
+                                    .await;
                                 }
+                                ended
+                            }
+                        };
+                        match watched {
+                            // the API server ends every watch after a while
+                            Ok(()) => continue,
+                            Err(err) if err.is_rejected() => {
+                                tracing::error!(error_kind = err.kind(), error = %err, "stopped watching");
+                                return;
+                            }
+                            Err(err) if err.is_gone() => {
+                                tracing::info!(resource_version = last_rv, "resourceVersion expired, relisting");
+                                continue 'list;
+                            }
+                            Err(err) => {
+                                let delay = backoff.next_backoff().unwrap_or(backoff.max_interval);
+                                if let Some(suppressed) = watch_errors.check() {
+                                    tracing::warn!(
+                                        error_kind = err.kind(),
+                                        error = %err,
+                                        suppressed,
+                                        resource_version = last_rv,
+                                        retry_in_ms = delay.as_millis() as u64,
+                                        "watch failed"
+                                    );
+                                }
+                                tokio::time::sleep(delay).await;
                             }
                         }
                     }
-                },
+                }
             }
-        });
+            .instrument(span),
+        );
+        let watch = Watch {
+            api_version: resource_api_version,
+            kind,
+            task,
+        };
+        if let Some(previous) = self
+            .tasks
+            .lock()
+            .expect("Engine tasks lock poisoned")
+            .insert(name, watch)
+        {
+            previous.task.abort();
+        }
     }
 
-    async fn watch(&self) -> Result<(), Error> {
+    /// finds the resource types to watch and registers them in `types`, without watching anything
+    pub async fn discover(&self) -> Result<Vec<Discovered>, Error> {
+        let resources = self.resources.lock().expect("Resource filter lock poisoned").clone();
+        let mut discovered = Vec::new();
+        let mut found = |group: Option<String>, version: &str, api_resource: ApiResource| {
+            // this filters out all "*/status", "*/scale", "*/approval", "v1/bindings" and "v1/componentstatuses" which we don't care about
+            if !api_resource.verbs.contains(&"watch".to_string()) {
+                return;
+            }
+            if !resources.matches(group.as_deref(), &api_resource.name) {
+                return;
+            }
+            let api_version = match &group {
+                None => version.to_string(),
+                Some(group) => format!("{}/{}", group, version),
+            };
+            self.types
+                .insert(api_version, api_resource.kind.clone(), api_resource.name.clone());
+            discovered.push(Discovered {
+                group,
+                version: version.to_string(),
+                api_resource,
+            });
+        };
         let group_list = self.k8s_client.get(&ApiGroupListGetter).await?;
         let api_versions = group_list.groups.into_iter().filter_map(|api_group| {
             let name = api_group.name;
@@ -159,7 +519,7 @@ impl Engine {
                 })
                 .await?;
             for resource in api_resources.resources {
-                self.watch_resource(Some(group.clone()), version.clone(), resource.clone());
+                found(Some(group.clone()), &version, resource);
             }
         }
 
@@ -172,12 +532,374 @@ impl Engine {
                 })
                 .await?;
             for core_resource in core_api_resources.resources {
-                self.watch_resource(None, version.clone(), core_resource.clone());
+                found(None, &version, core_resource);
             }
         }
+        Ok(discovered)
+    }
+
+    /// lists and watches every discovered resource into the cache, objects that are already cached with the
+    /// same resourceVersion (e.g. replicated from a previous leader) are not sent to subscribers again
+    pub async fn start(&self) -> Result<(), Error> {
+        self.started.store(true, Ordering::SeqCst);
+        for resource in self.discover().await? {
+            self.watch_resource(resource);
+        }
+        Ok(())
+    }
+
+    /// applies a changed resource filter: newly matching resources are listed and watched, the watches of resources
+    /// that no longer match are stopped and their objects removed from the cache
+    pub async fn reload(&self, resources: ResourceFilter) -> Result<(), Error> {
+        *self.resources.lock().expect("Resource filter lock poisoned") = resources;
+        let discovered = self.discover().await?;
+        if !self.started.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let names = discovered.iter().map(Discovered::name).collect::<HashSet<_>>();
+        let (stopped, added) = {
+            let mut tasks = self.tasks.lock().expect("Engine tasks lock poisoned");
+            let gone = tasks
+                .keys()
+                .filter(|name| !names.contains(*name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let stopped = gone.iter().filter_map(|name| tasks.remove(name)).collect::<Vec<_>>();
+            let added = discovered
+                .into_iter()
+                .filter(|resource| !tasks.contains_key(&resource.name()))
+                .collect::<Vec<_>>();
+            (stopped, added)
+        };
+        for watch in stopped {
+            tracing::info!(
+                api_version = watch.api_version.as_str(),
+                kind = watch.kind.as_str(),
+                "stopped watching"
+            );
+            watch.task.abort();
+            let _ = watch.task.await;
+            let mut cache = self.cache.write().await;
+            // a change of its own, clients that have seen the latest one resume after it
+            let rv = cache.last_resource_version().map_or(0, |rv| rv + 1);
+            cache.retain_listed(&watch.api_version, &watch.kind, &HashSet::new(), rv);
+        }
+        for resource in added {
+            self.watch_resource(resource);
+        }
         Ok(())
     }
     pub fn cache(&self) -> &Arc<RwLock<Cache>> {
         &self.cache
     }
+    pub fn pending(&self) -> &PendingLists {
+        &self.pending
+    }
+    pub fn types(&self) -> &ResourceTypes {
+        &self.types
+    }
+    /// cancels all watches, the cache keeps its content but stops changing until `start` is called again
+    pub async fn stop(&self) {
+        self.started.store(false, Ordering::SeqCst);
+        let tasks = std::mem::take(&mut *self.tasks.lock().expect("Engine tasks lock poisoned"));
+        for watch in tasks.values() {
+            watch.task.abort();
+        }
+        for (_, watch) in tasks {
+            let _ = watch.task.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::json;
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    fn pod(name: &str, rv: u64) -> serde_json::Value {
+        json!({"metadata": {"name": name, "namespace": "default", "resourceVersion": rv.to_string()}})
+    }
+
+    /// "b" is deleted after the first list, the watch from there has expired
+    async fn pods(lists: web::Data<AtomicUsize>, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        match query.get("resourceVersion").map(String::as_str) {
+            None => {
+                let (rv, items) = match lists.fetch_add(1, Ordering::SeqCst) {
+                    0 => (10, vec![pod("a", 5), pod("b", 6)]),
+                    _ => (20, vec![pod("a", 5)]),
+                };
+                HttpResponse::Ok().json(json!({
+                    "apiVersion": "v1",
+                    "kind": "PodList",
+                    "metadata": {"resourceVersion": rv.to_string()},
+                    "items": items,
+                }))
+            }
+            Some("10") => HttpResponse::Ok().body(concat!(
+                r#"{"type":"ERROR","object":{"kind":"Status","apiVersion":"v1","metadata":{},"status":"Failure","message":"too old resource version: 10 (15)","reason":"Expired","code":410}}"#,
+                "\n"
+            )),
+            Some(_) => HttpResponse::Ok()
+                .streaming(futures_util::stream::pending::<Result<bytes::Bytes, actix_web::Error>>()),
+        }
+    }
+
+    fn encoded_pod(name: &str, rv: u64) -> Vec<u8> {
+        use protobuf::tests::len;
+        len(1, [len(1, name), len(3, "default"), len(6, rv.to_string())].concat())
+    }
+
+    /// answers like an API server asked for protobuf
+    async fn encoded_pods(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        use protobuf::tests::{frame, len, object};
+        let accept = req.headers().get("accept").and_then(|accept| accept.to_str().ok());
+        assert_eq!(accept, Some(protobuf::ACCEPT));
+        match query.get("resourceVersion").map(String::as_str) {
+            None => {
+                let list = [len(1, len(2, "10")), len(2, encoded_pod("a", 5))].concat();
+                HttpResponse::Ok()
+                    .content_type("application/vnd.kubernetes.protobuf")
+                    .body(object("v1", "PodList", &list))
+            }
+            Some("10") => HttpResponse::Ok()
+                .content_type("application/vnd.kubernetes.protobuf;stream=watch")
+                .body(frame("MODIFIED", object("v1", "Pod", &encoded_pod("a", 11)))),
+            Some(_) => {
+                HttpResponse::Ok().streaming(futures_util::stream::pending::<Result<bytes::Bytes, actix_web::Error>>())
+            }
+        }
+    }
+
+    #[test]
+    fn protobuf() {
+        actix_web::rt::System::new().block_on(protobuf_inner());
+    }
+
+    async fn protobuf_inner() {
+        let server = HttpServer::new(|| App::new().route("/api/v1/pods", web::get().to(encoded_pods)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let config = EngineConfig {
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            compression: CacheCompression::None,
+            resources: ResourceFilter::default(),
+            encoding: ApiEncoding::Protobuf,
+        };
+        let engine = Engine::new(K8sClient::for_test(&format!("http://{}", addr)), &config);
+        let mut changes = Box::pin(engine.cache().read().await.stream(None, ()));
+        engine.watch_resource(Discovered {
+            group: None,
+            version: "v1".into(),
+            api_resource: ApiResource {
+                kind: "Pod".into(),
+                name: "pods".into(),
+                ..ApiResource::default()
+            },
+        });
+        let mut versions = Vec::new();
+        while versions.len() < 2 {
+            let (res, event) = changes.next().await.unwrap().unwrap();
+            assert_eq!(res.name, "a");
+            versions.push(event.resource_version());
+        }
+        assert_eq!(versions, vec![Some(5), Some(11)]);
+        assert_eq!(engine.cache().read().await.select(&Selector::default()).len(), 1);
+        engine.stop().await;
+    }
+
+    #[test]
+    fn relist() {
+        actix_web::rt::System::new().block_on(relist_inner());
+    }
+
+    async fn relist_inner() {
+        let lists = web::Data::new(AtomicUsize::new(0));
+        let data = lists.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/api/v1/pods", web::get().to(pods))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let config = EngineConfig {
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            compression: CacheCompression::None,
+            resources: ResourceFilter::default(),
+            encoding: ApiEncoding::default(),
+        };
+        let engine = Engine::new(K8sClient::for_test(&format!("http://{}", addr)), &config);
+        let mut changes = Box::pin(engine.cache().read().await.stream(None, ()));
+        engine.watch_resource(Discovered {
+            group: None,
+            version: "v1".into(),
+            api_resource: ApiResource {
+                kind: "Pod".into(),
+                name: "pods".into(),
+                ..ApiResource::default()
+            },
+        });
+        let mut names = Vec::new();
+        while names.len() < 3 {
+            let (res, event) = changes.next().await.unwrap().unwrap();
+            names.push((res.name.clone(), event.is_deleted(), event.resource_version()));
+        }
+        assert_eq!(
+            names,
+            vec![
+                ("a".to_string(), false, Some(5)),
+                ("b".to_string(), false, Some(6)),
+                // the relist doesn't repeat "a"
+                ("b".to_string(), true, Some(20)),
+            ]
+        );
+        assert_eq!(lists.load(Ordering::SeqCst), 2);
+        let cache = engine.cache().read().await;
+        assert_eq!(cache.last_resource_version(), Some(20));
+        assert_eq!(cache.select(&Selector::default()).len(), 1);
+        drop(cache);
+        engine.stop().await;
+    }
+    /// discovery and one object each of "pods" and "configmaps", watches never send anything
+    async fn core(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        let resource = |name: &str, kind: &str| json!({"name": name, "kind": kind, "namespaced": true, "singularName": "", "verbs": ["list", "watch"]});
+        match (req.path(), query.get("resourceVersion")) {
+            ("/api", _) => HttpResponse::Ok()
+                .json(json!({"kind": "APIVersions", "versions": ["v1"], "serverAddressByClientCIDRs": []})),
+            ("/apis", _) => HttpResponse::Ok().json(json!({"apiVersion": "v1", "kind": "APIGroupList", "groups": []})),
+            ("/api/v1", _) => HttpResponse::Ok().json(json!({
+                "groupVersion": "v1",
+                "resources": [resource("pods", "Pod"), resource("configmaps", "ConfigMap")],
+            })),
+            (path, None) => HttpResponse::Ok().json(json!({
+                "apiVersion": "v1",
+                "kind": if path.ends_with("pods") { "PodList" } else { "ConfigMapList" },
+                "metadata": {"resourceVersion": "10"},
+                "items": [pod(if path.ends_with("pods") { "p" } else { "c" }, 5)],
+            })),
+            (_, Some(_)) => {
+                HttpResponse::Ok().streaming(futures_util::stream::pending::<Result<bytes::Bytes, actix_web::Error>>())
+            }
+        }
+    }
+
+    /// a follower takes over: what it replicated from the previous leader is reconciled with its first list
+    #[test]
+    fn takeover() {
+        actix_web::rt::System::new().block_on(takeover_inner());
+    }
+
+    async fn takeover_inner() {
+        let server = HttpServer::new(|| App::new().default_service(web::get().to(core)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let config = EngineConfig {
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            compression: CacheCompression::None,
+            resources: ResourceFilter {
+                include: Some(vec!["pods".into()]),
+                exclude: Vec::new(),
+            },
+            encoding: ApiEncoding::Json,
+        };
+        let engine = Engine::new(K8sClient::for_test(&format!("http://{}", addr)), &config);
+        let replicated = |name: &str| ResourceId {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: name.into(),
+            namespace: Some("default".into()),
+        };
+        let mut changes = {
+            let mut cache = engine.cache().write().await;
+            cache.update(replicated("p"), 5, pod("p", 5));
+            // deleted while no replica was watching, between the old leader's last event and the takeover
+            cache.update(replicated("gone"), 7, pod("gone", 7));
+            Box::pin(cache.stream(Some(8), ()))
+        };
+        engine.start().await.unwrap();
+        let (res, event) = changes.next().await.unwrap().unwrap();
+        assert_eq!(res.name, "gone");
+        assert!(event.is_deleted());
+        assert_eq!(event.resource_version(), Some(10));
+        let cache = engine.cache().read().await;
+        let cached = cache.select(&Selector::default());
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].0.name, "p");
+        drop(cache);
+        engine.stop().await;
+    }
+
+    #[test]
+    fn reload() {
+        actix_web::rt::System::new().block_on(reload_inner());
+    }
+
+    async fn reload_inner() {
+        let server = HttpServer::new(|| App::new().default_service(web::get().to(core)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let only = |plural: &str| ResourceFilter {
+            include: Some(vec![plural.to_string()]),
+            exclude: Vec::new(),
+        };
+        let config = EngineConfig {
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            compression: CacheCompression::None,
+            resources: only("pods"),
+            encoding: ApiEncoding::Json,
+        };
+        let engine = Engine::new(K8sClient::for_test(&format!("http://{}", addr)), &config);
+        let mut changes = Box::pin(engine.cache().read().await.stream(None, ()));
+        engine.start().await.unwrap();
+        let (res, _) = changes.next().await.unwrap().unwrap();
+        assert_eq!((res.kind.as_str(), res.name.as_str()), ("Pod", "p"));
+        let last = engine.cache().read().await.last_resource_version().unwrap();
+
+        engine.reload(only("configmaps")).await.unwrap();
+        // a client that has seen everything before the reload gets the removal when it resumes
+        let (res, event) = Box::pin(engine.cache().read().await.stream(Some(last + 1), ()))
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.kind.as_str(), event.is_deleted()), ("Pod", true));
+        assert_eq!(event.resource_version(), Some(last + 1));
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            let (res, event) = changes.next().await.unwrap().unwrap();
+            seen.push((res.kind.clone(), event.is_deleted()));
+        }
+        seen.sort();
+        assert_eq!(seen, vec![("ConfigMap".to_string(), false), ("Pod".to_string(), true)]);
+        assert_eq!(
+            engine.tasks.lock().unwrap().keys().collect::<Vec<_>>(),
+            vec!["v1/configmaps"]
+        );
+        engine.stop().await;
+
+        // a stopped engine, e.g. of a follower, only discovers
+        engine.reload(only("pods")).await.unwrap();
+        assert!(engine.tasks.lock().unwrap().is_empty());
+    }
 }
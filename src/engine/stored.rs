@@ -0,0 +1,297 @@
+use super::to_serde::Json;
+use crate::k8s_client::api::{Resource, ResourceVersion};
+use bytes::Bytes;
+use destream_json::Value as DValue;
+use serde::{Deserialize, Serializer};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// how the cached objects are kept in memory, `zstd` saves over a third of it for the CPU to decompress the backlog
+/// of every new `/watch`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheCompression {
+    #[default]
+    None,
+    Zstd,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// what the indexes and selectors need from an object, parsed once when it's cached
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Meta {
+    pub resource_version: Option<ResourceVersion>,
+    pub uid: Option<Arc<str>>,
+    /// sorted by key, shared with the index of the cache
+    pub labels: Vec<(Arc<str>, Arc<str>)>,
+    /// `uid`s of the owner references, shared with the index of the cache
+    pub owners: Vec<Arc<str>>,
+}
+
+impl Meta {
+    pub fn of(object: &Value) -> Self {
+        let str_at = |pointer: &str| object.pointer(pointer).and_then(Value::as_str);
+        let mut labels = object
+            .pointer("/metadata/labels")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((Arc::from(key.as_str()), Arc::from(value.as_str()?))))
+            .collect::<Vec<_>>();
+        labels.sort_unstable();
+        Self {
+            resource_version: str_at("/metadata/resourceVersion").and_then(|rv| rv.parse().ok()),
+            uid: str_at("/metadata/uid").map(Arc::from),
+            labels,
+            owners: owner_references(object)
+                .filter_map(|owner| owner.get("uid").and_then(Value::as_str))
+                .map(Arc::from)
+                .collect(),
+        }
+    }
+
+    /// the same as `of`, without converting the object to a `serde_json::Value` first
+    pub fn of_destream(object: &DValue) -> Self {
+        fn get<'a>(value: &'a DValue, key: &str) -> Option<&'a DValue> {
+            match value {
+                DValue::Map(map) => map.get(key),
+                _ => None,
+            }
+        }
+        fn as_str(value: &DValue) -> Option<&str> {
+            match value {
+                DValue::String(s) => Some(s),
+                _ => None,
+            }
+        }
+        let metadata = get(object, "metadata");
+        let str_at = |key: &str| metadata.and_then(|metadata| get(metadata, key)).and_then(as_str);
+        let mut labels = match metadata.and_then(|metadata| get(metadata, "labels")) {
+            Some(DValue::Map(labels)) => labels
+                .iter()
+                .filter_map(|(key, value)| Some((Arc::from(key.as_str()), Arc::from(as_str(value)?))))
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        labels.sort_unstable();
+        let owners = match metadata.and_then(|metadata| get(metadata, "ownerReferences")) {
+            Some(DValue::List(owners)) => owners
+                .iter()
+                .filter_map(|owner| get(owner, "uid").and_then(as_str))
+                .map(Arc::from)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            resource_version: str_at("resourceVersion").and_then(|rv| rv.parse().ok()),
+            uid: str_at("uid").map(Arc::from),
+            labels,
+            owners,
+        }
+    }
+
+    pub fn label(&self, key: &str) -> Option<&str> {
+        let i = self.labels.binary_search_by(|(k, _)| (**k).cmp(key)).ok()?;
+        Some(&self.labels[i].1)
+    }
+
+    pub fn owner_uids(&self) -> impl Iterator<Item = &str> {
+        self.owners.iter().map(|uid| &**uid)
+    }
+}
+
+pub fn owner_references(object: &Value) -> impl Iterator<Item = &Value> {
+    object
+        .pointer("/metadata/ownerReferences")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+}
+
+/// an object the cache takes, serialized once into its line
+pub trait Object {
+    fn meta(&self) -> Meta;
+    fn write_json(&self, out: &mut Vec<u8>);
+}
+
+impl Object for Value {
+    fn meta(&self) -> Meta {
+        Meta::of(self)
+    }
+    fn write_json(&self, out: &mut Vec<u8>) {
+        serde_json::to_writer(out, self).expect("serializing JSON values can't fail");
+    }
+}
+
+/// as it was streamed from a JSON watch
+impl Object for DValue {
+    fn meta(&self) -> Meta {
+        Meta::of_destream(self)
+    }
+    fn write_json(&self, out: &mut Vec<u8>) {
+        serde_json::to_writer(out, &Json(self)).expect("values decoded from JSON serialize as JSON");
+    }
+}
+
+/// as listed, with the apiVersion and kind of the list among the keys in the order of a `serde_json::Value`
+impl Object for Resource {
+    fn meta(&self) -> Meta {
+        Meta::of(&self.rest)
+    }
+    fn write_json(&self, out: &mut Vec<u8>) {
+        let api_version = Value::String(self.api_version.clone());
+        let kind = Value::String(self.kind.clone());
+        let mut entries = self
+            .rest
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key.as_str(), value))
+            .filter(|(key, _)| *key != "apiVersion" && *key != "kind")
+            .chain([("apiVersion", &api_version), ("kind", &kind)])
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        serde_json::Serializer::new(out)
+            .collect_map(entries)
+            .expect("serializing JSON values can't fail");
+    }
+}
+
+impl<T: Object + ?Sized> Object for Box<T> {
+    fn meta(&self) -> Meta {
+        (**self).meta()
+    }
+    fn write_json(&self, out: &mut Vec<u8>) {
+        (**self).write_json(out)
+    }
+}
+
+/// the line of an event as `/watch` sends it, `{"type":...,"object":...}` and a newline
+pub fn event_line(ty: &str, object: &(impl Object + ?Sized)) -> Bytes {
+    let mut line = format!(r#"{{"type":"{}","object":"#, ty).into_bytes();
+    object.write_json(&mut line);
+    line.extend_from_slice(b"}\n");
+    Bytes::from(line)
+}
+
+const OBJECT_KEY: &[u8] = br#","object":"#;
+
+/// the object within a line from `event_line`
+fn object_json(line: &Bytes) -> Bytes {
+    let start = line
+        .windows(OBJECT_KEY.len())
+        .position(|window| window == OBJECT_KEY)
+        .expect("event lines have an object")
+        + OBJECT_KEY.len();
+    line.slice(start..line.len() - 2)
+}
+
+/// a cached object, kept as its `MODIFIED` line, which every `/watch` shares when it isn't compressed
+#[derive(Debug, Clone)]
+pub struct Stored {
+    data: Bytes,
+    compression: CacheCompression,
+    meta: Arc<Meta>,
+}
+
+impl Stored {
+    pub fn new(line: Bytes, meta: Arc<Meta>, compression: CacheCompression) -> Self {
+        let data = match compression {
+            CacheCompression::None => line,
+            CacheCompression::Zstd => {
+                Bytes::from(zstd::bulk::compress(&line, ZSTD_LEVEL).expect("compressing into memory can't fail"))
+            }
+        };
+        Self {
+            data,
+            compression,
+            meta,
+        }
+    }
+
+    pub fn line(&self) -> Bytes {
+        match self.compression {
+            CacheCompression::None => self.data.clone(),
+            CacheCompression::Zstd => {
+                Bytes::from(zstd::stream::decode_all(&self.data[..]).expect("the cache only holds valid zstd frames"))
+            }
+        }
+    }
+
+    /// just the object, as it was cached
+    pub fn json(&self) -> Bytes {
+        object_json(&self.line())
+    }
+
+    /// parsed again, prefer `json` to pass it on
+    pub fn object(&self) -> Value {
+        serde_json::from_slice(&self.json()).expect("the cache only holds valid JSON")
+    }
+
+    pub fn meta(&self) -> &Arc<Meta> {
+        &self.meta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stored() {
+        let object = json!({ "metadata": {
+            "uid": "u", "resourceVersion": "7", "labels": { "b": "2", "a": "1" },
+            "ownerReferences": [{ "uid": "o" }],
+        }});
+        let meta = Arc::new(Meta::of(&object));
+        assert_eq!(meta.resource_version, Some(7));
+        assert_eq!(meta.label("a"), Some("1"));
+        assert_eq!(meta.label("c"), None);
+        assert_eq!(meta.owner_uids().collect::<Vec<_>>(), ["o"]);
+
+        let line = event_line("MODIFIED", &object);
+        for compression in [CacheCompression::None, CacheCompression::Zstd] {
+            let stored = Stored::new(line.clone(), Arc::clone(&meta), compression);
+            assert_eq!(stored.line(), line);
+            assert_eq!(stored.object(), object);
+        }
+        let line: Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(line, json!({ "type": "MODIFIED", "object": object }));
+    }
+
+    /// objects streamed from a JSON watch are cached as if they were `serde_json::Value`s
+    #[tokio::test]
+    async fn destream() {
+        let object = json!({
+            "metadata": {
+                "uid": "u", "resourceVersion": "7", "labels": { "b": "2", "a": "1", "n": 1 },
+                "ownerReferences": [{ "uid": "o" }, { "name": "no uid" }],
+            },
+            "spec": { "replicas": 3, "ratio": 0.5, "paused": false, "selector": null, "tags": ["x", "\"y\""] },
+        });
+        let json = Bytes::from(serde_json::to_vec(&object).unwrap());
+        let decoded: DValue = destream_json::decode((), futures_util::stream::iter([json]))
+            .await
+            .unwrap();
+        assert_eq!(decoded.meta(), Meta::of(&object));
+        assert_eq!(event_line("MODIFIED", &decoded), event_line("MODIFIED", &object));
+    }
+
+    /// listed items are cached as if the list's apiVersion and kind were part of their `serde_json::Value`
+    #[test]
+    fn listed() {
+        let rest = json!({ "data": { "k": "v" }, "metadata": { "name": "n", "uid": "u", "resourceVersion": "3" } });
+        let listed = Resource {
+            api_version: "v1".into(),
+            kind: "ConfigMap".into(),
+            rest: rest.clone(),
+        };
+        let mut object = rest;
+        object["apiVersion"] = json!("v1");
+        object["kind"] = json!("ConfigMap");
+        assert_eq!(listed.meta(), Meta::of(&object));
+        assert_eq!(event_line("MODIFIED", &listed), event_line("MODIFIED", &object));
+    }
+}
@@ -1,45 +1,57 @@
 use destream_json::Value as DValue;
 use number_general::{Float as DFloat, Int as DInt, Number, UInt as DUInt};
-use serde_json::{value::Number as SNumber, Value as SValue};
+use serde::ser::{Error, Serialize, SerializeMap, SerializeSeq, Serializer};
 
-fn to_number<T: Into<f64>>(f: T) -> SValue {
-    fn to_number_inner<T: Into<f64>>(f: T) -> SValue {
-        SValue::Number(SNumber::from_f64(f.into()).expect("Unable to create serde_json::value::Number"))
-    }
-    let f = f.into();
+/// a `destream_json::Value` serialized as the JSON it was decoded from, with the keys of objects sorted like a
+/// `serde_json::Value` keeps them
+pub struct Json<'a>(pub &'a DValue);
+
+fn serialize_float<S: Serializer>(f: f64, serializer: S) -> Result<S::Ok, S::Error> {
     if f.is_finite() {
-        to_number_inner(f)
+        serializer.serialize_f64(f)
     } else if f.is_nan() {
-        SValue::Null
+        serializer.serialize_none()
     } else if f.is_sign_negative() {
-        to_number_inner(f64::MIN)
+        serializer.serialize_f64(f64::MIN)
     } else {
-        to_number_inner(f64::MAX)
+        serializer.serialize_f64(f64::MAX)
     }
 }
 
-pub fn convert_value_to_value(input: &DValue) -> SValue {
-    match input {
-        DValue::Bytes(_) => unimplemented!(),
-        DValue::List(l) => SValue::Array(l.into_iter().map(convert_value_to_value).collect::<Vec<_>>()),
-        DValue::Map(m) => SValue::Object(
-            m.into_iter()
-                .map(|(k, v)| (k.clone(), convert_value_to_value(v)))
-                .collect::<serde_json::value::Map<String, _>>(),
-        ),
-        DValue::None => SValue::Null,
-        DValue::Number(Number::Bool(b)) => SValue::Bool(b.into()),
-        DValue::Number(Number::Complex(_)) => unimplemented!(),
-        DValue::Number(Number::Float(DFloat::F64(f))) => to_number(*f),
-        DValue::Number(Number::Float(DFloat::F32(f))) => to_number(*f),
-        DValue::Number(Number::Int(DInt::I64(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::Int(DInt::I32(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::Int(DInt::I16(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::Int(DInt::I8(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::UInt(DUInt::U64(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::UInt(DUInt::U32(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::UInt(DUInt::U16(i))) => SValue::Number((*i).into()),
-        DValue::Number(Number::UInt(DUInt::U8(i))) => SValue::Number((*i).into()),
-        DValue::String(s) => SValue::String(s.clone()),
+impl Serialize for Json<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            DValue::Bytes(_) => Err(S::Error::custom("bytes can't be serialized as JSON")),
+            DValue::List(l) => {
+                let mut seq = serializer.serialize_seq(Some(l.len()))?;
+                for value in l {
+                    seq.serialize_element(&Json(value))?;
+                }
+                seq.end()
+            }
+            DValue::Map(m) => {
+                let mut entries = m.iter().collect::<Vec<_>>();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, &Json(value))?;
+                }
+                map.end()
+            }
+            DValue::None => serializer.serialize_none(),
+            DValue::Number(Number::Bool(b)) => serializer.serialize_bool(b.into()),
+            DValue::Number(Number::Complex(_)) => Err(S::Error::custom("complex numbers can't be serialized as JSON")),
+            DValue::Number(Number::Float(DFloat::F64(f))) => serialize_float(*f, serializer),
+            DValue::Number(Number::Float(DFloat::F32(f))) => serialize_float((*f).into(), serializer),
+            DValue::Number(Number::Int(DInt::I64(i))) => serializer.serialize_i64(*i),
+            DValue::Number(Number::Int(DInt::I32(i))) => serializer.serialize_i32(*i),
+            DValue::Number(Number::Int(DInt::I16(i))) => serializer.serialize_i16(*i),
+            DValue::Number(Number::Int(DInt::I8(i))) => serializer.serialize_i8(*i),
+            DValue::Number(Number::UInt(DUInt::U64(i))) => serializer.serialize_u64(*i),
+            DValue::Number(Number::UInt(DUInt::U32(i))) => serializer.serialize_u32(*i),
+            DValue::Number(Number::UInt(DUInt::U16(i))) => serializer.serialize_u16(*i),
+            DValue::Number(Number::UInt(DUInt::U8(i))) => serializer.serialize_u8(*i),
+            DValue::String(s) => serializer.serialize_str(s),
+        }
     }
 }
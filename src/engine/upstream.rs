@@ -0,0 +1,319 @@
+use super::Cache;
+use crate::{
+    k8s_client::{
+        api::{ResourceId, ResourceVersion},
+        RetryBackoff,
+    },
+    log::Throttle,
+    replay::RecordedEvent,
+    utils::{read_to_vec, read_token},
+};
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use reqwest::{header, Certificate, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tokio_stream::StreamExt;
+
+/// an upstream that keeps failing logs at most one warning per interval
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// even with `backoff.initialIntervalMs: 0`, an unreachable upstream isn't asked in a busy loop
+const MIN_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `upstream` in the configuration file, another big-brother whose `/watch` is mirrored instead of the API server
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// base URL of the other instance, `/watch` is appended
+    pub url: String,
+    /// bearer token for the upstream, read on every reconnect
+    pub token_path: Option<PathBuf>,
+    /// PEM certificates to verify an https upstream with, instead of the system roots
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl UpstreamConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+            _ => Err(format!("upstream: url {:?} is not an http(s) URL", self.url)),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("Request to the upstream failed: {:?}", _0)]
+    Request(#[from] reqwest::Error),
+    #[error("Could not read the token for the upstream: {:?}", _0)]
+    Token(#[source] io::Error),
+    #[error("Could not read \"{}\": {:?}", _0.display(), _1)]
+    CaCert(PathBuf, #[source] io::Error),
+    #[error("Invalid event from the upstream: {}", _0)]
+    Invalid(String),
+}
+
+/// mirrors the upstream into a cache until dropped
+#[derive(Debug)]
+pub struct Upstream {
+    url: String,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Upstream {
+    /// reconnects with `backoff` whenever the stream ends or fails, after a full resync of `cache`
+    pub fn new(
+        config: UpstreamConfig,
+        cache: Arc<RwLock<Cache>>,
+        backoff: &RetryBackoff,
+    ) -> Result<Self, UpstreamError> {
+        let mut builder = reqwest::Client::builder().use_rustls_tls();
+        if let Some(path) = &config.ca_cert {
+            let pem = fs::File::open(path)
+                .and_then(|mut file| read_to_vec(&mut file))
+                .map_err(|err| UpstreamError::CaCert(path.clone(), err))?;
+            let cert = Certificate::from_pem(&pem)?;
+            builder = builder.tls_built_in_root_certs(false).add_root_certificate(cert);
+        }
+        let http = builder.build()?;
+        let mut backoff = backoff.exponential(MIN_RECONNECT_INTERVAL);
+        let url = config.url.clone();
+        tracing::info!(upstream = url.as_str(), "following");
+        let task = tokio::task::spawn(async move {
+            let mut errors = Throttle::new(ERROR_LOG_INTERVAL);
+            loop {
+                match follow(&http, &config, &cache, &mut backoff).await {
+                    Ok(()) => errors.reset(),
+                    Err(err) => {
+                        if let Some(suppressed) = errors.check() {
+                            tracing::warn!(
+                                error = %err,
+                                suppressed,
+                                upstream = config.url.as_str(),
+                                "following the upstream failed"
+                            );
+                        }
+                    }
+                }
+                let delay = backoff.next_backoff().unwrap_or(backoff.max_interval);
+                tokio::time::sleep(delay).await;
+            }
+        });
+        Ok(Self { url, task })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+fn read_bearer(path: &Path) -> Result<String, UpstreamError> {
+    fs::File::open(path)
+        .and_then(|mut file| read_token(&mut file))
+        .map_err(UpstreamError::Token)
+}
+
+/// `/list?format=json` of the upstream
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpstreamList {
+    resource_version: String,
+    items: Vec<Value>,
+}
+
+fn resource_version(object: &Value) -> Option<ResourceVersion> {
+    object.pointer("/metadata/resourceVersion")?.as_str()?.parse().ok()
+}
+
+/// makes `cache` match the upstream's `/list`, removing what it no longer has, and returns the resourceVersion of the
+/// list
+async fn resync(
+    http: &reqwest::Client,
+    config: &UpstreamConfig,
+    cache: &RwLock<Cache>,
+    bearer: Option<&str>,
+) -> Result<ResourceVersion, UpstreamError> {
+    let mut request = http
+        .get(format!("{}/list", config.url.trim_end_matches('/')))
+        .query(&[("format", "json")]);
+    if let Some(bearer) = bearer {
+        request = request.header(header::AUTHORIZATION, bearer);
+    }
+    let list = request.send().await?.error_for_status()?.json::<UpstreamList>().await?;
+    let list_rv: ResourceVersion = list
+        .resource_version
+        .parse()
+        .map_err(|_| UpstreamError::Invalid(format!("invalid resourceVersion {:?}", list.resource_version)))?;
+    let mut cache = cache.write().await;
+    let mut listed = HashSet::new();
+    for item in list.items {
+        let res =
+            ResourceId::of(&item).ok_or_else(|| UpstreamError::Invalid("missing apiVersion, kind or name".into()))?;
+        let rv = resource_version(&item)
+            .ok_or_else(|| UpstreamError::Invalid("missing or invalid metadata.resourceVersion".into()))?;
+        listed.insert(res.clone());
+        cache.update(res, rv, item);
+    }
+    // a change of its own, clients that have seen the latest one resume after it
+    let rv = cache
+        .last_resource_version()
+        .map_or(list_rv, |last| list_rv.max(last + 1));
+    let types = cache
+        .ids()
+        .map(|res| (res.api_version.clone(), res.kind.clone()))
+        .collect::<HashSet<_>>();
+    for (api_version, kind) in types {
+        cache.retain_listed(&api_version, &kind, &listed, rv);
+    }
+    Ok(list_rv)
+}
+
+/// resyncs `cache` with the upstream and applies its `/watch` from there, the backoff is reset once the upstream
+/// answered
+///
+/// resuming after the latest change instead could miss the rest of the deletions the upstream wrote under one
+/// resourceVersion when the connection dropped between them, and the objects deleted while the upstream restarted,
+/// which it has no tombstones for
+async fn follow(
+    http: &reqwest::Client,
+    config: &UpstreamConfig,
+    cache: &RwLock<Cache>,
+    backoff: &mut ExponentialBackoff,
+) -> Result<(), UpstreamError> {
+    let bearer = config.token_path.as_deref().map(read_bearer).transpose()?;
+    let rv = resync(http, config, cache, bearer.as_deref()).await?;
+    let mut request = http
+        .get(format!("{}/watch", config.url.trim_end_matches('/')))
+        .query(&[("resourceVersion", rv + 1)]);
+    if let Some(bearer) = &bearer {
+        request = request.header(header::AUTHORIZATION, bearer);
+    }
+    let response = request.send().await?.error_for_status()?;
+    backoff.reset();
+    let mut body = response.bytes_stream();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.next().await {
+        buffer.extend_from_slice(&chunk?);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<_>>();
+            match RecordedEvent::parse(String::from_utf8_lossy(&line).trim()) {
+                Ok(Some(event)) => event.apply(&mut *cache.write().await),
+                // the upstream is shutting down
+                Ok(None) => {}
+                Err(err) => return Err(UpstreamError::Invalid(err)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::collections::HashMap;
+
+    const LIST: &str = concat!(
+        r#"{"resourceVersion":"3","items":["#,
+        r#"{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","resourceVersion":"3"}}"#,
+        "]}",
+    );
+
+    const WATCH: &str = concat!(
+        r#"{"type":"MODIFIED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"c","resourceVersion":"4"}}}"#,
+        "\n",
+        r#"{"type":"DELETED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","resourceVersion":"5"}}}"#,
+        "\n",
+        r#"{"type":"BOOKMARK","object":{"metadata":{"resourceVersion":"6"}}}"#,
+        "\n",
+    );
+
+    async fn list(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        assert_eq!(query.get("format").map(String::as_str), Some("json"));
+        assert_eq!(req.headers().get("authorization").unwrap(), "Bearer secret");
+        HttpResponse::Ok().body(LIST)
+    }
+
+    async fn watch(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        // after the list, not after the deletion the resync added
+        assert_eq!(query.get("resourceVersion").map(String::as_str), Some("4"));
+        assert_eq!(req.headers().get("authorization").unwrap(), "Bearer secret");
+        HttpResponse::Ok().body(WATCH)
+    }
+
+    fn pod(name: &str) -> ResourceId {
+        ResourceId {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: name.into(),
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn mirror() {
+        actix_web::rt::System::new().block_on(mirror_inner());
+    }
+
+    async fn mirror_inner() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/list", web::get().to(list))
+                .route("/watch", web::get().to(watch))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let token_path = std::env::temp_dir().join(format!("big-brother-upstream-{}", std::process::id()));
+        fs::write(&token_path, "secret\n").unwrap();
+        let config = UpstreamConfig {
+            url: format!("http://{}/", addr),
+            token_path: Some(token_path),
+            ca_cert: None,
+        };
+        let mut cache = Cache::new();
+        // deleted while the upstream restarted, it has no tombstone
+        cache.update(pod("b"), 1, serde_json::json!({}));
+        let cache = RwLock::new(cache);
+        let mut backoff = RetryBackoff::default().exponential(MIN_RECONNECT_INTERVAL);
+        follow(&reqwest::Client::new(), &config, &cache, &mut backoff)
+            .await
+            .unwrap();
+        let cache = cache.read().await;
+        assert_eq!(cache.last_resource_version(), Some(5));
+        let mut stream = Box::pin(cache.stream(Some(2), ()));
+        let mut seen = Vec::new();
+        while let Some(Ok((res, event))) = tokio::time::timeout(Duration::from_millis(100), stream.next())
+            .await
+            .ok()
+            .flatten()
+        {
+            seen.push((res.name.clone(), event.resource_version(), event.is_deleted()));
+        }
+        // the resync removes "b" after the listed "a", the watch continues after the list
+        assert_eq!(
+            seen,
+            [
+                ("b".into(), Some(4), true),
+                ("c".into(), Some(4), false),
+                ("a".into(), Some(5), true)
+            ]
+        );
+    }
+}
@@ -1,6 +1,14 @@
 use crate::{
+    cli::CliError,
+    config::ConfigError,
+    engine::UpstreamError,
     event::EventParseError,
-    k8s_client::{api::cluster_config::ClusterConfigError, K8sClientError},
+    jwt::JwtError,
+    k8s_client::{api::cluster_config::ClusterConfigError, protobuf::ProtobufError, K8sClientError},
+    replay::ReplayError,
+    sink::SinkError,
+    telemetry::TelemetryError,
+    tls::TlsError,
 };
 use std::{io, path::PathBuf};
 
@@ -18,14 +26,71 @@ pub enum Error {
     Deserialize(#[from] serde_json::Error),
     #[error("Error parsing kubernetes event: {:?}", _0)]
     EventParseError(#[from] EventParseError),
+    #[error("Protobuf decoding error: {}", _0)]
+    Protobuf(#[from] ProtobufError),
     #[error("Server could not bind to port: {:?}", _0)]
     ServerBind(io::Error),
     #[error("Server could not run server: {:?}", _0)]
     ServerRun(io::Error),
+    #[error("Could not listen for shutdown signals: {:?}", _0)]
+    Signal(io::Error),
     #[error("Could not obtain cluster config: {:?}", _0)]
     ClusterConfig(#[from] ClusterConfigError),
     #[error("Unable to receive value from stream: {:?}", _0)]
     StreamRecv(#[from] tokio_stream::wrappers::errors::BroadcastStreamRecvError),
     #[error("Unable to read token from \"{}\"", _0.display())]
     ReadToken(PathBuf),
+    #[error("{}", _0)]
+    Config(#[from] ConfigError),
+    #[error("Could not set up logging: {}", _0)]
+    Log(String),
+    #[error("Invalid JWT configuration: {}", _0)]
+    Jwt(#[from] JwtError),
+    #[error("Could not open audit log: {:?}", _0)]
+    AuditLog(io::Error),
+    #[error("Invalid sink configuration: {}", _0)]
+    Sink(#[from] SinkError),
+    #[error("Could not load recorded events: {}", _0)]
+    Replay(#[from] ReplayError),
+    #[error("--token-review needs a cluster and can't be used with --replay or `upstream`")]
+    TokenReviewWithoutCluster,
+    #[error("--replay-speed needs --replay")]
+    ReplaySpeed,
+    #[error("Invalid TLS configuration: {}", _0)]
+    Tls(#[from] TlsError),
+    #[error("Could not set up telemetry: {}", _0)]
+    Telemetry(#[from] TelemetryError),
+    #[error("Invalid upstream configuration: {}", _0)]
+    Upstream(#[from] UpstreamError),
+    #[error("{}", _0)]
+    Cli(#[from] CliError),
+}
+
+impl Error {
+    /// stable name of the variant for the `error_kind` log field
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::K8sClient(err) => err.kind(),
+            Self::UrlParse(_) => "url",
+            Self::Transport(_) => "request",
+            Self::DeserializeStream(_) | Self::Deserialize(_) => "deserialize",
+            Self::EventParseError(_) => "event_parse",
+            Self::Protobuf(_) => "protobuf",
+            Self::ServerBind(_) | Self::ServerRun(_) | Self::Signal(_) => "server",
+            Self::ClusterConfig(_) => "cluster_config",
+            Self::StreamRecv(_) => "stream_recv",
+            Self::ReadToken(_) => "read_token",
+            Self::Config(_) => "config",
+            Self::Log(_) => "log",
+            Self::Jwt(_) => "jwt",
+            Self::AuditLog(_) => "audit_log",
+            Self::Sink(_) => "sink",
+            Self::Replay(_) | Self::ReplaySpeed => "replay",
+            Self::TokenReviewWithoutCluster => "config",
+            Self::Upstream(_) => "upstream",
+            Self::Tls(_) => "tls",
+            Self::Telemetry(_) => "telemetry",
+            Self::Cli(_) => "cli",
+        }
+    }
 }
@@ -1,31 +1,18 @@
-use crate::k8s_client::api::{ResourceId, ResourceVersion};
+use crate::k8s_client::{
+    api::{ResourceId, ResourceVersion},
+    protobuf::WatchEvent,
+};
+/// shared with the client library
+pub use big_brother_client::EventType;
 use destream_json::Value;
-use serde::Serialize;
-use std::{convert::TryFrom, fmt};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
-#[serde(rename_all = "UPPERCASE")]
-pub enum EventType {
-    Added,
-    Modified,
-    Deleted,
-}
-
-impl fmt::Display for EventType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            EventType::Added => write!(f, "added"),
-            EventType::Modified => write!(f, "modified"),
-            EventType::Deleted => write!(f, "deleted"),
-        }
-    }
-}
+use std::convert::TryFrom;
 
+/// `value` is the object, as a `destream_json::Value` when streamed from JSON
 #[derive(Debug, Clone)]
-pub struct Event {
+pub struct Event<V = Value> {
     pub event_type: EventType,
     pub resource: ResourceId,
-    pub value: Value,
+    pub value: V,
     pub resource_version: ResourceVersion,
 }
 
@@ -53,6 +40,16 @@ pub enum EventParseError {
     RootNotObject(Value),
 }
 
+fn event_type(ty: &str) -> Result<EventType, EventParseError> {
+    match ty {
+        "ADDED" => Ok(EventType::Added),
+        "MODIFIED" => Ok(EventType::Modified),
+        "DELETED" => Ok(EventType::Deleted),
+        // unknown event type, e.g. BOOKMARK
+        _ => Err(EventParseError::UnknownEvent(ty.to_string())),
+    }
+}
+
 impl TryFrom<Value> for Event {
     type Error = EventParseError;
     fn try_from(value: Value) -> Result<Self, Self::Error> {
@@ -71,7 +68,7 @@ impl TryFrom<Value> for Event {
                                         let rv = match resource_version_str.parse::<ResourceVersion>() {
                                             Ok(rv) => rv,
                                             Err(e) => {
-                                                eprintln!("Invalid resource version received: {:?}", e);
+                                                tracing::debug!(error = %e, "invalid resourceVersion in event");
                                                 return Err(EventParseError::InvalidResourceVersion(
                                                     resource_version_str.clone(),
                                                 ));
@@ -105,18 +102,7 @@ impl TryFrom<Value> for Event {
                 };
 
                 let event_type = match map.get("type") {
-                    Some(Value::String(ty)) => {
-                        if ty.as_str() == "ADDED" {
-                            EventType::Added
-                        } else if ty.as_str() == "MODIFIED" {
-                            EventType::Modified
-                        } else if ty.as_str() == "DELETED" {
-                            EventType::Deleted
-                        } else {
-                            // unknown event type, e.g. BOOKMARK
-                            return Err(EventParseError::UnknownEvent(ty.clone()));
-                        }
-                    }
+                    Some(Value::String(ty)) => event_type(ty)?,
                     Some(val) => return Err(EventParseError::TypeNotString(val.clone())),
                     None => return Err(EventParseError::MissingType),
                 };
@@ -127,7 +113,30 @@ impl TryFrom<Value> for Event {
                     resource_version,
                 })
             }
-            val @ _ => return Err(EventParseError::RootNotObject(val)),
+            val => Err(EventParseError::RootNotObject(val)),
         }
     }
 }
+
+/// protobuf events are decoded into JSON with all the fields in place
+impl TryFrom<WatchEvent> for Event<serde_json::Value> {
+    type Error = EventParseError;
+    fn try_from(event: WatchEvent) -> Result<Self, Self::Error> {
+        let event_type = event_type(&event.event_type)?;
+        let object = event.object;
+        let resource = ResourceId::of(&object).ok_or(EventParseError::MissingApiVersionKindMetadata)?;
+        let resource_version = object
+            .pointer("/metadata/resourceVersion")
+            .and_then(serde_json::Value::as_str)
+            .ok_or(EventParseError::MissingNameOrResourceVersion)?;
+        let resource_version = resource_version
+            .parse::<ResourceVersion>()
+            .map_err(|_| EventParseError::InvalidResourceVersion(resource_version.to_string()))?;
+        Ok(Event {
+            event_type,
+            resource,
+            value: object,
+            resource_version,
+        })
+    }
+}
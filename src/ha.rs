@@ -0,0 +1,389 @@
+use crate::{
+    engine::{Engine, Upstream, UpstreamConfig},
+    k8s_client::{
+        api::{Lease, LeaseCreator, LeaseGetter, LeaseSpec, LeaseUpdater},
+        K8sClient, K8sClientError, RetryBackoff,
+    },
+    log::Throttle,
+};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::Mutex, task::JoinHandle};
+
+/// annotation of the lease with the `advertiseUrl` of its holder, where the followers replicate from
+pub const LEADER_URL_ANNOTATION: &str = "big-brother/leader-url";
+
+/// a lease that keeps failing to update logs at most one warning per interval
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `ha` in the configuration file, replicas compete for a `coordination.k8s.io/v1` Lease
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HaConfig {
+    pub lease_name: String,
+    pub lease_namespace: String,
+    /// where the other replicas reach this one's `/watch` while it leads, e.g. `http://10.0.0.5:8080`
+    pub advertise_url: String,
+    /// unique per replica, defaults to `advertiseUrl`
+    pub identity: Option<String>,
+    /// a holder that doesn't renew for this long is replaced
+    #[serde(default = "default_lease_duration_seconds")]
+    pub lease_duration_seconds: u64,
+    /// the leader stops watching the API server when it couldn't renew the lease for this long
+    #[serde(default = "default_renew_deadline_seconds")]
+    pub renew_deadline_seconds: u64,
+    /// how often the lease is renewed or checked
+    #[serde(default = "default_retry_period_seconds")]
+    pub retry_period_seconds: u64,
+    /// bearer token the followers present to the leader's `/watch`, read on every reconnect
+    pub token_path: Option<PathBuf>,
+    /// PEM certificates to verify an https `advertiseUrl` with, instead of the system roots
+    pub ca_cert: Option<PathBuf>,
+}
+
+fn default_lease_duration_seconds() -> u64 {
+    15
+}
+fn default_renew_deadline_seconds() -> u64 {
+    10
+}
+fn default_retry_period_seconds() -> u64 {
+    2
+}
+
+impl HaConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match Url::parse(&self.advertise_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => {
+                return Err(format!(
+                    "ha: advertiseUrl {:?} is not an http(s) URL",
+                    self.advertise_url
+                ))
+            }
+        }
+        if self.retry_period_seconds == 0
+            || self.retry_period_seconds >= self.renew_deadline_seconds
+            || self.renew_deadline_seconds >= self.lease_duration_seconds
+        {
+            return Err("ha: retryPeriodSeconds < renewDeadlineSeconds < leaseDurationSeconds is required".into());
+        }
+        Ok(())
+    }
+
+    fn identity(&self) -> &str {
+        self.identity.as_deref().unwrap_or(&self.advertise_url)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Leader,
+    /// `advertiseUrl` of the leader, `None` while there is none
+    Follower(Option<String>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Create(Lease),
+    Update(Lease),
+    Follow(Option<String>),
+}
+
+#[derive(Debug)]
+struct Elector {
+    config: HaConfig,
+    /// the holder's part of the lease and when we first saw it, a holder is gone when it doesn't change for
+    /// `leaseDurationSeconds`, which doesn't depend on the clocks of the replicas agreeing
+    observed: Option<(LeaseSpec, Instant)>,
+    /// last successful acquisition or renewal
+    renewed: Option<Instant>,
+    role: Role,
+    errors: Throttle,
+}
+
+impl Elector {
+    fn new(config: HaConfig) -> Self {
+        Self {
+            config,
+            observed: None,
+            renewed: None,
+            role: Role::Follower(None),
+            errors: Throttle::new(ERROR_LOG_INTERVAL),
+        }
+    }
+
+    fn decide(&mut self, lease: Option<Lease>, now: Instant, time: DateTime<Utc>) -> Decision {
+        let mut lease = match lease {
+            Some(lease) => lease,
+            None => {
+                let mut lease = Lease::new(&self.config.lease_namespace, &self.config.lease_name);
+                lease.spec.acquire_time = Some(time);
+                lease.spec.lease_transitions = Some(0);
+                self.claim(&mut lease, time);
+                return Decision::Create(lease);
+            }
+        };
+        let observed_at = match &self.observed {
+            Some((spec, at)) if *spec == lease.spec => *at,
+            _ => now,
+        };
+        self.observed = Some((lease.spec.clone(), observed_at));
+        let duration = lease
+            .spec
+            .lease_duration_seconds
+            .unwrap_or(self.config.lease_duration_seconds);
+        match lease
+            .spec
+            .holder_identity
+            .as_deref()
+            .filter(|holder| !holder.is_empty())
+        {
+            Some(holder) if holder == self.config.identity() => {}
+            Some(_) if now.duration_since(observed_at) < Duration::from_secs(duration) => {
+                return Decision::Follow(lease.metadata.annotations.get(LEADER_URL_ANNOTATION).cloned());
+            }
+            // released or expired
+            _ => {
+                lease.spec.acquire_time = Some(time);
+                lease.spec.lease_transitions = Some(lease.spec.lease_transitions.unwrap_or_default() + 1);
+            }
+        }
+        self.claim(&mut lease, time);
+        Decision::Update(lease)
+    }
+
+    fn claim(&self, lease: &mut Lease, time: DateTime<Utc>) {
+        lease.spec.holder_identity = Some(self.config.identity().to_string());
+        lease.spec.lease_duration_seconds = Some(self.config.lease_duration_seconds);
+        lease.spec.renew_time = Some(time);
+        lease
+            .metadata
+            .annotations
+            .insert(LEADER_URL_ANNOTATION.to_string(), self.config.advertise_url.clone());
+    }
+
+    fn getter(&self) -> LeaseGetter<'_> {
+        LeaseGetter {
+            namespace: &self.config.lease_namespace,
+            name: &self.config.lease_name,
+        }
+    }
+
+    /// requests that don't finish within the renew deadline count as failed
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.renew_deadline_seconds)
+    }
+
+    async fn try_step(&mut self, k8s_client: &K8sClient, now: Instant) -> Result<Role, K8sClientError> {
+        let lease = match k8s_client.get(&self.getter()).await {
+            Ok(lease) => Some(lease),
+            Err(err) if err.is_not_found() => None,
+            Err(err) => return Err(err),
+        };
+        let written = match self.decide(lease, now, Utc::now()) {
+            Decision::Follow(leader) => return Ok(Role::Follower(leader)),
+            Decision::Create(lease) => k8s_client.get(&LeaseCreator { lease: &lease }).await?,
+            Decision::Update(lease) => k8s_client.get(&LeaseUpdater { lease: &lease }).await?,
+        };
+        // our own write is no sign of life of a previous holder
+        self.observed = Some((written.spec, now));
+        Ok(Role::Leader)
+    }
+
+    /// acquires or renews the lease if possible, the leader keeps its role until the renew deadline passes
+    async fn step(&mut self, k8s_client: &K8sClient) -> &Role {
+        let now = Instant::now();
+        let result = match tokio::time::timeout(self.timeout(), self.try_step(k8s_client, now)).await {
+            Ok(result) => result.map_err(|err| (err.kind(), err.to_string(), err.is_conflict())),
+            Err(_) => Err(("timeout", "lease request timed out".to_string(), false)),
+        };
+        match result {
+            Ok(role) => {
+                self.errors.reset();
+                self.renewed = Some(now).filter(|_| role == Role::Leader);
+                self.role = role;
+            }
+            // another replica updated the lease between our read and write
+            Err((_, _, true)) => tracing::debug!("lost the race for the lease"),
+            Err((error_kind, error, false)) => {
+                if let Some(suppressed) = self.errors.check() {
+                    tracing::warn!(
+                        error_kind,
+                        error = error.as_str(),
+                        suppressed,
+                        "could not update the lease"
+                    );
+                }
+            }
+        }
+        if let Some(renewed) = self.renewed {
+            if now.duration_since(renewed) >= Duration::from_secs(self.config.renew_deadline_seconds) {
+                self.renewed = None;
+                self.role = Role::Follower(None);
+            }
+        }
+        &self.role
+    }
+
+    /// lets a follower take over right away instead of waiting for the lease to expire
+    async fn release(&mut self, k8s_client: &K8sClient) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let release = async {
+            let mut lease = k8s_client.get(&self.getter()).await?;
+            if lease.spec.holder_identity.as_deref() != Some(self.config.identity()) {
+                return Ok(());
+            }
+            lease.spec.holder_identity = None;
+            lease.spec.lease_duration_seconds = Some(1);
+            lease.metadata.annotations.remove(LEADER_URL_ANNOTATION);
+            k8s_client.get(&LeaseUpdater { lease: &lease }).await.map(drop)
+        };
+        match tokio::time::timeout(self.timeout(), release).await {
+            Ok(Ok(())) => tracing::info!("released the lease"),
+            Ok(Err(err)) => tracing::warn!(error_kind = err.kind(), error = %err, "could not release the lease"),
+            Err(_) => tracing::warn!(error_kind = "timeout", "could not release the lease"),
+        }
+        self.role = Role::Follower(None);
+    }
+}
+
+/// the election running in the background, see `stop`
+#[derive(Debug)]
+pub struct Ha {
+    elector: Arc<Mutex<Elector>>,
+    k8s_client: K8sClient,
+    task: JoinHandle<()>,
+}
+
+/// the leader runs `engine`, followers fill its cache from the leader's `/watch`,
+/// reconnecting with `backoff`, so every replica serves the same objects with the resourceVersions of the API server
+pub fn start(config: HaConfig, k8s_client: K8sClient, engine: Arc<Engine>, backoff: RetryBackoff) -> Ha {
+    let elector = Arc::new(Mutex::new(Elector::new(config.clone())));
+    let task = tokio::task::spawn(run(config, k8s_client.clone(), engine, backoff, Arc::clone(&elector)));
+    Ha {
+        elector,
+        k8s_client,
+        task,
+    }
+}
+
+impl Ha {
+    /// ends the election and gives up the lease if this replica holds it, the engine is left to the caller
+    pub async fn stop(self) {
+        self.task.abort();
+        let _ = self.task.await;
+        self.elector.lock().await.release(&self.k8s_client).await;
+    }
+}
+
+async fn run(
+    config: HaConfig,
+    k8s_client: K8sClient,
+    engine: Arc<Engine>,
+    backoff: RetryBackoff,
+    elector: Arc<Mutex<Elector>>,
+) {
+    let retry_period = Duration::from_secs(config.retry_period_seconds);
+    let mut replica: Option<Upstream> = None;
+    let mut watching = false;
+    loop {
+        let role = elector.lock().await.step(&k8s_client).await.clone();
+        match role {
+            Role::Leader => {
+                replica = None;
+                if !watching {
+                    tracing::info!(identity = config.identity(), "became the leader");
+                    match engine.start().await {
+                        Ok(()) => watching = true,
+                        Err(err) => {
+                            tracing::error!(error_kind = err.kind(), error = %err, "could not start watching");
+                            engine.stop().await;
+                        }
+                    }
+                }
+            }
+            Role::Follower(leader) => {
+                if watching {
+                    tracing::warn!("lost the lease, stopped watching");
+                    engine.stop().await;
+                    watching = false;
+                }
+                if replica.as_ref().map(Upstream::url) != leader.as_deref() {
+                    replica = None;
+                    let leader = leader.map(|url| UpstreamConfig {
+                        url,
+                        token_path: config.token_path.clone(),
+                        ca_cert: config.ca_cert.clone(),
+                    });
+                    if let Some(leader) = leader {
+                        match Upstream::new(leader, Arc::clone(engine.cache()), &backoff) {
+                            Ok(upstream) => replica = Some(upstream),
+                            Err(err) => tracing::error!(error = %err, "could not follow the leader"),
+                        }
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(retry_period).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(identity: &str) -> HaConfig {
+        serde_yaml::from_str(&format!(
+            "{{leaseName: bb, leaseNamespace: default, advertiseUrl: 'http://{}:8080', identity: {}}}",
+            identity, identity
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn election() {
+        let start = Instant::now();
+        let time = Utc::now();
+        let mut a = Elector::new(config("a"));
+        let mut b = Elector::new(config("b"));
+        assert!(a.config.validate().is_ok());
+
+        let lease = match a.decide(None, start, time) {
+            Decision::Create(lease) => lease,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(lease.spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(
+            b.decide(Some(lease.clone()), start, time),
+            Decision::Follow(Some("http://a:8080".into()))
+        );
+        // a renews
+        assert!(matches!(
+            a.decide(Some(lease.clone()), start, time),
+            Decision::Update(_)
+        ));
+        // a stopped renewing, b only notices after a whole lease duration
+        let later = start + Duration::from_secs(14);
+        assert!(matches!(
+            b.decide(Some(lease.clone()), later, time),
+            Decision::Follow(_)
+        ));
+        let expired = start + Duration::from_secs(15);
+        let lease = match b.decide(Some(lease), expired, time) {
+            Decision::Update(lease) => lease,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(lease.spec.holder_identity.as_deref(), Some("b"));
+        assert_eq!(lease.spec.lease_transitions, Some(1));
+        assert_eq!(lease.metadata.annotations[LEADER_URL_ANNOTATION], "http://b:8080");
+    }
+}
@@ -0,0 +1,251 @@
+use crate::scope::{Identity, Scope, ScopeRules};
+use jsonwebtoken::{
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, JwkSet},
+    DecodingKey, Validation,
+};
+use serde::Deserialize;
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+/// verifies JWTs from an identity provider offline, against a local JWKS file
+#[derive(Debug)]
+pub struct JwtVerifier {
+    config: JwtConfig,
+    jwks: RwLock<Option<LoadedJwks>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub jwks: PathBuf,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// allowed clock skew in seconds for `exp` and `nbf`
+    pub leeway: u64,
+    /// claim holding the list of groups, e.g. "groups" or "roles"
+    pub groups_claim: String,
+    /// YAML file mapping subjects and groups to scopes, `None` gives every valid token full access
+    pub rules: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct LoadedJwks {
+    modified: SystemTime,
+    set: Arc<JwkSet>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: String,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("could not read JWKS: {:?}", _0)]
+    JwksRead(#[source] io::Error),
+    #[error("could not read rules: {:?}", _0)]
+    RulesRead(#[source] io::Error),
+    /// the message is sent back to the client
+    #[error("{}", _0)]
+    Invalid(String),
+    #[error("no rule matches subject {:?}", _0)]
+    NoMatchingRule(String),
+}
+
+impl JwtVerifier {
+    pub fn new(config: JwtConfig) -> Result<Self, JwtError> {
+        let verifier = Self {
+            config,
+            jwks: RwLock::new(None),
+        };
+        // just to fail early in case the files are unreadable or invalid
+        verifier.jwks()?;
+        if let Some(rules) = &verifier.config.rules {
+            ScopeRules::from_path(rules).map_err(JwtError::RulesRead)?;
+        }
+        Ok(verifier)
+    }
+
+    /// verifies the token and returns the scope its bearer is allowed to see
+    pub fn authorize(&self, token: &str) -> Result<(Identity, Scope), JwtError> {
+        let identity = self.verify(token)?;
+        let scope = match &self.config.rules {
+            None => Scope::full(),
+            Some(rules) => ScopeRules::from_path(rules)
+                .map_err(JwtError::RulesRead)?
+                .find(&identity)
+                .ok_or_else(|| JwtError::NoMatchingRule(identity.subject.clone()))?,
+        };
+        Ok((identity, scope))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Identity, JwtError> {
+        let invalid = |reason: &str| JwtError::Invalid(reason.to_string());
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid("malformed token"))?;
+        let jwks = self.jwks()?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            // without a key id, only an unambiguous JWKS can be used
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| invalid("unknown signing key"))?;
+        if matches!(jwk.common.algorithm, Some(alg) if alg != header.alg) {
+            return Err(invalid("algorithm does not match the signing key"));
+        }
+        let key = match &jwk.algorithm {
+            // JWKs encode symmetric keys as base64url, `from_jwk` expects the standard alphabet
+            AlgorithmParameters::OctetKey(params) => base64::decode_config(&params.value, base64::URL_SAFE_NO_PAD)
+                .map(|secret| DecodingKey::from_secret(&secret))
+                .ok(),
+            _ => DecodingKey::from_jwk(jwk).ok(),
+        }
+        .ok_or_else(|| invalid("unusable signing key"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.config.leeway;
+        validation.validate_nbf = true;
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        if let Some(audience) = &self.config.audience {
+            validation.set_audience(&[audience]);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|err| {
+                invalid(match err.kind() {
+                    ErrorKind::ExpiredSignature => "token expired",
+                    ErrorKind::ImmatureSignature => "token not yet valid",
+                    ErrorKind::InvalidIssuer => "invalid issuer",
+                    ErrorKind::InvalidAudience => "invalid audience",
+                    ErrorKind::MissingRequiredClaim(_) => "missing required claim",
+                    ErrorKind::InvalidSignature => "invalid signature",
+                    ErrorKind::InvalidAlgorithm => "algorithm does not match the signing key",
+                    _ => "malformed token",
+                })
+            })?
+            .claims;
+
+        let groups = match claims.other.get(&self.config.groups_claim) {
+            Some(serde_json::Value::Array(groups)) => groups
+                .iter()
+                .filter_map(|group| group.as_str().map(String::from))
+                .collect(),
+            Some(serde_json::Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            subject: claims.sub,
+            groups,
+        })
+    }
+
+    /// reloads the JWKS file whenever its modification time changes, so keys can be rotated without restart
+    fn jwks(&self) -> Result<Arc<JwkSet>, JwtError> {
+        let modified = fs::metadata(&self.config.jwks)
+            .and_then(|meta| meta.modified())
+            .map_err(JwtError::JwksRead)?;
+        if let Some(loaded) = &*self.jwks.read().expect("JWKS lock poisoned") {
+            if loaded.modified == modified {
+                return Ok(Arc::clone(&loaded.set));
+            }
+        }
+        let file = fs::File::open(&self.config.jwks).map_err(JwtError::JwksRead)?;
+        let set: JwkSet = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|err| JwtError::JwksRead(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+        let set = Arc::new(set);
+        *self.jwks.write().expect("JWKS lock poisoned") = Some(LoadedJwks {
+            modified,
+            set: Arc::clone(&set),
+        });
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::{encode, get_current_timestamp, Algorithm, EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"big-brother-test-secret";
+
+    fn write(test: &str, name: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("big-brother-jwt-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn sign(kid: &str, claims: serde_json::Value) -> String {
+        let header = Header {
+            kid: Some(kid.into()),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn verifier(test: &str) -> JwtVerifier {
+        let jwks = json!({"keys": [{"kty": "oct", "kid": "k1", "alg": "HS256", "k": base64::encode_config(SECRET, base64::URL_SAFE_NO_PAD)}]});
+        let rules = "rules:\n- groups: [team-a]\n  namespaces: [team-a]\n- subjects: [admin]\n";
+        JwtVerifier::new(JwtConfig {
+            jwks: write(test, "jwks.json", &jwks.to_string()),
+            issuer: Some("https://idp".into()),
+            audience: Some("big-brother".into()),
+            leeway: 30,
+            groups_claim: "groups".into(),
+            rules: Some(write(test, "rules.yaml", rules)),
+        })
+        .unwrap()
+    }
+
+    fn claims(sub: &str, exp_offset: i64) -> serde_json::Value {
+        let exp = get_current_timestamp() as i64 + exp_offset;
+        json!({"sub": sub, "iss": "https://idp", "aud": "big-brother", "exp": exp, "groups": ["team-a"]})
+    }
+
+    #[test]
+    fn verify() {
+        let verifier = verifier("verify");
+        let (identity, scope) = verifier.authorize(&sign("k1", claims("alice", 60))).unwrap();
+        assert_eq!(identity.groups, vec!["team-a".to_string()]);
+        assert_eq!(scope.namespaces, Some(std::iter::once("team-a".to_string()).collect()));
+
+        // within the allowed clock skew
+        assert!(verifier.verify(&sign("k1", claims("alice", -10))).is_ok());
+        let err = |token: String| match verifier.verify(&token) {
+            Err(JwtError::Invalid(reason)) => reason,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(err(sign("k1", claims("alice", -120))), "token expired");
+        assert_eq!(err(sign("k2", claims("alice", 60))), "unknown signing key");
+        let mut wrong_audience = claims("alice", 60);
+        wrong_audience["aud"] = json!("someone-else");
+        assert_eq!(err(sign("k1", wrong_audience)), "invalid audience");
+        assert_eq!(err("not a token".into()), "malformed token");
+    }
+
+    #[test]
+    fn rules() {
+        let verifier = verifier("rules");
+        let mut admin = claims("admin", 60);
+        admin["groups"] = json!([]);
+        assert_eq!(verifier.authorize(&sign("k1", admin)).unwrap().1, Scope::full());
+        let mut nobody = claims("nobody", 60);
+        nobody["groups"] = json!("team-b");
+        assert!(matches!(
+            verifier.authorize(&sign("k1", nobody)),
+            Err(JwtError::NoMatchingRule(_))
+        ));
+    }
+}
@@ -4,6 +4,7 @@ pub use api_group_list::ApiGroupList;
 use api_group_list::ApiGroupVersion;
 use serde::{Deserialize, Serialize};
 
+#[allow(dead_code)]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct ApiGroup {
     #[serde(rename = "apiVersion")]
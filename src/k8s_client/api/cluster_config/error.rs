@@ -1,5 +1,5 @@
 use reqwest::header::InvalidHeaderValue;
-use std::{io, path::PathBuf};
+use std::{io, path::PathBuf, process::ExitStatus};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ClusterConfigError {
@@ -15,6 +15,8 @@ pub enum ClusterConfigError {
     Identity(reqwest::Error),
     #[error("Could not create certificate: {:?}", _0)]
     Certificate(reqwest::Error),
+    #[error("Kubeconfig has no \"current-context\" and no context was requested")]
+    MissingCurrentContext,
     #[error("Missing context \"{}\"", _0)]
     MissingContext(String),
     #[error("Missing cluster \"{}\"", _0)]
@@ -29,4 +31,18 @@ pub enum ClusterConfigError {
     InvalidBase64Cacert(base64::DecodeError),
     #[error("Invalid token: {:?}", _0)]
     InvalidToken(InvalidHeaderValue),
+    #[error("Both client certificate and client key must be set")]
+    IncompleteIdentity,
+    #[error("Could not run credential plugin \"{}\": {:?} {}", command, err, hint.as_deref().unwrap_or_default())]
+    ExecSpawn {
+        command: String,
+        hint: Option<String>,
+        err: io::Error,
+    },
+    #[error("Credential plugin \"{}\" failed: {}", command, status)]
+    ExecFailed { command: String, status: ExitStatus },
+    #[error("Could not deserialize credential plugin output: {:?}", _0)]
+    ExecDeserialize(serde_json::Error),
+    #[error("Credential plugin returned neither token nor client certificate")]
+    ExecMissingCredential,
 }
@@ -0,0 +1,91 @@
+use super::{error::ClusterConfigError as Error, kubeconfig::ExecConfig};
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderValue, Identity};
+use serde::Deserialize;
+use std::process::{Command, Stdio};
+
+/// client-go credential plugin (`users[].user.exec` in kubeconfig)
+/// https://kubernetes.io/docs/reference/access-authn-authz/authentication/#client-go-credential-plugins
+#[derive(Debug, Clone)]
+pub struct ExecPlugin {
+    config: ExecConfig,
+}
+
+pub enum ExecAuth {
+    Token(HeaderValue),
+    Identity(Identity),
+}
+
+pub struct ExecCredential {
+    pub auth: ExecAuth,
+    /// `None` means the credential never expires
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExecCredentialOutput {
+    status: Option<ExecCredentialStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredentialStatus {
+    token: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key_data: Option<String>,
+    expiration_timestamp: Option<DateTime<Utc>>,
+}
+
+impl ExecPlugin {
+    pub fn new(config: ExecConfig) -> Self {
+        Self { config }
+    }
+
+    /// runs the plugin synchronously, call from `spawn_blocking` when inside of async context
+    pub fn run(&self) -> Result<ExecCredential, Error> {
+        let exec_info = serde_json::json!({
+            "apiVersion": self.config.api_version,
+            "kind": "ExecCredential",
+            "spec": { "interactive": false },
+        });
+        let output = Command::new(&self.config.command)
+            .args(&self.config.args)
+            .envs(self.config.env.iter().map(|var| (&var.name, &var.value)))
+            .env("KUBERNETES_EXEC_INFO", exec_info.to_string())
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|err| Error::ExecSpawn {
+                command: self.config.command.clone(),
+                hint: self.config.install_hint.clone(),
+                err,
+            })?;
+        if !output.status.success() {
+            return Err(Error::ExecFailed {
+                command: self.config.command.clone(),
+                status: output.status,
+            });
+        }
+        let status = serde_json::from_slice::<ExecCredentialOutput>(&output.stdout)
+            .map_err(Error::ExecDeserialize)?
+            .status
+            .ok_or(Error::ExecMissingCredential)?;
+
+        let auth = match (status.token, status.client_certificate_data, status.client_key_data) {
+            (Some(token), _, _) => {
+                ExecAuth::Token(HeaderValue::from_str(&format!("Bearer {}", token)).map_err(Error::InvalidToken)?)
+            }
+            (None, Some(cert), Some(key)) => {
+                let mut pem = key.into_bytes();
+                pem.push(b'\n');
+                pem.extend_from_slice(cert.as_bytes());
+                ExecAuth::Identity(Identity::from_pem(&pem).map_err(Error::Identity)?)
+            }
+            _ => return Err(Error::ExecMissingCredential),
+        };
+        Ok(ExecCredential {
+            auth,
+            expiration: status.expiration_timestamp,
+        })
+    }
+}
@@ -1,14 +1,23 @@
 use super::error::ClusterConfigError;
 use serde::Deserialize;
-use std::{fs, io, path::Path};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct Kubeconfig {
+    #[serde(default)]
     pub clusters: Vec<NamedCluster>,
+    #[serde(default)]
     pub users: Vec<NamedUser>,
+    #[serde(default)]
     pub contexts: Vec<NamedContext>,
-    #[serde(rename = "current-context")]
-    pub current_context: String,
+    #[serde(rename = "current-context", default)]
+    pub current_context: Option<String>,
+    /// directory of the kubeconfig file, relative file references are resolved against it
+    #[serde(skip)]
+    pub base_dir: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -21,7 +30,11 @@ pub struct NamedCluster {
 pub struct Cluster {
     pub server: String,
     #[serde(rename = "certificate-authority-data")]
-    pub certificate_authority_data: String,
+    pub certificate_authority_data: Option<String>,
+    #[serde(rename = "certificate-authority")]
+    pub certificate_authority: Option<PathBuf>,
+    #[serde(rename = "insecure-skip-tls-verify", default)]
+    pub insecure_skip_tls_verify: bool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -33,9 +46,36 @@ pub struct NamedUser {
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct User {
     #[serde(rename = "client-certificate-data")]
-    pub client_certificate_data: String,
+    pub client_certificate_data: Option<String>,
     #[serde(rename = "client-key-data")]
-    pub client_key_data: String,
+    pub client_key_data: Option<String>,
+    #[serde(rename = "client-certificate")]
+    pub client_certificate: Option<PathBuf>,
+    #[serde(rename = "client-key")]
+    pub client_key: Option<PathBuf>,
+    pub token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    pub token_file: Option<PathBuf>,
+    pub exec: Option<ExecConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExecConfig {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<ExecEnvVar>,
+    #[serde(rename = "installHint")]
+    pub install_hint: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExecEnvVar {
+    pub name: String,
+    pub value: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -56,12 +96,7 @@ impl Kubeconfig {
             path: path.as_ref().into(),
             err,
         })?;
-        let kubeconfig: Kubeconfig =
-            serde_yaml::from_reader(file).map_err(|err| ClusterConfigError::FileDeserialize {
-                path: path.as_ref().into(),
-                err,
-            })?;
-        Ok(kubeconfig)
+        Self::from_file(path.as_ref(), file)
     }
 
     pub fn from_default_path() -> Option<Result<Kubeconfig, ClusterConfigError>> {
@@ -74,13 +109,25 @@ impl Kubeconfig {
                 _ => return Some(Err(ClusterConfigError::FileOpen { path, err })),
             },
         };
-        Some(serde_yaml::from_reader(file).map_err(|err| ClusterConfigError::FileDeserialize { path, err }))
+        Some(Self::from_file(&path, file))
     }
 
     pub fn from_env() -> Option<Result<Self, ClusterConfigError>> {
-        match std::env::var_os("KUBECONFIG") {
-            Some(path) => Some(Self::from_path(path)),
-            None => None,
+        std::env::var_os("KUBECONFIG").map(Self::from_path)
+    }
+
+    fn from_file(path: &Path, file: fs::File) -> Result<Kubeconfig, ClusterConfigError> {
+        let mut kubeconfig: Kubeconfig = serde_yaml::from_reader(file)
+            .map_err(|err| ClusterConfigError::FileDeserialize { path: path.into(), err })?;
+        kubeconfig.base_dir = path.parent().map(Into::into);
+        Ok(kubeconfig)
+    }
+
+    /// resolves file references the same way `kubectl` does: relative to the directory of the kubeconfig
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        match &self.base_dir {
+            Some(base_dir) if path.is_relative() => base_dir.join(path),
+            _ => path.into(),
         }
     }
 }
@@ -1,26 +1,49 @@
 mod error;
+mod exec;
 mod kubeconfig;
 
 use crate::{
-    k8s_client::api::cluster_config::kubeconfig::Kubeconfig,
+    k8s_client::api::cluster_config::kubeconfig::{Cluster, Kubeconfig, User},
     utils::{read_to_vec, read_token},
 };
 pub use error::ClusterConfigError;
+pub use exec::{ExecAuth, ExecCredential, ExecPlugin};
 use reqwest::{header::HeaderValue, Certificate, Identity};
-use std::{fs, io};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 use ClusterConfigError as Error;
 
 pub enum AuthMethod {
+    None,
     Identity(Identity),
     Token(reqwest::header::HeaderValue),
+    /// token which is periodically re-read from `path`, `initial` is its current content
+    TokenFile {
+        path: PathBuf,
+        initial: HeaderValue,
+    },
+    /// token obtained from a credential plugin, `initial` is the result of the first run
+    Exec {
+        plugin: ExecPlugin,
+        initial: ExecCredential,
+    },
 }
 
 pub struct ClusterConfig {
     pub server: String,
-    pub cacert: Certificate,
+    /// `None` means the built-in root certificates are used
+    pub cacert: Option<Certificate>,
+    pub insecure_skip_tls_verify: bool,
     pub auth: AuthMethod,
 }
 
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut file = fs::File::open(path).map_err(|err| Error::FileOpen { path: path.into(), err })?;
+    read_to_vec(&mut file).map_err(|err| Error::FileRead { path: path.into(), err })
+}
+
 impl ClusterConfig {
     pub fn in_cluster() -> Option<Result<Self, Error>> {
         const TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
@@ -38,88 +61,127 @@ impl ClusterConfig {
                 }
             },
         };
-        return match in_cluster_inner(token) {
-            Ok(cc) => Some(Ok(cc)),
-            Err(err) => Some(Err(err)),
-        };
+        return Some(in_cluster_inner(token));
 
         fn in_cluster_inner(mut token_file: fs::File) -> Result<ClusterConfig, Error> {
-            let mut cacert_file = fs::File::open(CACERT_PATH).map_err(|err| Error::FileOpen {
-                path: CACERT_PATH.into(),
-                err,
-            })?;
-
-            let cacert = read_to_vec(&mut cacert_file).map_err(|err| Error::FileRead {
-                path: CACERT_PATH.into(),
-                err,
-            })?;
+            let cacert = read_file(Path::new(CACERT_PATH))?;
             let token = read_token(&mut token_file).map_err(|err| Error::FileRead {
                 path: TOKEN_PATH.into(),
                 err,
             })?;
             Ok(ClusterConfig {
-                auth: AuthMethod::Token(HeaderValue::from_str(&token).map_err(|err| Error::InvalidToken(err))?),
-                cacert: Certificate::from_pem(&cacert).map_err(|err| Error::Certificate(err))?,
+                // bound service account tokens expire, kubelet rotates the file before that happens
+                auth: AuthMethod::TokenFile {
+                    path: TOKEN_PATH.into(),
+                    initial: HeaderValue::from_str(&token).map_err(Error::InvalidToken)?,
+                },
+                cacert: Some(Certificate::from_pem(&cacert).map_err(Error::Certificate)?),
+                insecure_skip_tls_verify: false,
                 server: "https://kubernetes.default.svc:443".into(),
             })
         }
     }
 
-    pub fn from_kubeconfig(k: Kubeconfig) -> Result<Self, Error> {
-        let contexts = k.contexts;
-        let clusters = k.clusters;
-        let users = k.users;
-        let current_context = k.current_context;
-        let context = match contexts.into_iter().find(|c| c.name == current_context) {
-            None => Err(()),
-            Some(c) => Ok(c),
-        }
-        .map_err(|_| Error::MissingContext(current_context))?
-        .context;
-        let current_cluster = context.cluster;
-        let current_user = context.user;
-
-        let cluster = match clusters.into_iter().find(|c| c.name == current_cluster) {
-            None => Err(()),
-            Some(c) => Ok(c),
-        }
-        .map_err(|_| Error::MissingCluster(current_cluster))?
-        .cluster;
-
-        let user = match users.into_iter().find(|c| c.name == current_user) {
-            None => Err(()),
-            Some(c) => Ok(c),
-        }
-        .map_err(|_| Error::MissingUser(current_user))?
-        .user;
-
-        let client_cert_data =
-            base64::decode(user.client_certificate_data).map_err(|err| Error::InvalidBase64Cert(err))?;
+    /// `context` overrides "current-context" of the kubeconfig
+    pub fn from_kubeconfig(k: Kubeconfig, context: Option<&str>) -> Result<Self, Error> {
+        let context_name = match context {
+            Some(context) => context.to_string(),
+            None => k.current_context.clone().ok_or(Error::MissingCurrentContext)?,
+        };
+        let context = match k.contexts.iter().find(|c| c.name == context_name) {
+            None => return Err(Error::MissingContext(context_name)),
+            Some(c) => &c.context,
+        };
 
-        let client_key_data = base64::decode(user.client_key_data).map_err(|err| Error::InvalidBase64Key(err))?;
-        let mut pem = client_key_data;
-        pem.push(b'\n');
-        pem.extend_from_slice(&client_cert_data);
-        let identity = Identity::from_pem(&pem).map_err(|err| Error::Identity(err))?;
+        let cluster = match k.clusters.iter().find(|c| c.name == context.cluster) {
+            None => return Err(Error::MissingCluster(context.cluster.clone())),
+            Some(c) => &c.cluster,
+        };
 
-        let cacert_data =
-            base64::decode(cluster.certificate_authority_data).map_err(|err| Error::InvalidBase64Cacert(err))?;
-        let cacert = Certificate::from_pem(&cacert_data).map_err(|err| Error::Certificate(err))?;
+        let user = match k.users.iter().find(|c| c.name == context.user) {
+            None => return Err(Error::MissingUser(context.user.clone())),
+            Some(c) => &c.user,
+        };
 
         Ok(Self {
-            auth: AuthMethod::Identity(identity),
-            cacert,
-            server: cluster.server,
+            auth: Self::user_auth(&k, user)?,
+            cacert: Self::cluster_cacert(&k, cluster)?,
+            insecure_skip_tls_verify: cluster.insecure_skip_tls_verify,
+            server: cluster.server.clone(),
         })
     }
 
-    pub fn detect() -> Result<Self, Error> {
+    fn cluster_cacert(k: &Kubeconfig, cluster: &Cluster) -> Result<Option<Certificate>, Error> {
+        let cacert_data = match (&cluster.certificate_authority_data, &cluster.certificate_authority) {
+            (Some(data), _) => base64::decode(data).map_err(Error::InvalidBase64Cacert)?,
+            (None, Some(path)) => read_file(&k.resolve_path(path))?,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Certificate::from_pem(&cacert_data).map_err(Error::Certificate)?))
+    }
+
+    fn user_auth(k: &Kubeconfig, user: &User) -> Result<AuthMethod, Error> {
+        let client_cert_data = match (&user.client_certificate_data, &user.client_certificate) {
+            (Some(data), _) => Some(base64::decode(data).map_err(Error::InvalidBase64Cert)?),
+            (None, Some(path)) => Some(read_file(&k.resolve_path(path))?),
+            (None, None) => None,
+        };
+        let client_key_data = match (&user.client_key_data, &user.client_key) {
+            (Some(data), _) => Some(base64::decode(data).map_err(Error::InvalidBase64Key)?),
+            (None, Some(path)) => Some(read_file(&k.resolve_path(path))?),
+            (None, None) => None,
+        };
+        match (client_cert_data, client_key_data) {
+            (Some(client_cert_data), Some(client_key_data)) => {
+                let mut pem = client_key_data;
+                pem.push(b'\n');
+                pem.extend_from_slice(&client_cert_data);
+                let identity = Identity::from_pem(&pem).map_err(Error::Identity)?;
+                return Ok(AuthMethod::Identity(identity));
+            }
+            (None, None) => {}
+            _ => return Err(Error::IncompleteIdentity),
+        }
+
+        if let Some(token) = &user.token {
+            let header = HeaderValue::from_str(&format!("Bearer {}", token.trim_end())).map_err(Error::InvalidToken)?;
+            return Ok(AuthMethod::Token(header));
+        }
+        if let Some(path) = &user.token_file {
+            let path = k.resolve_path(path);
+            let mut file = fs::File::open(&path).map_err(|err| Error::FileOpen {
+                path: path.clone(),
+                err,
+            })?;
+            let token = read_token(&mut file).map_err(|err| Error::FileRead {
+                path: path.clone(),
+                err,
+            })?;
+            return Ok(AuthMethod::TokenFile {
+                path,
+                initial: HeaderValue::from_str(&token).map_err(Error::InvalidToken)?,
+            });
+        }
+        if let Some(exec) = &user.exec {
+            let plugin = ExecPlugin::new(exec.clone());
+            let initial = plugin.run()?;
+            return Ok(match initial.auth {
+                // client certificates are bound to the TLS client, we can't swap them without rebuilding it
+                ExecAuth::Identity(identity) => AuthMethod::Identity(identity),
+                ExecAuth::Token(_) => AuthMethod::Exec { plugin, initial },
+            });
+        }
+        Ok(AuthMethod::None)
+    }
+
+    /// `context` only applies to kubeconfig, in-cluster config is skipped when it is set
+    pub fn detect(context: Option<&str>) -> Result<Self, Error> {
         let cc = match Kubeconfig::from_env() {
-            Some(r) => Self::from_kubeconfig(r?)?,
-            None => match Self::in_cluster() {
+            Some(r) => Self::from_kubeconfig(r?, context)?,
+            None => match context.map_or_else(Self::in_cluster, |_| None) {
                 Some(cc) => cc?,
                 None => match Kubeconfig::from_default_path() {
-                    Some(r) => Self::from_kubeconfig(r?)?,
+                    Some(r) => Self::from_kubeconfig(r?, context)?,
                     None => return Err(Error::Detect),
                 },
             },
@@ -127,3 +189,61 @@ impl ClusterConfig {
         Ok(cc)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KUBECONFIG: &str = r#"
+clusters:
+- name: a
+  cluster:
+    server: https://a.example.com
+    insecure-skip-tls-verify: true
+- name: b
+  cluster:
+    server: https://b.example.com
+users:
+- name: a
+  user:
+    token: secret-a
+- name: b
+  user: {}
+contexts:
+- name: a
+  context: { cluster: a, user: a }
+- name: b
+  context: { cluster: b, user: b }
+current-context: a
+"#;
+
+    fn kubeconfig() -> Kubeconfig {
+        serde_yaml::from_str(KUBECONFIG).unwrap()
+    }
+
+    #[test]
+    fn current_context() {
+        let cc = ClusterConfig::from_kubeconfig(kubeconfig(), None).unwrap();
+        assert_eq!(cc.server, "https://a.example.com");
+        assert!(cc.insecure_skip_tls_verify);
+        assert!(cc.cacert.is_none());
+        match cc.auth {
+            AuthMethod::Token(token) => assert_eq!(token, "Bearer secret-a"),
+            _ => panic!("expected token auth"),
+        }
+    }
+
+    #[test]
+    fn explicit_context() {
+        let cc = ClusterConfig::from_kubeconfig(kubeconfig(), Some("b")).unwrap();
+        assert_eq!(cc.server, "https://b.example.com");
+        assert!(!cc.insecure_skip_tls_verify);
+        assert!(matches!(cc.auth, AuthMethod::None));
+    }
+
+    #[test]
+    fn missing_context() {
+        let err = ClusterConfig::from_kubeconfig(kubeconfig(), Some("c")).err().unwrap();
+        assert!(matches!(err, Error::MissingContext(ctx) if ctx == "c"));
+    }
+}
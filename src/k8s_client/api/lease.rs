@@ -0,0 +1,161 @@
+use super::{ApiGetter, Req};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// `coordination.k8s.io/v1` `Lease`, fields we don't know are kept so updates don't drop them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lease {
+    #[serde(default = "lease_api_version")]
+    pub api_version: String,
+    #[serde(default = "lease_kind")]
+    pub kind: String,
+    pub metadata: LeaseMetadata,
+    #[serde(default)]
+    pub spec: LeaseSpec,
+}
+
+fn lease_api_version() -> String {
+    "coordination.k8s.io/v1".to_string()
+}
+fn lease_kind() -> String {
+    "Lease".to_string()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaseMetadata {
+    pub name: String,
+    pub namespace: String,
+    /// `None` when creating, the update is rejected with 409 if the lease changed since it was read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaseSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holder_identity: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_duration_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "micro_time")]
+    pub acquire_time: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "micro_time")]
+    pub renew_time: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_transitions: Option<u64>,
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+impl Lease {
+    pub fn new(namespace: &str, name: &str) -> Self {
+        Self {
+            api_version: lease_api_version(),
+            kind: lease_kind(),
+            metadata: LeaseMetadata {
+                name: name.to_string(),
+                namespace: namespace.to_string(),
+                ..LeaseMetadata::default()
+            },
+            spec: LeaseSpec::default(),
+        }
+    }
+}
+
+/// `metav1.MicroTime` is always written with exactly six fractional digits
+mod micro_time {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&time.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Option::<DateTime<Utc>>::deserialize(deserializer)
+    }
+}
+
+fn lease_path(namespace: &str) -> String {
+    format!("/apis/coordination.k8s.io/v1/namespaces/{}/leases", namespace)
+}
+
+#[derive(Debug, Clone)]
+pub struct LeaseGetter<'a> {
+    pub namespace: &'a str,
+    pub name: &'a str,
+}
+impl<'a> ApiGetter for LeaseGetter<'a> {
+    type Output = Lease;
+    fn get(&self) -> Req<Self::Output> {
+        Req::get(format!("{}/{}", lease_path(self.namespace), self.name), |resp| {
+            Ok(serde_json::from_slice(resp)?)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LeaseCreator<'a> {
+    pub lease: &'a Lease,
+}
+impl<'a> ApiGetter for LeaseCreator<'a> {
+    type Output = Lease;
+    fn get(&self) -> Req<Self::Output> {
+        // expectations:
+        // `Lease` only contains strings, numbers and maps with string keys
+        let body = serde_json::to_value(self.lease).expect("Lease serialization failed");
+        Req::post(lease_path(&self.lease.metadata.namespace), body, |resp| {
+            Ok(serde_json::from_slice(resp)?)
+        })
+    }
+}
+
+/// replaces the lease, fails with 409 if someone else updated it since `metadata.resourceVersion`
+#[derive(Debug, Clone)]
+pub struct LeaseUpdater<'a> {
+    pub lease: &'a Lease,
+}
+impl<'a> ApiGetter for LeaseUpdater<'a> {
+    type Output = Lease;
+    fn get(&self) -> Req<Self::Output> {
+        let metadata = &self.lease.metadata;
+        let body = serde_json::to_value(self.lease).expect("Lease serialization failed");
+        Req::put(
+            format!("{}/{}", lease_path(&metadata.namespace), metadata.name),
+            body,
+            |resp| Ok(serde_json::from_slice(resp)?),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let json = r#"{
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {"name": "big-brother", "namespace": "default", "resourceVersion": "12", "uid": "u"},
+            "spec": {"holderIdentity": "a", "leaseDurationSeconds": 15, "renewTime": "2021-01-01T00:00:00.123456Z"}
+        }"#;
+        let lease: Lease = serde_json::from_str(json).unwrap();
+        assert_eq!(lease.spec.holder_identity.as_deref(), Some("a"));
+        let value = serde_json::to_value(&lease).unwrap();
+        assert_eq!(value["metadata"]["uid"], "u");
+        assert_eq!(value["spec"]["renewTime"], "2021-01-01T00:00:00.123456Z");
+        assert!(value["spec"].get("acquireTime").is_none());
+    }
+}
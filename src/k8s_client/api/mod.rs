@@ -2,32 +2,34 @@ mod api_group;
 mod api_resource;
 mod api_version;
 pub mod cluster_config;
+mod lease;
 mod resource;
+mod review;
+mod status;
 
 use self::api_version::ApiVersions;
+use super::protobuf::{self, ProtobufError};
 use api_group::{ApiGroup, ApiGroupList};
 pub use api_resource::{ApiResource, ApiResourceList};
 use itertools::Itertools;
+pub use lease::{Lease, LeaseCreator, LeaseGetter, LeaseSpec, LeaseUpdater};
 use reqwest::{Method, StatusCode};
-pub use resource::{ListItem, Metadata, Resource, ResourceList};
+pub use resource::{ListItem, Resource, ResourceList};
+pub use review::{ResourceAttributes, SubjectAccessReviewCreator, TokenReviewCreator, UserInfo};
+pub use status::{Status, StatusError};
 use std::{convert::TryFrom, fmt};
 use uriparse::relative_reference::RelativeReference;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct ResourceId {
-    pub api_version: String,
-    pub kind: String,
-    pub name: String,
-    pub namespace: Option<String>,
-}
+const JSON: &str = "application/json";
 
-pub type ResourceVersion = u64;
+/// shared with the client library
+pub use big_brother_client::{ResourceId, ResourceVersion};
 
 pub trait ApiWatcher: ApiGetter {
     fn watch(&self, rv: ResourceVersion) -> Req<Self::Output> {
         let mut req = self.get();
         let mut uri = RelativeReference::try_from(req.relative_url.as_str())
-            .expect(&format!("Invalid uri reference {:?}", req.relative_url));
+            .unwrap_or_else(|_| panic!("Invalid uri reference {:?}", req.relative_url));
         let query = match uri.query() {
             Some(q) => {
                 let it = qstring::QString::from(q.as_str())
@@ -65,8 +67,12 @@ pub trait ApiGetter: Clone {
 pub enum K8sApiError {
     #[error("Unexpected status [{}]", _0)]
     UnexpectedStatus(StatusCode),
+    #[error("{}", _0)]
+    Status(#[from] Box<StatusError>),
     #[error("Deserialization error: {:?}", _0)]
     Deserialize(#[from] serde_json::Error),
+    #[error("Protobuf error: {}", _0)]
+    Protobuf(#[from] ProtobufError),
 }
 
 #[derive(Clone)]
@@ -75,6 +81,8 @@ pub struct Req<T> {
     // TODO: change to `RelativeReference`
     pub relative_url: String,
     pub body: Vec<u8>,
+    /// `application/json` unless the getter can decode protobuf
+    pub accept: &'static str,
     pub status_check: fn(StatusCode) -> bool,
     pub response: fn(&[u8]) -> Result<T, K8sApiError>,
 }
@@ -95,6 +103,29 @@ impl<T> Req<T> {
             method: Method::GET,
             relative_url: relative_url.into(),
             body: Vec::new(),
+            accept: JSON,
+            status_check: |status_code| status_code == StatusCode::OK,
+            response: f,
+        }
+    }
+    fn post<S: Into<String>>(relative_url: S, body: serde_json::Value, f: fn(&[u8]) -> Result<T, K8sApiError>) -> Self {
+        Self {
+            method: Method::POST,
+            relative_url: relative_url.into(),
+            // expectations:
+            // `serde_json::Value` always serializes successfully
+            body: serde_json::to_vec(&body).expect("Request body serialization failed"),
+            accept: JSON,
+            status_check: |status_code| status_code == StatusCode::CREATED || status_code == StatusCode::OK,
+            response: f,
+        }
+    }
+    fn put<S: Into<String>>(relative_url: S, body: serde_json::Value, f: fn(&[u8]) -> Result<T, K8sApiError>) -> Self {
+        Self {
+            method: Method::PUT,
+            relative_url: relative_url.into(),
+            body: serde_json::to_vec(&body).expect("Request body serialization failed"),
+            accept: JSON,
             status_check: |status_code| status_code == StatusCode::OK,
             response: f,
         }
@@ -149,6 +180,7 @@ impl ApiGetter for CoreResourceListGetter {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct ApiGroupGetter<'a> {
     group_name: &'a str,
@@ -167,6 +199,8 @@ pub struct ResourceListGetter {
     pub group: Option<String>,
     pub version: String,
     pub plural: String,
+    /// asks for protobuf, see `protobuf::supports`
+    pub protobuf: bool,
 }
 
 impl ApiGetter for ResourceListGetter {
@@ -176,7 +210,17 @@ impl ApiGetter for ResourceListGetter {
             Some(ref group) => format!("/apis/{}/{}/{}", group, self.version, self.plural),
             None => format!("/api/{}/{}", self.version, self.plural),
         };
-        Req::get(path, |resp| Ok(serde_json::from_slice(resp)?))
+        let mut req = Req::get(path, |resp| {
+            if protobuf::is_encoded(resp) {
+                Ok(serde_json::from_value(protobuf::decode(resp)?)?)
+            } else {
+                Ok(serde_json::from_slice(resp)?)
+            }
+        });
+        if self.protobuf {
+            req.accept = protobuf::ACCEPT;
+        }
+        req
     }
 }
 impl ApiWatcher for ResourceListGetter {}
@@ -0,0 +1,98 @@
+use super::{ApiGetter, Req};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// `authentication.k8s.io/v1` `UserInfo`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UserInfo {
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub uid: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub extra: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TokenReviewStatus {
+    #[serde(default)]
+    pub authenticated: bool,
+    pub user: Option<UserInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenReview {
+    #[serde(default)]
+    pub status: TokenReviewStatus,
+}
+
+/// asks the API server who the bearer of `token` is
+#[derive(Debug, Clone)]
+pub struct TokenReviewCreator<'a> {
+    pub token: &'a str,
+}
+impl<'a> ApiGetter for TokenReviewCreator<'a> {
+    type Output = TokenReview;
+    fn get(&self) -> Req<Self::Output> {
+        let body = serde_json::json!({
+            "apiVersion": "authentication.k8s.io/v1",
+            "kind": "TokenReview",
+            "spec": { "token": self.token },
+        });
+        Req::post("/apis/authentication.k8s.io/v1/tokenreviews", body, |resp| {
+            Ok(serde_json::from_slice(resp)?)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct ResourceAttributes {
+    pub namespace: String,
+    pub verb: String,
+    pub group: String,
+    pub resource: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SubjectAccessReviewStatus {
+    #[serde(default)]
+    pub allowed: bool,
+    pub reason: Option<String>,
+    #[serde(rename = "evaluationError")]
+    pub evaluation_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubjectAccessReview {
+    #[serde(default)]
+    pub status: SubjectAccessReviewStatus,
+}
+
+/// asks the API server whether `user` may perform `attributes`
+#[derive(Debug, Clone)]
+pub struct SubjectAccessReviewCreator<'a> {
+    pub user: &'a UserInfo,
+    pub attributes: &'a ResourceAttributes,
+}
+impl<'a> ApiGetter for SubjectAccessReviewCreator<'a> {
+    type Output = SubjectAccessReview;
+    fn get(&self) -> Req<Self::Output> {
+        let body = serde_json::json!({
+            "apiVersion": "authorization.k8s.io/v1",
+            "kind": "SubjectAccessReview",
+            "spec": {
+                "user": self.user.username,
+                "uid": self.user.uid,
+                "groups": self.user.groups,
+                "extra": self.user.extra,
+                "resourceAttributes": self.attributes,
+            },
+        });
+        Req::post("/apis/authorization.k8s.io/v1/subjectaccessreviews", body, |resp| {
+            Ok(serde_json::from_slice(resp)?)
+        })
+    }
+}
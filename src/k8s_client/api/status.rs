@@ -0,0 +1,155 @@
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Deserialize;
+use std::{fmt, time::Duration};
+
+/// `metav1.Status` returned by the API server in the body of unsuccessful responses
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Status {
+    pub status: Option<String>,
+    pub message: Option<String>,
+    pub reason: Option<String>,
+    pub details: Option<StatusDetails>,
+    pub code: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct StatusDetails {
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub causes: Vec<StatusCause>,
+    #[serde(rename = "retryAfterSeconds")]
+    pub retry_after_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct StatusCause {
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub field: Option<String>,
+}
+
+/// unsuccessful HTTP response, `status` is `None` when the body is not a `Status` object
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusError {
+    pub code: StatusCode,
+    pub status: Option<Status>,
+    pub retry_after: Option<Duration>,
+}
+
+impl StatusError {
+    pub fn new(code: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+        let status = serde_json::from_slice::<Status>(body).ok();
+        // "Retry-After" can also be an HTTP date, but API server only ever sends seconds
+        let retry_after = headers
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .or_else(|| status.as_ref()?.details.as_ref()?.retry_after_seconds)
+            .map(Duration::from_secs);
+        Self {
+            code,
+            status,
+            retry_after,
+        }
+    }
+
+    /// the object of an `ERROR` watch event, the API server ends the watch after sending it
+    pub fn from_status(status: Status) -> Self {
+        let code = status
+            .code
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let retry_after = status
+            .details
+            .as_ref()
+            .and_then(|details| details.retry_after_seconds)
+            .map(Duration::from_secs);
+        Self {
+            code,
+            status: Some(status),
+            retry_after,
+        }
+    }
+
+    /// server is overloaded or temporarily broken, the same request may succeed later
+    pub fn is_transient(&self) -> bool {
+        self.code == StatusCode::TOO_MANY_REQUESTS || self.code.is_server_error()
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self.code {
+            StatusCode::UNAUTHORIZED => Some("credentials were rejected, check the token or client certificate"),
+            StatusCode::FORBIDDEN => Some("missing RBAC permissions, check big-brother's ClusterRole"),
+            StatusCode::NOT_FOUND => Some("the resource does not exist (anymore), e.g. its CRD was deleted"),
+            StatusCode::GONE => Some("the requested resourceVersion is too old"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}]", self.code)?;
+        if let Some(status) = &self.status {
+            if let Some(reason) = &status.reason {
+                write!(f, " {}:", reason)?;
+            }
+            if let Some(message) = &status.message {
+                write!(f, " {}", message)?;
+            }
+            for cause in status.details.iter().flat_map(|details| &details.causes) {
+                let field = cause.field.as_deref().unwrap_or("-");
+                write!(f, "; {}: {}", field, cause.message.as_deref().unwrap_or_default())?;
+            }
+        }
+        if let Some(hint) = self.hint() {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn parse_forbidden() {
+        let body = br#"{"kind":"Status","apiVersion":"v1","metadata":{},"status":"Failure","message":"pods is forbidden: User \"system:serviceaccount:default:big-brother\" cannot list resource \"pods\"","reason":"Forbidden","details":{"kind":"pods"},"code":403}"#;
+        let err = StatusError::new(StatusCode::FORBIDDEN, &HeaderMap::new(), body);
+        let status = err.status.as_ref().unwrap();
+        assert_eq!(status.reason.as_deref(), Some("Forbidden"));
+        assert_eq!(status.code, Some(403));
+        assert!(!err.is_transient());
+        assert!(err
+            .to_string()
+            .starts_with("[403 Forbidden] Forbidden: pods is forbidden"));
+    }
+
+    #[test]
+    fn watch_event() {
+        let object = br#"{"kind":"Status","apiVersion":"v1","metadata":{},"status":"Failure","message":"too old resource version: 1 (5)","reason":"Expired","code":410}"#;
+        let err = StatusError::from_status(serde_json::from_slice(object).unwrap());
+        assert_eq!(err.code, StatusCode::GONE);
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, HeaderValue::from_static("3"));
+        let err = StatusError::new(StatusCode::TOO_MANY_REQUESTS, &headers, b"Too many requests");
+        assert!(err.status.is_none());
+        assert!(err.is_transient());
+        assert_eq!(err.retry_after, Some(Duration::from_secs(3)));
+
+        let body = br#"{"kind":"Status","reason":"TooManyRequests","details":{"retryAfterSeconds":5},"code":429}"#;
+        let err = StatusError::new(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), body);
+        assert_eq!(err.retry_after, Some(Duration::from_secs(5)));
+    }
+}
@@ -1,22 +1,112 @@
 pub mod api;
+pub mod protobuf;
+mod token;
 
+use crate::rate_limit::RateLimiter;
 use api::{
-    cluster_config::{AuthMethod, ClusterConfig},
-    ApiGetter, ApiWatcher, K8sApiError, ResourceVersion,
+    cluster_config::{AuthMethod, ClusterConfig, ClusterConfigError},
+    ApiGetter, ApiWatcher, K8sApiError, ResourceVersion, StatusError,
 };
 use backoff::{future::retry_notify, ExponentialBackoff};
-use futures_util::TryFutureExt;
-use reqwest::{header::HeaderValue, Method, Request, Response, Url};
+use reqwest::{header::HeaderValue, Method, Request, Response, StatusCode, Url};
+use serde::Deserialize;
 use std::{
     str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
 };
+use token::TokenSource;
+use tokio::sync::Semaphore;
 
 #[derive(Debug, Clone)]
 pub struct K8sClient {
     base_url: Url,
     client: reqwest::Client,
-    token: Option<HeaderValue>,
+    token: Option<TokenSource>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    lists: Arc<Semaphore>,
+    backoff: RetryBackoff,
+    /// `None` retries until the request succeeds
+    deadline: Option<Duration>,
+}
+
+/// protects the API server from our startup fan-out, see API Priority and Fairness
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientLimits {
+    /// `None` disables rate limiting
+    pub qps: Option<f64>,
+    pub burst: u32,
+    pub max_concurrent_lists: usize,
+    pub backoff: RetryBackoff,
+}
+
+/// delays between retries of failed requests
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RetryBackoff {
+    #[serde(default)]
+    pub initial_interval_ms: u64,
+    #[serde(default = "default_max_interval_ms")]
+    pub max_interval_ms: u64,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// each delay is randomly shortened or lengthened by up to this fraction
+    #[serde(default = "default_randomization_factor")]
+    pub randomization_factor: f64,
+}
+
+fn default_max_interval_ms() -> u64 {
+    10_000
+}
+fn default_multiplier() -> f64 {
+    1.3
+}
+fn default_randomization_factor() -> f64 {
+    0.1
+}
+
+impl Default for RetryBackoff {
+    fn default() -> Self {
+        Self {
+            initial_interval_ms: 0,
+            max_interval_ms: default_max_interval_ms(),
+            multiplier: default_multiplier(),
+            randomization_factor: default_randomization_factor(),
+        }
+    }
+}
+
+impl RetryBackoff {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            return Err(format!("multiplier must be at least 1, got {}", self.multiplier));
+        }
+        if !(0.0..=1.0).contains(&self.randomization_factor) {
+            return Err(format!(
+                "randomizationFactor must be between 0 and 1, got {}",
+                self.randomization_factor
+            ));
+        }
+        if self.initial_interval_ms > self.max_interval_ms {
+            return Err("initialIntervalMs must not exceed maxIntervalMs".into());
+        }
+        Ok(())
+    }
+
+    /// `min_interval` raises the initial interval, where 0 would otherwise retry right away every time
+    pub fn exponential(&self, min_interval: Duration) -> ExponentialBackoff {
+        let initial_interval = Duration::from_millis(self.initial_interval_ms).max(min_interval);
+        ExponentialBackoff {
+            initial_interval,
+            current_interval: initial_interval,
+            max_elapsed_time: None,
+            randomization_factor: self.randomization_factor,
+            max_interval: Duration::from_millis(self.max_interval_ms),
+            multiplier: self.multiplier,
+            clock: Default::default(),
+            start_time: Instant::now(),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -25,8 +115,56 @@ pub enum K8sClientError {
     Reqwest(#[from] reqwest::Error),
     #[error("Url parse error {:?}", _0)]
     UrlParse(#[from] url::ParseError),
-    #[error("Url parse error {:?}", _0)]
+    #[error("Kubernetes API error {}", _0)]
     K8sApi(#[from] K8sApiError),
+    #[error("Credential plugin error {:?}", _0)]
+    Exec(#[from] ClusterConfigError),
+    #[error("Credential plugin returned client certificate instead of token on refresh")]
+    ExecIdentity,
+    #[error("Token was rejected by the server and has been reloaded")]
+    TokenRotated,
+}
+
+impl K8sClientError {
+    /// the API server will keep rejecting this request, retrying it is pointless
+    pub fn is_rejected(&self) -> bool {
+        match self {
+            Self::K8sApi(K8sApiError::Status(err)) => matches!(
+                err.code,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+            ),
+            _ => false,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::K8sApi(K8sApiError::Status(err)) if err.code == StatusCode::NOT_FOUND)
+    }
+
+    /// the resourceVersion to watch from has been compacted away, only a new list can continue
+    pub fn is_gone(&self) -> bool {
+        matches!(self, Self::K8sApi(K8sApiError::Status(err)) if err.code == StatusCode::GONE)
+    }
+
+    /// someone else modified the object since it was read
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Self::K8sApi(K8sApiError::Status(err)) if err.code == StatusCode::CONFLICT)
+    }
+
+    /// stable name of the variant for the `error_kind` log field
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Reqwest(_) => "request",
+            Self::UrlParse(_) => "url",
+            Self::K8sApi(K8sApiError::UnexpectedStatus(_)) => "unexpected_status",
+            Self::K8sApi(K8sApiError::Status(_)) => "status",
+            Self::K8sApi(K8sApiError::Deserialize(_)) => "deserialize",
+            Self::K8sApi(K8sApiError::Protobuf(_)) => "protobuf",
+            Self::Exec(_) => "credential_plugin",
+            Self::ExecIdentity => "credential_plugin_identity",
+            Self::TokenRotated => "token_rotated",
+        }
+    }
 }
 
 impl K8sClient {
@@ -36,63 +174,150 @@ impl K8sClient {
             .tls_built_in_root_certs(false)
             .https_only(true)
     }
-    pub fn from_cluster_config(cluster_config: ClusterConfig) -> Result<Self, K8sClientError> {
-        let builder = Self::client_builder().add_root_certificate(cluster_config.cacert);
+    pub fn from_cluster_config(cluster_config: ClusterConfig, limits: &ClientLimits) -> Result<Self, K8sClientError> {
+        let builder = match cluster_config.cacert {
+            Some(cacert) => Self::client_builder().add_root_certificate(cacert),
+            None => Self::client_builder().tls_built_in_root_certs(true),
+        };
+        let builder = builder.danger_accept_invalid_certs(cluster_config.insecure_skip_tls_verify);
         let (token, builder) = match cluster_config.auth {
+            AuthMethod::None => (None, builder),
             AuthMethod::Identity(identity) => (None, builder.identity(identity)),
-            AuthMethod::Token(token) => (Some(token), builder),
+            AuthMethod::Token(token) => (Some(TokenSource::Static(token)), builder),
+            AuthMethod::TokenFile { path, initial } => (Some(TokenSource::file(path, initial)), builder),
+            AuthMethod::Exec { plugin, initial } => (Some(TokenSource::exec(plugin, initial)?), builder),
         };
         let client = builder.build().map_err(K8sClientError::Reqwest)?;
         Ok(Self {
             base_url: reqwest::Url::from_str(&cluster_config.server).map_err(K8sClientError::UrlParse)?,
             client,
             token,
+            rate_limiter: limits
+                .qps
+                .filter(|qps| *qps > 0.0)
+                .map(|qps| Arc::new(RateLimiter::new(qps, limits.burst))),
+            lists: Arc::new(Semaphore::new(limits.max_concurrent_lists.max(1))),
+            backoff: limits.backoff.clone(),
+            deadline: None,
         })
     }
 
-    fn backoff() -> ExponentialBackoff {
+    /// plain HTTP client without credentials or limits, talks to mock API servers in tests
+    #[cfg(test)]
+    pub fn for_test(server: &str) -> Self {
+        Self {
+            base_url: Url::from_str(server).expect("Invalid mock server url"),
+            client: reqwest::Client::new(),
+            token: None,
+            rate_limiter: None,
+            lists: Arc::new(Semaphore::new(1)),
+            backoff: RetryBackoff::default(),
+            deadline: None,
+        }
+    }
+
+    /// same server and credentials, but not throttled by the shared rate limiter,
+    /// a request (including its retries) fails once it has taken longer than `deadline`
+    pub fn bounded(&self, deadline: Duration) -> Self {
+        Self {
+            rate_limiter: None,
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    fn backoff(&self) -> ExponentialBackoff {
         ExponentialBackoff {
-            initial_interval: Duration::ZERO,
-            current_interval: Duration::ZERO,
-            max_elapsed_time: None,
-            randomization_factor: 0.1,
-            max_interval: Duration::from_secs(10),
-            multiplier: 1.3,
-            clock: Default::default(),
-            start_time: Instant::now(),
+            max_elapsed_time: self.deadline,
+            ..self.backoff.exponential(Duration::ZERO)
         }
     }
+    /// the configured backoff, for retries outside of the client, e.g. of a failed watch
+    pub fn retry_backoff(&self, min_interval: Duration) -> ExponentialBackoff {
+        self.backoff.exponential(min_interval)
+    }
     fn notify(err: K8sClientError, duration: Duration) {
-        eprintln!(
-            "Error sending request (duration: {}.{:.3}):\n{:?}",
-            duration.as_secs(),
-            duration.as_millis(),
-            err
+        tracing::warn!(
+            error_kind = err.kind(),
+            error = %err,
+            retry_in_ms = duration.as_millis() as u64,
+            "request failed, retrying"
         );
     }
-    async fn send(&self, method: &Method, uri: &str, body: Vec<u8>) -> Result<Response, K8sClientError> {
+    fn classify_reqwest_error(e: reqwest::Error) -> backoff::Error<K8sClientError> {
+        if e.is_connect() || e.is_decode() || e.is_timeout() {
+            backoff::Error::transient(K8sClientError::from(e))
+        } else {
+            backoff::Error::permanent(K8sClientError::from(e))
+        }
+    }
+    /// 2xx responses are returned as they are,
+    /// 429 and 5xx are retried (honouring "Retry-After"), everything else is turned into `StatusError`
+    #[tracing::instrument(name = "send", skip(self, body), fields(method = %method, status = tracing::field::Empty))]
+    async fn send(
+        &self,
+        method: &Method,
+        uri: &str,
+        accept: &'static str,
+        body: Vec<u8>,
+    ) -> Result<Response, K8sClientError> {
         let url = &self.base_url.join(uri)?;
-        let send = move || {
+        let body = &body;
+        let send = move || async move {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire().await;
+            }
             let mut req = Request::new(method.clone(), url.clone());
+            *req.timeout_mut() = self.deadline;
             *req.body_mut() = Some(reqwest::Body::from(body.clone()));
-            if let Some(token) = &self.token {
-                req.headers_mut().append(reqwest::header::AUTHORIZATION, token.clone());
+            req.headers_mut()
+                .insert(reqwest::header::ACCEPT, HeaderValue::from_static(accept));
+            if !body.is_empty() {
+                req.headers_mut().insert(
+                    reqwest::header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                );
             }
+            let token = match &self.token {
+                Some(token) => {
+                    let header = token.header().await.map_err(backoff::Error::permanent)?;
+                    req.headers_mut().append(reqwest::header::AUTHORIZATION, header.clone());
+                    Some((token, header))
+                }
+                None => None,
+            };
 
-            self.client.execute(req).map_err(|e| {
-                if e.is_connect() || e.is_decode() || e.is_timeout() {
-                    backoff::Error::Transient(K8sClientError::from(e))
-                } else {
-                    backoff::Error::Permanent(K8sClientError::from(e))
+            let resp = self.client.execute(req).await.map_err(Self::classify_reqwest_error)?;
+            let status = resp.status();
+            tracing::Span::current().record("status", status.as_u16());
+            if status.is_success() {
+                return Ok(resp);
+            }
+            if let (StatusCode::UNAUTHORIZED, Some((token, header))) = (status, token) {
+                // the token might have been rotated since we last read it
+                if token.invalidate(&header).await.map_err(backoff::Error::permanent)? {
+                    return Err(backoff::Error::transient(K8sClientError::TokenRotated));
                 }
+            }
+            let headers = resp.headers().clone();
+            let body = resp.bytes().await.map_err(Self::classify_reqwest_error)?;
+            let err = StatusError::new(status, &headers, &body);
+            let retry_after = err.retry_after;
+            let transient = err.is_transient();
+            let err = K8sClientError::from(K8sApiError::from(Box::new(err)));
+            Err(match (transient, retry_after) {
+                (true, Some(retry_after)) => backoff::Error::retry_after(err, retry_after),
+                (true, None) => backoff::Error::transient(err),
+                (false, _) => backoff::Error::permanent(err),
             })
         };
-        retry_notify(Self::backoff(), &send, Self::notify).await
+        retry_notify(self.backoff(), &send, Self::notify).await
     }
 
+    #[tracing::instrument(name = "get", skip_all)]
     pub async fn get<T: ApiGetter>(&self, getter: &T) -> Result<T::Output, K8sClientError> {
         let req = getter.get();
-        let resp = self.send(&req.method, &req.relative_url, req.body).await?;
+        let resp = self.send(&req.method, &req.relative_url, req.accept, req.body).await?;
         let status = resp.status();
         if !(req.status_check)(status) {
             return Err(K8sClientError::K8sApi(K8sApiError::UnexpectedStatus(status)));
@@ -102,8 +327,15 @@ impl K8sClient {
         Ok(result)
     }
 
+    /// same as `get`, but waits until fewer than `max_concurrent_lists` lists are in flight
+    pub async fn list<T: ApiGetter>(&self, getter: &T) -> Result<T::Output, K8sClientError> {
+        let _permit = self.lists.acquire().await.expect("List semaphore closed");
+        self.get(getter).await
+    }
+
+    #[tracing::instrument(name = "start_watch", skip(self, watcher))]
     pub async fn watch<T: ApiWatcher>(&self, watcher: &T, rv: ResourceVersion) -> Result<Response, K8sClientError> {
         let req = watcher.watch(rv);
-        self.send(&req.method, &req.relative_url, req.body).await
+        self.send(&req.method, &req.relative_url, req.accept, req.body).await
     }
 }
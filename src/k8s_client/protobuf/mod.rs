@@ -0,0 +1,891 @@
+//! `application/vnd.kubernetes.protobuf`, which the API server serves for built-in types
+//!
+//! It is several times smaller and cheaper to parse than JSON, but not self-describing: objects are turned back into
+//! the JSON the API server would have sent with `schema.json`, generated from the Kubernetes types by
+//! `hack/protobuf-schema.py`. Kinds missing there (custom resources) are requested as JSON.
+//!
+//! Like Go, zero values of optional fields are omitted unless the field is a pointer, and missing lists and maps that
+//! aren't optional are `null`. Which numbers and booleans are values instead of pointers is listed by hand in the
+//! generator, so objects match the API server's JSON only as far as that list is complete.
+
+use bytes::{Buf, Bytes, BytesMut};
+use chrono::SecondsFormat;
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    str,
+    sync::OnceLock,
+};
+
+const MEDIA_TYPE: &str = "application/vnd.kubernetes.protobuf";
+/// the API server answers with JSON when it can't encode a type as protobuf (e.g. custom resources)
+pub const ACCEPT: &str = "application/vnd.kubernetes.protobuf, application/json";
+/// every encoded object starts with it, followed by a `runtime.Unknown` wrapping the object
+const MAGIC: &[u8] = b"k8s\0";
+
+const TIME: &str = "apimachinery.pkg.apis.meta.v1.Time";
+const MICRO_TIME: &str = "apimachinery.pkg.apis.meta.v1.MicroTime";
+const DURATION: &str = "apimachinery.pkg.apis.meta.v1.Duration";
+const FIELDS_V1: &str = "apimachinery.pkg.apis.meta.v1.FieldsV1";
+const QUANTITY: &str = "apimachinery.pkg.api.resource.Quantity";
+const INT_OR_STRING: &str = "apimachinery.pkg.util.intstr.IntOrString";
+const RAW_EXTENSION: &str = "apimachinery.pkg.runtime.RawExtension";
+
+/// how objects are requested from the API server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiEncoding {
+    /// protobuf for the built-in types, JSON for everything else
+    Protobuf,
+    #[default]
+    Json,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtobufError {
+    #[error("Message ends in the middle of a field")]
+    Truncated,
+    #[error("Varint is longer than 10 bytes")]
+    Varint,
+    #[error("Field {} has unexpected wire type {}", _0, _1)]
+    WireType(u32, u8),
+    #[error("Field {} does not match its type in the schema", _0)]
+    FieldType(u32),
+    #[error("Object does not start with \"k8s\\0\"")]
+    Magic,
+    #[error("Unknown kind {:?}", _0)]
+    UnknownKind(String),
+    #[error("Unsupported content encoding {:?}", _0)]
+    ContentEncoding(String),
+    #[error("Field {} is not valid UTF-8", _0)]
+    Utf8(u32),
+    #[error("Time out of range: {}s {}ns", _0, _1)]
+    Time(i64, i32),
+    #[error("Invalid JSON in a raw field: {}", _0)]
+    Raw(serde_json::Error),
+    #[error("Request error {:?}", _0)]
+    Reqwest(#[from] reqwest::Error),
+}
+
+/// a `watch.Event`, `object` is a `Status` for "ERROR" events
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchEvent {
+    pub event_type: String,
+    pub object: Value,
+}
+
+/// content of `schema.json`
+#[derive(Debug, Deserialize)]
+struct SchemaFile {
+    /// message of each `<apiVersion>/<kind>`
+    kinds: HashMap<String, String>,
+    /// sorted by tag
+    messages: HashMap<String, Vec<FieldFile>>,
+    /// messages that are a `[]string` in Go
+    arrays: HashSet<String>,
+}
+
+/// `[tag, jsonName, type, mode, omitempty, pointer]`, the type is `string`, `bool`, `int32`, `int64`, `bytes` or the
+/// name of a message
+type FieldFile = (u32, String, String, Mode, bool, bool);
+
+/// `SchemaFile` with the messages referenced by index instead of looked up by name for every field
+#[derive(Debug)]
+struct Schema {
+    kinds: HashMap<String, usize>,
+    messages: Vec<Vec<Field>>,
+}
+
+#[derive(Debug)]
+struct Field {
+    tag: u32,
+    /// empty for inlined messages
+    name: String,
+    ty: Type,
+    mode: Mode,
+    omitempty: bool,
+    /// a zero value was set explicitly and is kept
+    pointer: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    String,
+    Bytes,
+    Bool,
+    Int32,
+    Int64,
+    Time,
+    MicroTime,
+    Duration,
+    Quantity,
+    IntOrString,
+    /// `RawExtension` and `FieldsV1`, JSON in a bytes field
+    Raw,
+    /// `repeated string items = 1`
+    Array,
+    Message(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    #[serde(rename = "")]
+    Single,
+    Repeated,
+    /// repeated `{key = 1, value = 2}` entries, keys are strings
+    Map,
+    /// fields are merged into the parent, `json:",inline"` in Go
+    Inline,
+}
+
+impl From<SchemaFile> for Schema {
+    fn from(file: SchemaFile) -> Self {
+        let names = file.messages.keys().cloned().collect::<Vec<_>>();
+        let index = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), i))
+            .collect::<HashMap<_, _>>();
+        let ty = |name: &str| match name {
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            "bool" => Type::Bool,
+            "int32" => Type::Int32,
+            "int64" => Type::Int64,
+            TIME => Type::Time,
+            MICRO_TIME => Type::MicroTime,
+            DURATION => Type::Duration,
+            QUANTITY => Type::Quantity,
+            INT_OR_STRING => Type::IntOrString,
+            RAW_EXTENSION | FIELDS_V1 => Type::Raw,
+            _ if file.arrays.contains(name) => Type::Array,
+            // expectations:
+            // the generator adds every message a field refers to, checked by the tests
+            _ => Type::Message(
+                *index
+                    .get(name)
+                    .unwrap_or_else(|| panic!("Unknown protobuf message {}", name)),
+            ),
+        };
+        let messages = names
+            .iter()
+            .map(|name| {
+                file.messages[name]
+                    .iter()
+                    .map(|(tag, name, field_type, mode, omitempty, pointer)| Field {
+                        tag: *tag,
+                        name: name.clone(),
+                        ty: ty(field_type),
+                        mode: *mode,
+                        omitempty: *omitempty,
+                        pointer: *pointer,
+                    })
+                    .collect()
+            })
+            .collect();
+        let kinds = file
+            .kinds
+            .iter()
+            .map(|(kind, name)| (kind.clone(), index[name.as_str()]))
+            .collect();
+        Self { kinds, messages }
+    }
+}
+
+fn schema() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| {
+        // expectations:
+        // the schema is generated and checked by the tests
+        let file: SchemaFile = serde_json::from_str(include_str!("schema.json")).expect("Invalid protobuf schema");
+        Schema::from(file)
+    })
+}
+
+/// whether objects of the kind can be decoded, otherwise they are requested as JSON
+pub fn supports(api_version: &str, kind: &str) -> bool {
+    schema().kinds.contains_key(&format!("{}/{}", api_version, kind))
+}
+
+/// whether a response body is protobuf, lists are answered as JSON when any item can't be encoded
+pub fn is_encoded(body: &[u8]) -> bool {
+    body.starts_with(MAGIC)
+}
+
+/// whether a response is protobuf, a watch is a stream of frames that don't start with `MAGIC`
+pub fn is_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(MEDIA_TYPE))
+}
+
+/// the JSON of an encoded object, including its `apiVersion` and `kind`
+pub fn decode(body: &[u8]) -> Result<Value, ProtobufError> {
+    schema().object(body)
+}
+
+/// the events of a watch, each one in a frame preceded by its big-endian 32 bit length
+pub fn watch_events<S>(body: S) -> impl Stream<Item = Result<WatchEvent, ProtobufError>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>>,
+{
+    stream::unfold(Some((Box::pin(body), BytesMut::new())), |state| async move {
+        let (mut body, mut buffer) = state?;
+        loop {
+            if buffer.len() >= 4 {
+                let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+                if buffer.len() >= 4 + len {
+                    buffer.advance(4);
+                    let frame = buffer.split_to(len);
+                    return Some((schema().watch_event(&frame), Some((body, buffer))));
+                }
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(err)) => return Some((Err(err.into()), None)),
+                None if buffer.is_empty() => return None,
+                None => return Some((Err(ProtobufError::Truncated), None)),
+            }
+        }
+    })
+}
+
+enum Wire<'a> {
+    Varint(u64),
+    Len(&'a [u8]),
+    /// not used by any Kubernetes type
+    Fixed,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtobufError> {
+        if len > self.buf.len() {
+            return Err(ProtobufError::Truncated);
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, ProtobufError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.buf.split_first().ok_or(ProtobufError::Truncated)?;
+            self.buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ProtobufError::Varint)
+    }
+
+    /// tag and value of the next field
+    fn field(&mut self) -> Result<(u32, Wire<'a>), ProtobufError> {
+        let key = self.varint()?;
+        let tag = (key >> 3) as u32;
+        let wire = match key & 7 {
+            0 => Wire::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Wire::Fixed
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| ProtobufError::Truncated)?;
+                Wire::Len(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                Wire::Fixed
+            }
+            wire_type => return Err(ProtobufError::WireType(tag, wire_type as u8)),
+        };
+        Ok((tag, wire))
+    }
+}
+
+fn string(tag: u32, bytes: &[u8]) -> Result<String, ProtobufError> {
+    str::from_utf8(bytes)
+        .map(str::to_string)
+        .map_err(|_| ProtobufError::Utf8(tag))
+}
+
+impl Schema {
+    /// `runtime.Unknown` after `MAGIC`, its `raw` is the object
+    fn object(&self, body: &[u8]) -> Result<Value, ProtobufError> {
+        let mut reader = Reader::new(body.strip_prefix(MAGIC).ok_or(ProtobufError::Magic)?);
+        let (mut api_version, mut kind, mut raw) = (String::new(), String::new(), &[][..]);
+        while !reader.is_empty() {
+            match reader.field()? {
+                (1, Wire::Len(type_meta)) => {
+                    let mut reader = Reader::new(type_meta);
+                    while !reader.is_empty() {
+                        match reader.field()? {
+                            (1, Wire::Len(bytes)) => api_version = string(1, bytes)?,
+                            (2, Wire::Len(bytes)) => kind = string(2, bytes)?,
+                            _ => {}
+                        }
+                    }
+                }
+                (2, Wire::Len(bytes)) => raw = bytes,
+                (3, Wire::Len(bytes)) if !bytes.is_empty() => {
+                    return Err(ProtobufError::ContentEncoding(string(3, bytes)?))
+                }
+                _ => {}
+            }
+        }
+        let key = format!("{}/{}", api_version, kind);
+        let message = *self.kinds.get(&key).ok_or(ProtobufError::UnknownKind(key))?;
+        let mut object = Map::new();
+        object.insert("apiVersion".to_string(), Value::String(api_version));
+        object.insert("kind".to_string(), Value::String(kind));
+        self.merge(message, raw, &mut object)?;
+        Ok(Value::Object(object))
+    }
+
+    /// `{type = 1, object = 2}`, the object is a `RawExtension` holding an encoded object
+    fn watch_event(&self, frame: &[u8]) -> Result<WatchEvent, ProtobufError> {
+        let mut reader = Reader::new(frame);
+        let (mut event_type, mut raw) = (String::new(), &[][..]);
+        while !reader.is_empty() {
+            match reader.field()? {
+                (1, Wire::Len(bytes)) => event_type = string(1, bytes)?,
+                (2, Wire::Len(extension)) => {
+                    let mut reader = Reader::new(extension);
+                    while !reader.is_empty() {
+                        if let (1, Wire::Len(bytes)) = reader.field()? {
+                            raw = bytes;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(WatchEvent {
+            event_type,
+            object: self.object(raw)?,
+        })
+    }
+
+    fn merge(&self, message: usize, buf: &[u8], object: &mut Map<String, Value>) -> Result<(), ProtobufError> {
+        let fields = &self.messages[message];
+        let mut reader = Reader::new(buf);
+        while !reader.is_empty() {
+            let (tag, wire) = reader.field()?;
+            let field = match fields.binary_search_by_key(&tag, |field| field.tag) {
+                Ok(i) => &fields[i],
+                // added by a newer API server
+                Err(_) => continue,
+            };
+            match (field.mode, field.ty, wire) {
+                (Mode::Inline, Type::Message(inlined), Wire::Len(bytes)) => self.merge(inlined, bytes, object)?,
+                (Mode::Map, _, Wire::Len(bytes)) => {
+                    let (key, value) = self.map_entry(field, bytes)?;
+                    if let Value::Object(map) = object
+                        .entry(field.name.clone())
+                        .or_insert_with(|| Value::Object(Map::new()))
+                    {
+                        map.insert(key, value);
+                    }
+                }
+                (Mode::Repeated, Type::Bool | Type::Int32 | Type::Int64, Wire::Len(bytes)) => {
+                    // packed
+                    let mut packed = Reader::new(bytes);
+                    while !packed.is_empty() {
+                        let value = self.value(field, Wire::Varint(packed.varint()?))?;
+                        push(object, &field.name, value);
+                    }
+                }
+                (Mode::Repeated, _, wire) => {
+                    let value = self.value(field, wire)?;
+                    push(object, &field.name, value);
+                }
+                (Mode::Single, _, wire) => {
+                    let value = self.value(field, wire)?;
+                    if !(field.omitempty && !field.pointer && is_zero(field.ty, &value)) {
+                        object.insert(field.name.clone(), value);
+                    }
+                }
+                _ => return Err(ProtobufError::FieldType(tag)),
+            }
+        }
+        // empty lists and maps aren't encoded, Go has them as nil
+        for field in fields {
+            if !field.omitempty && matches!(field.mode, Mode::Repeated | Mode::Map) {
+                object.entry(field.name.clone()).or_insert(Value::Null);
+            }
+        }
+        Ok(())
+    }
+
+    fn map_entry(&self, field: &Field, entry: &[u8]) -> Result<(String, Value), ProtobufError> {
+        let (mut key, mut value) = (String::new(), None);
+        let mut reader = Reader::new(entry);
+        while !reader.is_empty() {
+            match reader.field()? {
+                (1, Wire::Len(bytes)) => key = string(1, bytes)?,
+                (2, wire) => value = Some(self.value(field, wire)?),
+                _ => {}
+            }
+        }
+        let value = match (value, field.ty) {
+            (Some(value), _) => value,
+            // default values are not encoded
+            (None, Type::Bool | Type::Int32 | Type::Int64) => self.value(field, Wire::Varint(0))?,
+            (None, _) => self.value(field, Wire::Len(&[]))?,
+        };
+        Ok((key, value))
+    }
+
+    fn value(&self, field: &Field, wire: Wire<'_>) -> Result<Value, ProtobufError> {
+        match (field.ty, wire) {
+            (Type::Bool, Wire::Varint(value)) => Ok(Value::Bool(value != 0)),
+            // negative numbers are sign extended to 64 bits
+            (Type::Int32, Wire::Varint(value)) => Ok(Value::from(value as i32)),
+            (Type::Int64, Wire::Varint(value)) => Ok(Value::from(value as i64)),
+            (Type::String, Wire::Len(bytes)) => Ok(Value::String(string(field.tag, bytes)?)),
+            (Type::Bytes, Wire::Len(bytes)) => Ok(Value::String(base64::encode(bytes))),
+            (Type::Time, Wire::Len(bytes)) => time(bytes, SecondsFormat::Secs),
+            (Type::MicroTime, Wire::Len(bytes)) => time(bytes, SecondsFormat::Micros),
+            (Type::Duration, Wire::Len(bytes)) => duration(bytes),
+            (Type::Quantity, Wire::Len(bytes)) => quantity(bytes),
+            (Type::IntOrString, Wire::Len(bytes)) => int_or_string(bytes),
+            (Type::Raw, Wire::Len(bytes)) => self.raw(bytes),
+            (Type::Array, Wire::Len(bytes)) => {
+                let mut items = Vec::new();
+                let mut reader = Reader::new(bytes);
+                while !reader.is_empty() {
+                    if let (1, Wire::Len(item)) = reader.field()? {
+                        items.push(Value::String(string(1, item)?));
+                    }
+                }
+                Ok(Value::Array(items))
+            }
+            (Type::Message(message), Wire::Len(bytes)) => {
+                let mut object = Map::new();
+                self.merge(message, bytes, &mut object)?;
+                Ok(Value::Object(object))
+            }
+            _ => Err(ProtobufError::FieldType(field.tag)),
+        }
+    }
+
+    /// JSON, or an encoded object, empty is `null`
+    fn raw(&self, buf: &[u8]) -> Result<Value, ProtobufError> {
+        let mut raw = &[][..];
+        let mut reader = Reader::new(buf);
+        while !reader.is_empty() {
+            if let (1, Wire::Len(bytes)) = reader.field()? {
+                raw = bytes;
+            }
+        }
+        if raw.is_empty() {
+            Ok(Value::Null)
+        } else if is_encoded(raw) {
+            self.object(raw)
+        } else {
+            serde_json::from_slice(raw).map_err(ProtobufError::Raw)
+        }
+    }
+}
+
+/// what Go's `omitempty` omits
+fn is_zero(ty: Type, value: &Value) -> bool {
+    match (ty, value) {
+        (Type::String | Type::Bytes, Value::String(s)) => s.is_empty(),
+        (Type::Bool, Value::Bool(b)) => !b,
+        (Type::Int32 | Type::Int64, Value::Number(n)) => n.as_i64() == Some(0),
+        _ => false,
+    }
+}
+
+fn push(object: &mut Map<String, Value>, name: &str, value: Value) {
+    if let Value::Array(items) = object
+        .entry(name.to_string())
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        items.push(value);
+    }
+}
+
+/// the varint fields of a message, by tag, missing ones are 0
+fn varints(buf: &[u8]) -> Result<HashMap<u32, u64>, ProtobufError> {
+    let mut values = HashMap::new();
+    let mut reader = Reader::new(buf);
+    while !reader.is_empty() {
+        if let (tag, Wire::Varint(value)) = reader.field()? {
+            values.insert(tag, value);
+        }
+    }
+    Ok(values)
+}
+
+/// `{seconds = 1, nanos = 2}`, the zero time is `null` like in Go
+fn time(buf: &[u8], format: SecondsFormat) -> Result<Value, ProtobufError> {
+    let values = varints(buf)?;
+    let seconds = values.get(&1).copied().unwrap_or_default() as i64;
+    let nanos = values.get(&2).copied().unwrap_or_default() as i32;
+    if seconds == 0 && nanos == 0 {
+        return Ok(Value::Null);
+    }
+    let time = u32::try_from(nanos)
+        .ok()
+        .and_then(|nanos| chrono::DateTime::from_timestamp(seconds, nanos))
+        .ok_or(ProtobufError::Time(seconds, nanos))?;
+    Ok(Value::String(time.to_rfc3339_opts(format, true)))
+}
+
+/// `{duration = 1}` in nanoseconds, formatted like Go's `time.Duration.String()`
+fn duration(buf: &[u8]) -> Result<Value, ProtobufError> {
+    let nanos = varints(buf)?.get(&1).copied().unwrap_or_default() as i64;
+    Ok(Value::String(go_duration(nanos)))
+}
+
+fn go_duration(nanos: i64) -> String {
+    /// the digits of `value % 10^precision` without trailing zeros, `value / 10^precision`
+    fn fraction(value: u64, precision: u32) -> (String, u64) {
+        let divisor = 10u64.pow(precision);
+        let digits = format!("{:0width$}", value % divisor, width = precision as usize);
+        let digits = digits.trim_end_matches('0');
+        let fraction = if digits.is_empty() {
+            String::new()
+        } else {
+            format!(".{}", digits)
+        };
+        (fraction, value / divisor)
+    }
+    let sign = if nanos < 0 { "-" } else { "" };
+    let value = nanos.unsigned_abs();
+    let formatted = match value {
+        0 => return "0s".to_string(),
+        1..=999 => format!("{}ns", value),
+        1_000..=999_999 => {
+            let (fraction, micros) = fraction(value, 3);
+            format!("{}{}µs", micros, fraction)
+        }
+        1_000_000..=999_999_999 => {
+            let (fraction, millis) = fraction(value, 6);
+            format!("{}{}ms", millis, fraction)
+        }
+        _ => {
+            let (fraction, seconds) = fraction(value, 9);
+            match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+                (0, 0, s) => format!("{}{}s", s, fraction),
+                (0, m, s) => format!("{}m{}{}s", m, s, fraction),
+                (h, m, s) => format!("{}h{}m{}{}s", h, m, s, fraction),
+            }
+        }
+    };
+    format!("{}{}", sign, formatted)
+}
+
+/// `{string = 1}`
+fn quantity(buf: &[u8]) -> Result<Value, ProtobufError> {
+    let mut quantity = "0".to_string();
+    let mut reader = Reader::new(buf);
+    while !reader.is_empty() {
+        if let (1, Wire::Len(bytes)) = reader.field()? {
+            quantity = string(1, bytes)?;
+        }
+    }
+    Ok(Value::String(quantity))
+}
+
+/// `{type = 1, intVal = 2, strVal = 3}`, type 1 is a string
+fn int_or_string(buf: &[u8]) -> Result<Value, ProtobufError> {
+    let (mut is_string, mut int_val, mut str_val) = (false, 0, String::new());
+    let mut reader = Reader::new(buf);
+    while !reader.is_empty() {
+        match reader.field()? {
+            (1, Wire::Varint(ty)) => is_string = ty == 1,
+            (2, Wire::Varint(value)) => int_val = value as i32,
+            (3, Wire::Len(bytes)) => str_val = string(3, bytes)?,
+            _ => {}
+        }
+    }
+    Ok(if is_string {
+        Value::String(str_val)
+    } else {
+        Value::from(int_val)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(tag: u32, wire_type: u8, out: &mut Vec<u8>) {
+        varint(u64::from(tag) << 3 | u64::from(wire_type), out);
+    }
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    pub(crate) fn int(tag: u32, value: i64) -> Vec<u8> {
+        let mut out = Vec::new();
+        key(tag, 0, &mut out);
+        varint(value as u64, &mut out);
+        out
+    }
+
+    pub(crate) fn len(tag: u32, bytes: impl AsRef<[u8]>) -> Vec<u8> {
+        let bytes = bytes.as_ref();
+        let mut out = Vec::new();
+        key(tag, 2, &mut out);
+        varint(bytes.len() as u64, &mut out);
+        out.extend_from_slice(bytes);
+        out
+    }
+
+    pub(crate) fn object(api_version: &str, kind: &str, raw: &[u8]) -> Vec<u8> {
+        let type_meta = [len(1, api_version), len(2, kind)].concat();
+        [MAGIC, &len(1, type_meta), &len(2, raw)].concat()
+    }
+
+    /// a frame of a watch
+    pub(crate) fn frame(event_type: &str, raw: Vec<u8>) -> Vec<u8> {
+        let event = [len(1, event_type), len(2, len(1, raw))].concat();
+        [&(event.len() as u32).to_be_bytes()[..], &event].concat()
+    }
+
+    fn pod() -> Vec<u8> {
+        let metadata = [
+            len(1, "a"),
+            // omitempty
+            len(2, ""),
+            len(3, "default"),
+            len(6, "5"),
+            len(8, int(1, 1_600_000_000)),
+            len(11, [len(1, "app"), len(2, "web")].concat()),
+            len(11, [len(1, "tier"), len(2, "")].concat()),
+            // unknown to us
+            len(99, "future"),
+        ]
+        .concat();
+        let container = [
+            len(1, "c"),
+            len(2, "nginx"),
+            len(6, int(3, 80)),
+            len(8, len(1, [len(1, "cpu"), len(2, len(1, "100m"))].concat())),
+        ]
+        .concat();
+        let spec = [len(2, container), int(4, 30), len(3, "")].concat();
+        object("v1", "Pod", &[len(1, metadata), len(2, spec)].concat())
+    }
+
+    #[test]
+    fn schema() {
+        assert!(supports("v1", "Pod"));
+        assert!(supports("apps/v1", "DeploymentList"));
+        assert!(!supports("example.com/v1", "Widget"));
+        // every type a field refers to is known
+        for fields in &super::schema().messages {
+            assert!(fields.windows(2).all(|pair| pair[0].tag < pair[1].tag));
+        }
+    }
+
+    #[test]
+    fn decode_pod() {
+        assert!(is_encoded(&pod()));
+        assert_eq!(
+            decode(&pod()).unwrap(),
+            json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": {
+                    "name": "a",
+                    "namespace": "default",
+                    "resourceVersion": "5",
+                    "creationTimestamp": "2020-09-13T12:26:40Z",
+                    "labels": {"app": "web", "tier": ""},
+                },
+                "spec": {
+                    "containers": [{
+                        "name": "c",
+                        "image": "nginx",
+                        "ports": [{"containerPort": 80}],
+                        "resources": {"limits": {"cpu": "100m"}},
+                    }],
+                    "terminationGracePeriodSeconds": 30,
+                },
+            })
+        );
+    }
+
+    /// zero values are omitted like Go omits them, missing lists that aren't optional are `null`
+    #[test]
+    fn omitempty() {
+        let container = [
+            len(1, "c"),
+            // `hostPort` isn't a pointer
+            len(6, [int(2, 0), int(3, 80)].concat()),
+            // neither is `readOnly`
+            len(9, [len(1, "v"), int(2, 0), len(3, "/v")].concat()),
+        ]
+        .concat();
+        // `hostNetwork` and `automountServiceAccountToken`, only the latter is a pointer
+        let spec = [len(2, container), int(11, 0), int(21, 0)].concat();
+        assert_eq!(
+            decode(&object("v1", "Pod", &len(2, spec))).unwrap(),
+            json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "spec": {
+                    "containers": [{
+                        "name": "c",
+                        "ports": [{"containerPort": 80}],
+                        "volumeMounts": [{"name": "v", "mountPath": "/v"}],
+                    }],
+                    "automountServiceAccountToken": false,
+                },
+            })
+        );
+        let rule = len(2, "apps");
+        assert_eq!(
+            decode(&object(
+                "rbac.authorization.k8s.io/v1",
+                "ClusterRole",
+                &[len(1, ""), len(2, rule)].concat()
+            ))
+            .unwrap(),
+            json!({
+                "apiVersion": "rbac.authorization.k8s.io/v1",
+                "kind": "ClusterRole",
+                "metadata": {},
+                "rules": [{"apiGroups": ["apps"], "verbs": null}],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_list() {
+        let list = [len(1, len(2, "7")), len(2, len(1, "")), len(2, len(1, len(1, "b")))].concat();
+        assert_eq!(
+            decode(&object("v1", "PodList", &list)).unwrap(),
+            json!({
+                "apiVersion": "v1",
+                "kind": "PodList",
+                "metadata": {"resourceVersion": "7"},
+                "items": [{"metadata": {}}, {"metadata": {"name": "b"}}],
+            })
+        );
+        assert!(matches!(
+            decode(&object("example.com/v1", "Widget", &[])),
+            Err(ProtobufError::UnknownKind(_))
+        ));
+        assert!(matches!(decode(b"{}"), Err(ProtobufError::Magic)));
+        assert!(matches!(
+            decode(&object("v1", "Pod", &len(1, "a")[..2])),
+            Err(ProtobufError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn special_types() {
+        let port = |target: Vec<u8>| len(1, [int(3, 80), len(4, target)].concat());
+        let spec = [
+            port([int(1, 0), int(2, 8080)].concat()),
+            port([int(1, 1), len(3, "http")].concat()),
+            len(5, "10.0.0.1"),
+            len(5, "10.0.0.2"),
+        ]
+        .concat();
+        assert_eq!(
+            decode(&object("v1", "Service", &len(2, spec))).unwrap()["spec"],
+            json!({
+                "ports": [{"port": 80, "targetPort": 8080}, {"port": 80, "targetPort": "http"}],
+                "externalIPs": ["10.0.0.1", "10.0.0.2"],
+            })
+        );
+        let review = [
+            len(1, "alice"),
+            len(
+                4,
+                [len(1, "scopes"), len(2, [len(1, "a"), len(1, "b")].concat())].concat(),
+            ),
+        ]
+        .concat();
+        let status = [int(1, 1), len(2, review)].concat();
+        assert_eq!(
+            decode(&object("authentication.k8s.io/v1", "TokenReview", &len(3, status))).unwrap()["status"],
+            json!({"authenticated": true, "user": {"username": "alice", "extra": {"scopes": ["a", "b"]}}})
+        );
+        let event = [
+            len(10, [int(1, 1_600_000_000), int(2, 5_000)].concat()),
+            len(6, ""),
+            // negative numbers take 10 bytes
+            int(8, -1),
+        ]
+        .concat();
+        let decoded = decode(&object("v1", "Event", &event)).unwrap();
+        assert_eq!(decoded["eventTime"], json!("2020-09-13T12:26:40.000005Z"));
+        assert_eq!(decoded["firstTimestamp"], Value::Null);
+        assert_eq!(decoded["count"], -1);
+        for (nanos, formatted) in [
+            (0, "0s"),
+            (999, "999ns"),
+            (1_500, "1.5µs"),
+            (2_000_000, "2ms"),
+            (90_000_000_000, "1m30s"),
+            (3_600_000_000_000, "1h0m0s"),
+            (-1_500_000_000, "-1.5s"),
+        ] {
+            assert_eq!(go_duration(nanos), formatted);
+        }
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let added = frame("ADDED", pod());
+        let error = frame(
+            "ERROR",
+            object("v1", "Status", &[len(2, "Failure"), int(6, 410)].concat()),
+        );
+        let body = [added, error].concat();
+        // frames are split across chunks
+        let chunks = body
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        let events = watch_events(stream::iter(chunks)).collect::<Vec<_>>().await;
+        assert_eq!(events.len(), 2);
+        let added = events[0].as_ref().unwrap();
+        assert_eq!(added.event_type, "ADDED");
+        assert_eq!(added.object["metadata"]["name"], "a");
+        let error = events[1].as_ref().unwrap();
+        assert_eq!(error.event_type, "ERROR");
+        assert_eq!(
+            error.object,
+            json!({"apiVersion": "v1", "kind": "Status", "status": "Failure", "code": 410})
+        );
+
+        let truncated = watch_events(stream::iter(vec![Ok(Bytes::from_static(&[0, 0, 0, 9, 1]))]))
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(truncated[..], [Err(ProtobufError::Truncated)]));
+    }
+}
@@ -0,0 +1,163 @@
+use super::{
+    api::cluster_config::{ClusterConfigError, ExecAuth, ExecCredential, ExecPlugin},
+    K8sClientError,
+};
+use crate::utils::read_token;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::HeaderValue;
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+use tokio::sync::Mutex;
+
+/// refresh exec credentials this long before they actually expire
+const EXPIRATION_MARGIN_SECS: i64 = 30;
+/// same period client-go uses to re-read projected service account tokens
+const FILE_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    Static(HeaderValue),
+    File(Arc<FileToken>),
+    Exec(Arc<ExecToken>),
+}
+
+/// token read from a file which may be rotated underneath us (bound service account tokens)
+#[derive(Debug)]
+pub struct FileToken {
+    path: PathBuf,
+    // all clones of `K8sClient` share this, so a swap is visible to every task at once
+    current: RwLock<(HeaderValue, Instant)>,
+}
+
+#[derive(Debug)]
+pub struct ExecToken {
+    plugin: ExecPlugin,
+    current: Mutex<(HeaderValue, Option<DateTime<Utc>>)>,
+}
+
+impl TokenSource {
+    pub fn file(path: PathBuf, initial: HeaderValue) -> Self {
+        Self::File(Arc::new(FileToken {
+            path,
+            current: RwLock::new((initial, Instant::now())),
+        }))
+    }
+
+    pub fn exec(plugin: ExecPlugin, initial: ExecCredential) -> Result<Self, K8sClientError> {
+        let token = match initial.auth {
+            ExecAuth::Token(token) => token,
+            ExecAuth::Identity(_) => return Err(K8sClientError::ExecIdentity),
+        };
+        Ok(Self::Exec(Arc::new(ExecToken {
+            plugin,
+            current: Mutex::new((token, initial.expiration)),
+        })))
+    }
+
+    pub async fn header(&self) -> Result<HeaderValue, K8sClientError> {
+        match self {
+            Self::Static(token) => Ok(token.clone()),
+            Self::File(file) => Ok(file.header().await),
+            Self::Exec(exec) => exec.header(false).await,
+        }
+    }
+
+    /// called when the API server rejected the token,
+    /// returns `true` if a different token is now available and the request is worth retrying
+    pub async fn invalidate(&self, rejected: &HeaderValue) -> Result<bool, K8sClientError> {
+        match self {
+            Self::Static(_) => Ok(false),
+            Self::File(file) => {
+                // someone else might have already reloaded it in the meantime
+                if file.header().await != rejected {
+                    return Ok(true);
+                }
+                Ok(file.reload().await? != rejected)
+            }
+            Self::Exec(exec) => Ok(exec.header(true).await? != rejected),
+        }
+    }
+}
+
+impl FileToken {
+    async fn header(&self) -> HeaderValue {
+        let (token, read_at) = self.current.read().expect("Token lock poisoned").clone();
+        if read_at.elapsed() < FILE_REFRESH_INTERVAL {
+            return token;
+        }
+        match self.reload().await {
+            Ok(token) => token,
+            Err(err) => {
+                tracing::error!(error_kind = err.kind(), error = %err, "could not reload token, keeping the old one");
+                token
+            }
+        }
+    }
+
+    async fn reload(&self) -> Result<HeaderValue, K8sClientError> {
+        // the file is read on a blocking thread, not on the worker every other request shares
+        let path = self.path.clone();
+        let token = tokio::task::spawn_blocking(move || read_file(path))
+            .await
+            .expect("Token read task panicked")?;
+        *self.current.write().expect("Token lock poisoned") = (token.clone(), Instant::now());
+        Ok(token)
+    }
+}
+
+fn read_file(path: PathBuf) -> Result<HeaderValue, ClusterConfigError> {
+    let mut file = fs::File::open(&path).map_err(|err| ClusterConfigError::FileOpen {
+        path: path.clone(),
+        err,
+    })?;
+    let token = read_token(&mut file).map_err(|err| ClusterConfigError::FileRead { path, err })?;
+    HeaderValue::from_str(&token).map_err(ClusterConfigError::InvalidToken)
+}
+
+impl ExecToken {
+    async fn header(&self, force: bool) -> Result<HeaderValue, K8sClientError> {
+        // holding the lock while the plugin runs makes concurrent requests wait for a single refresh
+        let mut current = self.current.lock().await;
+        let expired = match current.1 {
+            Some(expiration) => expiration - Duration::seconds(EXPIRATION_MARGIN_SECS) <= Utc::now(),
+            None => false,
+        };
+        if expired || force {
+            let plugin = self.plugin.clone();
+            let credential = tokio::task::spawn_blocking(move || plugin.run())
+                .await
+                .expect("Credential plugin task panicked")?;
+            match credential.auth {
+                ExecAuth::Token(token) => *current = (token, credential.expiration),
+                ExecAuth::Identity(_) => return Err(K8sClientError::ExecIdentity),
+            }
+        }
+        Ok(current.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_token_invalidate() {
+        let path = std::env::temp_dir().join(format!("big-brother-token-{}", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+        let source = TokenSource::file(path.clone(), HeaderValue::from_static("Bearer first"));
+        let rejected = source.header().await.unwrap();
+        assert_eq!(rejected, "Bearer first");
+
+        // nothing changed on disk, retrying won't help
+        assert!(!source.invalidate(&rejected).await.unwrap());
+
+        fs::write(&path, "second").unwrap();
+        assert!(source.invalidate(&rejected).await.unwrap());
+        assert_eq!(source.header().await.unwrap(), "Bearer second");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+//! everything but the HTTP server in `main.rs`, a library for the benchmarks in `benches/`
+
+pub mod args;
+pub mod audit;
+pub mod bearer;
+pub mod cli;
+pub mod config;
+pub mod engine;
+pub mod error;
+pub mod event;
+pub mod ha;
+pub mod jwt;
+pub mod k8s_client;
+pub mod log;
+pub mod rate_limit;
+pub mod replay;
+pub mod scope;
+pub mod shutdown;
+pub mod sink;
+pub mod telemetry;
+pub mod tls;
+pub mod token_review;
+pub mod utils;
+pub mod watch_limits;
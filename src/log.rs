@@ -0,0 +1,202 @@
+use opentelemetry_sdk::trace::Tracer;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tracing::Level;
+use tracing_subscriber::{filter, prelude::*, reload, EnvFilter, Registry};
+
+/// module paths in `logFilters` are relative to this one
+const CRATE: &str = "big_brother";
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        })
+    }
+}
+
+/// `text` is meant for humans, `json` writes one object per line for log pipelines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// `filters` overrides `level` for single modules, e.g. `engine: debug` or `k8s_client::token: warn`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LogFilter {
+    pub level: LogLevel,
+    pub filters: BTreeMap<String, LogLevel>,
+}
+
+impl LogFilter {
+    /// dependencies only log warnings, the `big_brother::` prefix of a module is optional
+    fn directives(&self) -> String {
+        let mut directives = vec![format!("warn,{}={}", CRATE, self.level)];
+        for (module, level) in &self.filters {
+            match module.split("::").next() {
+                Some(first) if first == CRATE => directives.push(format!("{}={}", module, level)),
+                _ => directives.push(format!("{}::{}={}", CRATE, module, level)),
+            }
+        }
+        directives.join(",")
+    }
+
+    pub fn env_filter(&self) -> Result<EnvFilter, String> {
+        for module in self.filters.keys() {
+            if module.is_empty() || !module.split("::").all(is_identifier) {
+                return Err(format!("logFilters: {:?} is not a module path", module));
+            }
+        }
+        EnvFilter::try_new(self.directives()).map_err(|err| format!("logFilters: {}", err))
+    }
+}
+
+fn is_identifier(segment: &str) -> bool {
+    !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// installs the global subscriber writing to stderr, can only be called once,
+/// spans are exported with `tracer` regardless of the log filter
+pub fn init(format: LogFormat, filter: &LogFilter, tracer: Option<Tracer>) -> Result<(), String> {
+    let (filter, handle) = reload::Layer::new(filter.env_filter()?);
+    let output = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let output = match format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output.json().flatten_event(true).boxed(),
+    };
+    // the span of a resource's watch lasts as long as the process, what happens in it is traced on its own
+    let spans = tracer.map(|tracer| {
+        let filter = filter::filter_fn(|meta| {
+            meta.target().starts_with(CRATE)
+                && *meta.level() <= Level::INFO
+                && !(meta.is_span() && meta.name() == "watch")
+        });
+        tracing_opentelemetry::layer().with_tracer(tracer).with_filter(filter)
+    });
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(spans)
+        .try_init()
+        .map_err(|err| err.to_string())?;
+    FILTER
+        .set(handle)
+        .map_err(|_| "logging is already initialized".to_string())
+}
+
+/// swaps the filter of the subscriber installed by `init`
+pub fn reload(filter: &LogFilter) -> Result<(), String> {
+    let filter = filter.env_filter()?;
+    match FILTER.get() {
+        Some(handle) => handle.reload(filter).map_err(|err| err.to_string()),
+        None => Ok(()),
+    }
+}
+
+/// keeps a repeating error from flooding the log: the first occurrence passes, then at most one per `interval`
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// `Some` with the number of occurrences swallowed since the last one if this one should be logged
+    pub fn check(&mut self) -> Option<u64> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&mut self, now: Instant) -> Option<u64> {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.interval => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+
+    /// the next occurrence is logged right away, e.g. after the operation succeeded again
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn throttle() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(Duration::from_secs(10));
+        assert_eq!(throttle.check_at(start), Some(0));
+        assert_eq!(throttle.check_at(start + Duration::from_secs(1)), None);
+        assert_eq!(throttle.check_at(start + Duration::from_secs(9)), None);
+        assert_eq!(throttle.check_at(start + Duration::from_secs(10)), Some(2));
+        assert_eq!(throttle.check_at(start + Duration::from_secs(11)), None);
+        throttle.reset();
+        assert_eq!(throttle.check_at(start + Duration::from_secs(12)), Some(1));
+    }
+
+    #[test]
+    fn directives() {
+        let filter = LogFilter {
+            level: LogLevel::Warn,
+            filters: vec![
+                ("engine".to_string(), LogLevel::Debug),
+                ("big_brother::sink".to_string(), LogLevel::Off),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(
+            filter.directives(),
+            "warn,big_brother=warn,big_brother::sink=off,big_brother::engine=debug"
+        );
+        assert!(filter.env_filter().is_ok());
+        let invalid = LogFilter {
+            filters: vec![("engine=debug".to_string(), LogLevel::Info)].into_iter().collect(),
+            ..LogFilter::default()
+        };
+        assert!(invalid.env_filter().unwrap_err().contains("not a module path"));
+    }
+}
@@ -1,68 +1,310 @@
-mod args;
-mod bearer;
-mod engine;
-mod error;
-mod event;
-mod k8s_client;
-mod utils;
+use big_brother::{
+    args, audit, bearer, cli, config, engine, error, ha, jwt, k8s_client, log, otry, replay, scope, shutdown, sink,
+    telemetry, tls, token_review, watch_limits,
+};
 
 use actix_web::{
     body::BodyStream,
+    dev::Service,
     web::{self, Bytes, Data},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
-use bearer::{Bearer, BearerConfig};
-use engine::Cache;
+use audit::{AuditConfig, AuditLog};
+use bearer::{Bearer, BearerConfig, BearerResponseError, ClientCertRules, TokenRegistry};
+use config::{Config, ConfigError, Reloadable};
+use engine::{Cache, Engine, EngineConfig, Kinds, OutputEvent, PendingLists, Selector, Stored, Subtree, Upstream};
 use error::Error;
-use event::EventType;
+use jwt::{JwtConfig, JwtVerifier};
 use k8s_client::{
-    api::{cluster_config::ClusterConfig, ResourceVersion},
-    K8sClient,
+    api::{cluster_config::ClusterConfig, ResourceId, ResourceVersion},
+    ClientLimits, K8sClient,
+};
+use scope::Endpoint;
+use serde::{Deserialize, Serialize};
+use shutdown::Shutdown;
+use sink::{SinkStatus, SinkStatuses, Sinks, SinksConfig};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
 };
-use serde::Deserialize;
-use std::{collections::HashSet, sync::Arc};
+use telemetry::Telemetry;
+use tls::TlsConfig;
+use token_review::TokenReviewer;
 use tokio::sync::RwLock;
-use tokio_stream::StreamExt;
+use tracing::Instrument;
+use watch_limits::{WatchLimits, WatchLimitsConfig};
 
-#[derive(serde::Serialize)]
-struct OutputEvent {
-    #[serde(rename = "type")]
-    event_type: EventType,
-    object: destream_json::Value,
-}
+const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
 
 #[derive(Debug, Clone)]
 struct AppData {
     cache: Arc<RwLock<Cache>>,
+    pending: PendingLists,
+    audit: AuditLog,
+    limits: WatchLimits,
+    sinks: SinkStatuses,
+    shutdown: Shutdown,
 }
 
 fn main() -> Result<(), Error> {
-    let args = args::parse();
-    eprintln!("version: {}", env!("CARGO_PKG_VERSION"));
-    let cc = ClusterConfig::detect()?;
-    let k8s_client = K8sClient::from_cluster_config(cc)?;
-    actix_web::rt::System::new().block_on(async move {
-        let engine = engine::watch(k8s_client).await?;
-        let cache = engine.cache().clone();
-        let bearer_config = BearerConfig::new(args.token.path.clone()).map_err(|_| {
-            Error::ReadToken(
-                args.token
-                    .path
-                    .expect("token.path is not set, but BearerConfig::new failed"),
+    let mut args = args::parse();
+    if let Some(command) = args.command.take() {
+        return actix_web::rt::System::new()
+            .block_on(cli::run(command))
+            .map_err(Error::from);
+    }
+    // not `requires`, clap would demand --replay for the subcommands and whenever the default applies
+    if args.replay.is_empty() && args.replay_speed != 0.0 {
+        return Err(Error::ReplaySpeed);
+    }
+    let config = Config::load(args.config.as_deref())?;
+    // flushes the remaining spans when main returns
+    let telemetry = config.telemetry.as_ref().map(Telemetry::new).transpose()?;
+    log::init(
+        config.log_format,
+        &config.log_filter(),
+        telemetry.as_ref().map(Telemetry::tracer),
+    )
+    .map_err(Error::Log)?;
+    tracing::info!(version = env!("CARGO_PKG_VERSION"), "starting");
+    let token_flag = args.token.path.is_some()
+        || args.token.registry.is_some()
+        || args.token.review
+        || args.token.jwks.is_some()
+        || args.token.none;
+    match (token_flag, config.tokens.is_some()) {
+        (true, true) => return Err(ConfigError::Invalid("`tokens` can't be combined with a token flag".into()).into()),
+        (false, false) => {
+            return Err(ConfigError::Invalid(
+                "one of --token-path, --token-registry, --token-review, --jwks, --insecure-no-token \
+                 or `tokens` is required"
+                    .into(),
             )
-        })?;
+            .into())
+        }
+        _ => {}
+    }
+    if args.sinks.is_some() && config.sinks.is_some() {
+        return Err(ConfigError::Invalid("`sinks` can't be combined with --sinks".into()).into());
+    }
+    if !args.replay.is_empty() && config.ha.is_some() {
+        return Err(ConfigError::Invalid("`ha` can't be combined with --replay".into()).into());
+    }
+    if !args.replay.is_empty() && config.upstream.is_some() {
+        return Err(ConfigError::Invalid("`upstream` can't be combined with --replay".into()).into());
+    }
+    // replay and upstream mode work without a cluster
+    let (k8s_client, recording) = match args.replay.is_empty() {
+        true if config.upstream.is_some() => (None, None),
+        true => {
+            let cc = ClusterConfig::detect(args.context.as_deref())?;
+            let limits = ClientLimits {
+                qps: Some(args.kube_api_qps),
+                burst: args.kube_api_burst,
+                max_concurrent_lists: args.max_concurrent_lists,
+                backoff: config.backoff.clone(),
+            };
+            (Some(K8sClient::from_cluster_config(cc, &limits)?), None)
+        }
+        false => (None, Some(replay::load(&args.replay)?)),
+    };
+    let tls_config = match (&args.tls.cert, &args.tls.key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(&TlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: args.tls.client_ca.clone(),
+            // without a token mode the certificate is the only authentication
+            require_client_cert: args.token.none,
+        })?),
+        _ => None,
+    };
+    let client_cert_rules = ClientCertRules(args.tls.client_cert_rules.clone());
+    let audit = AuditLog::new(&AuditConfig {
+        level: args.audit.level,
+        path: args.audit.path.clone(),
+        max_size: args.audit.max_size * 1024 * 1024,
+        max_backups: args.audit.max_backups,
+    })
+    .map_err(Error::AuditLog)?;
+    let limits = WatchLimits::new(WatchLimitsConfig {
+        max_connections: args.watch_limits.max_connections,
+        reconnect_qps: args.watch_limits.reconnect_qps,
+        reconnect_burst: args.watch_limits.reconnect_burst,
+        bytes_per_second: args.watch_limits.bytes_per_second,
+        max_concurrent_replays: args.watch_limits.max_concurrent_replays,
+    });
+    let grace_period = Duration::from_secs(config.shutdown_grace_period_seconds);
+    actix_web::rt::System::new().block_on(async move {
+        let signals = shutdown::signals().map_err(Error::Signal)?;
+        let (shutdown_trigger, shutdown) = shutdown::channel();
+        let engine_config = EngineConfig {
+            broadcast_capacity: config.broadcast_capacity,
+            compression: config.cache_compression,
+            resources: config.resources.clone(),
+            encoding: config.api_encoding,
+        };
+        let engine = match &k8s_client {
+            Some(k8s_client) => {
+                let engine = Arc::new(Engine::new(k8s_client.clone(), &engine_config));
+                match &config.ha {
+                    None => engine.start().await?,
+                    // only the leader watches, the others still need the resource types for --token-review
+                    Some(_) => drop(engine.discover().await?),
+                }
+                Some(engine)
+            }
+            None => None,
+        };
+        let ha = match (&k8s_client, &engine, &config.ha) {
+            (Some(k8s_client), Some(engine), Some(ha)) => Some(ha::start(
+                ha.clone(),
+                k8s_client.clone(),
+                Arc::clone(engine),
+                config.backoff.clone(),
+            )),
+            _ => None,
+        };
+        let mut upstream = None;
+        let (cache, pending) = match &engine {
+            Some(engine) => (engine.cache().clone(), engine.pending().clone()),
+            None => {
+                let cache = Arc::new(RwLock::new(Cache::with_capacity(
+                    config.broadcast_capacity,
+                    config.cache_compression,
+                )));
+                match &config.upstream {
+                    Some(upstream_config) => {
+                        upstream = Some(Upstream::new(
+                            upstream_config.clone(),
+                            Arc::clone(&cache),
+                            &config.backoff,
+                        )?)
+                    }
+                    None => replay::replay(recording.unwrap_or_default(), Arc::clone(&cache), args.replay_speed).await,
+                }
+                (cache, PendingLists::default())
+            }
+        };
+        let sink_statuses = SinkStatuses::default();
+        let mut sinks = Sinks::new(cache.clone(), pending.clone(), sink_statuses.clone(), shutdown.clone());
+        let sink_tasks = sinks.tasks();
+        let sinks_from_file = args.sinks.is_some();
+        let sinks_config = match &args.sinks {
+            Some(path) => Some(SinksConfig::from_path(path)?),
+            None => config.sinks.clone(),
+        };
+        sinks.replace(sinks_config).await?;
+        let tokens = config
+            .tokens
+            .clone()
+            .map(|tokens| Arc::new(std::sync::RwLock::new(TokenRegistry { tokens })));
+        let bearer_config = match (&tokens, args.token.path, args.token.registry) {
+            (Some(tokens), _, _) => BearerConfig::Tokens(Arc::clone(tokens)),
+            (None, Some(path), _) => BearerConfig::token(path.clone()).map_err(|_| Error::ReadToken(path))?,
+            (None, None, Some(path)) => BearerConfig::registry(path.clone()).map_err(|_| Error::ReadToken(path))?,
+            (None, None, None) if args.token.jwks.is_some() => {
+                let config = JwtConfig {
+                    jwks: args.token.jwks.unwrap_or_default(),
+                    issuer: args.jwt.issuer,
+                    audience: args.jwt.audience,
+                    leeway: args.jwt.leeway,
+                    groups_claim: args.jwt.groups_claim,
+                    rules: args.jwt.rules,
+                };
+                BearerConfig::Jwt(Arc::new(JwtVerifier::new(config)?))
+            }
+            (None, None, None) if args.token.review => match (k8s_client, &engine) {
+                (Some(k8s_client), Some(engine)) => {
+                    BearerConfig::TokenReview(Arc::new(TokenReviewer::new(k8s_client, engine.types().clone())))
+                }
+                _ => return Err(Error::TokenReviewWithoutCluster),
+            },
+            (None, None, None) => BearerConfig::None,
+        };
+        let listen = args
+            .listen
+            .clone()
+            .or_else(|| config.listen.clone())
+            .unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+        if let Some(path) = args.config.clone() {
+            let reloadable = Reloadable {
+                tokens,
+                // sinks from --sinks are not part of the configuration
+                sinks: Some(sinks).filter(|_| !sinks_from_file),
+                engine: engine.clone(),
+            };
+            config::watch(path, config, reloadable, shutdown.clone())?;
+        }
         let server = HttpServer::new(move || {
             // TODO: understand why we can't just move data into this closure
             App::new() //
-                .app_data(Data::new(AppData { cache: cache.clone() }))
+                .wrap_fn(|req, srv| {
+                    let span = telemetry::request_span(&req);
+                    let response = span.in_scope(|| srv.call(req));
+                    let request = span.clone();
+                    async move {
+                        let response = response.await;
+                        if let Ok(response) = &response {
+                            request.record("status", response.status().as_u16());
+                        }
+                        response
+                    }
+                    .instrument(span)
+                })
+                .app_data(Data::new(AppData {
+                    cache: cache.clone(),
+                    pending: pending.clone(),
+                    audit: audit.clone(),
+                    limits: limits.clone(),
+                    sinks: sink_statuses.clone(),
+                    shutdown: shutdown.clone(),
+                }))
                 .app_data(bearer_config.clone())
+                .app_data(client_cert_rules.clone())
                 .service(watch)
                 .service(list)
+                .service(descendants)
+                .service(ancestors)
                 .service(status)
         })
-        .bind(("0.0.0.0", 8080))
-        .map_err(Error::ServerBind)?;
-        server.run().await.map_err(Error::ServerRun)?;
+        .on_connect(tls::on_connect)
+        .disable_signals()
+        .shutdown_timeout(grace_period.as_secs());
+        let server = match tls_config {
+            Some(tls_config) => server.bind_rustls(&listen, tls_config),
+            None => server.bind(&listen),
+        }
+        .map_err(Error::ServerBind)?
+        .run();
+        let handle = server.clone();
+        actix_web::rt::spawn(async move {
+            signals.await;
+            let drain = async {
+                handle.pause().await;
+                if let Some(ha) = ha {
+                    ha.stop().await;
+                }
+                if let Some(engine) = &engine {
+                    engine.stop().await;
+                }
+                drop(upstream);
+                // open watches end with a bookmark, sinks deliver what they have collected
+                shutdown_trigger.trigger();
+                sink_tasks.join().await;
+                handle.stop(true).await;
+            };
+            if tokio::time::timeout(grace_period, drain).await.is_err() {
+                tracing::warn!(
+                    grace_period_seconds = grace_period.as_secs(),
+                    "grace period exceeded, closing the remaining connections"
+                );
+                handle.stop(false).await;
+            }
+        });
+        server.await.map_err(Error::ServerRun)?;
         Ok::<_, Error>(())
     })?;
     Ok(())
@@ -76,50 +318,462 @@ enum Filter {
     Exclude(String),
 }
 
+impl Filter {
+    /// as given in the query string
+    fn describe(&self) -> String {
+        match self {
+            Filter::Include(filter) => format!("include={}", filter),
+            Filter::Exclude(filter) => format!("exclude={}", filter),
+        }
+    }
+
+    fn kinds(&self) -> Kinds {
+        match self {
+            Filter::Include(filter) => Kinds::Include(filter.split(',').map(|x| x.to_string()).collect()),
+            Filter::Exclude(filter) => Kinds::Exclude(filter.split(',').map(|x| x.to_string()).collect()),
+        }
+    }
+}
+
+/// the parameters of `/watch` and `/list` selecting objects
+#[derive(Debug, Deserialize)]
+struct SelectorQuery {
+    #[serde(flatten)]
+    filter: Option<Filter>,
+    namespace: Option<String>,
+    /// `key=value` pairs separated by commas
+    #[serde(rename = "labelSelector")]
+    label_selector: Option<String>,
+    #[serde(rename = "ownerUid")]
+    owner_uid: Option<String>,
+}
+
+impl SelectorQuery {
+    fn selector(&self) -> Result<Selector, actix_web::Error> {
+        let labels = match &self.label_selector {
+            Some(selector) => Selector::parse_labels(selector).map_err(actix_web::error::ErrorBadRequest)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Selector {
+            kinds: self.filter.as_ref().map(Filter::kinds).unwrap_or_default(),
+            namespace: self.namespace.clone(),
+            labels,
+            owner_uid: self.owner_uid.clone(),
+        })
+    }
+
+    /// as given in the query string
+    fn describe(&self) -> Option<String> {
+        let params = self
+            .filter
+            .iter()
+            .map(Filter::describe)
+            .chain(
+                self.namespace
+                    .iter()
+                    .map(|namespace| format!("namespace={}", namespace)),
+            )
+            .chain(
+                self.label_selector
+                    .iter()
+                    .map(|labels| format!("labelSelector={}", labels)),
+            )
+            .chain(self.owner_uid.iter().map(|uid| format!("ownerUid={}", uid)))
+            .collect::<Vec<_>>();
+        (!params.is_empty()).then(|| params.join("&"))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Query {
     #[serde(rename = "resourceVersion")]
     resource_version: Option<ResourceVersion>,
     #[serde(flatten)]
-    filter: Option<Filter>,
+    selector: SelectorQuery,
+    /// uid of an object, which is watched with everything it transitively owns
+    subtree: Option<String>,
 }
 
 #[actix_web::get("/watch")]
-async fn watch(query: web::Query<Query>, appdata: web::Data<AppData>, _bearer: Bearer) -> impl Responder {
-    let filter: Box<dyn Fn(&str) -> bool> = match &query.filter {
-        None => Box::new(move |_| true),
-        Some(Filter::Include(filter)) => {
-            let names = filter.split(',').map(|x| x.to_string()).collect::<HashSet<String>>();
-            Box::new(move |name| names.contains(name))
-        }
-        Some(Filter::Exclude(filter)) => {
-            let names = filter.split(',').map(|x| x.to_string()).collect::<HashSet<_>>();
-            Box::new(move |name| !names.contains(name))
-        }
+async fn watch(
+    req: HttpRequest,
+    query: web::Query<Query>,
+    appdata: web::Data<AppData>,
+    bearer: Bearer,
+) -> Result<HttpResponse, actix_web::Error> {
+    bearer.require(Endpoint::Watch)?;
+    let selector = query.selector.selector()?;
+    let client = match &bearer.name {
+        Some(name) => name.clone(),
+        None => req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default(),
     };
+    let mut permit = appdata.limits.admit(&client, query.resource_version.is_none())?;
+    let replay = permit.replay.take();
+    // written when the client disconnects and the stream is dropped
+    let audit = Rc::new(RefCell::new(
+        appdata.audit.start(
+            bearer.name.as_deref(),
+            Endpoint::Watch,
+            req.peer_addr().map(|addr| addr.to_string()),
+            query
+                .selector
+                .describe()
+                .into_iter()
+                .chain(query.subtree.as_ref().map(|uid| format!("subtree={}", uid)))
+                .reduce(|a, b| format!("{}&{}", a, b)),
+            query.resource_version,
+        ),
+    ));
     let cache = appdata.get_ref().cache.read().await;
-    let stream = cache.stream(query.resource_version);
-    let stream = stream.filter_map(move |evt| {
-        let (res, evt) = otry!(evt);
-        if filter(&res.kind) {
-            let mut vec = otry!(serde_json::to_vec(&evt));
-            vec.push(b'\n');
-            Some(Ok::<_, Error>(Bytes::from(vec)))
-        } else {
-            None
+    // every event has to pass through the subtree to keep track of its members, the selector can only be applied
+    // after it
+    let backlog = match &query.subtree {
+        Some(_) => Selector::default(),
+        None => selector.clone(),
+    };
+    let stream = cache.stream_selected(query.resource_version, &backlog, replay);
+    let subtree = query
+        .subtree
+        .clone()
+        .map(|uid| Subtree::new(&cache, uid, query.resource_version));
+    let subtree = Rc::new(RefCell::new(subtree));
+    let permit = Rc::new(permit);
+    let bearer = Rc::new(bearer);
+    let selector = Rc::new(selector);
+    let client = Rc::new(client);
+    // where this client would have to resume, the resourceVersion after the last event it has seen
+    let position = Rc::new(Cell::new(query.resource_version));
+    let resume = Rc::clone(&position);
+    // authorization may need to ask the API server, hence the async filter
+    let stream = futures_util::StreamExt::filter_map(stream, move |evt| {
+        let bearer = Rc::clone(&bearer);
+        let selector = Rc::clone(&selector);
+        let subtree = Rc::clone(&subtree);
+        let audit = Rc::clone(&audit);
+        let permit = Rc::clone(&permit);
+        let client = Rc::clone(&client);
+        let position = Rc::clone(&position);
+        async move {
+            let (res, evt) = otry!(evt);
+            let next = evt.resource_version().map(|rv| rv + 1);
+            let in_subtree = subtree
+                .borrow_mut()
+                .as_mut()
+                .is_none_or(|subtree| subtree.update(&res, &evt));
+            // a failed review ends the stream before `position` moves past the event,
+            // the client resumes from there instead of silently missing it
+            let allowed = match in_subtree && selector.matches_event(&res, &evt) {
+                true => otry!(bearer.allows(Endpoint::Watch, &res).await),
+                false => false,
+            };
+            if allowed {
+                let delivery = evt.origin().map(|origin| (origin, origin.deliver_span(&client)));
+                // serialized once when it was cached, shared with every other client
+                let line = evt.line().clone();
+                permit.throttle(line.len()).await;
+                if let Some((origin, span)) = &delivery {
+                    origin.delivered(span);
+                }
+                audit.borrow_mut().delivered(&res, line.len());
+                position.set(next.or_else(|| position.get()));
+                Some(Ok::<_, Error>(line))
+            } else {
+                position.set(next.or_else(|| position.get()));
+                None
+            }
         }
     });
+    // on shutdown the stream ends with a bookmark, instead of an abrupt EOF
+    let shutdown = appdata.shutdown.clone();
+    let stream = futures_util::StreamExt::take_until(stream, shutdown.clone().requested());
+    let bookmark = futures_util::stream::once(async move {
+        if !shutdown.is_requested() {
+            return None;
+        }
+        let bookmark = OutputEvent::bookmark(resume.get().unwrap_or_default());
+        Some(Ok::<_, Error>(bookmark.line().clone()))
+    });
+    let bookmark = futures_util::StreamExt::filter_map(bookmark, futures_util::future::ready);
+    let stream = futures_util::StreamExt::chain(stream, bookmark);
     let ret = BodyStream::new(stream);
-    HttpResponse::Ok().body(ret)
+    Ok(HttpResponse::Ok().body(ret))
+}
+
+/// objects of the same type in the same namespace share the authorization decision
+fn type_key(res: &ResourceId) -> ResourceId {
+    ResourceId {
+        name: String::new(),
+        ..res.clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListFormat {
+    Html,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    format: Option<ListFormat>,
+    #[serde(flatten)]
+    selector: SelectorQuery,
+}
+
+/// `/list?format=json`, `{"resourceVersion":...,"items":[...]}` with all objects as of `resourceVersion`, which a
+/// watch can resume after, put together from the objects as they are cached
+fn list_output(resource_version: ResourceVersion, items: impl Iterator<Item = Bytes>) -> Vec<u8> {
+    let mut body = format!(r#"{{"resourceVersion":"{}","items":["#, resource_version).into_bytes();
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        body.extend_from_slice(&item);
+    }
+    body.extend_from_slice(b"]}");
+    body
 }
 
 #[actix_web::get("/list")]
-async fn list(appdata: web::Data<AppData>, _bearer: Bearer) -> impl Responder {
+async fn list(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    appdata: web::Data<AppData>,
+    bearer: Bearer,
+) -> Result<HttpResponse, actix_web::Error> {
+    bearer.require(Endpoint::List)?;
+    let selector = query.selector.selector()?;
+    let audit = RefCell::new(appdata.audit.start(
+        bearer.name.as_deref(),
+        Endpoint::List,
+        req.peer_addr().map(|addr| addr.to_string()),
+        query.selector.describe(),
+        None,
+    ));
+    let keys = {
+        let cache = appdata.get_ref().cache.read().await;
+        match selector.is_empty() {
+            true => cache.ids().map(type_key).collect::<HashSet<_>>(),
+            false => cache
+                .select(&selector)
+                .into_iter()
+                .map(|(res, _)| type_key(res))
+                .collect(),
+        }
+    };
+    let mut allowed = HashSet::new();
+    for key in keys {
+        if bearer
+            .allows(Endpoint::List, &key)
+            .await
+            .map_err(BearerResponseError::from)?
+        {
+            allowed.insert(key);
+        }
+    }
+    // a single read lock, so the objects are consistent with the resourceVersion
     let cache = appdata.get_ref().cache.read().await;
-    HttpResponse::Ok().body(cache.list())
+    let visible = |res: &ResourceId| {
+        let allowed = allowed.contains(&type_key(res));
+        if allowed {
+            audit.borrow_mut().delivered(res, 0);
+        }
+        allowed
+    };
+    let response = match query.format.unwrap_or(ListFormat::Html) {
+        ListFormat::Html => {
+            let body = cache.list(&selector, visible);
+            audit.borrow_mut().delivered_bytes(body.len());
+            HttpResponse::Ok().body(body)
+        }
+        ListFormat::Json => {
+            let items = cache
+                .select(&selector)
+                .into_iter()
+                .filter(|(res, _)| visible(res))
+                .map(|(_, object)| object.json());
+            let body = list_output(cache.last_resource_version().unwrap_or_default(), items);
+            audit.borrow_mut().delivered_bytes(body.len());
+            HttpResponse::Ok().content_type("application/json").body(body)
+        }
+    };
+    Ok(response)
+}
+
+/// identifies the object a graph query starts from, either by `uid` or by all of `apiVersion`, `kind` and `name`
+#[derive(Debug, Deserialize)]
+struct GraphQuery {
+    uid: Option<String>,
+    #[serde(rename = "apiVersion")]
+    api_version: Option<String>,
+    kind: Option<String>,
+    namespace: Option<String>,
+    name: Option<String>,
+}
+
+impl GraphQuery {
+    /// as given in the query string
+    fn describe(&self) -> String {
+        match &self.uid {
+            Some(uid) => format!("uid={}", uid),
+            None => {
+                let params = [
+                    ("apiVersion", &self.api_version),
+                    ("kind", &self.kind),
+                    ("namespace", &self.namespace),
+                    ("name", &self.name),
+                ];
+                let params = params
+                    .iter()
+                    .filter_map(|(key, value)| value.as_ref().map(|value| format!("{}={}", key, value)));
+                params.collect::<Vec<_>>().join("&")
+            }
+        }
+    }
+
+    fn root<'a>(&self, cache: &'a Cache) -> Result<Option<(ResourceId, &'a Stored)>, actix_web::Error> {
+        if let Some(uid) = &self.uid {
+            return Ok(cache.by_uid(uid).map(|(res, object)| (res.clone(), object)));
+        }
+        let res = match (&self.api_version, &self.kind, &self.name) {
+            (Some(api_version), Some(kind), Some(name)) => ResourceId {
+                api_version: api_version.clone(),
+                kind: kind.clone(),
+                name: name.clone(),
+                namespace: self.namespace.clone(),
+            },
+            _ => {
+                return Err(actix_web::error::ErrorBadRequest(
+                    "either uid or apiVersion, kind and name are required",
+                ))
+            }
+        };
+        Ok(cache.get(&res).map(|object| (res, object)))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Descendants,
+    Ancestors,
+}
+
+#[derive(Debug, Serialize)]
+struct GraphOutput {
+    #[serde(rename = "resourceVersion")]
+    resource_version: String,
+    object: serde_json::Value,
+    /// closest first
+    items: Vec<serde_json::Value>,
+    /// owner references to objects that aren't cached
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unresolved: Vec<serde_json::Value>,
+}
+
+#[actix_web::get("/graph/descendants")]
+async fn descendants(
+    req: HttpRequest,
+    query: web::Query<GraphQuery>,
+    appdata: web::Data<AppData>,
+    bearer: Bearer,
+) -> Result<HttpResponse, actix_web::Error> {
+    graph(req, query, appdata, bearer, Direction::Descendants).await
+}
+
+#[actix_web::get("/graph/ancestors")]
+async fn ancestors(
+    req: HttpRequest,
+    query: web::Query<GraphQuery>,
+    appdata: web::Data<AppData>,
+    bearer: Bearer,
+) -> Result<HttpResponse, actix_web::Error> {
+    graph(req, query, appdata, bearer, Direction::Ancestors).await
+}
+
+/// authorized like `/list`, an object the client may not list is treated as if it wasn't cached
+async fn graph(
+    req: HttpRequest,
+    query: web::Query<GraphQuery>,
+    appdata: web::Data<AppData>,
+    bearer: Bearer,
+    direction: Direction,
+) -> Result<HttpResponse, actix_web::Error> {
+    bearer.require(Endpoint::List)?;
+    let audit = RefCell::new(appdata.audit.start(
+        bearer.name.as_deref(),
+        Endpoint::List,
+        req.peer_addr().map(|addr| addr.to_string()),
+        Some(query.describe()),
+        None,
+    ));
+    // copied, so authorization doesn't hold the lock
+    let (resource_version, root, items, unresolved) = {
+        let cache = appdata.get_ref().cache.read().await;
+        let (root, object) = match query.root(&cache)? {
+            Some(root) => root,
+            None => return Err(actix_web::error::ErrorNotFound("object not found")),
+        };
+        let (items, unresolved) = match direction {
+            Direction::Descendants => match &object.meta().uid {
+                Some(uid) => (engine::descendants(&cache, uid), Vec::new()),
+                None => (Vec::new(), Vec::new()),
+            },
+            Direction::Ancestors => engine::ancestors(&cache, object),
+        };
+        let items = items
+            .into_iter()
+            .map(|(res, object)| (res.clone(), object.object()))
+            .collect::<Vec<_>>();
+        (
+            cache.last_resource_version().unwrap_or_default(),
+            (root, object.object()),
+            items,
+            unresolved,
+        )
+    };
+    let mut allowed = HashMap::new();
+    for res in std::iter::once(&root.0).chain(items.iter().map(|(res, _)| res)) {
+        if let Entry::Vacant(entry) = allowed.entry(type_key(res)) {
+            let allows = bearer
+                .allows(Endpoint::List, entry.key())
+                .await
+                .map_err(BearerResponseError::from)?;
+            entry.insert(allows);
+        }
+    }
+    if !allowed[&type_key(&root.0)] {
+        return Err(actix_web::error::ErrorNotFound("object not found"));
+    }
+    let mut visible = Vec::new();
+    for (res, object) in items {
+        if allowed[&type_key(&res)] {
+            audit.borrow_mut().delivered(&res, 0);
+            visible.push(object);
+        }
+    }
+    let output = GraphOutput {
+        resource_version: resource_version.to_string(),
+        object: root.1,
+        items: visible,
+        unresolved,
+    };
+    let body = serde_json::to_vec(&output).expect("serializing JSON values can't fail");
+    audit.borrow_mut().delivered_bytes(body.len());
+    Ok(HttpResponse::Ok().content_type("application/json").body(body))
+}
+
+#[derive(Debug, Serialize)]
+struct StatusOutput {
+    #[serde(rename = "pendingLists")]
+    pending_lists: Vec<String>,
+    sinks: BTreeMap<String, SinkStatus>,
 }
 
 #[actix_web::get("/status")]
-async fn status() -> impl Responder {
-    HttpResponse::Ok()
+async fn status(appdata: web::Data<AppData>) -> impl Responder {
+    HttpResponse::Ok().json(StatusOutput {
+        pending_lists: appdata.pending.get(),
+        sinks: appdata.sinks.get(),
+    })
 }
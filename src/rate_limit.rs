@@ -0,0 +1,107 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// token bucket, same semantics as client-go's `--kube-api-qps`/`--kube-api-burst`
+#[derive(Debug)]
+pub struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// can go negative, which means that many requests are already waiting for a token
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// `qps` must be positive, `burst` is the number of requests allowed to go through at once
+    pub fn new(qps: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            qps,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        self.acquire_many(1).await
+    }
+
+    /// e.g. one token per byte, `n` may exceed the burst
+    pub async fn acquire_many(&self, n: u32) {
+        let wait = self.reserve(Instant::now(), f64::from(n));
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// takes a token if one is available right now, otherwise returns how long until there is one
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.refill(Instant::now());
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.qps))
+        }
+    }
+
+    /// takes `n` tokens and returns how long the caller has to wait before they become valid
+    fn reserve(&self, now: Instant, n: f64) -> Duration {
+        let mut bucket = self.refill(now);
+        bucket.tokens -= n;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.qps)
+        }
+    }
+
+    fn refill(&self, now: Instant) -> MutexGuard<'_, Bucket> {
+        let mut bucket = self.bucket.lock().expect("Rate limiter lock poisoned");
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.qps).min(self.burst);
+        bucket.updated = now;
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn burst_then_qps() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.reserve(now, 1.0), Duration::ZERO);
+        }
+        assert_eq!(limiter.reserve(now, 1.0), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(now, 1.0), Duration::from_millis(1000));
+        // after 1s two tokens were refilled, both already reserved by waiting callers
+        assert_eq!(
+            limiter.reserve(now + Duration::from_secs(1), 1.0),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn try_acquire() {
+        let limiter = RateLimiter::new(2.0, 1);
+        assert_eq!(limiter.try_acquire(), Ok(()));
+        let wait = limiter.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        // a rejected attempt doesn't take a token
+        assert!(limiter.try_acquire().unwrap_err() <= wait);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    engine::Cache,
+    k8s_client::api::{ResourceId, ResourceVersion},
+};
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::RwLock;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("Could not read \"{}\": {:?}", _0.display(), _1)]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Invalid event in \"{}\" line {}: {}", _0.display(), _1, _2)]
+    Invalid(PathBuf, usize, String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum LineType {
+    Added,
+    Modified,
+    Deleted,
+    /// ends a `/watch` response on shutdown, there is nothing to apply
+    Bookmark,
+}
+
+/// one line of a dump, as emitted by `/watch` or a file sink, `timestamp` is only needed for pacing
+#[derive(Debug, Deserialize)]
+struct Line {
+    #[serde(rename = "type")]
+    ty: LineType,
+    object: Value,
+    timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedEvent {
+    resource: ResourceId,
+    resource_version: ResourceVersion,
+    /// `None` for deletions
+    object: Option<Value>,
+    timestamp: Option<DateTime<Utc>>,
+}
+
+impl RecordedEvent {
+    /// `None` for bookmarks
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line: Line = serde_json::from_str(line).map_err(|err| err.to_string())?;
+        if let LineType::Bookmark = line.ty {
+            return Ok(None);
+        }
+        let resource = ResourceId::of(&line.object).ok_or("missing apiVersion, kind or metadata.name")?;
+        let resource_version = line
+            .object
+            .pointer("/metadata/resourceVersion")
+            .and_then(Value::as_str)
+            .and_then(|rv| rv.parse().ok())
+            .ok_or("missing or invalid metadata.resourceVersion")?;
+        let object = match line.ty {
+            LineType::Deleted => None,
+            _ => Some(line.object),
+        };
+        Ok(Some(Self {
+            resource,
+            resource_version,
+            object,
+            timestamp: line.timestamp,
+        }))
+    }
+
+    pub fn apply(self, cache: &mut Cache) {
+        match self.object {
+            Some(object) => cache.update(self.resource, self.resource_version, object),
+            None => cache.remove(self.resource, self.resource_version),
+        }
+    }
+}
+
+/// reads NDJSON dumps, `.gz` and `.zst` files are decompressed, events are replayed in the order of the files
+pub fn load(paths: &[PathBuf]) -> Result<Vec<RecordedEvent>, ReplayError> {
+    let mut events = Vec::new();
+    for path in paths {
+        let read_err = |err| ReplayError::Read(path.clone(), err);
+        let reader = BufReader::new(open(path).map_err(read_err)?);
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.map_err(read_err)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = RecordedEvent::parse(&line).map_err(|err| ReplayError::Invalid(path.clone(), n + 1, err))?;
+            events.extend(event);
+        }
+    }
+    Ok(events)
+}
+
+fn open(path: &Path) -> Result<Box<dyn Read>, io::Error> {
+    let file = fs::File::open(path)?;
+    Ok(match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Box::new(MultiGzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::new(file)?),
+        _ => Box::new(file),
+    })
+}
+
+/// `speed` 0 applies all events at once, otherwise events are spaced by the difference of their timestamps divided
+/// by `speed` (1 is the original pace) in a background task
+pub async fn replay(events: Vec<RecordedEvent>, cache: Arc<RwLock<Cache>>, speed: f64) {
+    let count = events.len();
+    if speed <= 0.0 {
+        let mut cache = cache.write().await;
+        for event in events {
+            event.apply(&mut cache);
+        }
+        return tracing::info!(events = count, "replayed recorded events");
+    }
+    tokio::task::spawn(async move {
+        let mut previous: Option<DateTime<Utc>> = None;
+        for event in events {
+            if let (Some(previous), Some(timestamp)) = (previous, event.timestamp) {
+                let delay = (timestamp - previous).to_std().unwrap_or_default();
+                tokio::time::sleep(delay.div_f64(speed)).await;
+            }
+            previous = event.timestamp.or(previous);
+            event.apply(&mut *cache.write().await);
+        }
+        tracing::info!(events = count, "replay finished");
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tokio_stream::StreamExt;
+
+    const DUMP: &str = r#"
+{"type":"ADDED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","namespace":"x","resourceVersion":"1"}},"timestamp":"2021-01-01T00:00:00Z"}
+{"type":"MODIFIED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"b","namespace":"x","resourceVersion":"2"}}}
+{"type":"DELETED","object":{"apiVersion":"v1","kind":"Pod","metadata":{"name":"a","namespace":"x","resourceVersion":"3"}},"timestamp":"2021-01-01T00:00:10Z"}
+"#;
+
+    fn dump(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("big-brother-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(DUMP.as_bytes()).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_and_apply() {
+        let events = load(&[dump("load.ndjson.gz")]).unwrap();
+        assert_eq!(events.len(), 3);
+        let cache = Arc::new(RwLock::new(Cache::new()));
+        tokio_test::block_on(replay(events, Arc::clone(&cache), 0.0));
+        let cache = tokio_test::block_on(cache.read());
+        // the deletion is kept as a tombstone
+        assert_eq!(cache.ids().count(), 2);
+        let mut stream = Box::pin(cache.stream(None, ()));
+        let (res, _) = tokio_test::block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(res.name, "b");
+    }
+
+    #[tokio::test]
+    async fn paced() {
+        let events = load(&[dump("paced.ndjson.gz")]).unwrap();
+        let cache = Arc::new(RwLock::new(Cache::new()));
+        let mut stream = Box::pin(cache.read().await.stream(None, ()));
+        tokio::time::pause();
+        replay(events, Arc::clone(&cache), 2.0).await;
+        assert_eq!(stream.next().await.unwrap().unwrap().0.name, "a");
+        assert_eq!(stream.next().await.unwrap().unwrap().0.name, "b");
+        let start = tokio::time::Instant::now();
+        assert_eq!(stream.next().await.unwrap().unwrap().0.name, "a");
+        assert_eq!(start.elapsed().as_secs(), 5);
+    }
+
+    #[test]
+    fn invalid() {
+        let err = RecordedEvent::parse(r#"{"type":"MODIFIED","object":{"apiVersion":"v1","kind":"Pod"}}"#).unwrap_err();
+        assert_eq!(err, "missing apiVersion, kind or metadata.name");
+    }
+}
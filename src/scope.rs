@@ -0,0 +1,175 @@
+use crate::k8s_client::api::ResourceId;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, io, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endpoint {
+    Watch,
+    List,
+}
+
+impl Endpoint {
+    /// Kubernetes RBAC verb needed to use the endpoint
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Self::Watch => "watch",
+            Self::List => "list",
+        }
+    }
+}
+
+/// what an authenticated client is allowed to see, `None` means no restriction
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scope {
+    /// matched against `kind` of the object, e.g. "Pod"
+    #[serde(default)]
+    pub kinds: Option<HashSet<String>>,
+    /// cluster-scoped objects are hidden when namespaces are restricted
+    #[serde(default)]
+    pub namespaces: Option<HashSet<String>>,
+    #[serde(default)]
+    pub endpoints: Option<HashSet<Endpoint>>,
+}
+
+impl Scope {
+    pub fn full() -> Self {
+        Self::default()
+    }
+
+    pub fn allows_endpoint(&self, endpoint: Endpoint) -> bool {
+        match &self.endpoints {
+            None => true,
+            Some(endpoints) => endpoints.contains(&endpoint),
+        }
+    }
+
+    pub fn allows(&self, res: &ResourceId) -> bool {
+        let kind = match &self.kinds {
+            None => true,
+            Some(kinds) => kinds.contains(&res.kind),
+        };
+        let namespace = match (&self.namespaces, &res.namespace) {
+            (None, _) => true,
+            (Some(namespaces), Some(namespace)) => namespaces.contains(namespace),
+            (Some(_), None) => false,
+        };
+        kind && namespace
+    }
+}
+
+/// authenticated client of an identity provider or a client certificate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub subject: String,
+    pub groups: Vec<String>,
+}
+
+/// content of a rules file, re-read on every request like the token registry
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScopeRules {
+    pub rules: Vec<ScopeRule>,
+}
+
+/// the first rule matching the subject or one of the groups decides the scope
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "ScopeRuleEntry")]
+pub struct ScopeRule {
+    pub subjects: HashSet<String>,
+    pub groups: HashSet<String>,
+    pub scope: Scope,
+}
+
+/// a rule as written, the scope next to the matching fields: `#[serde(flatten)]` can't reject unknown fields, a
+/// misspelled restriction (`namespace: [team-a]`) would grant full access
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScopeRuleEntry {
+    #[serde(default)]
+    subjects: HashSet<String>,
+    #[serde(default)]
+    groups: HashSet<String>,
+    #[serde(default)]
+    kinds: Option<HashSet<String>>,
+    #[serde(default)]
+    namespaces: Option<HashSet<String>>,
+    #[serde(default)]
+    endpoints: Option<HashSet<Endpoint>>,
+}
+
+impl From<ScopeRuleEntry> for ScopeRule {
+    fn from(entry: ScopeRuleEntry) -> Self {
+        Self {
+            subjects: entry.subjects,
+            groups: entry.groups,
+            scope: Scope {
+                kinds: entry.kinds,
+                namespaces: entry.namespaces,
+                endpoints: entry.endpoints,
+            },
+        }
+    }
+}
+
+impl ScopeRules {
+    pub fn from_path(path: &Path) -> Result<Self, io::Error> {
+        let file = fs::File::open(path)?;
+        serde_yaml::from_reader(file).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn find(self, identity: &Identity) -> Option<Scope> {
+        self.rules
+            .into_iter()
+            .find(|rule| {
+                rule.subjects.contains(&identity.subject) || identity.groups.iter().any(|g| rule.groups.contains(g))
+            })
+            .map(|rule| rule.scope)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn res(kind: &str, namespace: Option<&str>) -> ResourceId {
+        ResourceId {
+            api_version: "v1".into(),
+            kind: kind.into(),
+            name: "n".into(),
+            namespace: namespace.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn full() {
+        let scope = Scope::full();
+        assert!(scope.allows_endpoint(Endpoint::Watch));
+        assert!(scope.allows(&res("Secret", Some("kube-system"))));
+        assert!(scope.allows(&res("Node", None)));
+    }
+
+    #[test]
+    fn restricted() {
+        let scope: Scope =
+            serde_yaml::from_str("{kinds: [Pod, ConfigMap], namespaces: [team-a], endpoints: [watch]}").unwrap();
+        assert!(scope.allows_endpoint(Endpoint::Watch));
+        assert!(!scope.allows_endpoint(Endpoint::List));
+        assert!(scope.allows(&res("Pod", Some("team-a"))));
+        assert!(!scope.allows(&res("Pod", Some("team-b"))));
+        assert!(!scope.allows(&res("Secret", Some("team-a"))));
+        assert!(!scope.allows(&res("Pod", None)));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let rules: ScopeRules = serde_yaml::from_str("{rules: [{groups: [a], namespaces: [team-a]}]}").unwrap();
+        assert_eq!(
+            rules.rules[0].scope.namespaces,
+            Some(HashSet::from(["team-a".to_string()]))
+        );
+        let err = serde_yaml::from_str::<ScopeRules>("{rules: [{groups: [a], namespace: [team-a]}]}").unwrap_err();
+        assert!(err.to_string().contains("unknown field `namespace`"), "{}", err);
+        assert!(serde_yaml::from_str::<Scope>("{kind: [Pod]}").is_err());
+    }
+}
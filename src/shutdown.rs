@@ -0,0 +1,52 @@
+use std::io;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+pub const DEFAULT_GRACE_PERIOD_SECONDS: u64 = 30;
+
+/// tells long running tasks to wind down, cloned into everything that has something to finish
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+#[derive(Debug)]
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    (ShutdownTrigger(tx), Shutdown(rx))
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        let _ = self.0.send(true);
+    }
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// never resolves if the trigger is dropped without being used
+    pub async fn requested(mut self) {
+        while !*self.0.borrow() {
+            if self.0.changed().await.is_err() {
+                futures_util::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// registered before the server starts, resolves on the first SIGTERM or SIGINT
+pub fn signals() -> Result<impl std::future::Future<Output = ()>, io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!(signal = "SIGTERM", "shutting down"),
+            _ = interrupt.recv() => tracing::info!(signal = "SIGINT", "shutting down"),
+        }
+    })
+}
@@ -0,0 +1,514 @@
+use super::{default_batch_delay_ms, default_batch_size, Sink, SinkError, SinkFilter, SinkOptions};
+use crate::{engine::OutputEvent, k8s_client::api::ResourceVersion};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::{write::GzEncoder, Compression as GzLevel};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// the `<timestamp>` in file names
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "FileSinkEntry")]
+pub struct FileSinkConfig {
+    pub options: SinkOptions,
+    pub dir: PathBuf,
+    pub compression: Compression,
+    /// MiB, files are finished when a batch arrives after they have reached this size...
+    pub max_file_size: u64,
+    /// ...or have been open this long
+    pub max_file_age_seconds: u64,
+}
+
+/// a file sink as written, see `WebhookEntry`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct FileSinkEntry {
+    name: String,
+    dir: PathBuf,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default = "default_max_file_size")]
+    max_file_size: u64,
+    #[serde(default = "default_max_file_age_seconds")]
+    max_file_age_seconds: u64,
+    #[serde(default)]
+    kinds: Option<HashSet<String>>,
+    #[serde(default)]
+    namespaces: Option<HashSet<String>>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default = "default_batch_size")]
+    batch_size: usize,
+    #[serde(default = "default_batch_delay_ms")]
+    batch_delay_ms: u64,
+}
+
+impl From<FileSinkEntry> for FileSinkConfig {
+    fn from(entry: FileSinkEntry) -> Self {
+        Self {
+            options: SinkOptions {
+                name: entry.name,
+                filter: SinkFilter {
+                    kinds: entry.kinds,
+                    namespaces: entry.namespaces,
+                    labels: entry.labels,
+                },
+                batch_size: entry.batch_size,
+                batch_delay_ms: entry.batch_delay_ms,
+            },
+            dir: entry.dir,
+            compression: entry.compression,
+            max_file_size: entry.max_file_size,
+            max_file_age_seconds: entry.max_file_age_seconds,
+        }
+    }
+}
+
+fn default_max_file_size() -> u64 {
+    100
+}
+fn default_max_file_age_seconds() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::None => "ndjson",
+            Self::Gzip => "ndjson.gz",
+            Self::Zstd => "ndjson.zst",
+        }
+    }
+
+    /// by the extension, as `--replay` decompresses it
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+
+    /// every batch becomes a complete gzip member or zstd frame, their concatenation is a valid file
+    pub fn encode(self, lines: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Self::None => Ok(lines),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+                encoder.write_all(&lines)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::encode_all(&lines[..], 0),
+        }
+    }
+}
+
+/// one line of `<name>.index.ndjson` per finished file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEntry {
+    pub file: String,
+    pub first_resource_version: Option<ResourceVersion>,
+    pub last_resource_version: Option<ResourceVersion>,
+    pub first_timestamp: DateTime<Utc>,
+    pub last_timestamp: DateTime<Utc>,
+    pub events: u64,
+    /// size on disk
+    pub bytes: u64,
+}
+
+/// writes events as NDJSON to `<dir>/<name>-<timestamp>-<resourceVersion>.ndjson[.gz|.zst]`
+///
+/// the file being written has an additional `.part` extension, next to it `.part.json` records how much of it is
+/// complete, so a file left behind by a crash can be finished on the next start
+#[derive(Debug)]
+pub struct FileSink {
+    name: String,
+    dir: PathBuf,
+    compression: Compression,
+    max_size: u64,
+    max_age: Duration,
+    current: Mutex<Option<OpenFile>>,
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    file: fs::File,
+    opened: Instant,
+    entry: IndexEntry,
+}
+
+impl FileSink {
+    pub fn new(config: &FileSinkConfig) -> Result<Self, SinkError> {
+        fs::create_dir_all(&config.dir).map_err(SinkError::State)?;
+        let sink = Self {
+            name: config.options.name.clone(),
+            dir: config.dir.clone(),
+            compression: config.compression,
+            max_size: config.max_file_size * 1024 * 1024,
+            max_age: Duration::from_secs(config.max_file_age_seconds),
+            current: Mutex::new(None),
+        };
+        sink.recover().map_err(SinkError::State)?;
+        Ok(sink)
+    }
+
+    fn part_path(&self, file: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", file))
+    }
+
+    fn progress_path(&self, file: &str) -> PathBuf {
+        self.dir.join(format!("{}.part.json", file))
+    }
+
+    /// finishes files left open by a previous run, dropping whatever was written after the last complete batch
+    fn recover(&self) -> Result<(), io::Error> {
+        let mut file_names = HashSet::new();
+        for dirent in fs::read_dir(&self.dir)? {
+            let file_name = dirent?.file_name().to_string_lossy().into_owned();
+            if self.owns(&file_name) {
+                file_names.insert(file_name);
+            }
+        }
+        let indexed = self.indexed()?;
+        for file_name in &file_names {
+            if let Some(file) = file_name.strip_suffix(".part.json") {
+                if indexed.contains(file) {
+                    // crashed after the index was written, but before the progress was removed
+                    fs::remove_file(self.dir.join(file_name))?;
+                    continue;
+                }
+                let entry: IndexEntry = serde_json::from_slice(&fs::read(self.dir.join(file_name))?)?;
+                let part = self.part_path(file);
+                if part.exists() {
+                    fs::OpenOptions::new().write(true).open(&part)?.set_len(entry.bytes)?;
+                }
+                tracing::info!(sink = self.name.as_str(), file, "finishing interrupted file");
+                self.finish(&entry)?;
+            } else if let Some(file) = file_name.strip_suffix(".part") {
+                // not a single batch was completed
+                if !file_names.contains(&format!("{}.part.json", file)) {
+                    fs::remove_file(self.dir.join(file_name))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// whether `file_name` is `<name>-<timestamp>-<resourceVersion>.*` of this sink,
+    /// and not of another one in the same directory, e.g. `<name>-2`
+    fn owns(&self, file_name: &str) -> bool {
+        let rest = match file_name
+            .strip_prefix(&self.name)
+            .and_then(|rest| rest.strip_prefix('-'))
+        {
+            Some(rest) => rest,
+            None => return false,
+        };
+        let (timestamp, rest) = match rest.split_once('-') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let resource_version = rest.split('.').next().unwrap_or_default();
+        NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).is_ok()
+            && !resource_version.is_empty()
+            && resource_version.bytes().all(|b| b.is_ascii_digit())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.index.ndjson", self.name))
+    }
+
+    /// files already recorded in the index
+    fn indexed(&self) -> Result<HashSet<String>, io::Error> {
+        let index = match fs::read_to_string(self.index_path()) {
+            Ok(index) => index,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(err) => return Err(err),
+        };
+        let mut files = HashSet::new();
+        for line in index.lines().filter(|line| !line.is_empty()) {
+            files.insert(serde_json::from_str::<IndexEntry>(line)?.file);
+        }
+        Ok(files)
+    }
+
+    fn open(&self, first_resource_version: Option<ResourceVersion>) -> Result<OpenFile, io::Error> {
+        let now = Utc::now();
+        let file = format!(
+            "{}-{}-{}.{}",
+            self.name,
+            now.format(TIMESTAMP_FORMAT),
+            first_resource_version.unwrap_or_default(),
+            self.compression.extension()
+        );
+        Ok(OpenFile {
+            file: fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(self.part_path(&file))?,
+            opened: Instant::now(),
+            entry: IndexEntry {
+                file,
+                first_resource_version,
+                last_resource_version: first_resource_version,
+                first_timestamp: now,
+                last_timestamp: now,
+                events: 0,
+                bytes: 0,
+            },
+        })
+    }
+
+    /// moves the file to its final name and records it in the index
+    fn finish(&self, entry: &IndexEntry) -> Result<(), io::Error> {
+        let part = self.part_path(&entry.file);
+        if part.exists() {
+            fs::rename(&part, self.dir.join(&entry.file))?;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut index = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.index_path())?;
+        index.write_all(&line)?;
+        index.sync_data()?;
+        fs::remove_file(self.progress_path(&entry.file))
+    }
+
+    fn write(&self, current: &mut Option<OpenFile>, events: &[OutputEvent]) -> Result<(), io::Error> {
+        if let Some(open) = current {
+            if open.entry.bytes >= self.max_size || open.opened.elapsed() >= self.max_age {
+                self.finish(&open.entry)?;
+                *current = None;
+            }
+        }
+        let resource_versions = events.iter().filter_map(OutputEvent::resource_version);
+        let open = match current {
+            Some(open) => open,
+            None => current.insert(self.open(resource_versions.clone().min())?),
+        };
+
+        let mut lines = Vec::new();
+        for evt in events {
+            lines.extend_from_slice(&evt.timestamped_line());
+        }
+        let encoded = self.compression.encode(lines)?;
+        // a previous attempt may have failed halfway
+        open.file.set_len(open.entry.bytes)?;
+        open.file.seek(SeekFrom::End(0))?;
+        open.file.write_all(&encoded)?;
+        open.file.sync_data()?;
+
+        let mut entry = open.entry.clone();
+        entry.last_resource_version = resource_versions.chain(entry.last_resource_version).max();
+        entry.last_timestamp = Utc::now();
+        entry.events += events.len() as u64;
+        entry.bytes += encoded.len() as u64;
+        let progress = self.progress_path(&entry.file);
+        let tmp = progress.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&entry)?)?;
+        fs::rename(&tmp, &progress)?;
+        open.entry = entry;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for FileSink {
+    async fn deliver(&self, events: &[OutputEvent]) -> Result<(), SinkError> {
+        let mut current = self.current.lock().expect("File sink lock poisoned");
+        self.write(&mut current, events).map_err(SinkError::State)
+    }
+
+    /// finishes the open file, instead of leaving it to be recovered on the next start
+    async fn close(&self) -> Result<(), SinkError> {
+        let mut current = self.current.lock().expect("File sink lock poisoned");
+        match current.take() {
+            Some(open) => self.finish(&open.entry).map_err(SinkError::State),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::{io::Read, path::Path};
+
+    fn event(name: &str, rv: u64) -> OutputEvent {
+        serde_json::from_value(json!({"type": "MODIFIED", "object": {"metadata": {
+            "name": name, "uid": name, "resourceVersion": rv.to_string(),
+        }}}))
+        .unwrap()
+    }
+
+    fn config(dir: &Path, compression: &str) -> FileSinkConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: archive, dir: {:?}, compression: {}}}",
+            dir, compression
+        ))
+        .unwrap()
+    }
+
+    fn index(dir: &Path) -> Vec<IndexEntry> {
+        fs::read_to_string(dir.join("archive.index.ndjson"))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn names(path: &Path, compression: Compression) -> Vec<String> {
+        let raw = fs::read(path).unwrap();
+        let mut decoded = String::new();
+        match compression {
+            Compression::None => decoded = String::from_utf8(raw).unwrap(),
+            Compression::Gzip => {
+                flate2::read::MultiGzDecoder::new(&raw[..])
+                    .read_to_string(&mut decoded)
+                    .unwrap();
+            }
+            Compression::Zstd => decoded = String::from_utf8(zstd::decode_all(&raw[..]).unwrap()).unwrap(),
+        }
+        decoded
+            .lines()
+            .map(|line| {
+                let evt: Value = serde_json::from_str(line).unwrap();
+                evt["object"]["metadata"]["name"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("big-brother-file-sink-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn rotation() {
+        for (compression, name) in &[(Compression::Gzip, "gzip"), (Compression::Zstd, "zstd")] {
+            let dir = dir(name);
+            let mut sink = FileSink::new(&config(&dir, name)).unwrap();
+            sink.max_size = 1;
+            tokio_test::block_on(async {
+                sink.deliver(&[event("a", 1), event("b", 2)]).await.unwrap();
+                sink.deliver(&[event("c", 5)]).await.unwrap();
+                sink.deliver(&[event("d", 6)]).await.unwrap();
+            });
+            let index = index(&dir);
+            assert_eq!(index.len(), 2);
+            assert_eq!(index[0].first_resource_version, Some(1));
+            assert_eq!(index[0].last_resource_version, Some(2));
+            assert_eq!(index[0].events, 2);
+            assert_eq!(index[1].first_resource_version, Some(5));
+            assert!(index[1].file.ends_with(compression.extension()));
+            assert_eq!(names(&dir.join(&index[0].file), *compression), vec!["a", "b"]);
+            assert_eq!(names(&dir.join(&index[1].file), *compression), vec!["c"]);
+        }
+    }
+
+    #[test]
+    fn recover() {
+        let dir = dir("recover");
+        let sink = FileSink::new(&config(&dir, "gzip")).unwrap();
+        tokio_test::block_on(async {
+            sink.deliver(&[event("a", 1)]).await.unwrap();
+            sink.deliver(&[event("b", 2)]).await.unwrap();
+        });
+        let file = sink.current.lock().unwrap().as_ref().unwrap().entry.file.clone();
+        drop(sink);
+        // crashed in the middle of the next batch
+        let mut part = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(format!("{}.part", file)))
+            .unwrap();
+        part.write_all(b"\x1f\x8b\x08garbage").unwrap();
+
+        FileSink::new(&config(&dir, "gzip")).unwrap();
+        let index = index(&dir);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].file, file);
+        assert_eq!(index[0].last_resource_version, Some(2));
+        assert_eq!(names(&dir.join(&file), Compression::Gzip), vec!["a", "b"]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+    /// a sink named `archive-2` in the same directory is left alone, even though its files start with `archive-`
+    #[test]
+    fn recover_similar_name() {
+        let dir = dir("recover-similar-name");
+        let other: FileSinkConfig = serde_yaml::from_str(&format!("{{name: archive-2, dir: {:?}}}", dir)).unwrap();
+        let sink = FileSink::new(&other).unwrap();
+        tokio_test::block_on(sink.deliver(&[event("a", 1)])).unwrap();
+        let file = sink.current.lock().unwrap().as_ref().unwrap().entry.file.clone();
+        drop(sink);
+
+        FileSink::new(&config(&dir, "none")).unwrap();
+        assert!(dir.join(format!("{}.part", file)).exists());
+        assert!(dir.join(format!("{}.part.json", file)).exists());
+        assert!(!dir.join("archive.index.ndjson").exists());
+    }
+
+    /// crashed between writing the index and removing the progress
+    #[test]
+    fn recover_indexed() {
+        let dir = dir("recover-indexed");
+        let sink = FileSink::new(&config(&dir, "none")).unwrap();
+        let file = tokio_test::block_on(async {
+            sink.deliver(&[event("a", 1)]).await.unwrap();
+            let file = sink.current.lock().unwrap().as_ref().unwrap().entry.file.clone();
+            let progress = fs::read(sink.progress_path(&file)).unwrap();
+            sink.close().await.unwrap();
+            fs::write(sink.progress_path(&file), progress).unwrap();
+            file
+        });
+        drop(sink);
+
+        FileSink::new(&config(&dir, "none")).unwrap();
+        let index = index(&dir);
+        assert_eq!(index.len(), 1);
+        assert_eq!(names(&dir.join(&file), Compression::None), vec!["a"]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn close() {
+        let dir = dir("close");
+        let sink = FileSink::new(&config(&dir, "zstd")).unwrap();
+        tokio_test::block_on(async {
+            sink.deliver(&[event("a", 1)]).await.unwrap();
+            sink.close().await.unwrap();
+        });
+        let index = index(&dir);
+        assert_eq!(index.len(), 1);
+        assert_eq!(names(&dir.join(&index[0].file), Compression::Zstd), vec!["a"]);
+        // for `--replay-speed`
+        let raw = zstd::decode_all(&fs::read(dir.join(&index[0].file)).unwrap()[..]).unwrap();
+        let line: Value = serde_json::from_slice(&raw).unwrap();
+        let timestamp: DateTime<Utc> = serde_json::from_value(line["timestamp"].clone()).unwrap();
+        assert!((timestamp - index[0].first_timestamp).num_seconds().abs() < 5);
+        // only the finished file and the index are left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,725 @@
+mod file;
+mod webhook;
+
+use crate::{
+    engine::{Cache, Meta, OutputEvent, PendingLists},
+    k8s_client::api::{ResourceId, ResourceVersion},
+    shutdown::Shutdown,
+};
+use backoff::{future::retry_notify, ExponentialBackoff};
+pub use file::{Compression, FileSink, FileSinkConfig};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tokio_stream::{wrappers::errors::BroadcastStreamRecvError, StreamExt};
+pub use webhook::{WebhookConfig, WebhookSink};
+
+/// content of the `--sinks` file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SinksConfig {
+    /// delivery cursors are persisted here, one file per sink
+    pub state_dir: PathBuf,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub files: Vec<FileSinkConfig>,
+}
+
+/// settings shared by all kinds of sinks, written next to those of the sink: see `WebhookEntry` and `FileSinkEntry`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SinkOptions {
+    /// unique, also names the cursor file
+    pub name: String,
+    pub filter: SinkFilter,
+    /// events are delivered once this many have been collected...
+    pub batch_size: usize,
+    /// ...or this long after the first one arrived
+    pub batch_delay_ms: u64,
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+fn default_batch_delay_ms() -> u64 {
+    1000
+}
+
+/// omitted means unrestricted
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkFilter {
+    #[serde(default)]
+    pub kinds: Option<HashSet<String>>,
+    #[serde(default)]
+    pub namespaces: Option<HashSet<String>>,
+    /// all of the labels must match, deletions by the labels the object had
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl SinkFilter {
+    pub fn matches(&self, res: &ResourceId, meta: &Meta) -> bool {
+        if let Some(kinds) = &self.kinds {
+            if !kinds.contains(&res.kind) {
+                return false;
+            }
+        }
+        if let Some(namespaces) = &self.namespaces {
+            match &res.namespace {
+                Some(namespace) if namespaces.contains(namespace) => {}
+                _ => return false,
+            }
+        }
+        self.labels.iter().all(|(key, value)| meta.label(key) == Some(value))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SinkError {
+    #[error("Could not read sinks config: {:?}", _0)]
+    Config(#[source] io::Error),
+    #[error("Could not access sink state: {:?}", _0)]
+    State(#[source] io::Error),
+    #[error("Invalid sink {:?}: {}", _0, _1)]
+    Invalid(String, String),
+    #[error("Request error: {:?}", _0)]
+    Request(#[from] reqwest::Error),
+    #[error("Receiver responded with {}", _0)]
+    Status(reqwest::StatusCode),
+}
+
+#[async_trait::async_trait]
+pub trait Sink: Send + Sync {
+    /// called again with the same events until it succeeds
+    async fn deliver(&self, events: &[OutputEvent]) -> Result<(), SinkError>;
+    /// called once after the last delivery on shutdown
+    async fn close(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// what `/status` reports per sink
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SinkStatus {
+    /// resourceVersion of the last delivered event
+    pub cursor: Option<ResourceVersion>,
+    pub delivered_events: u64,
+    /// events collected but not delivered yet
+    pub pending_events: usize,
+    /// age of the oldest undelivered event
+    pub lag_seconds: f64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SinkStatuses(Arc<Mutex<BTreeMap<String, SinkState>>>);
+
+#[derive(Debug, Default)]
+struct SinkState {
+    status: SinkStatus,
+    /// arrival of the oldest undelivered event, `lag_seconds` is computed from it
+    oldest_pending: Option<Instant>,
+}
+
+impl SinkStatuses {
+    pub fn get(&self) -> BTreeMap<String, SinkStatus> {
+        let statuses = self.0.lock().expect("Sink status lock poisoned");
+        statuses
+            .iter()
+            .map(|(name, state)| {
+                let lag_seconds = state
+                    .oldest_pending
+                    .map(|t| t.elapsed().as_secs_f64())
+                    .unwrap_or_default();
+                (
+                    name.clone(),
+                    SinkStatus {
+                        lag_seconds,
+                        ..state.status.clone()
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// forgets sinks that no longer exist
+    fn retain(&self, names: &HashSet<String>) {
+        let mut statuses = self.0.lock().expect("Sink status lock poisoned");
+        statuses.retain(|name, _| names.contains(name));
+    }
+
+    fn update<F: FnOnce(&mut SinkStatus, &mut Option<Instant>)>(&self, name: &str, f: F) {
+        let mut statuses = self.0.lock().expect("Sink status lock poisoned");
+        let state = statuses.entry(name.to_string()).or_default();
+        f(&mut state.status, &mut state.oldest_pending)
+    }
+}
+
+impl SinksConfig {
+    pub fn from_path(path: &Path) -> Result<Self, SinkError> {
+        let file = fs::File::open(path).map_err(SinkError::Config)?;
+        serde_yaml::from_reader(file).map_err(|err| SinkError::Config(io::Error::new(io::ErrorKind::InvalidData, err)))
+    }
+
+    /// validates all sinks, file sinks also finish files left behind by a previous run
+    fn runners(&self) -> Result<Vec<SinkRunner>, SinkError> {
+        fs::create_dir_all(&self.state_dir).map_err(SinkError::State)?;
+        let mut runners = Vec::new();
+        for webhook in &self.webhooks {
+            let sink = WebhookSink::new(webhook)?;
+            runners.push(SinkRunner::new(
+                webhook.options.clone(),
+                &self.state_dir,
+                Box::new(sink),
+            ));
+        }
+        for file in &self.files {
+            let sink = FileSink::new(file)?;
+            runners.push(SinkRunner::new(file.options.clone(), &self.state_dir, Box::new(sink)));
+        }
+        let mut names = HashSet::new();
+        for runner in &runners {
+            if !names.insert(runner.options.name.clone()) {
+                return Err(SinkError::Invalid(runner.options.name.clone(), "duplicate name".into()));
+            }
+            runner.cursor()?;
+        }
+        Ok(runners)
+    }
+}
+
+/// the running sinks, each delivering from its own task, replaced as a whole when the configuration changes
+#[derive(Debug)]
+pub struct Sinks {
+    cache: Arc<RwLock<Cache>>,
+    pending: PendingLists,
+    statuses: SinkStatuses,
+    config: Option<SinksConfig>,
+    shutdown: Shutdown,
+    tasks: SinkTasks,
+}
+
+/// the tasks of the current sinks, they deliver what they have collected and end on shutdown
+#[derive(Debug, Clone, Default)]
+pub struct SinkTasks(Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl SinkTasks {
+    fn take(&self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut *self.0.lock().expect("Sink tasks lock poisoned"))
+    }
+
+    pub async fn join(&self) {
+        for task in self.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Sinks {
+    pub fn new(cache: Arc<RwLock<Cache>>, pending: PendingLists, statuses: SinkStatuses, shutdown: Shutdown) -> Self {
+        Self {
+            cache,
+            pending,
+            statuses,
+            config: None,
+            shutdown,
+            tasks: SinkTasks::default(),
+        }
+    }
+
+    pub fn tasks(&self) -> SinkTasks {
+        self.tasks.clone()
+    }
+
+    pub fn config(&self) -> Option<&SinksConfig> {
+        self.config.as_ref()
+    }
+
+    /// on error the previous sinks keep running
+    pub async fn replace(&mut self, config: Option<SinksConfig>) -> Result<(), SinkError> {
+        // stopped first, a file sink must not recover the file its predecessor is still writing
+        for task in self.tasks.take() {
+            task.abort();
+            let _ = task.await;
+        }
+        let runners = match config.as_ref().map(SinksConfig::runners).transpose() {
+            Ok(runners) => runners.unwrap_or_default(),
+            Err(err) => {
+                let previous = self.config.as_ref().map(SinksConfig::runners).transpose();
+                self.spawn(previous.unwrap_or_default().unwrap_or_default());
+                return Err(err);
+            }
+        };
+        self.spawn(runners);
+        self.config = config;
+        Ok(())
+    }
+
+    fn spawn(&mut self, runners: Vec<SinkRunner>) {
+        let names = runners.iter().map(|runner| runner.options.name.clone()).collect();
+        self.statuses.retain(&names);
+        for runner in runners {
+            self.statuses.update(&runner.options.name, |_, _| {});
+            let cache = Arc::clone(&self.cache);
+            let pending = self.pending.clone();
+            let statuses = self.statuses.clone();
+            let shutdown = self.shutdown.clone();
+            let task = tokio::task::spawn(async move { runner.run(cache, pending, statuses, shutdown).await });
+            self.tasks.0.lock().expect("Sink tasks lock poisoned").push(task);
+        }
+    }
+}
+
+struct SinkRunner {
+    options: SinkOptions,
+    cursor_path: PathBuf,
+    sent_path: PathBuf,
+    sink: Box<dyn Sink>,
+}
+
+/// one line of `<name>.objects`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SentLine {
+    api_version: String,
+    kind: String,
+    name: String,
+    namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+}
+
+/// the objects a sink was sent and not the deletion of, appended to `<name>.objects` and compacted on start, so the
+/// deletions made while big-brother was down, which the cache has no tombstones for, are sent after a restart
+struct Sent {
+    path: PathBuf,
+    objects: HashSet<ResourceId>,
+    /// in the file, it is compacted once most of them are outdated
+    lines: usize,
+}
+
+impl Sent {
+    fn load(path: PathBuf) -> Result<Self, SinkError> {
+        let mut objects = HashSet::new();
+        match fs::read_to_string(&path) {
+            // a line cut off by a crash is ignored, its batch was delivered again after the restart
+            Ok(content) => {
+                for line in content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<SentLine>(line).ok())
+                {
+                    let res = ResourceId {
+                        api_version: line.api_version,
+                        kind: line.kind,
+                        name: line.name,
+                        namespace: line.namespace,
+                    };
+                    match line.deleted {
+                        true => objects.remove(&res),
+                        false => objects.insert(res),
+                    };
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SinkError::State(err)),
+        }
+        let mut sent = Self {
+            path,
+            objects,
+            lines: 0,
+        };
+        sent.compact()?;
+        Ok(sent)
+    }
+
+    fn line(res: &ResourceId, deleted: bool) -> Vec<u8> {
+        let line = SentLine {
+            api_version: res.api_version.clone(),
+            kind: res.kind.clone(),
+            name: res.name.clone(),
+            namespace: res.namespace.clone(),
+            deleted,
+        };
+        let mut line = serde_json::to_vec(&line).expect("serializing ids can't fail");
+        line.push(b'\n');
+        line
+    }
+
+    fn compact(&mut self) -> Result<(), SinkError> {
+        // written to a temporary file first, like the cursor
+        let tmp = self.path.with_extension("objects.tmp");
+        let content = self
+            .objects
+            .iter()
+            .flat_map(|res| Self::line(res, false))
+            .collect::<Vec<_>>();
+        fs::write(&tmp, content).map_err(SinkError::State)?;
+        fs::rename(&tmp, &self.path).map_err(SinkError::State)?;
+        self.lines = self.objects.len();
+        Ok(())
+    }
+
+    /// records the objects that are sent or deleted for the first time
+    fn record<'a>(&mut self, changes: impl Iterator<Item = (&'a ResourceId, bool)>) -> Result<(), SinkError> {
+        let mut appended = Vec::new();
+        for (res, deleted) in changes {
+            let changed = match deleted {
+                true => self.objects.remove(res),
+                false => self.objects.insert(res.clone()),
+            };
+            if changed {
+                appended.extend(Self::line(res, deleted));
+                self.lines += 1;
+            }
+        }
+        if appended.is_empty() {
+            return Ok(());
+        }
+        if self.lines > 2 * self.objects.len() + 1024 {
+            return self.compact();
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(SinkError::State)?;
+        file.write_all(&appended).map_err(SinkError::State)
+    }
+
+    /// deletions of the objects that were sent but aren't cached anymore, unless the cache still replays their
+    /// deletion after `cursor`, at `cursor` so the cursor doesn't move past anything
+    fn gone(&self, cache: &Cache, cursor: Option<ResourceVersion>) -> Vec<(Arc<ResourceId>, OutputEvent)> {
+        let replayed = cache
+            .deleted_since(cursor.map_or(0, |rv| rv + 1))
+            .map(|(res, _)| res)
+            .collect::<HashSet<_>>();
+        self.objects
+            .iter()
+            .filter(|res| cache.get(res).is_none() && !replayed.contains(res))
+            .map(|res| {
+                let event = OutputEvent::deleted(res.clone(), cursor.unwrap_or_default());
+                (Arc::new(res.clone()), event)
+            })
+            .collect()
+    }
+}
+
+impl SinkRunner {
+    fn new(options: SinkOptions, state_dir: &Path, sink: Box<dyn Sink>) -> Self {
+        let cursor_path = state_dir.join(format!("{}.cursor", options.name));
+        let sent_path = state_dir.join(format!("{}.objects", options.name));
+        Self {
+            options,
+            cursor_path,
+            sent_path,
+            sink,
+        }
+    }
+
+    fn cursor(&self) -> Result<Option<ResourceVersion>, SinkError> {
+        match fs::read_to_string(&self.cursor_path) {
+            Ok(cursor) => cursor.trim().parse().map(Some).map_err(|_| {
+                SinkError::State(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid cursor in {:?}", self.cursor_path),
+                ))
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(SinkError::State(err)),
+        }
+    }
+
+    fn persist(&self, cursor: ResourceVersion) -> Result<(), SinkError> {
+        // written to a temporary file first, so a crash can't leave a truncated cursor behind
+        let tmp = self.cursor_path.with_extension("cursor.tmp");
+        fs::write(&tmp, cursor.to_string()).map_err(SinkError::State)?;
+        fs::rename(&tmp, &self.cursor_path).map_err(SinkError::State)
+    }
+
+    async fn run(self, cache: Arc<RwLock<Cache>>, pending: PendingLists, statuses: SinkStatuses, shutdown: Shutdown) {
+        let name = self.options.name.clone();
+        // replaying from an incomplete cache would advance the cursor past objects that are still being listed
+        while !pending.get().is_empty() {
+            if shutdown.is_requested() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let stopped = shutdown.requested();
+        tokio::pin!(stopped);
+        let (mut cursor, mut sent) = match self
+            .cursor()
+            .and_then(|cursor| Ok((cursor, Sent::load(self.sent_path.clone())?)))
+        {
+            Ok(state) => state,
+            Err(err) => return tracing::error!(sink = name.as_str(), error = %err, "sink stopped"),
+        };
+        statuses.update(&name, |status, _| status.cursor = cursor);
+        let mut gone = sent.gone(&*cache.read().await, cursor);
+        if !gone.is_empty() {
+            tracing::info!(
+                sink = name.as_str(),
+                deleted = gone.len(),
+                "sending the deletions of objects that are gone since the last run"
+            );
+            cursor = self.flush(&mut gone, cursor, &statuses, &mut sent).await;
+        }
+        let batch_delay = Duration::from_millis(self.options.batch_delay_ms);
+        loop {
+            // everything newer than the cursor is replayed from the cache, then changes follow live
+            let stream = cache.read().await.stream(cursor.map(|rv| rv + 1), ());
+            let mut stream = Box::pin(stream);
+            let mut batch = Vec::new();
+            let mut deadline = None;
+            let lagged = loop {
+                let next = tokio::select! {
+                    _ = &mut stopped => {
+                        self.flush(&mut batch, cursor, &statuses, &mut sent).await;
+                        if let Err(err) = self.sink.close().await {
+                            tracing::error!(sink = name.as_str(), error = %err, "could not close the sink");
+                        }
+                        return;
+                    }
+                    next = async {
+                        match deadline {
+                            None => Some(stream.next().await),
+                            Some(until) => tokio::time::timeout_at(until, stream.next()).await.ok(),
+                        }
+                    } => next,
+                };
+                let next = match next {
+                    Some(next) => next,
+                    // the batch is due
+                    None => {
+                        cursor = self.flush(&mut batch, cursor, &statuses, &mut sent).await;
+                        deadline = None;
+                        continue;
+                    }
+                };
+                match next {
+                    None => return,
+                    Some(Err(BroadcastStreamRecvError::Lagged(n))) => break n,
+                    Some(Ok((res, evt))) => {
+                        if !self.options.filter.matches(&res, evt.meta()) {
+                            continue;
+                        }
+                        if batch.is_empty() {
+                            deadline = Some(tokio::time::Instant::now() + batch_delay);
+                            statuses.update(&name, |_, oldest| *oldest = Some(Instant::now()));
+                        }
+                        batch.push((res, evt));
+                        statuses.update(&name, |status, _| status.pending_events = batch.len());
+                        if batch.len() >= self.options.batch_size {
+                            cursor = self.flush(&mut batch, cursor, &statuses, &mut sent).await;
+                            deadline = None;
+                        }
+                    }
+                }
+            };
+            // missed events are recovered by replaying from the cursor
+            tracing::warn!(
+                sink = name.as_str(),
+                lagged,
+                "sink fell behind, resuming from the cache"
+            );
+            cursor = self.flush(&mut batch, cursor, &statuses, &mut sent).await;
+        }
+    }
+
+    /// delivers the batch, retrying until it succeeds, and returns the new cursor
+    async fn flush(
+        &self,
+        batch: &mut Vec<(Arc<ResourceId>, OutputEvent)>,
+        cursor: Option<ResourceVersion>,
+        statuses: &SinkStatuses,
+        sent: &mut Sent,
+    ) -> Option<ResourceVersion> {
+        if batch.is_empty() {
+            return cursor;
+        }
+        let name = &self.options.name;
+        // recorded before they are delivered, a crash in between may only send the deletion of an object too many
+        let added = batch.iter().filter(|(_, event)| !event.is_deleted());
+        if let Err(err) = sent.record(added.map(|(res, _)| (&**res, false))) {
+            tracing::error!(sink = name, error = %err, "could not record the sent objects");
+        }
+        let backoff = ExponentialBackoff {
+            max_elapsed_time: None,
+            max_interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let events = batch.iter().map(|(_, event)| event.clone()).collect::<Vec<_>>();
+        let events = &events[..];
+        let deliver = || async move { self.sink.deliver(events).await.map_err(backoff::Error::transient) };
+        let notify = |err: SinkError, wait: Duration| {
+            tracing::warn!(
+                sink = name,
+                error = %err,
+                retry_in_ms = wait.as_millis() as u64,
+                "delivery failed, retrying"
+            );
+            statuses.update(name, |status, _| status.last_error = Some(err.to_string()));
+        };
+        // only transient errors are produced, so this can't fail
+        let _ = retry_notify(backoff, deliver, notify).await;
+        let deleted = batch.iter().filter(|(_, event)| event.is_deleted());
+        if let Err(err) = sent.record(deleted.map(|(res, _)| (&**res, true))) {
+            tracing::error!(sink = name, error = %err, "could not record the sent objects");
+        }
+
+        let delivered = batch.len();
+        let cursor = batch
+            .iter()
+            .filter_map(|(_, event)| event.resource_version())
+            .chain(cursor)
+            .max();
+        batch.clear();
+        if let Some(cursor) = cursor {
+            if let Err(err) = self.persist(cursor) {
+                tracing::error!(sink = name, error = %err, "could not persist the cursor");
+            }
+        }
+        statuses.update(name, |status, oldest| {
+            status.cursor = cursor;
+            status.delivered_events += delivered as u64;
+            status.pending_events = 0;
+            status.last_error = None;
+            *oldest = None;
+        });
+        cursor
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn pod(namespace: &str) -> ResourceId {
+        ResourceId {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: "p".into(),
+            namespace: Some(namespace.into()),
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter: SinkFilter = serde_yaml::from_str("{kinds: [Pod], namespaces: [a], labels: {app: web}}").unwrap();
+        let meta = |labels: Value| Meta::of(&json!({"metadata": {"uid": "1", "labels": labels}}));
+        assert!(filter.matches(&pod("a"), &meta(json!({"app": "web", "tier": "x"}))));
+        assert!(!filter.matches(&pod("a"), &meta(json!({"app": "db"}))));
+        assert!(!filter.matches(&pod("a"), &Meta::of(&json!({"metadata": {"uid": "1"}}))));
+        assert!(!filter.matches(&pod("b"), &meta(json!({"app": "web"}))));
+    }
+
+    /// a misspelled filter would deliver everything
+    #[test]
+    fn unknown_fields() {
+        let parse = |webhook: &str, file: &str| {
+            serde_yaml::from_str::<SinksConfig>(&format!(
+                "{{stateDir: /s, webhooks: [{{name: w, url: 'http://w', {}}}], files: [{{name: f, dir: /f, {}}}]}}",
+                webhook, file
+            ))
+        };
+        let config = parse("namespaces: [a], batchSize: 10", "kinds: [Pod], labels: {app: web}").unwrap();
+        assert_eq!(
+            config.webhooks[0].options.filter.namespaces,
+            Some(HashSet::from(["a".into()]))
+        );
+        assert_eq!(config.webhooks[0].options.batch_size, 10);
+        assert_eq!(config.webhooks[0].options.batch_delay_ms, 1000);
+        assert_eq!(
+            config.files[0].options.filter.kinds,
+            Some(HashSet::from(["Pod".into()]))
+        );
+        assert_eq!(config.files[0].options.filter.labels["app"], "web");
+
+        for (webhook, file) in [
+            ("namspace: [a]", ""),
+            ("", "kind: [Pod]"),
+            ("labelSelectr: {app: web}", ""),
+        ] {
+            let err = parse(webhook, file).unwrap_err().to_string();
+            assert!(err.contains("unknown field"), "{}", err);
+        }
+        assert!(serde_yaml::from_str::<SinksConfig>("{stateDir: /s, webhook: []}").is_err());
+    }
+
+    #[derive(Default)]
+    struct Recording(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait::async_trait]
+    impl Sink for Recording {
+        async fn deliver(&self, events: &[OutputEvent]) -> Result<(), SinkError> {
+            let mut delivered = self.0.lock().unwrap();
+            for event in events {
+                let line: Value = serde_json::from_slice(event.line()).unwrap();
+                delivered.push(format!(
+                    "{} {}",
+                    line["type"].as_str().unwrap(),
+                    line["object"]["metadata"]["name"]
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /// objects deleted while big-brother was down have no tombstone, their deletion is sent after the restart
+    #[tokio::test]
+    async fn reconcile() {
+        let dir = std::env::temp_dir().join(format!("big-brother-sink-reconcile-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let named = |name: &str| ResourceId {
+            name: name.into(),
+            ..pod("a")
+        };
+        fs::write(dir.join("s.cursor"), "3").unwrap();
+        let log = [Sent::line(&named("a"), false), Sent::line(&named("b"), false)].concat();
+        fs::write(dir.join("s.objects"), log).unwrap();
+        let mut cache = Cache::with_capacity(16, Default::default());
+        cache.update(
+            named("b"),
+            2,
+            json!({ "metadata": { "name": "b", "resourceVersion": "2" } }),
+        );
+        cache.update(
+            named("c"),
+            5,
+            json!({ "metadata": { "name": "c", "resourceVersion": "5" } }),
+        );
+        let cache = Arc::new(RwLock::new(cache));
+
+        let options = SinkOptions {
+            name: "s".into(),
+            filter: SinkFilter::default(),
+            batch_size: 100,
+            batch_delay_ms: 10,
+        };
+        let sink = Recording::default();
+        let delivered = Arc::clone(&sink.0);
+        let runner = SinkRunner::new(options, &dir, Box::new(sink));
+        let (trigger, shutdown) = crate::shutdown::channel();
+        let task = tokio::spawn(runner.run(cache, PendingLists::default(), SinkStatuses::default(), shutdown));
+        while delivered.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        trigger.trigger();
+        task.await.unwrap();
+        assert_eq!(*delivered.lock().unwrap(), [r#"DELETED "a""#, r#"MODIFIED "c""#]);
+        assert_eq!(fs::read_to_string(dir.join("s.cursor")).unwrap(), "5");
+        let sent = Sent::load(dir.join("s.objects")).unwrap();
+        assert_eq!(sent.objects, HashSet::from([named("b"), named("c")]));
+    }
+}
@@ -0,0 +1,251 @@
+use super::{default_batch_delay_ms, default_batch_size, Sink, SinkError, SinkFilter, SinkOptions};
+use crate::engine::OutputEvent;
+use reqwest::{header, Client, Url};
+use ring::hmac;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    fs,
+    path::PathBuf,
+};
+
+/// header carrying `sha256=<hex HMAC of the body>` when a secret is configured
+pub const SIGNATURE_HEADER: &str = "X-Big-Brother-Signature";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "WebhookEntry")]
+pub struct WebhookConfig {
+    pub options: SinkOptions,
+    pub url: String,
+    /// HMAC-SHA256 key, re-read on every delivery so it can be rotated without restart
+    pub secret_file: Option<PathBuf>,
+}
+
+/// a webhook as written, with the options of every sink next to its own: `#[serde(flatten)]` can't reject unknown
+/// fields, a misspelled filter (`namespace: [prod]`) would deliver everything
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct WebhookEntry {
+    name: String,
+    url: String,
+    secret_file: Option<PathBuf>,
+    #[serde(default)]
+    kinds: Option<HashSet<String>>,
+    #[serde(default)]
+    namespaces: Option<HashSet<String>>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default = "default_batch_size")]
+    batch_size: usize,
+    #[serde(default = "default_batch_delay_ms")]
+    batch_delay_ms: u64,
+}
+
+impl From<WebhookEntry> for WebhookConfig {
+    fn from(entry: WebhookEntry) -> Self {
+        Self {
+            options: SinkOptions {
+                name: entry.name,
+                filter: SinkFilter {
+                    kinds: entry.kinds,
+                    namespaces: entry.namespaces,
+                    labels: entry.labels,
+                },
+                batch_size: entry.batch_size,
+                batch_delay_ms: entry.batch_delay_ms,
+            },
+            url: entry.url,
+            secret_file: entry.secret_file,
+        }
+    }
+}
+
+/// POSTs `{"events": [...]}` to `url`
+#[derive(Debug)]
+pub struct WebhookSink {
+    client: Client,
+    url: Url,
+    secret_file: Option<PathBuf>,
+}
+
+impl WebhookSink {
+    pub fn new(config: &WebhookConfig) -> Result<Self, SinkError> {
+        let invalid = |reason: String| SinkError::Invalid(config.options.name.clone(), reason);
+        let url = Url::parse(&config.url).map_err(|err| invalid(format!("invalid url: {}", err)))?;
+        let sink = Self {
+            client: Client::new(),
+            url,
+            secret_file: config.secret_file.clone(),
+        };
+        // just to fail early in case the secret is unreadable
+        sink.key()
+            .map_err(|err| invalid(format!("unreadable secret: {}", err)))?;
+        Ok(sink)
+    }
+
+    fn key(&self) -> Result<Option<hmac::Key>, SinkError> {
+        let path = match &self.secret_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let secret = fs::read_to_string(path).map_err(SinkError::State)?;
+        Ok(Some(hmac::Key::new(hmac::HMAC_SHA256, secret.trim_end().as_bytes())))
+    }
+}
+
+pub fn signature(key: &hmac::Key, body: &[u8]) -> String {
+    let tag = hmac::sign(key, body);
+    let mut signature = "sha256=".to_string();
+    for byte in tag.as_ref() {
+        write!(signature, "{:02x}", byte).expect("Writing to a String can't fail");
+    }
+    signature
+}
+
+/// `{"events":[...]}`, put together from the lines the events are already serialized to
+fn body(events: &[OutputEvent]) -> Vec<u8> {
+    let mut body = br#"{"events":["#.to_vec();
+    for (i, evt) in events.iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        body.extend_from_slice(evt.line().strip_suffix(b"\n").unwrap_or(evt.line()));
+    }
+    body.extend_from_slice(b"]}");
+    body
+}
+
+#[async_trait::async_trait]
+impl Sink for WebhookSink {
+    async fn deliver(&self, events: &[OutputEvent]) -> Result<(), SinkError> {
+        let body = body(events);
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(key) = self.key()? {
+            req = req.header(SIGNATURE_HEADER, signature(&key, &body));
+        }
+        let resp = req.body(body).send().await?;
+        match resp.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(SinkError::Status(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        engine::{Cache, PendingLists},
+        k8s_client::api::ResourceId,
+        sink::{SinkStatuses, Sinks, SinksConfig},
+    };
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::sync::RwLock;
+
+    type Received = Arc<Mutex<Vec<(Option<String>, web::Bytes)>>>;
+
+    async fn receive(req: HttpRequest, body: web::Bytes, received: web::Data<Received>) -> HttpResponse {
+        let mut received = received.lock().unwrap();
+        // the first delivery fails, to be retried
+        if received.is_empty() {
+            received.push((None, web::Bytes::new()));
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .map(|sig| sig.to_str().unwrap().to_string());
+        received.push((signature, body));
+        HttpResponse::Ok().finish()
+    }
+
+    fn pod(name: &str) -> ResourceId {
+        ResourceId {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: name.into(),
+            namespace: Some("default".into()),
+        }
+    }
+
+    fn object(name: &str, rv: u64, app: &str) -> Value {
+        json!({"apiVersion": "v1", "kind": "Pod", "metadata": {
+            "name": name, "namespace": "default", "uid": name, "resourceVersion": rv.to_string(), "labels": {"app": app},
+        }})
+    }
+
+    #[test]
+    fn deliver() {
+        actix_web::rt::System::new().block_on(deliver_inner());
+    }
+
+    async fn deliver_inner() {
+        let received = Received::default();
+        let data = web::Data::new(Arc::clone(&received));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/hook", web::post().to(receive))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let dir = std::env::temp_dir().join(format!("big-brother-webhook-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("secret"), "s3cret\n").unwrap();
+        let config: SinksConfig = serde_yaml::from_str(&format!(
+            "{{stateDir: {:?}, webhooks: [{{name: hook, url: 'http://{}/hook', secretFile: {:?}, labels: {{app: web}}, batchSize: 2, batchDelayMs: 1000}}]}}",
+            dir.join("state"),
+            addr,
+            dir.join("secret"),
+        ))
+        .unwrap();
+
+        let cache = Arc::new(RwLock::new(Cache::new()));
+        cache.write().await.update(pod("a"), 10, object("a", 10, "web"));
+        let statuses = SinkStatuses::default();
+        let (_trigger, shutdown) = crate::shutdown::channel();
+        let mut sinks = Sinks::new(Arc::clone(&cache), PendingLists::default(), statuses.clone(), shutdown);
+        sinks.replace(Some(config)).await.unwrap();
+
+        // filtered out
+        cache.write().await.update(pod("b"), 11, object("b", 11, "db"));
+        cache.write().await.update(pod("c"), 12, object("c", 12, "web"));
+
+        let status = loop {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let status = statuses.get()["hook"].clone();
+            if status.delivered_events == 2 {
+                break status;
+            }
+        };
+        assert_eq!(status.cursor, Some(12));
+        assert_eq!(fs::read_to_string(dir.join("state/hook.cursor")).unwrap(), "12");
+
+        let received = received.lock().unwrap();
+        let (signature, body) = &received[1];
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+        assert_eq!(signature.as_deref(), Some(super::signature(&key, body).as_str()));
+        let body: Value = serde_json::from_slice(body).unwrap();
+        let names = body["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|evt| evt["object"]["metadata"]["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "c"]);
+    }
+}
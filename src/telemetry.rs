@@ -0,0 +1,245 @@
+use actix_web::dev::ServiceRequest;
+use chrono::{DateTime, Utc};
+use opentelemetry::{
+    trace::{SpanContext, TraceContextExt, TraceError, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{self, BatchSpanProcessor, Sampler, Tracer, TracerProvider},
+    Resource,
+};
+use serde::Deserialize;
+use std::{io, time::Instant};
+use tracing::{field, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// `telemetry` in the configuration file, spans are sent to `<otlpEndpoint>/v1/traces` as OTLP/HTTP protobuf
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// fraction of upstream events and requests that are traced, the spans they cause follow their decision
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_service_name() -> String {
+    "big-brother".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+impl TelemetryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sample_ratio.is_nan() || !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err("telemetry: sampleRatio must be between 0 and 1".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TelemetryError {
+    #[error("Could not start the exporter runtime: {:?}", _0)]
+    Runtime(#[source] io::Error),
+    #[error("Invalid OTLP exporter configuration: {}", _0)]
+    Exporter(#[from] TraceError),
+}
+
+/// exports in the background, the spans still queued are flushed when this is dropped
+#[derive(Debug)]
+pub struct Telemetry {
+    // dropped before the runtime, which has to keep running for the final export
+    provider: TracerProvider,
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl Telemetry {
+    /// the exporter gets a runtime of its own, so a busy or blocked server thread can't hold up exports
+    pub fn new(config: &TelemetryConfig) -> Result<Self, TelemetryError> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("telemetry")
+            .enable_all()
+            .build()
+            .map_err(TelemetryError::Runtime)?;
+        let _guard = runtime.enter();
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(config.otlp_endpoint.trim_end_matches('/'))
+            .build_span_exporter()?;
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+        let provider = TracerProvider::builder()
+            .with_span_processor(BatchSpanProcessor::builder(exporter, runtime::Tokio).build())
+            .with_config(
+                trace::config()
+                    .with_sampler(sampler)
+                    .with_resource(Resource::new([KeyValue::new(
+                        "service.name",
+                        config.service_name.clone(),
+                    )])),
+            )
+            .build();
+        Ok(Self {
+            provider,
+            _runtime: runtime,
+        })
+    }
+
+    pub fn tracer(&self) -> Tracer {
+        self.provider.tracer(env!("CARGO_PKG_NAME"))
+    }
+
+    /// blocks until the queued spans have been exported
+    #[cfg(test)]
+    pub fn flush(&self) {
+        for result in self.provider.force_flush() {
+            if let Err(err) = result {
+                tracing::warn!(error = %err, "could not export spans");
+            }
+        }
+    }
+}
+
+/// the span an event was cached in and when, to trace and time its way to the subscribers
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub span: SpanContext,
+    pub cached: Instant,
+    /// `cached` as wall-clock time, recorded by file sinks
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Origin {
+    pub fn current() -> Self {
+        Self {
+            span: Span::current().context().span().span_context().clone(),
+            cached: Instant::now(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// child of the span that cached the event, `latency_ms` is recorded by `delivered`
+    pub fn deliver_span(&self, client: &str) -> Span {
+        let span = tracing::info_span!(parent: None, "deliver", client, latency_ms = field::Empty);
+        if self.span.is_valid() {
+            span.set_parent(Context::new().with_remote_span_context(self.span.clone()));
+        }
+        span
+    }
+
+    pub fn delivered(&self, span: &Span) {
+        let latency_ms = self.cached.elapsed().as_secs_f64() * 1000.0;
+        span.record("latency_ms", latency_ms);
+        span.in_scope(|| tracing::debug!(latency_ms, "event delivered"));
+    }
+}
+
+/// root span of an incoming request, `status` is recorded once the handler is done
+pub fn request_span(req: &ServiceRequest) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = req.path(),
+        peer = req.peer_addr().map(|addr| addr.to_string()).unwrap_or_default().as_str(),
+        status = field::Empty,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    type Received = Arc<Mutex<Vec<(String, web::Bytes)>>>;
+
+    async fn receive(req: HttpRequest, body: web::Bytes, received: web::Data<Received>) -> HttpResponse {
+        let content_type = req
+            .headers()
+            .get("content-type")
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        received.lock().unwrap().push((content_type, body));
+        HttpResponse::Ok().finish()
+    }
+
+    #[test]
+    fn export() {
+        actix_web::rt::System::new().block_on(export_inner());
+    }
+
+    async fn export_inner() {
+        let received = Received::default();
+        let data = web::Data::new(Arc::clone(&received));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/v1/traces", web::post().to(receive))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let telemetry = Telemetry::new(&TelemetryConfig {
+            otlp_endpoint: format!("http://{}/", addr),
+            service_name: "collector-test".into(),
+            sample_ratio: 1.0,
+        })
+        .unwrap();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(telemetry.tracer()));
+        tracing::subscriber::with_default(subscriber, || {
+            let origin = tracing::info_span!("event").in_scope(Origin::current);
+            assert!(origin.span.is_valid());
+            let span = origin.deliver_span("client-a");
+            origin.delivered(&span);
+            // the delivery belongs to the trace of the event
+            assert_eq!(span.context().span().span_context().trace_id(), origin.span.trace_id());
+        });
+        // flushing blocks until the collector, which runs on this thread, has answered
+        actix_web::rt::task::spawn_blocking(move || {
+            telemetry.flush();
+            drop(telemetry);
+        })
+        .await
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        assert!(!received.is_empty());
+        assert!(received
+            .iter()
+            .all(|(content_type, _)| content_type == "application/x-protobuf"));
+        // the batch processor may export the spans in more than one request
+        let contains = |needle: &str| {
+            received
+                .iter()
+                .any(|(_, body)| body.windows(needle.len()).any(|window| window == needle.as_bytes()))
+        };
+        assert!(contains("collector-test"));
+        assert!(contains("event"));
+        assert!(contains("deliver"));
+        assert!(contains("latency_ms"));
+    }
+
+    #[test]
+    fn sample_ratio() {
+        let config: TelemetryConfig = serde_yaml::from_str("{otlpEndpoint: 'http://localhost:4318'}").unwrap();
+        assert_eq!(config.sample_ratio, 1.0);
+        assert!(config.validate().is_ok());
+        let config = TelemetryConfig {
+            sample_ratio: 1.5,
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::scope::Identity;
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::{
+    internal::pemfile,
+    sign::{self, CertifiedKey},
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert,
+    RootCertStore, ServerConfig, Session,
+};
+use std::{
+    any::Any,
+    fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle to verify client certificates against
+    pub client_ca: Option<PathBuf>,
+    /// reject connections without a client certificate instead of falling back to bearer tokens
+    pub require_client_cert: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("Could not read \"{}\": {:?}", _0.display(), _1)]
+    Read(PathBuf, #[source] io::Error),
+    #[error("No certificate found in \"{}\"", _0.display())]
+    MissingCertificate(PathBuf),
+    #[error("No usable private key found in \"{}\"", _0.display())]
+    InvalidKey(PathBuf),
+    #[error("Invalid CA certificate in \"{}\"", _0.display())]
+    InvalidCa(PathBuf),
+}
+
+/// verified client certificate, available in the request's extensions
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub Identity);
+
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, TlsError> {
+    let verifier = match &config.client_ca {
+        None => NoClientAuth::new(),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (valid, _) = roots
+                .add_pem_file(&mut open(path)?)
+                .map_err(|_| TlsError::InvalidCa(path.clone()))?;
+            if valid == 0 {
+                return Err(TlsError::InvalidCa(path.clone()));
+            }
+            match config.require_client_cert {
+                true => AllowAnyAuthenticatedClient::new(roots),
+                false => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            }
+        }
+    };
+    let mut server = ServerConfig::new(verifier);
+    server.cert_resolver = Arc::new(ReloadingCert::new(config.cert.clone(), config.key.clone())?);
+    Ok(server)
+}
+
+/// `HttpServer::on_connect` callback, makes the verified client certificate available to `Bearer`
+pub fn on_connect(conn: &dyn Any, extensions: &mut Extensions) {
+    let tls = match conn.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(tls) => tls,
+        None => return,
+    };
+    let certs = tls.get_ref().1.get_peer_certificates().unwrap_or_default();
+    if let Some(identity) = certs.first().and_then(|cert| identity(&cert.0)) {
+        extensions.insert(ClientCertificate(identity));
+    }
+}
+
+/// same convention as Kubernetes: common name is the subject, organizations are the groups
+fn identity(der: &[u8]) -> Option<Identity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let subject = cert.subject();
+    let name = subject.iter_common_name().next()?.as_str().ok()?;
+    let groups = subject
+        .iter_organization()
+        .filter_map(|org| org.as_str().ok())
+        .map(String::from)
+        .collect();
+    Some(Identity {
+        subject: name.to_string(),
+        groups,
+    })
+}
+
+fn open(path: &Path) -> Result<BufReader<fs::File>, TlsError> {
+    fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| TlsError::Read(path.to_path_buf(), err))
+}
+
+/// serves certificate and key from disk, reloading them whenever a mounted secret is rotated
+struct ReloadingCert {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<(Option<(SystemTime, SystemTime)>, CertifiedKey)>,
+}
+
+impl ReloadingCert {
+    fn new(cert: PathBuf, key: PathBuf) -> Result<Self, TlsError> {
+        let modified = modified(&cert, &key);
+        let certified = load(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            current: RwLock::new((modified, certified)),
+        })
+    }
+
+    fn current(&self) -> CertifiedKey {
+        let modified = modified(&self.cert, &self.key);
+        {
+            let current = self.current.read().expect("Certificate lock poisoned");
+            if modified.is_none() || current.0 == modified {
+                return current.1.clone();
+            }
+        }
+        let mut current = self.current.write().expect("Certificate lock poisoned");
+        match load(&self.cert, &self.key) {
+            Ok(certified) => {
+                tracing::info!(path = %self.cert.display(), "reloaded TLS certificate");
+                *current = (modified, certified);
+            }
+            // keep serving the previous certificate, e.g. while only one of the files has been replaced
+            Err(err) => tracing::error!(error = %err, "could not reload TLS certificate"),
+        }
+        current.1.clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current())
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    Some((modified(cert)?, modified(key)?))
+}
+
+fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = pemfile::certs(&mut open(cert)?).unwrap_or_default();
+    if certs.is_empty() {
+        return Err(TlsError::MissingCertificate(cert.to_path_buf()));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key)?).unwrap_or_default();
+    }
+    let signing_key = keys
+        .first()
+        .and_then(|key| sign::any_supported_type(key).ok())
+        .ok_or_else(|| TlsError::InvalidKey(key.to_path_buf()))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{Certificate, CertificateParams, DnType};
+
+    #[test]
+    fn client_identity() {
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.distinguished_name.push(DnType::OrganizationName, "team-a");
+        let cert = Certificate::from_params(params).unwrap();
+        let identity = identity(&cert.serialize_der().unwrap()).unwrap();
+        assert_eq!(
+            identity,
+            Identity {
+                subject: "alice".into(),
+                groups: vec!["team-a".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn reload() {
+        let dir = std::env::temp_dir().join(format!("big-brother-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("tls.crt"), dir.join("tls.key"));
+        let write = |name: &str| {
+            let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+            fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+            fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+            let modified = SystemTime::now() + std::time::Duration::from_secs(name.len() as u64 * 10);
+            // mtime resolution may be too coarse to notice the rewrite
+            for path in &[&cert_path, &key_path] {
+                fs::File::options()
+                    .write(true)
+                    .open(path)
+                    .unwrap()
+                    .set_modified(modified)
+                    .unwrap();
+            }
+        };
+        write("first");
+        let resolver = ReloadingCert::new(cert_path.clone(), key_path.clone()).unwrap();
+        let first = resolver.current().cert;
+        assert_eq!(resolver.current().cert, first);
+
+        write("second");
+        let second = resolver.current().cert;
+        assert_ne!(second, first);
+
+        // a broken key keeps the previous certificate
+        fs::write(&key_path, "garbage").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&key_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(resolver.current().cert, second);
+    }
+}
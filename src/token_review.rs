@@ -0,0 +1,275 @@
+use crate::{
+    engine::ResourceTypes,
+    k8s_client::{
+        api::{ResourceAttributes, ResourceId, SubjectAccessReviewCreator, TokenReviewCreator, UserInfo},
+        K8sClient, K8sClientError,
+    },
+};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// how long authentication results and access decisions are reused before asking the API server again
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// expired entries are only purged once a cache grows beyond this
+const CACHE_PURGE_THRESHOLD: usize = 10_000;
+/// a review still failing after this is reported to the client, instead of holding its request until the API server
+/// recovers
+const REVIEW_DEADLINE: Duration = Duration::from_secs(5);
+
+/// authenticates clients with `TokenReview` and authorizes them with `SubjectAccessReview`,
+/// so big-brother enforces the same RBAC rules as the API server
+#[derive(Debug)]
+pub struct TokenReviewer {
+    client: K8sClient,
+    types: ResourceTypes,
+    tokens: TtlCache<String, Option<UserInfo>>,
+    decisions: TtlCache<(UserInfo, ResourceAttributes), bool>,
+}
+
+/// client authenticated by `TokenReviewer`
+#[derive(Debug, Clone)]
+pub struct ReviewedUser {
+    reviewer: Arc<TokenReviewer>,
+    pub user: UserInfo,
+}
+
+impl TokenReviewer {
+    /// reviews don't wait for the engine's rate limiter, a client shouldn't be delayed by startup lists
+    pub fn new(client: K8sClient, types: ResourceTypes) -> Self {
+        Self {
+            client: client.bounded(REVIEW_DEADLINE),
+            types,
+            tokens: TtlCache::default(),
+            decisions: TtlCache::default(),
+        }
+    }
+
+    /// `Ok(None)` means the token is not valid
+    pub async fn authenticate(self: &Arc<Self>, token: &str) -> Result<Option<ReviewedUser>, K8sClientError> {
+        let user = match self.tokens.get(token) {
+            Some(user) => user,
+            None => {
+                let review = self.client.get(&TokenReviewCreator { token }).await?;
+                if let Some(error) = &review.status.error {
+                    tracing::warn!(error = error.as_str(), "TokenReview returned an error");
+                }
+                let user = match review.status.authenticated {
+                    true => review.status.user,
+                    false => None,
+                };
+                self.tokens.insert(token.to_string(), user.clone());
+                user
+            }
+        };
+        Ok(user.map(|user| ReviewedUser {
+            reviewer: Arc::clone(self),
+            user,
+        }))
+    }
+
+    /// an `Err` is neither an allow nor a deny, the API server couldn't be asked
+    async fn authorize(&self, user: &UserInfo, verb: &str, res: &ResourceId) -> Result<bool, K8sClientError> {
+        let (group, resource) = match self.types.get(&res.api_version, &res.kind) {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+        let attributes = ResourceAttributes {
+            namespace: res.namespace.clone().unwrap_or_default(),
+            verb: verb.to_string(),
+            group,
+            resource,
+        };
+        let key = (user.clone(), attributes);
+        if let Some(allowed) = self.decisions.get(&key) {
+            return Ok(allowed);
+        }
+        let review = self
+            .client
+            .get(&SubjectAccessReviewCreator {
+                user,
+                attributes: &key.1,
+            })
+            .await;
+        match review {
+            Ok(review) => {
+                if let Some(error) = &review.status.evaluation_error {
+                    tracing::warn!(error = error.as_str(), "SubjectAccessReview evaluation error");
+                }
+                if !review.status.allowed {
+                    tracing::info!(
+                        user = ?user.username,
+                        verb,
+                        resource = ?key.1.resource,
+                        namespace = ?key.1.namespace,
+                        reason = review.status.reason.as_deref().unwrap_or_default(),
+                        "access denied by SubjectAccessReview"
+                    );
+                }
+                self.decisions.insert(key, review.status.allowed);
+                Ok(review.status.allowed)
+            }
+            Err(err) => {
+                // not cached, so the next object of this type asks again
+                tracing::error!(error_kind = err.kind(), error = %err, "SubjectAccessReview failed");
+                Err(err)
+            }
+        }
+    }
+}
+
+impl ReviewedUser {
+    pub async fn allows(&self, verb: &str, res: &ResourceId) -> Result<bool, K8sClientError> {
+        self.reviewer.authorize(&self.user, verb, res).await
+    }
+}
+
+#[derive(Debug)]
+struct TtlCache<K, V>(Mutex<HashMap<K, (V, Instant)>>);
+
+impl<K, V> Default for TtlCache<K, V> {
+    fn default() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    fn get<Q: Eq + Hash + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+    {
+        let map = self.0.lock().expect("Cache lock poisoned");
+        match map.get(key) {
+            Some((value, inserted)) if inserted.elapsed() < CACHE_TTL => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: K, value: V) {
+        let mut map = self.0.lock().expect("Cache lock poisoned");
+        if map.len() >= CACHE_PURGE_THRESHOLD {
+            map.retain(|_, (_, inserted)| inserted.elapsed() < CACHE_TTL);
+        }
+        map.insert(key, (value, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+
+    /// stands in for the API server: "good" token belongs to "alice" who may watch pods in "team-a"
+    async fn token_review(body: web::Json<Value>) -> HttpResponse {
+        let status = match body["spec"]["token"].as_str() {
+            Some("good") => json!({"authenticated": true, "user": {"username": "alice", "groups": ["team-a"]}}),
+            _ => json!({"authenticated": false}),
+        };
+        HttpResponse::Created().json(json!({ "status": status }))
+    }
+
+    async fn subject_access_review(body: web::Json<Value>) -> HttpResponse {
+        let attributes = &body["spec"]["resourceAttributes"];
+        let allowed = body["spec"]["user"] == "alice"
+            && attributes["namespace"] == "team-a"
+            && attributes["group"] == ""
+            && attributes["resource"] == "pods"
+            && attributes["verb"] == "watch";
+        HttpResponse::Created().json(json!({ "status": { "allowed": allowed } }))
+    }
+
+    fn pod(namespace: &str) -> ResourceId {
+        ResourceId {
+            api_version: "v1".into(),
+            kind: "Pod".into(),
+            name: "p".into(),
+            namespace: Some(namespace.into()),
+        }
+    }
+
+    #[test]
+    fn review() {
+        actix_web::rt::System::new().block_on(review_inner());
+    }
+
+    async fn review_inner() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/apis/authentication.k8s.io/v1/tokenreviews",
+                    web::post().to(token_review),
+                )
+                .route(
+                    "/apis/authorization.k8s.io/v1/subjectaccessreviews",
+                    web::post().to(subject_access_review),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let types = ResourceTypes::default();
+        types.insert("v1".into(), "Pod".into(), "pods".into());
+        let client = K8sClient::for_test(&format!("http://{}", addr));
+        let reviewer = Arc::new(TokenReviewer::new(client, types));
+
+        assert!(reviewer.authenticate("bad").await.unwrap().is_none());
+        let alice = reviewer.authenticate("good").await.unwrap().unwrap();
+        assert_eq!(alice.user.username, "alice");
+        assert!(alice.allows("watch", &pod("team-a")).await.unwrap());
+        assert!(!alice.allows("watch", &pod("team-b")).await.unwrap());
+        assert!(!alice.allows("list", &pod("team-a")).await.unwrap());
+        // unknown types are never allowed
+        let node = ResourceId {
+            kind: "Node".into(),
+            namespace: None,
+            ..pod("")
+        };
+        assert!(!alice.allows("watch", &node).await.unwrap());
+    }
+
+    /// an API server that can't review anything, a decision must neither be made nor cached
+    #[test]
+    fn review_unavailable() {
+        actix_web::rt::System::new().block_on(review_unavailable_inner());
+    }
+
+    async fn review_unavailable_inner() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/apis/authentication.k8s.io/v1/tokenreviews",
+                    web::post().to(token_review),
+                )
+                .route(
+                    "/apis/authorization.k8s.io/v1/subjectaccessreviews",
+                    web::post().to(HttpResponse::ServiceUnavailable),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(async { server.run().await.unwrap() });
+
+        let types = ResourceTypes::default();
+        types.insert("v1".into(), "Pod".into(), "pods".into());
+        let client = K8sClient::for_test(&format!("http://{}", addr));
+        let reviewer = Arc::new(TokenReviewer {
+            client: client.bounded(Duration::from_millis(200)),
+            ..TokenReviewer::new(client, types)
+        });
+
+        let alice = reviewer.authenticate("good").await.unwrap().unwrap();
+        let started = Instant::now();
+        assert!(alice.allows("watch", &pod("team-a")).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(reviewer.decisions.0.lock().unwrap().is_empty());
+    }
+}
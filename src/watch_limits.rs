@@ -0,0 +1,215 @@
+use crate::rate_limit::RateLimiter;
+use actix_web::{http, HttpResponse, ResponseError};
+use reqwest::StatusCode;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// clients are only forgotten once there are more than this many, and only those without open watches
+const CLIENTS_PURGE_THRESHOLD: usize = 10_000;
+
+/// `0` disables the respective limit
+#[derive(Debug, Clone)]
+pub struct WatchLimitsConfig {
+    /// concurrent watches per client
+    pub max_connections: usize,
+    /// sustained new watches per second per client
+    pub reconnect_qps: f64,
+    pub reconnect_burst: u32,
+    /// delivery rate per client, shared by all of its watches
+    pub bytes_per_second: u32,
+    /// concurrent watches without `resourceVersion`, which replay every cached object
+    pub max_concurrent_replays: usize,
+}
+
+/// per-client limits on `/watch`, clients are identified by their token name or, without one, their address
+#[derive(Debug, Clone)]
+pub struct WatchLimits {
+    config: WatchLimitsConfig,
+    clients: Arc<Mutex<HashMap<String, Arc<ClientState>>>>,
+    replays: Option<Arc<Semaphore>>,
+}
+
+#[derive(Debug)]
+struct ClientState {
+    connections: AtomicUsize,
+    reconnects: Option<RateLimiter>,
+    bytes: Option<RateLimiter>,
+}
+
+/// admitted watch, counts against the client's connections until dropped
+#[derive(Debug)]
+pub struct WatchPermit {
+    client: Arc<ClientState>,
+    /// held while the backlog is replayed
+    pub replay: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitExceeded {
+    Reconnects(Duration),
+    Connections,
+    Replays,
+}
+
+impl LimitExceeded {
+    /// whole seconds for `Retry-After`, rounded up
+    fn retry_after(&self) -> u64 {
+        match self {
+            Self::Reconnects(wait) => wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+            Self::Connections | Self::Replays => 1,
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reconnects(_) => write!(f, "too many reconnects"),
+            Self::Connections => write!(f, "too many concurrent watches"),
+            Self::Replays => write!(f, "too many concurrent watches without resourceVersion"),
+        }
+    }
+}
+
+impl ResponseError for LimitExceeded {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header((http::header::RETRY_AFTER, self.retry_after()))
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+impl WatchLimits {
+    pub fn new(config: WatchLimitsConfig) -> Self {
+        let replays = match config.max_concurrent_replays {
+            0 => None,
+            n => Some(Arc::new(Semaphore::new(n))),
+        };
+        Self {
+            config,
+            clients: Arc::default(),
+            replays,
+        }
+    }
+
+    /// `replay` means the watch starts without `resourceVersion`
+    pub fn admit(&self, client: &str, replay: bool) -> Result<WatchPermit, LimitExceeded> {
+        let state = self.client(client);
+        if let Some(reconnects) = &state.reconnects {
+            reconnects.try_acquire().map_err(LimitExceeded::Reconnects)?;
+        }
+        let replay = match (&self.replays, replay) {
+            (Some(replays), true) => Some(
+                Arc::clone(replays)
+                    .try_acquire_owned()
+                    .map_err(|_| LimitExceeded::Replays)?,
+            ),
+            _ => None,
+        };
+        let connections = state.connections.fetch_add(1, Ordering::SeqCst) + 1;
+        // from here on, dropping the permit gives the connection back
+        let permit = WatchPermit { client: state, replay };
+        if self.config.max_connections != 0 && connections > self.config.max_connections {
+            return Err(LimitExceeded::Connections);
+        }
+        Ok(permit)
+    }
+
+    fn client(&self, client: &str) -> Arc<ClientState> {
+        let mut clients = self.clients.lock().expect("Watch limits lock poisoned");
+        if let Some(state) = clients.get(client) {
+            return Arc::clone(state);
+        }
+        if clients.len() >= CLIENTS_PURGE_THRESHOLD {
+            clients.retain(|_, state| state.connections.load(Ordering::SeqCst) > 0);
+        }
+        let config = &self.config;
+        let state = Arc::new(ClientState {
+            connections: AtomicUsize::new(0),
+            reconnects: Some(config.reconnect_qps)
+                .filter(|qps| *qps > 0.0)
+                .map(|qps| RateLimiter::new(qps, config.reconnect_burst)),
+            bytes: Some(config.bytes_per_second)
+                .filter(|bps| *bps > 0)
+                .map(|bps| RateLimiter::new(f64::from(bps), bps)),
+        });
+        clients.insert(client.to_string(), Arc::clone(&state));
+        state
+    }
+}
+
+impl WatchPermit {
+    /// waits until the client may receive another `bytes`
+    pub async fn throttle(&self, bytes: usize) {
+        if let Some(limiter) = &self.client.bytes {
+            limiter.acquire_many(bytes.min(u32::MAX as usize) as u32).await;
+        }
+    }
+}
+
+impl Drop for WatchPermit {
+    fn drop(&mut self) {
+        self.client.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> WatchLimits {
+        WatchLimits::new(WatchLimitsConfig {
+            max_connections: 2,
+            reconnect_qps: 0.001,
+            reconnect_burst: 3,
+            bytes_per_second: 0,
+            max_concurrent_replays: 1,
+        })
+    }
+
+    #[test]
+    fn connections() {
+        let limits = limits();
+        let first = limits.admit("a", false).unwrap();
+        let _second = limits.admit("a", false).unwrap();
+        assert_eq!(limits.admit("a", false).unwrap_err(), LimitExceeded::Connections);
+        // other clients are not affected
+        assert!(limits.admit("b", false).is_ok());
+        drop(first);
+        // every attempt counts as a reconnect, rejected or not
+        assert!(matches!(
+            limits.admit("a", false).unwrap_err(),
+            LimitExceeded::Reconnects(wait) if wait > Duration::from_secs(900)
+        ));
+    }
+
+    #[test]
+    fn replays() {
+        let limits = limits();
+        let mut first = limits.admit("a", true).unwrap();
+        assert_eq!(limits.admit("b", true).unwrap_err(), LimitExceeded::Replays);
+        assert!(limits.admit("b", false).is_ok());
+        // released once the backlog has been delivered
+        first.replay.take();
+        assert!(limits.admit("b", true).is_ok());
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(LimitExceeded::Reconnects(Duration::from_millis(1500)).retry_after(), 2);
+        assert_eq!(LimitExceeded::Reconnects(Duration::from_secs(3)).retry_after(), 3);
+    }
+}